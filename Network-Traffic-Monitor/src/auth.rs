@@ -0,0 +1,35 @@
+/// Authorizationヘッダの値が `Bearer <token>` で、トークンが一致するか
+///
+/// 認証方式の名前は大文字小文字を区別しない（RFC 7235）。応答時間からトークンを推測されないよう、
+/// トークンは一致しない位置にかかわらず全体を比較する。
+pub fn bearer_token_matches(authorization: Option<&str>, token: &str) -> bool {
+    let Some((scheme, credentials)) = authorization.and_then(|value| value.trim().split_once(' '))
+    else {
+        return false;
+    };
+    scheme.eq_ignore_ascii_case("Bearer") && constant_time_eq(credentials.trim(), token)
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_matching_bearer_token_is_accepted() {
+        assert!(bearer_token_matches(Some("Bearer s3cret"), "s3cret"));
+        assert!(bearer_token_matches(Some("bearer  s3cret "), "s3cret"));
+        assert!(!bearer_token_matches(Some("Bearer s3cre"), "s3cret"));
+        assert!(!bearer_token_matches(Some("Bearer s3cretx"), "s3cret"));
+        assert!(!bearer_token_matches(Some("Basic czNjcmV0"), "s3cret"));
+        assert!(!bearer_token_matches(Some("s3cret"), "s3cret"));
+        assert!(!bearer_token_matches(None, "s3cret"));
+    }
+}
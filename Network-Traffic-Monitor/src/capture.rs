@@ -1,24 +1,97 @@
-use crate::prometheus_server::start_prometheus_server;
-use crate::stats::IpStatsMap;
+use crate::gre::parse_gre;
+use crate::http::{parse_http_request, HttpRequestSummary, HTTP_PORT};
+use crate::tls::{parse_tls_client_hello, TLS_PORT};
+use crate::ring::{PacketRing, PacketRingHandle, DEFAULT_CAPTURE_RING};
+use crate::prometheus_server::{push_metrics_periodically, start_prometheus_server, PushgatewayConfig};
+use crate::stats::{
+    ranked_ips, top_ips, IpStats, IpStatsMap, MetricsSnapshot, ProtocolStats, TalkerSnapshot,
+    TrafficStatistics,
+};
+use crate::timeseries::{TimeSeriesHandle, TimeSeriesStore};
 use anyhow::{Context, Result};
-use log::{error, info, warn};
-use pnet::datalink::{self, NetworkInterface};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::ipv4::Ipv4Packet;
+use log::{debug, error, info, warn};
+use pnet::datalink::{self, DataLinkReceiver, MacAddr, NetworkInterface};
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::Packet;
 use prometheus::Registry;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::IpAddr;
 use std::sync::mpsc;
-use pnet::packet::tcp::TcpPacket;
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio::time;
 
+/// ロスイベント配信チャネルのバッファ数（遅い購読者はこれを超えると取りこぼす）
+const LOSS_EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// キャプチャソケットの読み取りバッファの既定サイズ（GRO/LROで結合された最大64KiBのフレームを収める）
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 65536;
+
+/// キャプチャスレッドから処理ループへのキューの既定の容量（パケット数）
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 65536;
+
+/// キャプチャエラー後に最初の再接続を試みるまでの待ち時間（失敗するごとに倍にする）
+const CAPTURE_RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// 再接続の待ち時間の上限
+const CAPTURE_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// 入れ子のトンネルを辿る最大の深さ
+const MAX_TUNNEL_DEPTH: usize = 4;
+
+/// 最後のパケットからこの時間内の接続をアクティブとみなす（過ぎた接続の状態は定期的に削除する）
+const CONNECTION_ACTIVE_WINDOW: Duration = Duration::from_secs(60);
+
+/// 解析できなかったパケットの警告を出す最短の間隔
+const UNPARSED_WARN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 個別に出力しない系列（`--metrics-only-subnet` の範囲外のIP、上限を超えたSNI・HTTPのHost）をまとめるラベル値
+const OTHER_LABEL: &str = "_other";
+
+/// 欠損とみなすシーケンスギャップの既定の下限（バイト）
+///
+/// 送信側は受信側のウィンドウを超えて先行送信できないため、それより大きなギャップは
+/// 実際の欠損ではなく、途中から観測し始めた接続・シーケンスのリセット・破損した
+/// パケットなどによるものとみなして無視する。
+pub const DEFAULT_MAX_PLAUSIBLE_GAP: u32 = 1_000_000;
+
+/// 受信側のウィンドウの何倍までのギャップを欠損として扱うか
+const WINDOW_GAP_FACTOR: u32 = 2;
+
+/// 接続横断のロス率に含める接続の既定の最小パケット数
+pub const DEFAULT_MIN_PACKETS_FOR_LOSS: u64 = 10;
+
+/// `tcp_monitor_connections_loss_cdf` のしきい値（ロス率、%）
+const LOSS_CDF_THRESHOLDS: &[f64] = &[0.0, 0.1, 1.0, 5.0, 10.0];
+
+/// 接続の継続時間ヒストグラムのバケット（秒）。短いAPI呼び出しから長時間のストリーミングまでを区別する
+const CONNECTION_DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// MSSが分からない接続で欠損セグメント数の推定に使う値
+const DEFAULT_MSS: u32 = 1460;
+
+/// 定期ログに出力するHTTPの上位Host数
+const TOP_HTTP_HOSTS: usize = 5;
+
+/// 定期ログに出力するTLSの上位SNI数
+const TOP_TLS_SNIS: usize = 5;
+
+/// `tls_sni_total` で個別のラベルとして扱うSNIの上限（超えた分は `_other` に集約）
+const MAX_TLS_SNI_LABELS: usize = 1000;
+
+/// `http_host_requests` で個別に数えるHostの上限（超えた分は `_other` に集約）
+const MAX_HTTP_HOSTS: usize = 1000;
+
 /// パケット情報を格納する構造体
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PacketInfo {
     pub protocol: String,
     pub size: u64,
@@ -34,49 +107,238 @@ pub struct PacketInfo {
 pub struct TcpConnectionState {
     pub expected_seq: u32,
     pub total_packets: u64,
+    /// 欠損したと推定されるセグメント数
     pub lost_packets: u64,
     pub last_active: std::time::Instant,
+    /// 最初のデータセグメントを観測した時刻（継続時間ヒストグラム用）
+    pub created_at: std::time::Instant,
+    /// 欠損セグメント数の推定に使うMSS（SYNのオプションまたは観測した最大ペイロード長）
+    pub mss: u32,
+    /// 受信側（逆方向のパケット）が広告した最大ウィンドウサイズ
+    pub receiver_window: u32,
+}
+
+/// 検出されたパケットロスのイベント（WebSocketでJSONとして配信する）
+#[derive(Debug, Clone, Serialize)]
+pub struct PacketLossEvent {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub expected_seq: u32,
+    pub received_seq: u32,
+    pub gap_size: u32,
 }
 
 impl TcpConnectionState {
-    pub fn new(seq_num: u32, payload_len: u32) -> Self {
+    pub fn new(seq_num: u32, payload_len: u32, mss: u32, flags: u8) -> Self {
+        let now = std::time::Instant::now();
         Self {
-            expected_seq: seq_num.wrapping_add(payload_len),
+            expected_seq: seq_num.wrapping_add(sequence_length(payload_len, flags)),
             total_packets: 1,
             lost_packets: 0,
-            last_active: std::time::Instant::now(),
+            last_active: now,
+            created_at: now,
+            mss: mss.max(payload_len),
+            receiver_window: 0,
+        }
+    }
+
+    /// 欠損とみなすギャップの上限（`configured` 未指定時は受信側のウィンドウから決める）
+    pub fn max_plausible_gap(&self, configured: Option<u32>) -> u32 {
+        configured.unwrap_or_else(|| {
+            self.receiver_window
+                .saturating_mul(WINDOW_GAP_FACTOR)
+                .max(DEFAULT_MAX_PLAUSIBLE_GAP)
+        })
+    }
+
+    /// 後続のセグメントを反映し、シーケンスの欠損があればそのバイト数を返す
+    pub fn observe_segment(
+        &mut self,
+        seq_num: u32,
+        payload_len: u32,
+        flags: u8,
+        max_plausible_gap: u32,
+    ) -> Option<u32> {
+        self.last_active = std::time::Instant::now();
+        self.total_packets += 1;
+        self.mss = self.mss.max(payload_len);
+
+        let mut lost_gap = None;
+        if seq_after(seq_num, self.expected_seq) {
+            let gap = seq_num.wrapping_sub(self.expected_seq);
+            if gap > 0 && gap < max_plausible_gap {
+                self.lost_packets += lost_segments(gap, self.mss);
+                lost_gap = Some(gap);
+            }
+        }
+        // 遅れて届いたセグメントや再送で期待値を巻き戻すと、次のセグメントを欠損と誤検出する
+        let segment_end = seq_num.wrapping_add(sequence_length(payload_len, flags));
+        if seq_after(segment_end, self.expected_seq) {
+            self.expected_seq = segment_end;
+        }
+        lost_gap
+    }
+}
+
+/// シーケンス番号 `a` が `b` より後か（RFC 1982のシリアル番号演算で2^32の折り返しを考慮）
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// セグメントが消費するシーケンス番号の長さ（SYNとFINはペイロードとは別にそれぞれ1つ消費する）
+///
+/// TCP Fast OpenのようにSYNにデータを載せる接続では、これを数えないと次のセグメントを1バイトの欠損と誤検出する。
+fn sequence_length(payload_len: u32, flags: u8) -> u32 {
+    let syn = (flags & TcpFlags::SYN != 0) as u32;
+    let fin = (flags & TcpFlags::FIN != 0) as u32;
+    payload_len.wrapping_add(syn + fin)
+}
+
+/// 欠損バイト数をMSSで割って欠損セグメント数を推定する（端数は1セグメントとして数える）
+fn lost_segments(gap: u32, mss: u32) -> u64 {
+    gap.div_ceil(mss.max(1)) as u64
+}
+
+/// SYNセグメントのMSSオプションを取り出す
+fn syn_mss_option(tcp_packet: &TcpPacket) -> Option<u32> {
+    tcp_packet
+        .get_options_iter()
+        .find(|option| option.get_number() == TcpOptionNumbers::MSS)
+        .and_then(|option| match option.payload() {
+            [high, low] => Some(u16::from_be_bytes([*high, *low]) as u32),
+            _ => None,
+        })
+        .filter(|mss| *mss > 0)
+}
+
+/// 解析できなかったパケットを間隔ごとにまとめて警告するための集計
+#[derive(Debug, Default)]
+struct UnparsedPacketLog {
+    count: u64,
+    // 間隔内で最初に見つかった未対応のEtherType
+    sample_ethertype: Option<EtherType>,
+    last_warned: Option<std::time::Instant>,
+}
+
+impl UnparsedPacketLog {
+    /// 解析できなかったパケットを記録し、警告を出す時刻であれば件数とサンプルを返して集計をリセットする
+    fn record(
+        &mut self,
+        ethertype: Option<EtherType>,
+        now: std::time::Instant,
+    ) -> Option<(u64, Option<EtherType>)> {
+        self.count += 1;
+        if self.sample_ethertype.is_none() {
+            self.sample_ethertype = ethertype;
+        }
+        if self
+            .last_warned
+            .is_some_and(|last| now.duration_since(last) < UNPARSED_WARN_INTERVAL)
+        {
+            return None;
         }
+        self.last_warned = Some(now);
+        Some((
+            std::mem::take(&mut self.count),
+            self.sample_ethertype.take(),
+        ))
+    }
+}
+
+/// 通常のLANで常に流れるL2制御フレームか（ARP・LLDP・802.1X・LACPなど、802.3の長さフィールドのフレームを含む）
+///
+/// 解析対象外なのは正常なため、未対応のEtherTypeとして数えるが警告は出さない。
+fn is_routine_l2_ethertype(ethertype: EtherType) -> bool {
+    // 0x05DC以下はEtherTypeではなく802.3の長さフィールド（STP・CDPなどのLLCフレーム）
+    ethertype.0 <= 0x05dc
+        || matches!(
+            ethertype,
+            EtherTypes::Arp | EtherTypes::Rarp | EtherTypes::Lldp | EtherTypes::PppoeDiscovery
+        )
+        // 802.1X (EAPOL)・Slow Protocols (LACP)・PTP・Wake-on-LAN
+        || matches!(ethertype.0, 0x888e | 0x8809 | 0x88f7 | 0x0842)
+}
+
+/// `--exclude-ip` / `--exclude-port` に一致するパケットを解析前に除外する条件
+#[derive(Debug, Clone, Default)]
+pub struct PacketExclusions {
+    pub ips: Vec<IpAddr>,
+    pub ports: Vec<u16>,
+}
+
+impl PacketExclusions {
+    /// 送信元・宛先のいずれかのIPアドレスまたはTCP/UDPポートが除外対象か
+    fn matches(&self, src_ip: IpAddr, dst_ip: IpAddr, ports: Option<(u16, u16)>) -> bool {
+        self.ips.contains(&src_ip)
+            || self.ips.contains(&dst_ip)
+            || ports.is_some_and(|(src_port, dst_port)| {
+                self.ports.contains(&src_port) || self.ports.contains(&dst_port)
+            })
+    }
+}
+
+/// TCP/UDPヘッダの先頭4バイトから送信元・宛先ポートを取り出す
+fn transport_ports(protocol: IpNextHeaderProtocol, payload: &[u8]) -> Option<(u16, u16)> {
+    if protocol != IpNextHeaderProtocols::Tcp && protocol != IpNextHeaderProtocols::Udp {
+        return None;
     }
+    let header = payload.get(..4)?;
+    Some((
+        u16::from_be_bytes([header[0], header[1]]),
+        u16::from_be_bytes([header[2], header[3]]),
+    ))
+}
+
+/// キャプチャソケットの設定
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// 読み取りバッファサイズ（バイト、これを超えるフレームは切り詰められる）
+    pub read_buffer_size: usize,
+    /// falseの場合はこのホスト宛て・発のフレームのみを受け取る（`--no-promisc`）
+    pub promiscuous: bool,
 }
 
 /// パケットキャプチャを管理する構造体
 pub struct PacketCapture {
     interface: NetworkInterface,
-    packet_sender: mpsc::Sender<PacketInfo>,
+    packet_sender: mpsc::SyncSender<PacketInfo>,
     metrics: Arc<std::sync::Mutex<NetworkMetrics>>,
     traffic_stats: Arc<std::sync::Mutex<TrafficStats>>,
     ip_stats: IpStatsMap,
+    loss_events: broadcast::Sender<PacketLossEvent>,
+    socket: SocketOptions,
+    exclusions: PacketExclusions,
+    unparsed_log: std::sync::Mutex<UnparsedPacketLog>,
+    packet_ring: PacketRingHandle,
+    // パケットごとにメトリクスのロックを取らないよう、カウンタのハンドルを保持する
+    captured_packets_total: prometheus::Counter,
 }
 
 impl PacketCapture {
     /// 新しいPacketCaptureインスタンスを作成
     pub fn new(
         interface_name: &str,
-        packet_sender: mpsc::Sender<PacketInfo>,
-        local_ip: Option<IpAddr>,
-        local_subnet: Option<Ipv4Addr>,
+        packet_sender: mpsc::SyncSender<PacketInfo>,
+        local_addresses: &[IpNetwork],
+        socket: SocketOptions,
+        exclusions: PacketExclusions,
+        capture_ring: usize,
+        metric_prefix: Option<&str>,
     ) -> Result<Self> {
         let interface = find_interface(interface_name)
             .context(format!("Failed to find interface: {}", interface_name))?;
 
-        let metrics = Arc::new(std::sync::Mutex::new(NetworkMetrics::new(
-            local_ip,
-            local_subnet,
-        )));
+        let network_metrics = NetworkMetrics::new(local_addresses, metric_prefix);
+        let captured_packets_total = network_metrics.captured_packets_total.clone();
+        let metrics = Arc::new(std::sync::Mutex::new(network_metrics));
         let traffic_stats = Arc::new(std::sync::Mutex::new(TrafficStats::new(
             Duration::from_secs(10),
         )));
         let ip_stats = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let (loss_events, _) = broadcast::channel(LOSS_EVENT_CHANNEL_CAPACITY);
 
         Ok(Self {
             interface,
@@ -84,6 +346,12 @@ impl PacketCapture {
             metrics,
             traffic_stats,
             ip_stats,
+            loss_events,
+            socket,
+            exclusions,
+            unparsed_log: std::sync::Mutex::new(UnparsedPacketLog::default()),
+            packet_ring: Arc::new(std::sync::Mutex::new(PacketRing::new(capture_ring))),
+            captured_packets_total,
         })
     }
 
@@ -97,6 +365,16 @@ impl PacketCapture {
         self.ip_stats.clone()
     }
 
+    /// 直近のパケットのリングバッファへの参照を取得
+    pub fn get_packet_ring(&self) -> PacketRingHandle {
+        self.packet_ring.clone()
+    }
+
+    /// ロスイベント配信チャネルの送信側を取得（購読は `subscribe()` で行う）
+    pub fn get_loss_events(&self) -> broadcast::Sender<PacketLossEvent> {
+        self.loss_events.clone()
+    }
+
     /// パケットキャプチャを開始
     pub fn start_capture(&self) -> Result<()> {
         info!(
@@ -104,24 +382,7 @@ impl PacketCapture {
             self.interface.name
         );
 
-        // データリンクチャネルを作成
-        let config = datalink::Config {
-            write_buffer_size: 4096,
-            read_buffer_size: 4096,
-            read_timeout: Some(Duration::from_millis(100)),
-            write_timeout: None,
-            channel_type: datalink::ChannelType::Layer2,
-            bpf_fd_attempts: 1000,
-            linux_fanout: None,
-            promiscuous: true,
-            socket_fd: None,
-        };
-
-        let (_, mut rx) = match datalink::channel(&self.interface, config) {
-            Ok(datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => return Err(anyhow::anyhow!("Unhandled channel type")),
-            Err(e) => return Err(anyhow::anyhow!("Failed to create datalink channel: {}", e)),
-        };
+        let mut rx = self.open_receiver(&self.interface)?;
 
         // パケット処理ループ
         loop {
@@ -143,8 +404,11 @@ impl PacketCapture {
 
                         // debug!("Captured packet: {:?}", packet_info);
 
-                        if let Err(e) = self.packet_sender.send(packet_info) {
-                            error!("Failed to send packet info: {}", e);
+                        if let Ok(mut ring) = self.packet_ring.lock() {
+                            ring.push(packet_info.clone());
+                        }
+
+                        if !self.forward_packet(packet_info) {
                             break;
                         }
                     }
@@ -173,24 +437,7 @@ impl PacketCapture {
             self.interface.name
         );
 
-        // データリンクチャネルを作成
-        let config = datalink::Config {
-            write_buffer_size: 4096,
-            read_buffer_size: 4096,
-            read_timeout: Some(Duration::from_millis(100)),
-            write_timeout: None,
-            channel_type: datalink::ChannelType::Layer2,
-            bpf_fd_attempts: 1000,
-            linux_fanout: None,
-            promiscuous: true,
-            socket_fd: None,
-        };
-
-        let (_, mut rx) = match datalink::channel(&self.interface, config) {
-            Ok(datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => return Err(anyhow::anyhow!("Unhandled channel type")),
-            Err(e) => return Err(anyhow::anyhow!("Failed to create datalink channel: {}", e)),
-        };
+        let mut rx = self.open_receiver(&self.interface)?;
 
         // パケット処理ループ
         loop {
@@ -218,8 +465,11 @@ impl PacketCapture {
 
                         // debug!("Captured packet: {:?}", packet_info);
 
-                        if let Err(e) = self.packet_sender.send(packet_info) {
-                            error!("Failed to send packet info: {}", e);
+                        if let Ok(mut ring) = self.packet_ring.lock() {
+                            ring.push(packet_info.clone());
+                        }
+
+                        if !self.forward_packet(packet_info) {
                             break;
                         }
                     }
@@ -230,7 +480,14 @@ impl PacketCapture {
                     if e.kind() == std::io::ErrorKind::TimedOut {
                         continue;
                     }
-                    return Err(anyhow::anyhow!("Packet capture error: {}", e));
+                    warn!(
+                        "Packet capture error on interface {}: {}, reconnecting",
+                        self.interface.name, e
+                    );
+                    match self.reconnect(&shutdown_flag) {
+                        Some(receiver) => rx = receiver,
+                        None => break,
+                    }
                 }
             }
         }
@@ -238,6 +495,90 @@ impl PacketCapture {
         Ok(())
     }
 
+    /// データリンクチャネルを作成して受信側を返す
+    fn open_receiver(&self, interface: &NetworkInterface) -> Result<Box<dyn DataLinkReceiver>> {
+        let config = datalink::Config {
+            write_buffer_size: 4096,
+            read_buffer_size: self.socket.read_buffer_size,
+            read_timeout: Some(Duration::from_millis(100)),
+            write_timeout: None,
+            channel_type: datalink::ChannelType::Layer2,
+            bpf_fd_attempts: 1000,
+            linux_fanout: None,
+            promiscuous: self.socket.promiscuous,
+            socket_fd: None,
+        };
+
+        match datalink::channel(interface, config) {
+            Ok(datalink::Channel::Ethernet(_, rx)) => Ok(rx),
+            Ok(_) => Err(anyhow::anyhow!("Unhandled channel type")),
+            Err(e) => Err(anyhow::anyhow!("Failed to create datalink channel: {}", e)),
+        }
+    }
+
+    /// インターフェースが再び開けるまで待ち時間を延ばしながら再接続を試みる
+    ///
+    /// USB NICの抜き差しやVMの移行ではインターフェースのインデックスが変わるため、
+    /// 毎回名前から探し直す。シャットダウンが要求された場合はNoneを返す。
+    fn reconnect(
+        &self,
+        shutdown_flag: &std::sync::atomic::AtomicBool,
+    ) -> Option<Box<dyn DataLinkReceiver>> {
+        let mut backoff = CAPTURE_RECONNECT_INITIAL_BACKOFF;
+        loop {
+            // シャットダウンに素早く反応できるよう短い間隔で待つ
+            let wake_at = std::time::Instant::now() + backoff;
+            while std::time::Instant::now() < wake_at {
+                if shutdown_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                    info!("Shutdown signal received, stopping reconnect attempts");
+                    return None;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            let name = &self.interface.name;
+            match find_interface(name).and_then(|interface| self.open_receiver(&interface)) {
+                Ok(receiver) => {
+                    info!("Reconnected packet capture on interface: {}", name);
+                    if let Ok(metrics) = self.metrics.lock() {
+                        metrics.capture_reconnects_total.inc();
+                    }
+                    return Some(receiver);
+                }
+                Err(e) => {
+                    backoff = next_reconnect_backoff(backoff);
+                    warn!(
+                        "Failed to reopen interface {}: {}, retrying in {}s",
+                        name,
+                        e,
+                        backoff.as_secs()
+                    );
+                }
+            }
+        }
+    }
+
+    /// 処理側へパケット情報を渡す（処理側が切断されていればfalse）
+    ///
+    /// キューが満杯の場合はキャプチャスレッドを止めずに新しいパケットを捨て、
+    /// `channel_dropped_total` に数える。捨てたパケットも `captured_packets_total` には含める。
+    fn forward_packet(&self, packet_info: PacketInfo) -> bool {
+        self.captured_packets_total.inc();
+        match self.packet_sender.try_send(packet_info) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                if let Ok(metrics) = self.metrics.lock() {
+                    metrics.channel_dropped_total.inc();
+                }
+                true
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                error!("Failed to send packet info: processing side disconnected");
+                false
+            }
+        }
+    }
+
     /// IPアドレスごとの統計情報を更新
     fn update_ip_stats(&self, packet_info: &PacketInfo) {
         if let Ok(mut ip_stats) = self.ip_stats.lock() {
@@ -257,37 +598,164 @@ impl PacketCapture {
     fn parse_packet(&self, packet: &[u8]) -> Option<PacketInfo> {
         if let Some(ethernet_packet) = EthernetPacket::new(packet) {
             let timestamp = chrono::Utc::now();
-            match ethernet_packet.get_ethertype() {
+            let ethertype = ethernet_packet.get_ethertype();
+            match ethertype {
                 EtherTypes::Ipv4 => {
                     if let Some(ipv4_packet) = Ipv4Packet::new(ethernet_packet.payload()) {
-                        if ipv4_packet.get_next_level_protocol()
-                            == pnet::packet::ip::IpNextHeaderProtocols::Tcp
-                        {
-                            if let Some(tcp_packet) =
-                                pnet::packet::tcp::TcpPacket::new(ipv4_packet.payload())
-                            {
-                                self.detect_packet_loss(&ipv4_packet, &tcp_packet);
-                            }
+                        if self.is_excluded_ipv4(&ipv4_packet) {
+                            return None;
                         }
+                        self.inspect_ipv4_payload(&ipv4_packet, 0);
                         Self::parse_ipv4_packet(timestamp, &ipv4_packet)
                     } else {
+                        self.record_unparsed("truncated_ip", Some(ethertype));
                         None
                     }
                 }
                 EtherTypes::Ipv6 => {
                     if let Some(ipv6_packet) = Ipv6Packet::new(ethernet_packet.payload()) {
+                        if self.is_excluded_ipv6(&ipv6_packet) {
+                            return None;
+                        }
+                        self.inspect_ipv6_payload(&ipv6_packet, 0);
                         Self::parse_ipv6_packet(timestamp, &ipv6_packet)
                     } else {
+                        self.record_unparsed("truncated_ip", Some(ethertype));
                         None
                     }
                 }
-                _ => None,
+                _ => {
+                    self.record_unparsed("unsupported_ethertype", Some(ethertype));
+                    None
+                }
             }
         } else {
+            self.record_unparsed("truncated_ethernet", None);
             None
         }
     }
 
+    /// 解析できなかったパケットを理由別に数え、一定間隔ごとに件数をまとめて警告する
+    ///
+    /// リンク種別の違うインターフェースを指定した場合など、何も集計されない原因を示すため。
+    fn record_unparsed(&self, reason: &str, ethertype: Option<EtherType>) {
+        if let Ok(metrics) = self.metrics.lock() {
+            metrics
+                .unparsed_packets_total
+                .with_label_values(&[reason])
+                .inc();
+        }
+        if let Some(ethertype) = ethertype.filter(|ethertype| is_routine_l2_ethertype(*ethertype)) {
+            debug!("ignoring link-layer control frame (EtherType: 0x{:04x})", ethertype.0);
+            return;
+        }
+        let Ok(mut log) = self.unparsed_log.lock() else {
+            return;
+        };
+        if let Some((count, sample)) = log.record(ethertype, std::time::Instant::now()) {
+            match sample {
+                Some(ethertype) => warn!(
+                    "{} packet(s) could not be parsed as IPv4/IPv6 (sample EtherType: 0x{:04x}); check the interface link type",
+                    count, ethertype.0
+                ),
+                None => warn!(
+                    "{} packet(s) could not be parsed as IPv4/IPv6; check the interface link type",
+                    count
+                ),
+            }
+        }
+    }
+
+    /// `--exclude-ip` / `--exclude-port` の対象のIPv4パケットか
+    fn is_excluded_ipv4(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        // ポートは先頭フラグメントのヘッダからのみ取得できる
+        let ports = (ipv4_packet.get_fragment_offset() == 0)
+            .then(|| {
+                transport_ports(
+                    ipv4_packet.get_next_level_protocol(),
+                    ipv4_packet.payload(),
+                )
+            })
+            .flatten();
+        self.exclusions.matches(
+            IpAddr::V4(ipv4_packet.get_source()),
+            IpAddr::V4(ipv4_packet.get_destination()),
+            ports,
+        )
+    }
+
+    /// `--exclude-ip` / `--exclude-port` の対象のIPv6パケットか
+    fn is_excluded_ipv6(&self, ipv6_packet: &Ipv6Packet) -> bool {
+        // 拡張ヘッダは辿らないため、直後がTCP/UDPの場合のみポートで判定する
+        let ports = transport_ports(ipv6_packet.get_next_header(), ipv6_packet.payload());
+        self.exclusions.matches(
+            IpAddr::V6(ipv6_packet.get_source()),
+            IpAddr::V6(ipv6_packet.get_destination()),
+            ports,
+        )
+    }
+
+    /// IPv4パケットのTCPをロス検出などに渡す（GREの場合は内側のパケットを解析する）
+    fn inspect_ipv4_payload(&self, ipv4_packet: &Ipv4Packet, depth: usize) {
+        let protocol = ipv4_packet.get_next_level_protocol();
+        if protocol != IpNextHeaderProtocols::Tcp && protocol != IpNextHeaderProtocols::Gre {
+            return;
+        }
+
+        // フラグメントは再構築しないため、ロス検出の対象外とする
+        if is_ipv4_fragment(ipv4_packet) {
+            if let Ok(metrics) = self.metrics.lock() {
+                metrics.ipv4_fragments_skipped_total.inc();
+            }
+        } else if protocol == IpNextHeaderProtocols::Gre {
+            self.inspect_gre(ipv4_packet.payload(), depth);
+        } else if let Some(tcp_packet) = TcpPacket::new(ipv4_packet.payload()) {
+            self.detect_packet_loss(ipv4_packet, &tcp_packet);
+            self.inspect_http_request(&tcp_packet);
+            self.inspect_tls_client_hello(&tcp_packet);
+        }
+    }
+
+    /// IPv6パケットがGREの場合は内側のパケットを解析する（IPv6のTCPはロス検出の対象外）
+    fn inspect_ipv6_payload(&self, ipv6_packet: &Ipv6Packet, depth: usize) {
+        if ipv6_packet.get_next_header() == IpNextHeaderProtocols::Gre {
+            self.inspect_gre(ipv6_packet.payload(), depth);
+        }
+    }
+
+    /// GREトンネルの内側のIPv4/IPv6パケットを外側と同じように解析する
+    ///
+    /// 送受信バイト数は回線上の外側のパケットで集計するため、内側はロス検出などにのみ使う。
+    fn inspect_gre(&self, payload: &[u8], depth: usize) {
+        if depth >= MAX_TUNNEL_DEPTH {
+            return;
+        }
+        let Some((protocol, inner)) = parse_gre(payload) else {
+            return;
+        };
+        if let Ok(metrics) = self.metrics.lock() {
+            metrics.tunnel_packets_total.with_label_values(&["gre"]).inc();
+        }
+
+        match protocol {
+            EtherTypes::Ipv4 => {
+                if let Some(ipv4_packet) = Ipv4Packet::new(inner) {
+                    if !self.is_excluded_ipv4(&ipv4_packet) {
+                        self.inspect_ipv4_payload(&ipv4_packet, depth + 1);
+                    }
+                }
+            }
+            EtherTypes::Ipv6 => {
+                if let Some(ipv6_packet) = Ipv6Packet::new(inner) {
+                    if !self.is_excluded_ipv6(&ipv6_packet) {
+                        self.inspect_ipv6_payload(&ipv6_packet, depth + 1);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
     /// パケットロスを検出する
     fn detect_packet_loss(&self, ipv4_packet: &Ipv4Packet, tcp_packet: &TcpPacket) {
         let src_ip = ipv4_packet.get_source();
@@ -296,30 +764,80 @@ impl PacketCapture {
         let dst_port = tcp_packet.get_destination();
         let seq_num = tcp_packet.get_sequence();
         let payload_len = tcp_packet.payload().len() as u32;
+        let flags = tcp_packet.get_flags();
+        let closing = flags & (TcpFlags::FIN | TcpFlags::RST) != 0;
+        let connection_key = format!("{}:{}-{}:{}", src_ip, src_port, dst_ip, dst_port);
+        let reverse_key = format!("{}:{}-{}:{}", dst_ip, dst_port, src_ip, src_port);
+        let syn_mss = if flags & TcpFlags::SYN != 0 {
+            syn_mss_option(tcp_packet)
+        } else {
+            None
+        };
+        let window = tcp_packet.get_window() as u32;
 
-        if payload_len == 0 {
+        // 1パケットにつきロックは1回だけ取り、すべての更新をその中で行う
+        let Ok(mut metrics) = self.metrics.lock() else {
             return;
+        };
+
+        // このパケットのウィンドウは逆方向のデータ量の上限になる（純粋なACKも対象）
+        if let Some(reverse) = metrics.tcp_connection_states.get_mut(&reverse_key) {
+            reverse.receiver_window = reverse.receiver_window.max(window);
         }
 
-        let connection_key = format!("{}:{}-{}:{}", src_ip, src_port, dst_ip, dst_port);
+        // SYNのMSSオプションは相手側から送られてくるセグメントの上限になる
+        if let Some(mss) = syn_mss {
+            metrics
+                .advertised_mss
+                .insert(reverse_key, (mss, std::time::Instant::now()));
+        }
 
-        if let Ok(mut metrics) = self.metrics.lock() {
-            let state = metrics
-                .tcp_connection_states
-                .entry(connection_key)
-                .or_insert_with(|| TcpConnectionState::new(seq_num, payload_len));
+        if payload_len > 0 {
+            if let Some((expected_seq, gap)) =
+                metrics.observe_tcp_segment(&connection_key, seq_num, payload_len, flags)
+            {
+                // 購読者がいない場合の送信エラーは無視する
+                let _ = self.loss_events.send(PacketLossEvent {
+                    timestamp: chrono::Utc::now(),
+                    src_ip: IpAddr::V4(src_ip),
+                    dst_ip: IpAddr::V4(dst_ip),
+                    src_port,
+                    dst_port,
+                    expected_seq,
+                    received_seq: seq_num,
+                    gap_size: gap,
+                });
+            }
+        }
+
+        if closing {
+            metrics.finish_connection(&connection_key);
+        }
+    }
 
-            state.last_active = std::time::Instant::now();
-            state.total_packets += 1;
+    /// 平文HTTPのリクエストであればメソッドとHostを集計する
+    fn inspect_http_request(&self, tcp_packet: &TcpPacket) {
+        if tcp_packet.get_destination() != HTTP_PORT {
+            return;
+        }
 
-            if seq_num > state.expected_seq {
-                let gap = seq_num.wrapping_sub(state.expected_seq);
-                if gap > 0 && gap < 1_000_000 {
-                    // Assume gap is number of lost packets. This is a simplification.
-                    state.lost_packets += 1;
-                }
+        if let Some(summary) = parse_http_request(tcp_packet.payload()) {
+            if let Ok(mut metrics) = self.metrics.lock() {
+                metrics.record_http_request(&summary);
+            }
+        }
+    }
+
+    /// TLSのClientHelloであればSNIを集計する
+    fn inspect_tls_client_hello(&self, tcp_packet: &TcpPacket) {
+        if tcp_packet.get_destination() != TLS_PORT {
+            return;
+        }
+
+        if let Some(sni) = parse_tls_client_hello(tcp_packet.payload()) {
+            if let Ok(mut metrics) = self.metrics.lock() {
+                metrics.record_tls_sni(&sni);
             }
-            state.expected_seq = seq_num.wrapping_add(payload_len);
         }
     }
 
@@ -362,46 +880,149 @@ impl PacketCapture {
     }
 }
 
+/// IPv4パケットがフラグメント（MFフラグあり、またはオフセットが0以外）かどうか
+fn is_ipv4_fragment(ipv4_packet: &Ipv4Packet) -> bool {
+    ipv4_packet.get_flags() & Ipv4Flags::MoreFragments != 0
+        || ipv4_packet.get_fragment_offset() != 0
+}
+
 /// 指定された名前のネットワークインターフェースを検索
 pub fn find_interface(name: &str) -> Result<NetworkInterface> {
-    let interfaces = datalink::interfaces();
+    InterfaceSelector {
+        name: Some(name.to_string()),
+        ..Default::default()
+    }
+    .resolve(&datalink::interfaces())
+}
+
+/// 監視するインターフェースの指定（`--interface` / `--interface-index` / `--interface-mac`）
+///
+/// 名前は再起動や環境によって変わるため、インデックスやMACアドレスでも指定できる。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceSelector {
+    pub name: Option<String>,
+    pub index: Option<u32>,
+    pub mac: Option<MacAddr>,
+}
+
+impl InterfaceSelector {
+    /// 名前・インデックス・MACアドレスの順に一致するインターフェースを探す
+    pub fn resolve(&self, interfaces: &[NetworkInterface]) -> Result<NetworkInterface> {
+        let by_name = self
+            .name
+            .as_ref()
+            .and_then(|name| interfaces.iter().find(|iface| &iface.name == name));
+        let by_index = || {
+            self.index
+                .and_then(|index| interfaces.iter().find(|iface| iface.index == index))
+        };
+        let by_mac = || {
+            self.mac
+                .and_then(|mac| interfaces.iter().find(|iface| iface.mac == Some(mac)))
+        };
+        if let Some(interface) = by_name.or_else(by_index).or_else(by_mac) {
+            return Ok(interface.clone());
+        }
 
-    // 完全一致での検索
-    if let Some(interface) = interfaces.iter().find(|iface| iface.name == name) {
-        return Ok(interface.clone());
+        let available: Vec<String> = interfaces
+            .iter()
+            .map(|iface| match iface.mac {
+                Some(mac) => format!("{} (index {}, MAC {})", iface.name, iface.index, mac),
+                None => format!("{} (index {})", iface.name, iface.index),
+            })
+            .collect();
+        Err(anyhow::anyhow!(
+            "No interface matched {}; available interfaces: {}",
+            self.describe(),
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        ))
     }
 
-    // 利用可能なインターフェースをログ出力
-    warn!("Interface '{}' not found. Available interfaces:", name);
-    for iface in &interfaces {
-        warn!("  {} - {:?}", iface.name, iface.description);
+    /// 試した条件の説明（エラーメッセージ用）
+    fn describe(&self) -> String {
+        let mut tried = Vec::new();
+        if let Some(name) = &self.name {
+            tried.push(format!("name '{}'", name));
+        }
+        if let Some(index) = self.index {
+            tried.push(format!("index {}", index));
+        }
+        if let Some(mac) = self.mac {
+            tried.push(format!("MAC {}", mac));
+        }
+        if tried.is_empty() {
+            return "an empty selector".to_string();
+        }
+        tried.join(", then ")
     }
+}
 
-    Err(anyhow::anyhow!("Interface '{}' not found", name))
+/// バックグラウンドキャプチャから共有されるハンドル
+pub struct CaptureHandles {
+    pub shutdown_flag: Arc<std::sync::atomic::AtomicBool>,
+    /// キャプチャスレッドが動作中の間true（ループを抜けるとfalseになる）
+    pub capture_alive: Arc<std::sync::atomic::AtomicBool>,
+    pub metrics: Arc<std::sync::Mutex<NetworkMetrics>>,
+    pub ip_stats: IpStatsMap,
+    pub packet_receiver: mpsc::Receiver<PacketInfo>,
+    pub loss_events: broadcast::Sender<PacketLossEvent>,
+    pub packet_ring: PacketRingHandle,
+}
+
+/// 再接続に失敗した後の次の待ち時間（上限に達するまで倍にする）
+fn next_reconnect_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(CAPTURE_RECONNECT_MAX_BACKOFF)
+}
+
+/// ドロップ時にキャプチャスレッドの生存フラグを下ろす
+struct CaptureAliveGuard(Arc<std::sync::atomic::AtomicBool>);
+
+impl Drop for CaptureAliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
 }
 
 /// バックグラウンドでパケットキャプチャを開始する
 pub fn start_capture_background(
     interface_name: &str,
-    local_ip: Option<IpAddr>,
-    local_subnet: Option<Ipv4Addr>,
-) -> Result<(
-    Arc<std::sync::atomic::AtomicBool>,
-    Arc<std::sync::Mutex<NetworkMetrics>>,
-    IpStatsMap,
-    mpsc::Receiver<PacketInfo>,
-)> {
-    let (packet_sender, packet_receiver) = mpsc::channel::<PacketInfo>();
-    let capture = PacketCapture::new(interface_name, packet_sender, local_ip, local_subnet)?;
+    local_addresses: &[IpNetwork],
+    socket: SocketOptions,
+    exclusions: PacketExclusions,
+    channel_capacity: usize,
+    capture_ring: usize,
+    metric_prefix: Option<&str>,
+) -> Result<CaptureHandles> {
+    let (packet_sender, packet_receiver) = mpsc::sync_channel::<PacketInfo>(channel_capacity.max(1));
+    let capture = PacketCapture::new(
+        interface_name,
+        packet_sender,
+        local_addresses,
+        socket,
+        exclusions,
+        capture_ring,
+        metric_prefix,
+    )?;
     let metrics = capture.get_metrics();
     let ip_stats = capture.get_ip_stats();
+    let loss_events = capture.get_loss_events();
+    let packet_ring = capture.get_packet_ring();
     let interface_name = interface_name.to_string();
 
     // シャットダウンフラグを作成
     let shutdown_flag = Arc::new(std::sync::atomic::AtomicBool::new(false));
     let shutdown_flag_clone = shutdown_flag.clone();
+    let capture_alive = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let capture_alive_guard = CaptureAliveGuard(capture_alive.clone());
 
     thread::spawn(move || {
+        // パニックで終了した場合もfalseにするため、スレッド終了時のDropで更新する
+        let _capture_alive_guard = capture_alive_guard;
+
         info!(
             "Starting background packet capture for interface: {}",
             interface_name
@@ -418,18 +1039,106 @@ pub fn start_capture_background(
         info!("Packet capture stopped for interface: {}", interface_name);
     });
 
-    Ok((shutdown_flag, metrics, ip_stats, packet_receiver))
+    Ok(CaptureHandles {
+        shutdown_flag,
+        capture_alive,
+        metrics,
+        ip_stats,
+        packet_receiver,
+        loss_events,
+        packet_ring,
+    })
+}
+
+/// モニタリングシステムの動作設定
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    /// Pushgatewayへの送信設定（Noneの場合は送信しない）
+    pub pushgateway: Option<PushgatewayConfig>,
+    /// レートゲージのEWMA平滑化係数（1.0で平滑化なし）
+    pub rate_smoothing: f64,
+    /// キャプチャソケットの読み取りバッファサイズ（バイト）
+    pub buffer_size: usize,
+    /// プロミスキャスモードでキャプチャするか（falseの場合はこのホスト宛て・発のフレームのみ）
+    pub promiscuous: bool,
+    /// IP別レートを個別のラベルで出力するサブネット（空の場合は全ローカルIP）
+    pub metrics_only_subnets: Vec<IpNetwork>,
+    /// IP別レートをまとめるIPv4のプレフィックス長（32でホストごと）
+    pub aggregate_prefix: u8,
+    /// 欠損とみなすシーケンスギャップの上限（Noneの場合は受信側のウィンドウから決める）
+    pub max_plausible_gap: Option<u32>,
+    /// 接続横断のロス率（単純平均・加重平均）に含める接続の最小パケット数
+    pub min_packets_for_loss: u64,
+    /// 解析前に除外するIPアドレス・ポート
+    pub exclusions: PacketExclusions,
+    /// キャプチャスレッドと処理ループの間のキューに溜められるパケット数
+    pub channel_capacity: usize,
+    /// `/dump` で出力する直近のパケット数（0で保持しない）
+    pub capture_ring: usize,
+    /// 全メトリクス名の先頭に付ける名前空間（Noneの場合は付けない）
+    pub metric_prefix: Option<String>,
+    /// `POST /search` / `POST /query` に返すメトリクスの保持期間（Noneの場合は記録しない）
+    pub json_datasource_retention: Option<Duration>,
+    /// メトリクスを標準出力に書き出す間隔（Noneの場合は書き出さない）
+    pub stdout_metrics_interval: Option<Duration>,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            pushgateway: None,
+            rate_smoothing: 1.0,
+            buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            promiscuous: true,
+            metrics_only_subnets: Vec::new(),
+            aggregate_prefix: 32,
+            max_plausible_gap: None,
+            min_packets_for_loss: DEFAULT_MIN_PACKETS_FOR_LOSS,
+            exclusions: PacketExclusions::default(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            capture_ring: DEFAULT_CAPTURE_RING,
+            metric_prefix: None,
+            json_datasource_retention: None,
+            stdout_metrics_interval: None,
+        }
+    }
 }
 
 /// 完全なネットワークモニタリングシステムを開始する
 pub async fn start_network_monitoring_system(
     interface_name: &str,
-    local_ip: Option<IpAddr>,
-    local_subnet: Option<Ipv4Addr>,
+    local_addresses: &[IpNetwork],
+    config: MonitorConfig,
 ) -> Result<()> {
     // パケットキャプチャを開始
-    let (capture_shutdown_flag, metrics, ip_stats, packet_receiver) =
-        start_capture_background(interface_name, local_ip, local_subnet)?;
+    let CaptureHandles {
+        shutdown_flag: capture_shutdown_flag,
+        capture_alive,
+        metrics,
+        ip_stats,
+        packet_receiver,
+        loss_events,
+        packet_ring,
+    } = start_capture_background(
+        interface_name,
+        local_addresses,
+        SocketOptions {
+            read_buffer_size: config.buffer_size,
+            promiscuous: config.promiscuous,
+        },
+        config.exclusions.clone(),
+        config.channel_capacity,
+        config.capture_ring,
+        config.metric_prefix.as_deref(),
+    )?;
+
+    if let Ok(mut metrics) = metrics.lock() {
+        metrics.rate_smoothing = config.rate_smoothing;
+        metrics.metrics_only_subnets = config.metrics_only_subnets.clone();
+        metrics.aggregate_prefix = config.aggregate_prefix;
+        metrics.max_plausible_gap = config.max_plausible_gap;
+        metrics.min_packets_for_loss = config.min_packets_for_loss;
+    }
 
     // ネットワークメトリクスをprometheusサーバーに設定
     crate::prometheus_server::set_network_metrics(metrics.clone());
@@ -437,6 +1146,15 @@ pub async fn start_network_monitoring_system(
     // IP統計をprometheusサーバーに設定
     crate::prometheus_server::set_ip_stats(ip_stats.clone());
 
+    // キャプチャスレッドの生存状態を/healthに反映
+    crate::prometheus_server::set_capture_alive(capture_alive);
+
+    // ロスイベントの配信チャネルをprometheusサーバーに設定
+    crate::prometheus_server::set_loss_events(loss_events);
+
+    // 直近のパケットのリングバッファを/dumpに公開
+    crate::prometheus_server::set_packet_ring(packet_ring);
+
     // Prometheusサーバーを起動（指定されたポートで）
     const METRICS_PORT: u16 = 59121; // メトリクスサーバーのポート
     info!(
@@ -450,6 +1168,16 @@ pub async fn start_network_monitoring_system(
         }
     });
 
+    // Pushgatewayへの定期送信を開始（指定された場合のみ）
+    let push_handle = config.pushgateway.map(|config| {
+        let metrics_pusher = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = push_metrics_periodically(metrics_pusher, config).await {
+                error!("Pushgateway pusher error: {}", e);
+            }
+        })
+    });
+
     info!(
         "Network monitoring started on interface: {}",
         interface_name
@@ -457,8 +1185,9 @@ pub async fn start_network_monitoring_system(
 
     // メトリクスのログ出力を開始（1秒間隔）
     let metrics_logger = metrics.clone();
+    let logger_interface = interface_name.to_string();
     let log_handle = tokio::spawn(async move {
-        if let Err(e) = log_metrics_periodically(metrics_logger, 1).await {
+        if let Err(e) = log_metrics_periodically(metrics_logger, &logger_interface, 1).await {
             error!("Metrics logging error: {}", e);
         }
     });
@@ -488,24 +1217,51 @@ pub async fn start_network_monitoring_system(
         }
     });
 
+    // JSONデータソース用の時系列の記録を開始（指定された場合のみ、1秒間隔）
+    let time_series_handle = config.json_datasource_retention.map(|retention| {
+        let store: TimeSeriesHandle =
+            Arc::new(std::sync::Mutex::new(TimeSeriesStore::new(retention)));
+        crate::prometheus_server::set_time_series(store.clone());
+        let metrics_recorder = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = record_time_series_periodically(metrics_recorder, store).await {
+                error!("Time series recorder failed: {}", e);
+            }
+        })
+    });
+
+    // 標準出力へのメトリクスの書き出しを開始（指定された場合のみ）
+    let stdout_metrics_handle = config.stdout_metrics_interval.map(|period| {
+        let metrics_writer = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = write_metrics_to_stdout_periodically(metrics_writer, period).await {
+                error!("Stdout metrics writer failed: {}", e);
+            }
+        })
+    });
+
     // パケット処理ループ（メイン処理）
     let mut _packet_count = 0u64;
 
     loop {
         tokio::select! {
             _ = tokio::time::sleep(Duration::from_millis(10)) => {
-                // 非ブロッキングでパケットを受信を試行
-                match packet_receiver.try_recv() {
-                    Ok(_packet_info) => {
-                        _packet_count += 1;
-                    }
-                    Err(std::sync::mpsc::TryRecvError::Empty) => {
-                        // パケットなし、継続
-                    }
-                    Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                        info!("Packet receiver disconnected, stopping monitoring");
-                        break;
+                // 非ブロッキングでキューに溜まったパケットをすべて受信する
+                let disconnected = loop {
+                    match packet_receiver.try_recv() {
+                        Ok(_packet_info) => {
+                            _packet_count += 1;
+                        }
+                        Err(std::sync::mpsc::TryRecvError::Empty) => {
+                            // パケットなし、継続
+                            break false;
+                        }
+                        Err(std::sync::mpsc::TryRecvError::Disconnected) => break true,
                     }
+                };
+                if disconnected {
+                    info!("Packet receiver disconnected, stopping monitoring");
+                    break;
                 }
             }
             _ = tokio::signal::ctrl_c() => {
@@ -528,6 +1284,15 @@ pub async fn start_network_monitoring_system(
     rate_update_handle.abort();
     ip_stats_handle.abort();
     packet_loss_update_handle.abort();
+    if let Some(handle) = push_handle {
+        handle.abort();
+    }
+    if let Some(handle) = time_series_handle {
+        handle.abort();
+    }
+    if let Some(handle) = stdout_metrics_handle {
+        handle.abort();
+    }
 
     // タスクの終了を少し待つ
     tokio::time::sleep(Duration::from_millis(200)).await;
@@ -546,15 +1311,68 @@ pub struct NetworkMetrics {
     // 合計値用メトリクス
     pub total_tx_bytes_rate: prometheus::Gauge, // 全ローカルIPの送信バイト数レート合計
     pub total_rx_bytes_rate: prometheus::Gauge, // 全ローカルIPの受信バイト数レート合計
+    // 全ローカルIPの送受信バイト数（`rate()` を任意の範囲で適用するためのカウンタ）
+    pub total_tx_bytes_total: prometheus::Counter,
+    pub total_rx_bytes_total: prometheus::Counter,
     // パケットロス率メトリクス
     pub packet_loss_percentage: prometheus::Gauge, // パケットロス率（%）
+    pub connection_loss_percentage_mean: prometheus::Gauge, // 接続ごとのロス率の単純平均（%）
+    pub connection_loss_percentage_weighted: prometheus::Gauge, // 接続ごとのロス率のパケット数加重平均（%）
+    pub connections_loss_cdf: prometheus::GaugeVec, // ロス率がしきい値以上の接続数（しきい値別）
+    // FIN/RSTまたはアイドルで削除された接続の継続時間
+    pub connection_duration_seconds: prometheus::Histogram,
+    // アイドルで削除した接続数と、定期クリーンアップ後に残っている接続状態の数
+    pub connections_reaped_total: prometheus::Counter,
+    pub connection_map_size: prometheus::Gauge,
+    // ロス検出をスキップしたIPv4フラグメント数
+    pub ipv4_fragments_skipped_total: prometheus::Counter,
+    // 内側のパケットを解析したトンネルのパケット数（種別ごと）
+    pub tunnel_packets_total: prometheus::CounterVec,
+    // IPv4/IPv6として解析できなかったパケット数（理由ごと）
+    pub unparsed_packets_total: prometheus::CounterVec,
+    // 処理側のキューに渡したパケット数（満杯で捨てたものを含む）
+    pub captured_packets_total: prometheus::Counter,
+    // 処理側のキューが満杯で捨てたパケット数
+    pub channel_dropped_total: prometheus::Counter,
+    // キャプチャエラー後にインターフェースを開き直した回数
+    pub capture_reconnects_total: prometheus::Counter,
+    // HTTPリクエスト数（メソッド別）
+    pub http_requests_total: prometheus::CounterVec,
+    // HTTPリクエスト数（Host別、ラベルの爆発を避けるためログ出力のみ。MAX_HTTP_HOSTSを超えたHostは `_other` に集約）
+    pub http_host_requests: HashMap<String, u64>,
+    // TLS ClientHello数（SNI別、MAX_TLS_SNI_LABELSを超えたSNIは `_other` に集約）
+    pub tls_sni_total: prometheus::CounterVec,
+    pub tls_sni_connections: HashMap<String, u64>,
     // IP別内部カウンタ（差分計算用）
     pub internal_counters_per_ip: HashMap<String, LocalIpCounters>,
     pub last_update_time: std::time::Instant,
+    // レートのEWMA平滑化係数（1.0で平滑化なし）
+    pub rate_smoothing: f64,
+    // IP別レートを個別に出力するサブネット（範囲外は `_other` に集約、空なら全て出力）
+    pub metrics_only_subnets: Vec<IpNetwork>,
+    // IP別レートをまとめるIPv4のプレフィックス長（32でホストごと、IPv6は常にホストごと）
+    pub aggregate_prefix: u8,
     // ローカルネットワーク範囲定義
-    local_network_ranges: Vec<(Ipv4Addr, u8)>, // (network_addr, prefix_length)
+    local_network_ranges: Vec<IpNetwork>,
+    // モニタ自身のIPアドレス（インターフェースの全アドレス、LAN内通信の向きの判定に使う）
+    host_ips: Vec<IpAddr>,
+    // モニタ自身のIPの送受信バイト数
+    pub host_tx_bytes_total: prometheus::Counter,
+    pub host_rx_bytes_total: prometheus::Counter,
     // TCP接続追跡
     pub tcp_connection_states: HashMap<String, TcpConnectionState>,
+    // SYNで広告されたMSS（そのMSSが適用される向きの接続キーをキーとする）
+    pub advertised_mss: HashMap<String, (u32, std::time::Instant)>,
+    // 欠損とみなすシーケンスギャップの上限（Noneの場合は受信側のウィンドウから決める）
+    pub max_plausible_gap: Option<u32>,
+    // 接続横断のロス率に含める接続の最小パケット数
+    pub min_packets_for_loss: u64,
+    // プロトコル別統計（PacketInfo.protocol をキーとする）
+    pub protocol_stats: HashMap<String, ProtocolStats>,
+    // 全プロトコルの合計統計
+    pub total_stats: ProtocolStats,
+    // 計測開始時刻
+    pub start_time: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Debug, Clone)]
@@ -564,6 +1382,9 @@ pub struct LocalIpCounters {
     pub last_tx_bytes: u64,
     pub last_rx_bytes: u64,
     pub last_active: std::time::Instant,
+    // 平滑化済みのレート（bytes/sec）
+    pub smoothed_tx_bytes_rate: f64,
+    pub smoothed_rx_bytes_rate: f64,
 }
 
 impl LocalIpCounters {
@@ -574,10 +1395,15 @@ impl LocalIpCounters {
             last_tx_bytes: 0,
             last_rx_bytes: 0,
             last_active: std::time::Instant::now(),
+            smoothed_tx_bytes_rate: 0.0,
+            smoothed_rx_bytes_rate: 0.0,
         }
     }
 }
 
+/// `NetworkMetrics::snapshot` に含める上位のIPの数
+pub const SNAPSHOT_TOP_TALKERS: usize = 10;
+
 /// 数値（bps単位）を適切な単位（bps, Kbps, Mbps, Gbps）に変換して文字列で返す
 pub fn format_bps(value: f64) -> String {
     const KBPS: f64 = 1_000.0;
@@ -596,8 +1422,12 @@ pub fn format_bps(value: f64) -> String {
 }
 
 impl NetworkMetrics {
-    pub fn new(local_ip: Option<IpAddr>, local_subnet: Option<Ipv4Addr>) -> Self {
-        let registry = Registry::new();
+    /// `metric_prefix` を指定すると全メトリクス名の先頭に `<prefix>_` を付ける（`--metric-prefix`）
+    pub fn new(local_addresses: &[IpNetwork], metric_prefix: Option<&str>) -> Self {
+        let registry = match metric_prefix.filter(|prefix| !prefix.is_empty()) {
+            Some(prefix) => Registry::new_custom(Some(prefix.to_string()), None).unwrap(),
+            None => Registry::new(),
+        };
 
         // ローカルIP別レートメトリクス（1秒間隔）
         let local_ip_tx_bytes_rate = prometheus::GaugeVec::new(
@@ -631,10 +1461,142 @@ impl NetworkMetrics {
         )
         .unwrap();
 
+        let total_tx_bytes_total = prometheus::Counter::new(
+            "total_tx_bytes_total",
+            "Total bytes transmitted by all local IPs",
+        )
+        .unwrap();
+
+        let total_rx_bytes_total = prometheus::Counter::new(
+            "total_rx_bytes_total",
+            "Total bytes received by all local IPs",
+        )
+        .unwrap();
+
         // パケットロス率メトリクス
         let packet_loss_percentage = prometheus::Gauge::new(
-            "tcp_monitor_packet_loss_missing_per_second",
-            "Packet loss percentage",
+            "packet_loss_percentage",
+            "Percentage of TCP segments detected as lost across all tracked connections",
+        )
+        .unwrap();
+
+        let connection_loss_percentage_mean = prometheus::Gauge::new(
+            "tcp_connection_loss_percentage_mean",
+            "Unweighted mean of per-connection packet loss percentages",
+        )
+        .unwrap();
+
+        let connection_loss_percentage_weighted = prometheus::Gauge::new(
+            "tcp_connection_loss_percentage_weighted",
+            "Per-connection packet loss percentages weighted by packet count",
+        )
+        .unwrap();
+
+        let connections_loss_cdf = prometheus::GaugeVec::new(
+            prometheus::Opts::new(
+                "tcp_monitor_connections_loss_cdf",
+                "Number of tracked connections whose packet loss percentage is at or above the threshold",
+            ),
+            &["threshold"],
+        )
+        .unwrap();
+        // 該当する接続がなくても全しきい値の系列を出力する
+        for threshold in LOSS_CDF_THRESHOLDS {
+            connections_loss_cdf
+                .with_label_values(&[&threshold.to_string()])
+                .set(0.0);
+        }
+
+        let host_tx_bytes_total = prometheus::Counter::new(
+            "host_tx_bytes_total",
+            "Total bytes sent from the monitored interface's own IP",
+        )
+        .unwrap();
+
+        let host_rx_bytes_total = prometheus::Counter::new(
+            "host_rx_bytes_total",
+            "Total bytes received by the monitored interface's own IP",
+        )
+        .unwrap();
+
+        let connection_duration_seconds = prometheus::Histogram::with_opts(
+            prometheus::HistogramOpts::new(
+                "tcp_connection_duration_seconds",
+                "Lifetime of TCP connections ended by FIN/RST or idle eviction",
+            )
+            .buckets(CONNECTION_DURATION_BUCKETS.to_vec()),
+        )
+        .unwrap();
+
+        let ipv4_fragments_skipped_total = prometheus::Counter::new(
+            "ipv4_fragments_skipped_total",
+            "Number of IPv4 fragments skipped before TCP loss analysis",
+        )
+        .unwrap();
+
+        let tunnel_packets_total = prometheus::CounterVec::new(
+            prometheus::Opts::new(
+                "tunnel_packets_total",
+                "Number of tunnel packets whose inner packet was analyzed, by tunnel type",
+            ),
+            &["type"],
+        )
+        .unwrap();
+
+        let unparsed_packets_total = prometheus::CounterVec::new(
+            prometheus::Opts::new(
+                "unparsed_packets_total",
+                "Number of captured frames that could not be parsed as IPv4/IPv6, by reason",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        let captured_packets_total = prometheus::Counter::new(
+            "captured_packets_total",
+            "Number of captured packets handed to the processing queue, including dropped ones",
+        )
+        .unwrap();
+
+        let channel_dropped_total = prometheus::Counter::new(
+            "channel_dropped_total",
+            "Number of captured packets dropped because the processing queue was full",
+        )
+        .unwrap();
+
+        let connections_reaped_total = prometheus::Counter::new(
+            "tcp_monitor_connections_reaped_total",
+            "Number of idle TCP connection states removed by the periodic cleanup",
+        )
+        .unwrap();
+
+        let connection_map_size = prometheus::Gauge::new(
+            "tcp_monitor_connection_map_size",
+            "Number of TCP connection states held after the last periodic cleanup",
+        )
+        .unwrap();
+
+        let capture_reconnects_total = prometheus::Counter::new(
+            "capture_reconnects_total",
+            "Number of times the capture interface was reopened after a capture error",
+        )
+        .unwrap();
+
+        let http_requests_total = prometheus::CounterVec::new(
+            prometheus::Opts::new(
+                "http_requests_total",
+                "Number of plaintext HTTP requests observed per method",
+            ),
+            &["method"],
+        )
+        .unwrap();
+
+        let tls_sni_total = prometheus::CounterVec::new(
+            prometheus::Opts::new(
+                "tls_sni_total",
+                "Number of TLS ClientHello messages observed per SNI",
+            ),
+            &["sni"],
         )
         .unwrap();
 
@@ -651,18 +1613,76 @@ impl NetworkMetrics {
         registry
             .register(Box::new(total_rx_bytes_rate.clone()))
             .unwrap();
+        registry
+            .register(Box::new(total_tx_bytes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(total_rx_bytes_total.clone()))
+            .unwrap();
         registry
             .register(Box::new(packet_loss_percentage.clone()))
             .unwrap();
+        registry
+            .register(Box::new(connection_loss_percentage_mean.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connection_loss_percentage_weighted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_loss_cdf.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(host_tx_bytes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(host_rx_bytes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connection_duration_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(ipv4_fragments_skipped_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tunnel_packets_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(unparsed_packets_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(captured_packets_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(channel_dropped_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(capture_reconnects_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_reaped_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connection_map_size.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(http_requests_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tls_sni_total.clone()))
+            .unwrap();
 
         // ローカルネットワーク範囲の構築
-        let local_network_ranges = Self::build_local_network_ranges(local_ip, local_subnet);
+        let local_network_ranges = Self::build_local_network_ranges(local_addresses);
 
         // 構築されたローカルネットワーク範囲を表示
         info!("Configured local network ranges:");
-        for (network, prefix) in &local_network_ranges {
-            let (min_ip, max_ip) = Self::calculate_ip_range(*network, *prefix);
-            info!("  - {}/{} ({} - {})", network, prefix, min_ip, max_ip);
+        for network in &local_network_ranges {
+            info!(
+                "  - {} ({} - {})",
+                network,
+                network.network(),
+                network.broadcast()
+            );
         }
 
         NetworkMetrics {
@@ -671,14 +1691,51 @@ impl NetworkMetrics {
             local_ip_rx_bytes_rate,
             total_tx_bytes_rate,
             total_rx_bytes_rate,
+            total_tx_bytes_total,
+            total_rx_bytes_total,
             packet_loss_percentage,
+            connection_loss_percentage_mean,
+            connection_loss_percentage_weighted,
+            connections_loss_cdf,
+            connection_duration_seconds,
+            connections_reaped_total,
+            connection_map_size,
+            ipv4_fragments_skipped_total,
+            tunnel_packets_total,
+            unparsed_packets_total,
+            captured_packets_total,
+            channel_dropped_total,
+            capture_reconnects_total,
+            http_requests_total,
+            http_host_requests: HashMap::new(),
+            tls_sni_total,
+            tls_sni_connections: HashMap::new(),
             internal_counters_per_ip: HashMap::new(),
             last_update_time: std::time::Instant::now(),
+            rate_smoothing: 1.0,
+            metrics_only_subnets: Vec::new(),
+            aggregate_prefix: 32,
             local_network_ranges,
+            host_ips: local_addresses.iter().map(|address| address.ip()).collect(),
+            host_tx_bytes_total,
+            host_rx_bytes_total,
             tcp_connection_states: HashMap::new(),
+            advertised_mss: HashMap::new(),
+            max_plausible_gap: None,
+            min_packets_for_loss: DEFAULT_MIN_PACKETS_FOR_LOSS,
+            protocol_stats: HashMap::new(),
+            total_stats: ProtocolStats::default(),
+            start_time: chrono::Utc::now(),
         }
     }    /// Record a packet in the metrics
     pub fn record_packet(&mut self, packet_info: &PacketInfo) {
+        // プロトコル別・合計のパケット数とバイト数を集計
+        self.protocol_stats
+            .entry(packet_info.protocol.clone())
+            .or_default()
+            .record(packet_info.size);
+        self.total_stats.record(packet_info.size);
+
         // Update packet counts and byte counts based on the packet information
         if let (Some(src_ip), Some(dst_ip)) = (packet_info.src_ip, packet_info.dst_ip) {
             // Determine if this is local traffic based on configured ranges
@@ -692,6 +1749,7 @@ impl NetworkMetrics {
                     let counter = self.internal_counters_per_ip.entry(local_ip_str).or_insert_with(LocalIpCounters::new);
                     counter.tx_bytes += packet_info.size;
                     counter.last_active = std::time::Instant::now();
+                    self.total_tx_bytes_total.inc_by(packet_info.size as f64);
                 }
             } else if !is_local_src && is_local_dst {
                 // Inbound traffic to local IP
@@ -699,29 +1757,154 @@ impl NetworkMetrics {
                     let counter = self.internal_counters_per_ip.entry(local_ip_str).or_insert_with(LocalIpCounters::new);
                     counter.rx_bytes += packet_info.size;
                     counter.last_active = std::time::Instant::now();
+                    self.total_rx_bytes_total.inc_by(packet_info.size as f64);
                 }
+            } else if is_local_src && is_local_dst {
+                // LAN内の通信はモニタ自身のIPが関わる場合のみ、その送受信として扱う
+                if self.host_ips.contains(&src_ip) {
+                    let counter = self.internal_counters_per_ip.entry(src_ip.to_string()).or_insert_with(LocalIpCounters::new);
+                    counter.tx_bytes += packet_info.size;
+                    counter.last_active = std::time::Instant::now();
+                    self.total_tx_bytes_total.inc_by(packet_info.size as f64);
+                } else if self.host_ips.contains(&dst_ip) {
+                    let counter = self.internal_counters_per_ip.entry(dst_ip.to_string()).or_insert_with(LocalIpCounters::new);
+                    counter.rx_bytes += packet_info.size;
+                    counter.last_active = std::time::Instant::now();
+                    self.total_rx_bytes_total.inc_by(packet_info.size as f64);
+                }
+            }
+
+            // モニタ自身のIPの送受信バイト数（宛先がローカルかグローバルかを問わない）
+            if self.host_ips.contains(&src_ip) {
+                self.host_tx_bytes_total.inc_by(packet_info.size as f64);
+            }
+            if self.host_ips.contains(&dst_ip) {
+                self.host_rx_bytes_total.inc_by(packet_info.size as f64);
             }
         }
     }
 
-    /// Check if an IP address is in the local network ranges
-    fn is_local_ip(&self, ip: IpAddr) -> bool {
-        match ip {
-            IpAddr::V4(ipv4) => {
-                for (network, prefix) in &self.local_network_ranges {
-                    let mask = !((1u32 << (32 - prefix)) - 1);
-                    let network_u32 = u32::from(*network);
-                    let ip_u32 = u32::from(ipv4);
-                    if (ip_u32 & mask) == (network_u32 & mask) {
-                        return true;
-                    }
-                }
-                false
+    /// ペイロードを持つセグメントを接続の状態に反映し、欠損があれば期待していたシーケンス番号とギャップを返す
+    ///
+    /// 最初のセグメントは状態の初期化にのみ使う（SYNで広告されたMSSがあれば引き継ぐ）。
+    /// `flags` のSYN/FINはそれぞれシーケンス番号を1つ消費するものとして期待値に含める。
+    pub(crate) fn observe_tcp_segment(
+        &mut self,
+        connection_key: &str,
+        seq_num: u32,
+        payload_len: u32,
+        flags: u8,
+    ) -> Option<(u32, u32)> {
+        let state = match self.tcp_connection_states.entry(connection_key.to_string()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let mss = self
+                    .advertised_mss
+                    .remove(entry.key())
+                    .map_or(DEFAULT_MSS, |(mss, _)| mss);
+                entry.insert(TcpConnectionState::new(seq_num, payload_len, mss, flags));
+                return None;
+            }
+        };
+
+        let expected_seq = state.expected_seq;
+        let max_plausible_gap = state.max_plausible_gap(self.max_plausible_gap);
+        state
+            .observe_segment(seq_num, payload_len, flags, max_plausible_gap)
+            .map(|gap| (expected_seq, gap))
+    }
+
+    /// FIN/RSTを観測した接続の状態を削除し、継続時間を記録する
+    pub fn finish_connection(&mut self, connection_key: &str) {
+        if let Some(state) = self.tcp_connection_states.remove(connection_key) {
+            self.connection_duration_seconds
+                .observe(state.created_at.elapsed().as_secs_f64());
+        }
+    }
+
+    /// `CONNECTION_ACTIVE_WINDOW` 以上パケットのない接続の状態を削除し、継続時間と削除数・残りの接続数を記録する
+    pub fn reap_idle_connections(&mut self, now: std::time::Instant) {
+        let duration_histogram = &self.connection_duration_seconds;
+        let reaped_total = &self.connections_reaped_total;
+        self.tcp_connection_states.retain(|_, state| {
+            let active = now.duration_since(state.last_active) < CONNECTION_ACTIVE_WINDOW;
+            if !active {
+                duration_histogram
+                    .observe(state.last_active.duration_since(state.created_at).as_secs_f64());
+                reaped_total.inc();
             }
-            IpAddr::V6(_) => false, // IPv6 not supported for now
+            active
+        });
+        self.connection_map_size
+            .set(self.tcp_connection_states.len() as f64);
+    }
+
+    /// 最後のパケットが `CONNECTION_ACTIVE_WINDOW` 以内の接続数（削除前のアイドルな接続の状態は数えない）
+    pub fn active_connection_count(&self, now: std::time::Instant) -> usize {
+        self.tcp_connection_states
+            .values()
+            .filter(|state| now.duration_since(state.last_active) < CONNECTION_ACTIVE_WINDOW)
+            .count()
+    }
+
+    /// Record a parsed HTTP request, folding new Host names into `_other`
+    /// once MAX_HTTP_HOSTS distinct names have been seen
+    pub fn record_http_request(&mut self, summary: &HttpRequestSummary) {
+        self.http_requests_total
+            .with_label_values(&[&summary.method])
+            .inc();
+        if let Some(host) = &summary.host {
+            // Hostヘッダは送信側が自由に決められるため、種類数を制限しないとメモリが増え続ける
+            let key = if self.http_host_requests.contains_key(host)
+                || self.http_host_requests.len() < MAX_HTTP_HOSTS
+            {
+                host.as_str()
+            } else {
+                OTHER_LABEL
+            };
+            *self
+                .http_host_requests
+                .entry(key.to_string())
+                .or_default() += 1;
         }
     }
 
+    /// Most requested HTTP hosts, in descending order of request count
+    pub fn top_http_hosts(&self, limit: usize) -> Vec<(String, u64)> {
+        top_counts(&self.http_host_requests, limit)
+    }
+
+    /// Record the SNI of a TLS ClientHello, folding new names into `_other`
+    /// once MAX_TLS_SNI_LABELS distinct names have been seen
+    pub fn record_tls_sni(&mut self, sni: &str) {
+        let label = if self.tls_sni_connections.contains_key(sni)
+            || self.tls_sni_connections.len() < MAX_TLS_SNI_LABELS
+        {
+            sni
+        } else {
+            OTHER_LABEL
+        };
+
+        self.tls_sni_total.with_label_values(&[label]).inc();
+        *self
+            .tls_sni_connections
+            .entry(label.to_string())
+            .or_default() += 1;
+    }
+
+    /// Most frequent TLS SNIs, in descending order of ClientHello count
+    pub fn top_tls_snis(&self, limit: usize) -> Vec<(String, u64)> {
+        top_counts(&self.tls_sni_connections, limit)
+    }
+
+    /// Check if an IP address is in the local network ranges
+    fn is_local_ip(&self, ip: IpAddr) -> bool {
+        // IPv4/IPv6の範囲はそれぞれ同じファミリーのアドレスにのみ一致する
+        self.local_network_ranges
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+
     /// Get string representation of local IP for metrics
     fn get_local_ip_string(&self, ip: IpAddr) -> Option<String> {
         if self.is_local_ip(ip) {
@@ -731,10 +1914,84 @@ impl NetworkMetrics {
         }
     }
 
+    /// 現在の集計値から `TrafficStatistics` のスナップショットを作成
+    ///
+    /// 送信元・宛先IPとポートの内訳はこの構造体では追跡していないため空のまま返す。
+    pub fn traffic_statistics(&self, interface: &str) -> TrafficStatistics {
+        let end_time = chrono::Utc::now();
+        let mut statistics = TrafficStatistics {
+            start_time: self.start_time,
+            end_time,
+            interface: interface.to_string(),
+            total: self.total_stats.clone(),
+            protocols: self.protocol_stats.clone(),
+            source_ips: HashMap::new(),
+            destination_ips: HashMap::new(),
+            ports: HashMap::new(),
+            previous_total: ProtocolStats::default(),
+            last_reset_time: self.start_time,
+            packet_loss_percentage: self.packet_loss_percentage.get(),
+        };
+
+        // 計測開始からの平均レート（プロトコル別の集計も開始時点から累積している）
+        statistics.update_total_rates();
+        let elapsed_secs = (end_time - self.start_time)
+            .to_std()
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+        for stats in statistics.protocols.values_mut() {
+            stats.update_rates(&ProtocolStats::default(), elapsed_secs);
+        }
+        statistics
+    }
+
+    /// 終了時のサマリとして、送信・受信バイト数の上位 `top` 件のIPを加えたスナップショットを作成
+    pub fn run_summary(
+        &self,
+        interface: &str,
+        ip_stats: &HashMap<IpAddr, IpStats>,
+        top: usize,
+    ) -> TrafficStatistics {
+        let mut statistics = self.traffic_statistics(interface);
+        statistics.source_ips = top_ips(ip_stats, |stats| stats.tx_bytes, top);
+        statistics.destination_ips = top_ips(ip_stats, |stats| stats.rx_bytes, top);
+        statistics
+    }
+
+    /// 合計レート・ロス率・接続数と、`ip_stats` で送受信バイト数の多い上位 `SNAPSHOT_TOP_TALKERS` 件のIPを1回のロックで複製する
+    ///
+    /// 呼び出し側はロックを解放してから整形やシリアライズを行える。
+    pub fn snapshot(&self, ip_stats: &HashMap<IpAddr, IpStats>) -> MetricsSnapshot {
+        let top_talkers = ranked_ips(
+            ip_stats,
+            |stats| stats.tx_bytes + stats.rx_bytes,
+            SNAPSHOT_TOP_TALKERS,
+        )
+        .into_iter()
+        .map(|(ip, _)| TalkerSnapshot {
+            ip,
+            tx_bytes: ip_stats[&ip].tx_bytes,
+            rx_bytes: ip_stats[&ip].rx_bytes,
+        })
+        .collect();
+
+        MetricsSnapshot {
+            total_tx_bytes_rate: self.total_tx_bytes_rate.get(),
+            total_rx_bytes_rate: self.total_rx_bytes_rate.get(),
+            packet_loss_percentage: self.packet_loss_percentage.get(),
+            active_connections: self.active_connection_count(std::time::Instant::now()),
+            top_talkers,
+        }
+    }
+
+    /// Gather the registered metric families
+    pub fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.registry.gather()
+    }
+
     /// Export metrics in Prometheus format
     pub fn export(&self) -> String {
         let encoder = prometheus::TextEncoder::new();
-        let metric_families = self.registry.gather();
+        let metric_families = self.gather();
         encoder.encode_to_string(&metric_families).unwrap_or_default()
     }
 
@@ -751,8 +2008,13 @@ impl NetworkMetrics {
         // 合計値計算用の変数
         let mut total_tx_bytes_rate = 0.0;
         let mut total_rx_bytes_rate = 0.0;
+        let mut other_tx_bytes_rate = 0.0;
+        let mut other_rx_bytes_rate = 0.0;
+        // `--aggregate-prefix` でまとめたラベルごとの送受信レート
+        let mut label_rates: HashMap<String, (f64, f64)> = HashMap::new();
         let mut inactive_ips = Vec::new();
         const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(300); // 5分
+        let alpha = self.rate_smoothing;
 
         // 各ローカルIPのレートを計算して更新
         for (local_ip, counters) in self.internal_counters_per_ip.iter_mut() {
@@ -760,21 +2022,29 @@ impl NetworkMetrics {
             let tx_bytes_diff = counters.tx_bytes - counters.last_tx_bytes;
             let rx_bytes_diff = counters.rx_bytes - counters.last_rx_bytes;
 
-            // レート（秒あたり）を計算
-            let tx_bytes_rate = (tx_bytes_diff as f64) / elapsed_secs;
-            let rx_bytes_rate = (rx_bytes_diff as f64) / elapsed_secs;
+            // レート（秒あたり）を計算し、EWMAで平滑化
+            let tx_bytes_rate = alpha * (tx_bytes_diff as f64) / elapsed_secs
+                + (1.0 - alpha) * counters.smoothed_tx_bytes_rate;
+            let rx_bytes_rate = alpha * (rx_bytes_diff as f64) / elapsed_secs
+                + (1.0 - alpha) * counters.smoothed_rx_bytes_rate;
+            counters.smoothed_tx_bytes_rate = tx_bytes_rate;
+            counters.smoothed_rx_bytes_rate = rx_bytes_rate;
 
             // 合計値に加算
             total_tx_bytes_rate += tx_bytes_rate;
             total_rx_bytes_rate += rx_bytes_rate;
 
-            // Gaugeに設定
-            self.local_ip_tx_bytes_rate
-                .with_label_values(&[local_ip])
-                .set(tx_bytes_rate);
-            self.local_ip_rx_bytes_rate
-                .with_label_values(&[local_ip])
-                .set(rx_bytes_rate);
+            // 出力対象のIPはラベルごとに集計し、対象外のIPは `_other` に集約
+            if is_exported_local_ip(&self.metrics_only_subnets, local_ip) {
+                let rates = label_rates
+                    .entry(aggregate_label(local_ip, self.aggregate_prefix))
+                    .or_default();
+                rates.0 += tx_bytes_rate;
+                rates.1 += rx_bytes_rate;
+            } else {
+                other_tx_bytes_rate += tx_bytes_rate;
+                other_rx_bytes_rate += rx_bytes_rate;
+            }
 
             // 前回値を更新
             counters.last_tx_bytes = counters.tx_bytes;
@@ -794,20 +2064,50 @@ impl NetworkMetrics {
             }
         }
 
+        for (label, (tx_bytes_rate, rx_bytes_rate)) in &label_rates {
+            self.local_ip_tx_bytes_rate
+                .with_label_values(&[label])
+                .set(*tx_bytes_rate);
+            self.local_ip_rx_bytes_rate
+                .with_label_values(&[label])
+                .set(*rx_bytes_rate);
+        }
+
         // 非アクティブなIPを削除
-        for ip in inactive_ips {
+        for ip in &inactive_ips {
             info!("Removing inactive IP from metrics: {}", ip);
 
-            // メトリクスから削除
+            // 内部カウンタから削除
+            self.internal_counters_per_ip.remove(ip);
+        }
+
+        // 同じラベルのIPが残っていなければメトリクスを0にする
+        for ip in &inactive_ips {
+            if !is_exported_local_ip(&self.metrics_only_subnets, ip) {
+                continue;
+            }
+            let label = aggregate_label(ip, self.aggregate_prefix);
+            let still_active = self
+                .internal_counters_per_ip
+                .keys()
+                .any(|other| aggregate_label(other, self.aggregate_prefix) == label);
+            if !still_active {
+                self.local_ip_tx_bytes_rate
+                    .with_label_values(&[&label])
+                    .set(0.0);
+                self.local_ip_rx_bytes_rate
+                    .with_label_values(&[&label])
+                    .set(0.0);
+            }
+        }
+
+        if !self.metrics_only_subnets.is_empty() {
             self.local_ip_tx_bytes_rate
-                .with_label_values(&[&ip])
-                .set(0.0);
+                .with_label_values(&[OTHER_LABEL])
+                .set(other_tx_bytes_rate);
             self.local_ip_rx_bytes_rate
-                .with_label_values(&[&ip])
-                .set(0.0);
-
-            // 内部カウンタから削除
-            self.internal_counters_per_ip.remove(&ip);
+                .with_label_values(&[OTHER_LABEL])
+                .set(other_rx_bytes_rate);
         }
 
         // 合計値メトリクスを設定
@@ -823,29 +2123,20 @@ impl NetworkMetrics {
         Ok(())
     }
 
-    /// Build local network ranges from IP and subnet
-    fn build_local_network_ranges(
-        local_ip: Option<IpAddr>, 
-        local_subnet: Option<Ipv4Addr>
-    ) -> Vec<(Ipv4Addr, u8)> {
+    /// Build local network ranges from every address on the interface
+    fn build_local_network_ranges(local_addresses: &[IpNetwork]) -> Vec<IpNetwork> {
         let mut ranges = Vec::new();
-        
-        if let (Some(IpAddr::V4(ip)), Some(subnet)) = (local_ip, local_subnet) {
-            let prefix = calculate_prefix_length(subnet);
-            let network = calculate_network_address(ip, subnet);
-            ranges.push((network, prefix));
+
+        for address in local_addresses {
+            // ネットワークアドレスに正規化し、同じサブネットの複数アドレスは1つの範囲にまとめる
+            match IpNetwork::new(address.network(), address.prefix()) {
+                Ok(network) if !ranges.contains(&network) => ranges.push(network),
+                Ok(_) => {}
+                Err(e) => warn!("Invalid local network {}: {}", address, e),
+            }
         }
-        
-        ranges
-    }
 
-    /// Calculate IP range for a given network and prefix
-    fn calculate_ip_range(network: Ipv4Addr, prefix: u8) -> (Ipv4Addr, Ipv4Addr) {
-        let network_u32 = u32::from(network);
-        let mask = !((1u32 << (32 - prefix)) - 1);
-        let min_ip = Ipv4Addr::from(network_u32 & mask);
-        let max_ip = Ipv4Addr::from((network_u32 & mask) | ((1u32 << (32 - prefix)) - 1));
-        (min_ip, max_ip)
+        ranges
     }
 }
 
@@ -887,9 +2178,10 @@ impl TrafficStats {
     }
 }
 
-/// Prometheusメトリクスをログに出力する機能（簡素化版）
+/// プロトコル別のトラフィック内訳を定期的にログ出力する
 pub async fn log_metrics_periodically(
-    _metrics: Arc<std::sync::Mutex<NetworkMetrics>>,
+    metrics: Arc<std::sync::Mutex<NetworkMetrics>>,
+    interface_name: &str,
     interval_secs: u64,
 ) -> Result<()> {
     let mut interval = time::interval(Duration::from_secs(interval_secs));
@@ -897,7 +2189,29 @@ pub async fn log_metrics_periodically(
     loop {
         tokio::select! {
             _ = interval.tick() => {
-                // 現在は何もしない（簡素化版）
+                let (statistics, top_hosts, top_snis) = match metrics.lock() {
+                    Ok(metrics) => (
+                        metrics.traffic_statistics(interface_name),
+                        metrics.top_http_hosts(TOP_HTTP_HOSTS),
+                        metrics.top_tls_snis(TOP_TLS_SNIS),
+                    ),
+                    Err(_) => continue,
+                };
+
+                let mut protocols: Vec<_> = statistics.protocols.iter().collect();
+                protocols.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.byte_count));
+                for (protocol, stats) in protocols {
+                    info!(
+                        "Protocol {} - packets: {}, bytes: {}",
+                        protocol, stats.packet_count, stats.byte_count
+                    );
+                }
+                for (host, count) in top_hosts {
+                    info!("HTTP host {} - requests: {}", host, count);
+                }
+                for (sni, count) in top_snis {
+                    info!("TLS SNI {} - connections: {}", sni, count);
+                }
             }
             _ = tokio::signal::ctrl_c() => {
                 info!("Metrics logger received shutdown signal");
@@ -934,6 +2248,92 @@ pub async fn update_rate_metrics_periodically(
     Ok(())
 }
 
+/// メトリクスの現在値を定期的に時系列ストアへ記録する関数
+pub async fn record_time_series_periodically(
+    metrics: Arc<std::sync::Mutex<NetworkMetrics>>,
+    store: TimeSeriesHandle,
+) -> Result<()> {
+    let mut interval = time::interval(Duration::from_secs(1)); // 1秒間隔で記録
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // 収集はメトリクスのロック中に、記録はストアのロック中のみ行う
+                let families = match metrics.lock() {
+                    Ok(metrics) => metrics.gather(),
+                    Err(_) => continue,
+                };
+                if let Ok(mut store) = store.lock() {
+                    store.record(&families, chrono::Utc::now());
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Time series recorder received shutdown signal");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 標準出力に書き出す1回分のメトリクスの始まりと終わりの行
+///
+/// どちらもテキスト形式ではコメントとして読み飛ばされる。始まりの行には書き出した時刻を入れ、
+/// 後段のパーサーが起動時のメッセージなど他の出力と区別して1回分ずつ切り出せるようにする。
+const STDOUT_SCRAPE_START: &str = "# SCRAPE";
+const STDOUT_SCRAPE_END: &str = "# EOF";
+
+/// テキスト形式のメトリクスを、区切りの行で囲んだ1回分の出力にする
+fn stdout_scrape(exposition: &str, timestamp: chrono::DateTime<chrono::Utc>) -> String {
+    let mut scrape = format!(
+        "{} {}\n",
+        STDOUT_SCRAPE_START,
+        timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    );
+    scrape.push_str(exposition);
+    if !exposition.is_empty() && !exposition.ends_with('\n') {
+        scrape.push('\n');
+    }
+    scrape.push_str(STDOUT_SCRAPE_END);
+    scrape.push('\n');
+    scrape
+}
+
+/// メトリクスをテキスト形式で定期的に標準出力へ書き出す関数（HTTPでスクレイプできない環境向け）
+pub async fn write_metrics_to_stdout_periodically(
+    metrics: Arc<std::sync::Mutex<NetworkMetrics>>,
+    period: Duration,
+) -> Result<()> {
+    use std::io::Write;
+
+    let mut interval = time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let exposition = match metrics.lock() {
+                    Ok(metrics) => metrics.export(),
+                    Err(_) => continue,
+                };
+                // 1回分をまとめて書き、他の出力と行が混ざらないようにする
+                let scrape = stdout_scrape(&exposition, chrono::Utc::now());
+                let mut stdout = std::io::stdout().lock();
+                stdout
+                    .write_all(scrape.as_bytes())
+                    .and_then(|()| stdout.flush())
+                    .context("Failed to write metrics to stdout")?;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stdout metrics writer received shutdown signal");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// IP統計のレートを定期的に更新する関数
 pub async fn update_ip_stats_rates_periodically(ip_stats: IpStatsMap) -> Result<()> {
     let mut interval = time::interval(Duration::from_secs(1));
@@ -960,38 +2360,989 @@ pub async fn update_packet_loss_metrics_periodically(
             let mut total_packets = 0;
             let mut total_lost_packets = 0;
 
-            // 古い接続をクリーンアップ
+            // 古い接続をクリーンアップ（削除した接続の継続時間を記録）
             let now = std::time::Instant::now();
+            metrics.reap_idle_connections(now);
             metrics
-                .tcp_connection_states
-                .retain(|_, state| now.duration_since(state.last_active).as_secs() < 60);
+                .advertised_mss
+                .retain(|_, (_, seen)| now.duration_since(*seen).as_secs() < 60);
 
             for state in metrics.tcp_connection_states.values() {
                 total_packets += state.total_packets;
                 total_lost_packets += state.lost_packets;
             }
 
+            // 欠損セグメントは観測されていないため、分母は観測数と欠損数の和とする
             let loss_percentage = if total_packets > 0 {
-                (total_lost_packets as f64 / total_packets as f64) * 100.0
+                (total_lost_packets as f64 / (total_packets + total_lost_packets) as f64) * 100.0
             } else {
                 0.0
             };
 
             metrics.packet_loss_percentage.set(loss_percentage);
+
+            let (mean, weighted) = connection_loss_percentages(
+                metrics.tcp_connection_states.values(),
+                metrics.min_packets_for_loss,
+            );
+            metrics.connection_loss_percentage_mean.set(mean);
+            metrics.connection_loss_percentage_weighted.set(weighted);
+
+            let at_or_above = connections_at_or_above_loss(
+                metrics.tcp_connection_states.values(),
+                metrics.min_packets_for_loss,
+            );
+            for (threshold, connections) in LOSS_CDF_THRESHOLDS.iter().zip(at_or_above) {
+                metrics
+                    .connections_loss_cdf
+                    .with_label_values(&[&threshold.to_string()])
+                    .set(connections as f64);
+            }
+        }
+    }
+}
+
+/// 接続ごとのロス率の単純平均と、パケット数で重み付けした加重平均を返す
+///
+/// 単純平均では1セグメントを失っただけの短い接続が全体を左右するため、
+/// パケット数が `min_packets` 未満の接続はどちらの値からも除外する。
+fn connection_loss_percentages<'a>(
+    states: impl Iterator<Item = &'a TcpConnectionState>,
+    min_packets: u64,
+) -> (f64, f64) {
+    let mut connections = 0u64;
+    let mut percentage_sum = 0.0;
+    let mut weighted_sum = 0.0;
+    let mut weight_total = 0u64;
+
+    for state in states.filter(|state| state.total_packets >= min_packets.max(1)) {
+        let percentage = state.lost_packets as f64
+            / (state.total_packets + state.lost_packets) as f64
+            * 100.0;
+        connections += 1;
+        percentage_sum += percentage;
+        weighted_sum += percentage * state.total_packets as f64;
+        weight_total += state.total_packets;
+    }
+
+    if connections == 0 {
+        return (0.0, 0.0);
+    }
+    (
+        percentage_sum / connections as f64,
+        weighted_sum / weight_total as f64,
+    )
+}
+
+/// `LOSS_CDF_THRESHOLDS` の各しきい値について、ロス率がそれ以上の接続数を返す
+///
+/// ロス率の平均と同じく、パケット数が `min_packets` 未満の接続は数えない。
+/// しきい値0%の値は対象の接続数になるため、割合はそれで割って求められる。
+fn connections_at_or_above_loss<'a>(
+    states: impl Iterator<Item = &'a TcpConnectionState>,
+    min_packets: u64,
+) -> Vec<u64> {
+    let mut counts = vec![0u64; LOSS_CDF_THRESHOLDS.len()];
+    for state in states.filter(|state| state.total_packets >= min_packets.max(1)) {
+        let percentage = state.lost_packets as f64
+            / (state.total_packets + state.lost_packets) as f64
+            * 100.0;
+        for (count, threshold) in counts.iter_mut().zip(LOSS_CDF_THRESHOLDS) {
+            if percentage >= *threshold {
+                *count += 1;
+            }
         }
     }
+    counts
 }
 
-/// サブネットマスクからプレフィックス長を計算
-fn calculate_prefix_length(subnet_mask: Ipv4Addr) -> u8 {
-    u32::from(subnet_mask).count_ones() as u8
+/// ローカルIPを個別のラベルで出力するかどうか（フィルタ未指定なら常に出力）
+fn is_exported_local_ip(subnets: &[IpNetwork], local_ip: &str) -> bool {
+    if subnets.is_empty() {
+        return true;
+    }
+    match local_ip.parse::<IpAddr>() {
+        Ok(ip) => subnets.iter().any(|subnet| subnet.contains(ip)),
+        Err(_) => false,
+    }
 }
 
-/// IPアドレスとサブネットマスクからネットワークアドレスを計算
-fn calculate_network_address(ip: Ipv4Addr, subnet_mask: Ipv4Addr) -> Ipv4Addr {
-    let ip_u32 = u32::from(ip);
-    let mask_u32 = u32::from(subnet_mask);
-    let network_u32 = ip_u32 & mask_u32;
+/// IP別レートのラベル（IPv4は `prefix` でマスクしたネットワークアドレス、32未満ならCIDR表記）
+fn aggregate_label(local_ip: &str, prefix: u8) -> String {
+    match local_ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) if prefix < 32 => match IpNetwork::new(IpAddr::V4(ip), prefix) {
+            Ok(network) => format!("{}/{}", network.network(), prefix),
+            Err(_) => local_ip.to_string(),
+        },
+        _ => local_ip.to_string(),
+    }
+}
+
+/// カウントの多い順に上位 `limit` 件を返す
+fn top_counts(counts: &HashMap<String, u64>, limit: usize) -> Vec<(String, u64)> {
+    let mut top: Vec<_> = counts
+        .iter()
+        .map(|(key, count)| (key.clone(), *count))
+        .collect();
+    top.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    top.truncate(limit);
+    top
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stdout_scrapes_are_delimited() {
+        use chrono::TimeZone;
+
+        let timestamp = chrono::Utc.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap();
+        assert_eq!(
+            stdout_scrape("# TYPE up gauge\nup 1\n", timestamp),
+            "# SCRAPE 2026-10-14T12:00:00.000Z\n# TYPE up gauge\nup 1\n# EOF\n"
+        );
+        // 末尾に改行のない出力でも区切りの行は独立した行になる
+        assert_eq!(
+            stdout_scrape("up 1", timestamp),
+            "# SCRAPE 2026-10-14T12:00:00.000Z\nup 1\n# EOF\n"
+        );
+        assert_eq!(
+            stdout_scrape("", timestamp),
+            "# SCRAPE 2026-10-14T12:00:00.000Z\n# EOF\n"
+        );
+    }
+
+    #[test]
+    fn small_gap_counts_one_lost_segment() {
+        let mut state = TcpConnectionState::new(1000, 1460, DEFAULT_MSS, 0);
+
+        // 1460バイトのセグメントが1つ欠けた
+        assert_eq!(
+            state.observe_segment(3920, 1460, 0, DEFAULT_MAX_PLAUSIBLE_GAP),
+            Some(1460)
+        );
+        assert_eq!(state.lost_packets, 1);
+
+        // MSS未満の欠損も1セグメントとして数える
+        assert_eq!(
+            state.observe_segment(5500, 100, 0, DEFAULT_MAX_PLAUSIBLE_GAP),
+            Some(120)
+        );
+        assert_eq!(state.lost_packets, 2);
+    }
+
+    #[test]
+    fn late_or_retransmitted_segments_do_not_rewind_expected_seq() {
+        let mut state = TcpConnectionState::new(1000, 1460, DEFAULT_MSS, 0);
+        state.observe_segment(2460, 1460, 0, DEFAULT_MAX_PLAUSIBLE_GAP);
+        assert_eq!(state.expected_seq, 3920);
+
+        // 再送されたセグメントの後も、続きのセグメントを欠損とみなさない
+        assert_eq!(state.observe_segment(1000, 1460, 0, DEFAULT_MAX_PLAUSIBLE_GAP), None);
+        assert_eq!(state.expected_seq, 3920);
+        assert_eq!(state.observe_segment(3920, 1460, 0, DEFAULT_MAX_PLAUSIBLE_GAP), None);
+        assert_eq!(state.lost_packets, 0);
+    }
+
+    #[test]
+    fn large_gap_counts_segments_by_mss() {
+        let mut state = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+
+        // 50,000バイトの欠損は1460バイトのMSSで35セグメント
+        assert_eq!(
+            state.observe_segment(1460 + 50_000, 1460, 0, DEFAULT_MAX_PLAUSIBLE_GAP),
+            Some(50_000)
+        );
+        assert_eq!(state.lost_packets, 35);
+    }
+
+    #[test]
+    fn mss_follows_syn_option_and_observed_payload() {
+        // SYNで広告されたMSSを使う
+        let mut state = TcpConnectionState::new(0, 500, 1000, 0);
+        state.observe_segment(500 + 10_000, 500, 0, DEFAULT_MAX_PLAUSIBLE_GAP);
+        assert_eq!(state.lost_packets, 10);
+
+        // それより大きなペイロードを観測したらMSSを引き上げる
+        let mut state = TcpConnectionState::new(0, 8960, 1460, 0);
+        state.observe_segment(8960 + 89_600, 8960, 0, DEFAULT_MAX_PLAUSIBLE_GAP);
+        assert_eq!(state.mss, 8960);
+        assert_eq!(state.lost_packets, 10);
+    }
+
+    #[test]
+    fn sequence_wraparound_is_not_loss() {
+        let start = u32::MAX - 2000;
+        let mut state = TcpConnectionState::new(start, 1460, DEFAULT_MSS, 0);
+        for i in 1..10 {
+            assert_eq!(
+                state.observe_segment(
+                    start.wrapping_add(i * 1460),
+                    1460,
+                    0,
+                    DEFAULT_MAX_PLAUSIBLE_GAP
+                ),
+                None
+            );
+        }
+        assert_eq!(state.lost_packets, 0);
+    }
+
+    #[test]
+    fn ipv6_prefix_is_local() {
+        let metrics = NetworkMetrics::new(&["2001:db8:1::10/64".parse().unwrap()], None);
+
+        assert!(metrics.is_local_ip("2001:db8:1::ffff".parse().unwrap()));
+        assert!(!metrics.is_local_ip("2001:db8:2::1".parse().unwrap()));
+        assert!(!metrics.is_local_ip("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn gap_ceiling_follows_receiver_window() {
+        let mut state = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+        assert_eq!(state.max_plausible_gap(None), DEFAULT_MAX_PLAUSIBLE_GAP);
+        assert_eq!(state.max_plausible_gap(Some(5_000)), 5_000);
+
+        // 窓の2倍までのギャップを欠損として扱う
+        state.receiver_window = 4 << 20;
+        assert_eq!(state.max_plausible_gap(None), 8 << 20);
+        let gap_limit = state.max_plausible_gap(None);
+        assert_eq!(
+            state.observe_segment(1460 + 2_000_000, 1460, 0, gap_limit),
+            Some(2_000_000)
+        );
+
+        // 明示的な上限を超えるギャップは無視する
+        assert_eq!(state.observe_segment(2_002_920 + 10_000, 1460, 0, 5_000), None);
+    }
+
+    #[test]
+    fn weighted_loss_ignores_tiny_flows() {
+        // 1パケットで1セグメントを失った短い接続と、1000パケット中10セグメントを失った接続
+        let mut tiny = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+        tiny.lost_packets = 1;
+        let mut bulk = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+        bulk.total_packets = 990;
+        bulk.lost_packets = 10;
+
+        let (mean, weighted) = connection_loss_percentages([&tiny, &bulk].into_iter(), 1);
+        assert!((mean - 25.5).abs() < 1e-9);
+        assert!(weighted < 1.1);
+
+        let (mean, weighted) = connection_loss_percentages([&tiny, &bulk].into_iter(), 10);
+        assert!((mean - 1.0).abs() < 1e-9);
+        assert!((weighted - 1.0).abs() < 1e-9);
+        assert_eq!(connection_loss_percentages([&tiny].into_iter(), 10), (0.0, 0.0));
+    }
+
+    #[test]
+    fn connections_are_counted_at_or_above_each_loss_threshold() {
+        let connection = |total_packets: u64, lost_packets: u64| {
+            let mut state = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+            state.total_packets = total_packets;
+            state.lost_packets = lost_packets;
+            state
+        };
+        // ロス率 0%、0.2%、1.5%、約9.1%、短い接続の50%
+        let states = [
+            connection(1000, 0),
+            connection(998, 2),
+            connection(985, 15),
+            connection(100, 10),
+            connection(1, 1),
+        ];
+
+        assert_eq!(
+            connections_at_or_above_loss(states.iter(), 10),
+            vec![4, 3, 2, 1, 0]
+        );
+        assert_eq!(
+            connections_at_or_above_loss(states.iter(), 1),
+            vec![5, 4, 3, 2, 1]
+        );
+
+        let metrics = NetworkMetrics::new(&[], None);
+        assert_eq!(
+            metrics
+                .connections_loss_cdf
+                .with_label_values(&["0.1"])
+                .get(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn finished_connection_records_duration() {
+        let mut metrics = NetworkMetrics::new(&[], None);
+        let key = "192.168.1.10:50000-203.0.113.10:443".to_string();
+        metrics
+            .tcp_connection_states
+            .insert(key.clone(), TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0));
+
+        metrics.finish_connection(&key);
+        metrics.finish_connection(&key);
+        assert!(metrics.tcp_connection_states.is_empty());
+        assert_eq!(metrics.connection_duration_seconds.get_sample_count(), 1);
+    }
+
+    #[test]
+    fn idle_connections_are_reaped_and_counted() {
+        let mut metrics = NetworkMetrics::new(&[], None);
+        for port in [50000, 50001] {
+            metrics.tcp_connection_states.insert(
+                format!("192.168.1.10:{}-203.0.113.10:443", port),
+                TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0),
+            );
+        }
 
-    Ipv4Addr::from(network_u32)
+        let now = std::time::Instant::now();
+        metrics.reap_idle_connections(now);
+        assert_eq!(metrics.connections_reaped_total.get(), 0.0);
+        assert_eq!(metrics.connection_map_size.get(), 2.0);
+
+        metrics.reap_idle_connections(now + Duration::from_secs(61));
+        assert_eq!(metrics.connections_reaped_total.get(), 2.0);
+        assert_eq!(metrics.connection_map_size.get(), 0.0);
+        assert_eq!(metrics.connection_duration_seconds.get_sample_count(), 2);
+    }
+
+    #[test]
+    fn run_summary_includes_top_ips_and_loss() {
+        let mut metrics = NetworkMetrics::new(&[], None);
+        metrics.record_packet(&PacketInfo {
+            protocol: "TCP".to_string(),
+            size: 1500,
+            src_ip: Some("203.0.113.10".parse().unwrap()),
+            dst_ip: Some("192.168.1.10".parse().unwrap()),
+            src_port: None,
+            dst_port: None,
+            timestamp: chrono::Utc::now(),
+        });
+        metrics.packet_loss_percentage.set(1.5);
+
+        let mut ip_stats = HashMap::new();
+        for (ip, tx_bytes, rx_bytes) in [
+            ("192.168.1.10", 100, 3000),
+            ("203.0.113.10", 3000, 100),
+            ("203.0.113.20", 50, 0),
+        ] {
+            ip_stats.insert(
+                ip.parse().unwrap(),
+                IpStats {
+                    tx_bytes,
+                    rx_bytes,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let summary = metrics.run_summary("eth0", &ip_stats, 2);
+        assert_eq!(summary.interface, "eth0");
+        assert_eq!(summary.total.byte_count, 1500);
+        assert_eq!(summary.protocols["TCP"].packet_count, 1);
+        assert_eq!(summary.packet_loss_percentage, 1.5);
+        // 上位2件のみ、バイト数0のIPは含めない
+        assert_eq!(summary.source_ips.len(), 2);
+        assert_eq!(summary.source_ips[&"203.0.113.10".parse::<IpAddr>().unwrap()], 3000);
+        assert_eq!(summary.destination_ips.len(), 2);
+        assert!(!summary
+            .destination_ips
+            .contains_key(&"203.0.113.20".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn snapshot_copies_totals_and_top_talkers() {
+        let mut metrics = NetworkMetrics::new(&["192.168.1.10/24".parse().unwrap()], None);
+        metrics.total_tx_bytes_rate.set(125_000.0);
+        metrics.packet_loss_percentage.set(1.5);
+        metrics.tcp_connection_states.insert(
+            "192.168.1.10:50000-203.0.113.10:443".to_string(),
+            TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0),
+        );
+        // アクティブな時間枠を過ぎて削除待ちの接続は数えない
+        let mut idle = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+        idle.last_active = std::time::Instant::now() - CONNECTION_ACTIVE_WINDOW;
+        metrics
+            .tcp_connection_states
+            .insert("192.168.1.10:50001-203.0.113.10:443".to_string(), idle);
+
+        let mut ip_stats = HashMap::new();
+        for i in 0..12u64 {
+            ip_stats.insert(
+                format!("192.168.1.{}", 100 + i).parse().unwrap(),
+                IpStats {
+                    tx_bytes: i * 100,
+                    rx_bytes: 50,
+                    ..Default::default()
+                },
+            );
+        }
+        // ローカル以外のIPも送受信バイト数で順位付けし、通信のないIPは含めない
+        for (ip, tx_bytes) in [("203.0.113.10", 5000), ("203.0.113.20", 0)] {
+            ip_stats.insert(
+                ip.parse().unwrap(),
+                IpStats {
+                    tx_bytes,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let snapshot = metrics.snapshot(&ip_stats);
+        assert_eq!(snapshot.total_tx_bytes_rate, 125_000.0);
+        assert_eq!(snapshot.total_rx_bytes_rate, 0.0);
+        assert_eq!(snapshot.packet_loss_percentage, 1.5);
+        assert_eq!(snapshot.active_connections, 1);
+        assert_eq!(snapshot.top_talkers.len(), SNAPSHOT_TOP_TALKERS);
+        assert_eq!(
+            snapshot.top_talkers[0],
+            TalkerSnapshot {
+                ip: "203.0.113.10".parse().unwrap(),
+                tx_bytes: 5000,
+                rx_bytes: 0,
+            }
+        );
+        assert_eq!(snapshot.top_talkers[1].ip, "192.168.1.111".parse::<IpAddr>().unwrap());
+        assert_eq!(snapshot.top_talkers[9].ip, "192.168.1.103".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_the_cap() {
+        let mut backoff = CAPTURE_RECONNECT_INITIAL_BACKOFF;
+        let mut waits = Vec::new();
+        for _ in 0..7 {
+            backoff = next_reconnect_backoff(backoff);
+            waits.push(backoff.as_secs());
+        }
+        assert_eq!(waits, vec![2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn lan_traffic_is_attributed_to_the_host_ip() {
+        let mut metrics = NetworkMetrics::new(&["192.168.1.10/24".parse().unwrap()], None);
+        let packet = |src: &str, dst: &str, size| PacketInfo {
+            protocol: "TCP".to_string(),
+            size,
+            src_ip: Some(src.parse().unwrap()),
+            dst_ip: Some(dst.parse().unwrap()),
+            src_port: None,
+            dst_port: None,
+            timestamp: chrono::Utc::now(),
+        };
+
+        metrics.record_packet(&packet("192.168.1.10", "192.168.1.20", 100));
+        metrics.record_packet(&packet("192.168.1.20", "192.168.1.10", 200));
+        metrics.record_packet(&packet("192.168.1.10", "203.0.113.10", 300));
+        // モニタ自身が関わらないLAN内の通信はどちらにも数えない
+        metrics.record_packet(&packet("192.168.1.20", "192.168.1.30", 400));
+
+        let host = &metrics.internal_counters_per_ip["192.168.1.10"];
+        assert_eq!((host.tx_bytes, host.rx_bytes), (400, 200));
+        assert_eq!(metrics.internal_counters_per_ip.len(), 1);
+        assert_eq!(metrics.host_tx_bytes_total.get(), 400.0);
+        assert_eq!(metrics.host_rx_bytes_total.get(), 200.0);
+        // 合計のカウンタはIP別の内部カウンタと同じ向きで数える
+        assert_eq!(metrics.total_tx_bytes_total.get(), 400.0);
+        assert_eq!(metrics.total_rx_bytes_total.get(), 200.0);
+    }
+
+    #[test]
+    fn unparsed_packet_warnings_are_rate_limited() {
+        let mut log = UnparsedPacketLog::default();
+        let start = std::time::Instant::now();
+
+        // 最初の1件はすぐに警告し、間隔内の分は次の警告にまとめる
+        assert_eq!(
+            log.record(Some(EtherTypes::Arp), start),
+            Some((1, Some(EtherTypes::Arp)))
+        );
+        assert_eq!(log.record(None, start + Duration::from_secs(1)), None);
+        assert_eq!(
+            log.record(Some(EtherTypes::Vlan), start + Duration::from_secs(2)),
+            None
+        );
+        assert_eq!(
+            log.record(Some(EtherTypes::Arp), start + UNPARSED_WARN_INTERVAL),
+            Some((3, Some(EtherTypes::Vlan)))
+        );
+        assert_eq!(log.count, 0);
+    }
+
+    #[test]
+    fn routine_link_layer_frames_are_not_warned_about() {
+        assert!(is_routine_l2_ethertype(EtherTypes::Arp));
+        assert!(is_routine_l2_ethertype(EtherTypes::Lldp));
+        assert!(is_routine_l2_ethertype(EtherType(0x888e)));
+        assert!(is_routine_l2_ethertype(EtherType(0x0026)));
+        assert!(!is_routine_l2_ethertype(EtherTypes::Vlan));
+        assert!(!is_routine_l2_ethertype(EtherTypes::Mpls));
+    }
+
+    #[test]
+    fn rates_are_aggregated_by_prefix() {
+        assert_eq!(aggregate_label("192.168.1.10", 32), "192.168.1.10");
+        assert_eq!(aggregate_label("192.168.1.10", 24), "192.168.1.0/24");
+        assert_eq!(aggregate_label("10.20.30.40", 16), "10.20.0.0/16");
+        assert_eq!(aggregate_label("2001:db8::1", 24), "2001:db8::1");
+
+        let mut metrics = NetworkMetrics::new(&["10.20.0.1/16".parse().unwrap()], None);
+        metrics.aggregate_prefix = 24;
+        for (ip, tx_bytes) in [("10.20.1.5", 1000), ("10.20.1.6", 3000), ("10.20.2.5", 500)] {
+            let mut counters = LocalIpCounters::new();
+            counters.tx_bytes = tx_bytes;
+            metrics.internal_counters_per_ip.insert(ip.to_string(), counters);
+        }
+        metrics.last_update_time = std::time::Instant::now() - Duration::from_secs(2);
+        metrics.update_rate_metrics().unwrap();
+
+        let tx_rate = |label: &str| {
+            metrics
+                .local_ip_tx_bytes_rate
+                .with_label_values(&[label])
+                .get()
+        };
+        assert!((tx_rate("10.20.1.0/24") - 2000.0).abs() < 50.0);
+        assert!((tx_rate("10.20.2.0/24") - 250.0).abs() < 10.0);
+        let series = prometheus::core::Collector::collect(&metrics.local_ip_tx_bytes_rate);
+        assert_eq!(series[0].get_metric().len(), 2);
+    }
+
+    #[test]
+    fn metric_prefix_is_prepended_to_every_name() {
+        let metrics = NetworkMetrics::new(&[], Some("edge1"));
+        let names: Vec<String> = metrics
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert!(names.contains(&"edge1_total_tx_bytes_rate".to_string()));
+        assert!(names.iter().all(|name| name.starts_with("edge1_")));
+
+        let metrics = NetworkMetrics::new(&[], None);
+        assert!(metrics
+            .gather()
+            .iter()
+            .any(|family| family.get_name() == "total_tx_bytes_rate"));
+    }
+
+    #[test]
+    fn distinct_http_hosts_are_capped() {
+        let mut metrics = NetworkMetrics::new(&[], None);
+        let request = |host: String| HttpRequestSummary {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            host: Some(host),
+        };
+        for i in 0..MAX_HTTP_HOSTS + 5 {
+            metrics.record_http_request(&request(format!("host{}.example", i)));
+        }
+        // 既に数えているHostは上限に達した後も個別に数える
+        metrics.record_http_request(&request("host0.example".to_string()));
+
+        assert_eq!(metrics.http_host_requests.len(), MAX_HTTP_HOSTS + 1);
+        assert_eq!(metrics.http_host_requests[OTHER_LABEL], 5);
+        assert_eq!(metrics.http_host_requests["host0.example"], 2);
+        assert_eq!(
+            metrics
+                .http_requests_total
+                .with_label_values(&["GET"])
+                .get(),
+            (MAX_HTTP_HOSTS + 6) as f64
+        );
+    }
+
+    #[test]
+    fn every_metric_has_the_expected_name_type_and_help() {
+        use prometheus::proto::MetricType::{COUNTER, GAUGE, HISTOGRAM};
+
+        let metrics = NetworkMetrics::new(&[], None);
+        // ラベル付きのメトリクスは系列ができるまで出力されない
+        metrics
+            .local_ip_tx_bytes_rate
+            .with_label_values(&["192.0.2.1"])
+            .set(0.0);
+        metrics
+            .local_ip_rx_bytes_rate
+            .with_label_values(&["192.0.2.1"])
+            .set(0.0);
+        metrics.tunnel_packets_total.with_label_values(&["gre"]);
+        metrics
+            .unparsed_packets_total
+            .with_label_values(&["truncated_ip"]);
+        metrics.http_requests_total.with_label_values(&["GET"]);
+        metrics.tls_sni_total.with_label_values(&["example.com"]);
+
+        let expected = [
+            (
+                "local_ip_tx_bytes_rate",
+                GAUGE,
+                "Current transmission rate in bytes/sec per local IP",
+            ),
+            (
+                "local_ip_rx_bytes_rate",
+                GAUGE,
+                "Current reception rate in bytes/sec per local IP",
+            ),
+            (
+                "total_tx_bytes_rate",
+                GAUGE,
+                "Total transmission rate in bytes/sec for all local IPs",
+            ),
+            (
+                "total_rx_bytes_rate",
+                GAUGE,
+                "Total reception rate in bytes/sec for all local IPs",
+            ),
+            (
+                "total_tx_bytes_total",
+                COUNTER,
+                "Total bytes transmitted by all local IPs",
+            ),
+            (
+                "total_rx_bytes_total",
+                COUNTER,
+                "Total bytes received by all local IPs",
+            ),
+            (
+                "packet_loss_percentage",
+                GAUGE,
+                "Percentage of TCP segments detected as lost across all tracked connections",
+            ),
+            (
+                "tcp_connection_loss_percentage_mean",
+                GAUGE,
+                "Unweighted mean of per-connection packet loss percentages",
+            ),
+            (
+                "tcp_connection_loss_percentage_weighted",
+                GAUGE,
+                "Per-connection packet loss percentages weighted by packet count",
+            ),
+            (
+                "tcp_monitor_connections_loss_cdf",
+                GAUGE,
+                "Number of tracked connections whose packet loss percentage is at or above the threshold",
+            ),
+            (
+                "host_tx_bytes_total",
+                COUNTER,
+                "Total bytes sent from the monitored interface's own IP",
+            ),
+            (
+                "host_rx_bytes_total",
+                COUNTER,
+                "Total bytes received by the monitored interface's own IP",
+            ),
+            (
+                "tcp_connection_duration_seconds",
+                HISTOGRAM,
+                "Lifetime of TCP connections ended by FIN/RST or idle eviction",
+            ),
+            (
+                "ipv4_fragments_skipped_total",
+                COUNTER,
+                "Number of IPv4 fragments skipped before TCP loss analysis",
+            ),
+            (
+                "tunnel_packets_total",
+                COUNTER,
+                "Number of tunnel packets whose inner packet was analyzed, by tunnel type",
+            ),
+            (
+                "unparsed_packets_total",
+                COUNTER,
+                "Number of captured frames that could not be parsed as IPv4/IPv6, by reason",
+            ),
+            (
+                "captured_packets_total",
+                COUNTER,
+                "Number of captured packets handed to the processing queue, including dropped ones",
+            ),
+            (
+                "channel_dropped_total",
+                COUNTER,
+                "Number of captured packets dropped because the processing queue was full",
+            ),
+            (
+                "capture_reconnects_total",
+                COUNTER,
+                "Number of times the capture interface was reopened after a capture error",
+            ),
+            (
+                "tcp_monitor_connections_reaped_total",
+                COUNTER,
+                "Number of idle TCP connection states removed by the periodic cleanup",
+            ),
+            (
+                "tcp_monitor_connection_map_size",
+                GAUGE,
+                "Number of TCP connection states held after the last periodic cleanup",
+            ),
+            (
+                "http_requests_total",
+                COUNTER,
+                "Number of plaintext HTTP requests observed per method",
+            ),
+            (
+                "tls_sni_total",
+                COUNTER,
+                "Number of TLS ClientHello messages observed per SNI",
+            ),
+        ];
+
+        let mut gathered: Vec<(String, prometheus::proto::MetricType, String)> = metrics
+            .gather()
+            .iter()
+            .map(|family| {
+                (
+                    family.get_name().to_string(),
+                    family.get_field_type(),
+                    family.get_help().to_string(),
+                )
+            })
+            .collect();
+        gathered.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected: Vec<(String, prometheus::proto::MetricType, String)> = expected
+            .iter()
+            .map(|(name, kind, help)| (name.to_string(), *kind, help.to_string()))
+            .collect();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(gathered, expected);
+
+        // カウンタは `_total`、ヒストグラムは単位で終わる名前にする
+        for (name, kind, _) in &gathered {
+            match kind {
+                COUNTER => assert!(name.ends_with("_total"), "{}", name),
+                HISTOGRAM => assert!(name.ends_with("_seconds"), "{}", name),
+                _ => assert!(!name.ends_with("_total"), "{}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn interfaces_are_resolved_by_name_then_index_then_mac() {
+        let interface = |name: &str, index: u32, mac: Option<MacAddr>| NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac,
+            ips: Vec::new(),
+            flags: 0,
+        };
+        let mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0x00, 0x00, 0x02);
+        let interfaces = vec![interface("lo", 1, None), interface("enp3s0", 2, Some(mac))];
+        let resolved = |selector: InterfaceSelector| {
+            selector
+                .resolve(&interfaces)
+                .map(|iface| iface.name)
+                .map_err(|e| e.to_string())
+        };
+
+        let by_index = InterfaceSelector {
+            name: Some("ens19".to_string()),
+            index: Some(2),
+            mac: None,
+        };
+        assert_eq!(resolved(by_index), Ok("enp3s0".to_string()));
+        let by_mac = InterfaceSelector {
+            mac: Some("aa:bb:cc:00:00:02".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(resolved(by_mac), Ok("enp3s0".to_string()));
+        // 名前が一致すればインデックスより優先する
+        let by_name = InterfaceSelector {
+            name: Some("lo".to_string()),
+            index: Some(2),
+            mac: None,
+        };
+        assert_eq!(resolved(by_name), Ok("lo".to_string()));
+
+        let missing = InterfaceSelector {
+            name: Some("ens19".to_string()),
+            index: Some(7),
+            mac: None,
+        };
+        assert_eq!(
+            resolved(missing),
+            Err("No interface matched name 'ens19', then index 7; available interfaces: lo (index 1), enp3s0 (index 2, MAC aa:bb:cc:00:00:02)".to_string())
+        );
+    }
+
+    #[test]
+    fn every_interface_address_is_local() {
+        let mut metrics = NetworkMetrics::new(
+            &[
+                "192.168.1.10/24".parse().unwrap(),
+                "192.168.1.11/24".parse().unwrap(),
+                "10.0.0.5/16".parse().unwrap(),
+                "2001:db8:1::10/64".parse().unwrap(),
+            ],
+            None,
+        );
+        // 同じサブネットのセカンダリアドレスは1つの範囲にまとめる
+        assert_eq!(metrics.local_network_ranges.len(), 3);
+        assert!(metrics.is_local_ip("10.0.200.1".parse().unwrap()));
+        assert!(metrics.is_local_ip("2001:db8:1::1".parse().unwrap()));
+
+        let packet = PacketInfo {
+            protocol: "TCP".to_string(),
+            size: 500,
+            src_ip: Some("203.0.113.10".parse().unwrap()),
+            dst_ip: Some("10.0.0.5".parse().unwrap()),
+            src_port: None,
+            dst_port: None,
+            timestamp: chrono::Utc::now(),
+        };
+        metrics.record_packet(&packet);
+        assert_eq!(metrics.internal_counters_per_ip["10.0.0.5"].rx_bytes, 500);
+        assert_eq!(metrics.host_rx_bytes_total.get(), 500.0);
+    }
+
+    #[test]
+    fn exclusions_match_ips_and_transport_ports() {
+        let exclusions = PacketExclusions {
+            ips: vec!["192.168.1.50".parse().unwrap()],
+            ports: vec![22],
+        };
+        let local: IpAddr = "192.168.1.10".parse().unwrap();
+        let remote: IpAddr = "203.0.113.10".parse().unwrap();
+
+        let ssh = transport_ports(IpNextHeaderProtocols::Tcp, &[0xc3, 0x50, 0x00, 0x16]);
+        assert_eq!(ssh, Some((50000, 22)));
+        assert!(exclusions.matches(local, remote, ssh));
+        assert!(exclusions.matches("192.168.1.50".parse().unwrap(), remote, None));
+        assert!(!exclusions.matches(local, remote, Some((50000, 443))));
+        assert_eq!(
+            transport_ports(IpNextHeaderProtocols::Icmp, &[0, 22, 0, 22]),
+            None
+        );
+    }
+
+    #[test]
+    fn in_order_segments_report_no_loss() {
+        let mut state = TcpConnectionState::new(0, 1460, DEFAULT_MSS, 0);
+        for i in 1..10 {
+            assert_eq!(
+                state.observe_segment(i * 1460, 1460, 0, DEFAULT_MAX_PLAUSIBLE_GAP),
+                None
+            );
+        }
+        assert_eq!(state.lost_packets, 0);
+        assert_eq!(state.total_packets, 10);
+    }
+
+    /// 合成したTCPセグメントをライブキャプチャなしで欠損検出に直接流し込むハーネス
+    mod synthetic {
+        use super::*;
+
+        const CONNECTION: &str = "203.0.113.10:443-192.168.1.10:50000";
+
+        /// 1つの接続に (シーケンス番号, ペイロード長) のセグメントを順に流し、
+        /// 検出された (期待値, ギャップ) の一覧を返す
+        fn replay(metrics: &mut NetworkMetrics, segments: &[(u32, u32)]) -> Vec<(u32, u32)> {
+            segments
+                .iter()
+                .filter_map(|&(seq, len)| metrics.observe_tcp_segment(CONNECTION, seq, len, 0))
+                .collect()
+        }
+
+        fn lost_packets(metrics: &NetworkMetrics) -> u64 {
+            metrics.tcp_connection_states[CONNECTION].lost_packets
+        }
+
+        #[test]
+        fn clean_stream() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            let gaps = replay(&mut metrics, &[(1000, 1460), (2460, 1460), (3920, 1460)]);
+
+            assert!(gaps.is_empty());
+            assert_eq!(lost_packets(&metrics), 0);
+            assert_eq!(metrics.tcp_connection_states[CONNECTION].total_packets, 3);
+        }
+
+        #[test]
+        fn single_loss() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            let gaps = replay(&mut metrics, &[(1000, 1460), (3920, 1460), (5380, 1460)]);
+
+            assert_eq!(gaps, vec![(2460, 1460)]);
+            assert_eq!(lost_packets(&metrics), 1);
+        }
+
+        #[test]
+        fn reorder() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            // 2460が3920の後に届く。先行したセグメントのギャップは欠損として数えるが、
+            // 遅れたセグメントで期待値を巻き戻して次のセグメントを二重に数えない
+            let gaps = replay(
+                &mut metrics,
+                &[(1000, 1460), (3920, 1460), (2460, 1460), (5380, 1460)],
+            );
+
+            assert_eq!(gaps, vec![(2460, 1460)]);
+            assert_eq!(lost_packets(&metrics), 1);
+            assert_eq!(metrics.tcp_connection_states[CONNECTION].expected_seq, 6840);
+        }
+
+        #[test]
+        fn duplicate() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            let gaps = replay(
+                &mut metrics,
+                &[(1000, 1460), (2460, 1460), (2460, 1460), (3920, 1460)],
+            );
+
+            assert!(gaps.is_empty());
+            assert_eq!(lost_packets(&metrics), 0);
+        }
+
+        #[test]
+        fn wraparound() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            let start = u32::MAX - 2000;
+            let segments: Vec<(u32, u32)> = (0..5)
+                .map(|i| (start.wrapping_add(i * 1460), 1460))
+                .collect();
+            assert!(replay(&mut metrics, &segments).is_empty());
+
+            // 折り返し後に1セグメント欠けた
+            let gaps = replay(&mut metrics, &[(start.wrapping_add(6 * 1460), 1460)]);
+            assert_eq!(gaps, vec![(start.wrapping_add(5 * 1460), 1460)]);
+            assert_eq!(lost_packets(&metrics), 1);
+        }
+
+        #[test]
+        fn syn_with_data_consumes_a_sequence_number() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            // TCP Fast Open: ISN 1000のSYNに100バイトのデータ（1001〜1100）を載せる
+            assert_eq!(
+                metrics.observe_tcp_segment(CONNECTION, 1000, 100, TcpFlags::SYN),
+                None
+            );
+            assert_eq!(metrics.tcp_connection_states[CONNECTION].expected_seq, 1101);
+
+            let gaps = replay(&mut metrics, &[(1101, 1460), (2561, 1460)]);
+            assert!(gaps.is_empty());
+            assert_eq!(lost_packets(&metrics), 0);
+
+            // データ付きのFINも1つ消費する
+            metrics.observe_tcp_segment(CONNECTION, 4021, 10, TcpFlags::FIN | TcpFlags::ACK);
+            assert_eq!(metrics.tcp_connection_states[CONNECTION].expected_seq, 4032);
+        }
+
+        #[test]
+        fn first_segment_uses_the_advertised_mss() {
+            let mut metrics = NetworkMetrics::new(&[], None);
+            metrics
+                .advertised_mss
+                .insert(CONNECTION.to_string(), (500, std::time::Instant::now()));
+
+            // 5,000バイトの欠損はSYNで広告された500バイトのMSSで10セグメント
+            let gaps = replay(&mut metrics, &[(0, 500), (5500, 500)]);
+            assert_eq!(gaps, vec![(500, 5000)]);
+            assert_eq!(lost_packets(&metrics), 10);
+            assert!(metrics.advertised_mss.is_empty());
+        }
+    }
 }
@@ -0,0 +1,61 @@
+use pnet::packet::ethernet::EtherType;
+
+/// フラグ: Checksum Present（チェックサムと予約フィールドの4バイトが続く）
+const FLAG_CHECKSUM: u16 = 0x8000;
+/// フラグ: Routing Present（RFC 1701のソースルーティング、非対応）
+const FLAG_ROUTING: u16 = 0x4000;
+/// フラグ: Key Present（RFC 2890のキー4バイトが続く）
+const FLAG_KEY: u16 = 0x2000;
+/// フラグ: Sequence Number Present（RFC 2890のシーケンス番号4バイトが続く）
+const FLAG_SEQUENCE: u16 = 0x1000;
+/// バージョンフィールド（0以外はPPTPの拡張GREなどのため解析しない）
+const VERSION_MASK: u16 = 0x0007;
+
+/// 固定部分（フラグ・バージョンとProtocol Type）の長さ
+const BASE_HEADER_LEN: usize = 4;
+/// 省略可能なフィールド1つ分の長さ
+const OPTIONAL_FIELD_LEN: usize = 4;
+
+/// GREヘッダ（RFC 2784/2890）を解析し、内側のパケットのEtherTypeとペイロードを返す
+///
+/// ソースルーティング付きのヘッダとバージョン0以外のGREはNoneになる。
+pub fn parse_gre(payload: &[u8]) -> Option<(EtherType, &[u8])> {
+    let header = payload.get(..BASE_HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[0], header[1]]);
+    if flags & (FLAG_ROUTING | VERSION_MASK) != 0 {
+        return None;
+    }
+    let protocol = EtherType(u16::from_be_bytes([header[2], header[3]]));
+
+    let optional_fields = [FLAG_CHECKSUM, FLAG_KEY, FLAG_SEQUENCE]
+        .iter()
+        .filter(|flag| flags & **flag != 0)
+        .count();
+    let header_len = BASE_HEADER_LEN + optional_fields * OPTIONAL_FIELD_LEN;
+    Some((protocol, payload.get(header_len..)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::EtherTypes;
+
+    #[test]
+    fn skips_optional_fields_before_the_inner_packet() {
+        let inner = [0x45, 0x00, 0x00, 0x14];
+
+        let mut plain = vec![0x00, 0x00, 0x08, 0x00];
+        plain.extend(inner);
+        assert_eq!(parse_gre(&plain), Some((EtherTypes::Ipv4, &inner[..])));
+
+        // チェックサム・キー・シーケンス番号付き
+        let mut keyed = vec![0xb0, 0x00, 0x86, 0xdd];
+        keyed.extend([0u8; 12]);
+        keyed.extend(inner);
+        assert_eq!(parse_gre(&keyed), Some((EtherTypes::Ipv6, &inner[..])));
+
+        // 途中で切れたヘッダ、PPTPの拡張GRE（バージョン1）は解析しない
+        assert_eq!(parse_gre(&keyed[..10]), None);
+        assert_eq!(parse_gre(&[0x30, 0x81, 0x88, 0x0b, 0, 0, 0, 0]), None);
+    }
+}
@@ -0,0 +1,58 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Write};
+
+/// 圧縮レベル（Prometheusのスクレイプ間隔に対して十分速く、テキスト形式で1/10程度になる）
+const COMPRESSION_LEVEL: u32 = 6;
+
+/// Accept-Encodingヘッダの値がgzipを受け付けるかどうかを判定（`q=0` は拒否として扱う）
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let rejected = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+    })
+}
+
+/// データをgzip形式に圧縮する
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(COMPRESSION_LEVEL));
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compressed_output_is_a_valid_gzip_member() {
+        let body = "total_tx_bytes_rate 12345\n".repeat(100);
+        let gzipped = compress(body.as_bytes()).unwrap();
+
+        let mut inflated = String::new();
+        GzDecoder::new(gzipped.as_slice())
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, body);
+        assert!(gzipped.len() < body.len() / 10);
+    }
+
+    #[test]
+    fn accept_encoding_is_negotiated() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip("identity"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip("br, gzip; q=0.0"));
+    }
+}
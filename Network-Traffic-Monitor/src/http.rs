@@ -0,0 +1,110 @@
+/// 平文HTTPとして解析するTCPポート
+pub const HTTP_PORT: u16 = 80;
+
+/// リクエスト行の先頭として認識するメソッド
+const HTTP_METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// TCPペイロードから取り出したHTTPリクエストの概要
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequestSummary {
+    pub method: String,
+    pub path: String,
+    /// Hostヘッダ（同じセグメントに含まれていない場合はNone）
+    pub host: Option<String>,
+}
+
+/// TCPペイロードの先頭をHTTPリクエストとして解析する
+///
+/// セグメント境界を跨いだ再構築は行わず、ペイロードが既知のメソッドと空白で
+/// 始まる場合のみ解析する。続きのセグメントやレスポンスはNoneになる。
+pub fn parse_http_request(payload: &[u8]) -> Option<HttpRequestSummary> {
+    let method = HTTP_METHODS.iter().find(|method| {
+        payload.len() > method.len()
+            && payload.starts_with(method.as_bytes())
+            && payload[method.len()] == b' '
+    })?;
+
+    let text = String::from_utf8_lossy(payload);
+    let (request_line, headers) = text.split_once("\r\n").unwrap_or((&text, ""));
+
+    // リクエスト行: METHOD SP request-target SP HTTP-version
+    let path = request_line[method.len() + 1..]
+        .split(' ')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    // 最後のCRLF以降は次のセグメントに続く可能性があるため対象外とする
+    let headers = headers.rfind("\r\n").map_or("", |end| &headers[..end]);
+    let host = headers
+        .split("\r\n")
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_ascii_lowercase())
+        .filter(|host| !host.is_empty());
+
+    Some(HttpRequestSummary {
+        method: method.to_string(),
+        path,
+        host,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_path_and_host_are_extracted() {
+        let get = parse_http_request(b"GET /index.html HTTP/1.1\r\nHost: Example.COM\r\nAccept: */*\r\n\r\n").unwrap();
+        assert_eq!(
+            get,
+            HttpRequestSummary {
+                method: "GET".to_string(),
+                path: "/index.html".to_string(),
+                host: Some("example.com".to_string()),
+            }
+        );
+
+        let post = parse_http_request(
+            b"POST /api/v1/items?id=3 HTTP/1.1\r\nContent-Type: application/json\r\nHost: api.example.com:8080\r\n\r\n{\"a\":1}",
+        )
+        .unwrap();
+        assert_eq!(post.method, "POST");
+        assert_eq!(post.path, "/api/v1/items?id=3");
+        assert_eq!(post.host.as_deref(), Some("api.example.com:8080"));
+    }
+
+    #[test]
+    fn non_request_payloads_are_ignored() {
+        assert_eq!(parse_http_request(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"), None);
+        assert_eq!(parse_http_request(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00]), None);
+        // メソッド名で始まっていても空白が続かなければリクエスト行ではない
+        assert_eq!(parse_http_request(b"GETTING /"), None);
+        assert_eq!(parse_http_request(b"GET"), None);
+        assert_eq!(parse_http_request(b""), None);
+    }
+
+    #[test]
+    fn host_header_name_is_case_insensitive() {
+        let summary = parse_http_request(b"GET / HTTP/1.1\r\nhost: example.com\r\n\r\n").unwrap();
+        assert_eq!(summary.host.as_deref(), Some("example.com"));
+        let summary = parse_http_request(b"GET / HTTP/1.1\r\nHOST:example.org \r\n\r\n").unwrap();
+        assert_eq!(summary.host.as_deref(), Some("example.org"));
+    }
+
+    #[test]
+    fn headers_cut_before_the_host_line_have_no_host() {
+        // Hostヘッダが次のセグメントに続く
+        let summary = parse_http_request(b"GET /a HTTP/1.1\r\nUser-Agent: curl/8.0\r\n").unwrap();
+        assert_eq!(summary.method, "GET");
+        assert_eq!(summary.path, "/a");
+        assert_eq!(summary.host, None);
+        // Host行の途中で切れている場合は値を信用しない
+        let summary = parse_http_request(b"GET /a HTTP/1.1\r\nUser-Agent: curl/8.0\r\nHost: exam").unwrap();
+        assert_eq!(summary.host, None);
+    }
+}
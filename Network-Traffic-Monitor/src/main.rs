@@ -1,30 +1,310 @@
+mod auth;
 mod capture;
+mod config;
+mod gre;
+mod gzip;
+mod http;
+mod openmetrics;
 mod prometheus_server;
+mod ring;
 mod stats;
+mod timeseries;
+mod tls;
 
 use anyhow::Result;
-use capture::start_network_monitoring_system;
+use capture::{
+    start_network_monitoring_system, InterfaceSelector, MonitorConfig, PacketExclusions,
+    DEFAULT_CHANNEL_CAPACITY, DEFAULT_MIN_PACKETS_FOR_LOSS, DEFAULT_READ_BUFFER_SIZE,
+};
 use clap::Parser;
 use log::{error, info};
+use pnet::datalink::MacAddr;
+use pnet::ipnetwork::IpNetwork;
+use prometheus_server::PushgatewayConfig;
+use serde::{Deserialize, Serialize};
+use ring::DEFAULT_CAPTURE_RING;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::signal;
 
+/// Number of source and destination IPs included in the exit summary
+const SUMMARY_TOP_IPS: usize = 10;
+
+/// インターフェースを何も指定しない場合に監視するインターフェース名
+const DEFAULT_INTERFACE: &str = "ens19";
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Network interface to monitor (default: ens19)
-    #[arg(short, long, default_value = "ens19")]
-    interface: String,
+    /// Network interface to monitor (default: ens19 unless --interface-index or --interface-mac is given)
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// Interface index to monitor, tried after --interface when both are given
+    #[arg(long, value_name = "INDEX")]
+    interface_index: Option<u32>,
+
+    /// Interface MAC address to monitor (e.g. aa:bb:cc:dd:ee:ff), tried after --interface-index
+    #[arg(long, value_name = "MAC")]
+    interface_mac: Option<MacAddr>,
+
+    /// Pushgateway URL to push metrics to (e.g. http://localhost:9091)
+    #[arg(long)]
+    pushgateway: Option<String>,
+
+    /// Job name used when pushing to the Pushgateway
+    #[arg(long, default_value = "network_traffic_monitor")]
+    push_job: String,
+
+    /// Interval in seconds between pushes to the Pushgateway
+    #[arg(long, default_value = "10")]
+    push_interval: u64,
+
+    /// EWMA smoothing factor for the rate gauges, in (0, 1] (1.0 = no smoothing)
+    #[arg(long, default_value = "1.0", value_parser = parse_smoothing_alpha)]
+    rate_smoothing: f64,
+
+    /// Read buffer size in bytes for the capture socket; frames larger than this are
+    /// truncated, so it also acts as the snap length (pnet has no separate snaplen)
+    #[arg(long, default_value_t = DEFAULT_READ_BUFFER_SIZE)]
+    buffer_size: usize,
+
+    /// Disable promiscuous mode and capture only traffic to or from this host
+    /// (for virtualized or cloud environments that forbid promiscuous mode)
+    #[arg(long)]
+    no_promisc: bool,
+
+    /// Packets queued between the capture thread and the processing loop; when the
+    /// queue is full new packets are dropped and counted in channel_dropped_total
+    #[arg(long, default_value_t = DEFAULT_CHANNEL_CAPACITY)]
+    channel_capacity: usize,
+
+    /// Keep this many of the most recent packets in memory for GET /dump,
+    /// which returns them as JSON, oldest first (0 = disabled)
+    #[arg(long, value_name = "PACKETS", default_value_t = DEFAULT_CAPTURE_RING)]
+    capture_ring: usize,
+
+    /// Keep this many seconds of metric samples in memory and answer the Grafana
+    /// SimpleJSON datasource protocol on POST /search and POST /query
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    json_datasource_retention: Option<u64>,
+
+    /// Print the full metrics exposition to stdout every SECONDS, each scrape
+    /// between a "# SCRAPE <time>" line and a "# EOF" line, for collectors
+    /// that read a pipe or file instead of scraping over HTTP
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    stdout_metrics_interval: Option<u64>,
+
+    /// Require an Authorization: Bearer <TOKEN> header on every HTTP endpoint
+    /// except /health; other requests get 401 Unauthorized
+    #[arg(long, value_name = "TOKEN")]
+    metrics_token: Option<String>,
+
+    /// Namespace prepended to every metric name as <PREFIX>_ (e.g. edge1 gives
+    /// edge1_total_tx_bytes_rate), for running several instances side by side
+    #[arg(long, value_name = "PREFIX", value_parser = parse_metric_prefix)]
+    metric_prefix: Option<String>,
+
+    /// Only export per-IP rate series for local IPs in this CIDR (repeatable);
+    /// all other local IPs are aggregated under local_ip="_other"
+    #[arg(long = "metrics-only-subnet", value_name = "CIDR")]
+    metrics_only_subnets: Vec<IpNetwork>,
+
+    /// Group the per-IP rate series of IPv4 local addresses by this prefix
+    /// length, labelled with the network in CIDR form (32 = one series per host)
+    #[arg(long, value_name = "LEN", default_value = "32", value_parser = clap::value_parser!(u8).range(0..=32))]
+    aggregate_prefix: u8,
+
+    /// Largest sequence gap in bytes treated as loss; by default twice the
+    /// receiver's advertised window, but never below 1,000,000
+    #[arg(long)]
+    max_plausible_gap: Option<u32>,
+
+    /// Connections with fewer packets than this are left out of the
+    /// cross-connection mean and weighted loss percentages
+    #[arg(long, default_value_t = DEFAULT_MIN_PACKETS_FOR_LOSS)]
+    min_packets_for_loss: u64,
+
+    /// Skip packets to or from this TCP/UDP port before any analysis (repeatable)
+    #[arg(long = "exclude-port", value_name = "PORT")]
+    exclude_ports: Vec<u16>,
+
+    /// Skip packets to or from this IP address before any analysis (repeatable)
+    #[arg(long = "exclude-ip", value_name = "IP")]
+    exclude_ips: Vec<IpAddr>,
+
+    /// List available network interfaces and exit
+    #[arg(long)]
+    list_interfaces: bool,
+
+    /// Stop monitoring after this many seconds (0 = run until interrupted)
+    #[arg(long, default_value = "3600")]
+    duration: u64,
+
+    /// On shutdown, write a JSON summary of the whole run (per-protocol bytes,
+    /// top IPs, loss percentage) to this file, or to stdout when given as - or
+    /// without a value
+    #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "-")]
+    json_summary_on_exit: Option<PathBuf>,
+
+    /// Read option values from this TOML file, keyed by field name (e.g.
+    /// push_interval = 30); options given on the command line take precedence
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+}
+
+/// Contents of the --config TOML file, one optional key per field of `Args`
+///
+/// Values are handed to clap as if given on the command line, so they are
+/// validated and defaulted the same way; unknown keys are rejected.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    interface: Option<String>,
+    interface_index: Option<u32>,
+    interface_mac: Option<String>,
+    pushgateway: Option<String>,
+    push_job: Option<String>,
+    push_interval: Option<u64>,
+    rate_smoothing: Option<f64>,
+    buffer_size: Option<usize>,
+    no_promisc: Option<bool>,
+    channel_capacity: Option<usize>,
+    capture_ring: Option<usize>,
+    json_datasource_retention: Option<u64>,
+    stdout_metrics_interval: Option<u64>,
+    metrics_token: Option<String>,
+    metric_prefix: Option<String>,
+    metrics_only_subnets: Option<Vec<String>>,
+    aggregate_prefix: Option<u8>,
+    max_plausible_gap: Option<u32>,
+    min_packets_for_loss: Option<u64>,
+    exclude_ports: Option<Vec<u16>>,
+    exclude_ips: Option<Vec<String>>,
+    list_interfaces: Option<bool>,
+    duration: Option<u64>,
+    json_summary_on_exit: Option<PathBuf>,
+}
+
+fn parse_smoothing_alpha(value: &str) -> Result<f64, String> {
+    let alpha: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(alpha)
+    } else {
+        Err(format!("smoothing factor must be in (0, 1], got {}", alpha))
+    }
+}
+
+/// Validate a metric name prefix; a trailing _ is dropped because the
+/// separator is added when the names are built
+fn parse_metric_prefix(value: &str) -> Result<String, String> {
+    let prefix = value.trim_end_matches('_');
+    let mut chars = prefix.chars();
+    let valid = chars
+        .next()
+        .is_none_or(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if valid {
+        Ok(prefix.to_string())
+    } else {
+        Err(format!("not a valid metric name prefix: {}", value))
+    }
+}
+
+/// Resolve after `secs` seconds, or never when `secs` is 0
+async fn wait_for_duration(secs: u64) {
+    if secs == 0 {
+        std::future::pending::<()>().await;
+    }
+    tokio::time::sleep(tokio::time::Duration::from_secs(secs)).await;
+}
+
+/// Write the run summary as JSON to `path`, or to stdout when `path` is -
+fn write_json_summary(path: &Path, interface: &str) -> Result<()> {
+    let (Some(metrics), Some(ip_stats)) = (
+        prometheus_server::network_metrics(),
+        prometheus_server::ip_stats(),
+    ) else {
+        return Err(anyhow::anyhow!(
+            "Packet capture never started, no summary to write"
+        ));
+    };
+    let ip_stats = ip_stats
+        .lock()
+        .map(|stats| stats.clone())
+        .unwrap_or_default();
+    let summary = match metrics.lock() {
+        Ok(metrics) => metrics.run_summary(interface, &ip_stats, SUMMARY_TOP_IPS),
+        Err(_) => return Err(anyhow::anyhow!("Network metrics lock poisoned")),
+    };
+
+    let json = serde_json::to_string_pretty(&summary)?;
+    if path == Path::new("-") {
+        println!("{}", json);
+    } else {
+        std::fs::write(path, json + "\n")?;
+        info!("Wrote run summary to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Print every interface with its description, index, MAC, addresses and state flags
+fn list_interfaces() {
+    for interface in pnet_datalink::interfaces() {
+        let mut flags = Vec::new();
+        if interface.is_up() {
+            flags.push("UP");
+        }
+        if interface.is_running() {
+            flags.push("RUNNING");
+        }
+        if interface.is_loopback() {
+            flags.push("LOOPBACK");
+        }
+
+        println!("{} [{}]", interface.name, flags.join(" "));
+        if !interface.description.is_empty() {
+            println!("    description: {}", interface.description);
+        }
+        println!("    index: {}", interface.index);
+        if let Some(mac) = interface.mac {
+            println!("    mac: {}", mac);
+        }
+        for ip in &interface.ips {
+            println!("    address: {} netmask: {}", ip.ip(), ip.mask());
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let args: Args = config::parse::<Args, ConfigFile>();
+    if args.list_interfaces {
+        list_interfaces();
+        return Ok(());
+    }
     env_logger::Builder::from_default_env()
         .filter_level(log::LevelFilter::Info)
         .init();
 
     info!("Starting network traffic monitor with Prometheus integration");
-    info!("Interface: {}", args.interface);
+    if let Some(token) = args.metrics_token.clone() {
+        prometheus_server::set_metrics_token(token);
+        info!("HTTP endpoints other than /health require a bearer token");
+    }
+    let selector = InterfaceSelector {
+        // インデックスもMACアドレスも指定しない場合のみ既定の名前を使う
+        name: args.interface.clone().or_else(|| {
+            (args.interface_index.is_none() && args.interface_mac.is_none())
+                .then(|| DEFAULT_INTERFACE.to_string())
+        }),
+        index: args.interface_index,
+        mac: args.interface_mac,
+    };
+    let interface = selector.resolve(&pnet_datalink::interfaces())?;
+    let interface_name = interface.name.clone();
+    info!("Interface: {}", interface_name);
 
     // ルート権限の確認
     if unsafe { libc::geteuid() } != 0 {
@@ -33,49 +313,53 @@ async fn main() -> Result<()> {
         ));
     }
     // 指定インターフェースのIPアドレスとサブネットマスクを表示
-    let (ip_addr, netmask) = match pnet_datalink::interfaces()
-        .into_iter()
-        .find(|iface| iface.name == args.interface)
-    {
-        Some(interface) => {
-            for ip in &interface.ips {
-                info!(
-                    "Interface {}: IP address = {}, netmask = {}",
-                    args.interface,
-                    ip.ip(),
-                    ip.mask()
-                );
-            }
-            // Use the first IP address for monitoring
-            if let Some(ip) = interface.ips.first() {
-                (ip.ip(), ip.mask())
-            } else {
-                return Err(anyhow::anyhow!(
-                    "No IP addresses found for interface '{}'",
-                    args.interface
-                ));
-            }
-        }
-        None => {
-            return Err(anyhow::anyhow!("Interface '{}' not found", args.interface));
-        }
+    for ip in &interface.ips {
+        info!(
+            "Interface {}: IP address = {}, netmask = {}",
+            interface_name,
+            ip.ip(),
+            ip.mask()
+        );
+    }
+    // セカンダリアドレスやIPv6も含め、全てのアドレスをローカルとして扱う
+    if interface.ips.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No IP addresses found for interface '{}'",
+            interface_name
+        ));
+    }
+    let local_addresses = interface.ips;
+
+    let config = MonitorConfig {
+        // Pushgateway設定（指定された場合はスクレイプサーバーと並行して送信）
+        pushgateway: args.pushgateway.clone().map(|url| PushgatewayConfig {
+            url,
+            job: args.push_job.clone(),
+            interval_secs: args.push_interval,
+        }),
+        rate_smoothing: args.rate_smoothing,
+        buffer_size: args.buffer_size,
+        promiscuous: !args.no_promisc,
+        metrics_only_subnets: args.metrics_only_subnets.clone(),
+        aggregate_prefix: args.aggregate_prefix,
+        max_plausible_gap: args.max_plausible_gap,
+        min_packets_for_loss: args.min_packets_for_loss,
+        exclusions: PacketExclusions {
+            ips: args.exclude_ips.clone(),
+            ports: args.exclude_ports.clone(),
+        },
+        channel_capacity: args.channel_capacity,
+        capture_ring: args.capture_ring,
+        metric_prefix: args.metric_prefix.clone(),
+        json_datasource_retention: args.json_datasource_retention.map(Duration::from_secs),
+        stdout_metrics_interval: args.stdout_metrics_interval.map(Duration::from_secs),
     };
 
     // ネットワークモニタリングシステムを開始
-    let interface_name = args.interface.clone();
+    let monitoring_interface = interface_name.clone();
     let monitoring_task = tokio::spawn(async move {
-        let result = start_network_monitoring_system(
-            &interface_name,
-            Some(ip_addr),
-            Some(match netmask {
-                std::net::IpAddr::V4(v4) => v4,
-                std::net::IpAddr::V6(_) => {
-                    error!("IPv6 subnets not supported");
-                    return;
-                }
-            }),
-        )
-        .await;
+        let result =
+            start_network_monitoring_system(&monitoring_interface, &local_addresses, config).await;
 
         if let Err(e) = result {
             error!("Network monitoring system failed: {}", e);
@@ -100,8 +384,8 @@ async fn main() -> Result<()> {
                 }
             }
         }
-        _ = tokio::time::sleep(tokio::time::Duration::from_secs(3600)) => {
-            info!("Timeout reached, shutting down...");
+        _ = wait_for_duration(args.duration) => {
+            info!("Duration of {}s reached, shutting down...", args.duration);
             Ok(())
         }
     };
@@ -110,6 +394,12 @@ async fn main() -> Result<()> {
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     info!("Network traffic monitor stopped");
 
+    if let Some(path) = &args.json_summary_on_exit {
+        if let Err(e) = write_json_summary(path, &interface_name) {
+            error!("Failed to write run summary: {}", e);
+        }
+    }
+
     // 結果を確認して適切に終了
     match result {
         Ok(_) => {
@@ -0,0 +1,254 @@
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::fmt::Write;
+
+/// OpenMetricsのContent-Type
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// 名前の末尾から推定するOpenMetricsの単位
+const KNOWN_UNITS: &[&str] = &["seconds", "bytes", "percent"];
+
+/// Acceptヘッダの値がOpenMetricsを要求しているかどうかを判定
+pub fn accepts_openmetrics(accept: &str) -> bool {
+    accept
+        .split(',')
+        .any(|media_type| media_type.trim().starts_with("application/openmetrics-text"))
+}
+
+/// メトリクスファミリーをOpenMetricsテキスト形式にエンコードする
+///
+/// prometheusクレートにはOpenMetricsエンコーダがないため、TextEncoderと同じ
+/// 構造を辿りつつ `# UNIT`、カウンタの `_total` サフィックス、`# EOF` を付与する。
+pub fn encode_openmetrics(metric_families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+
+    for mf in metric_families {
+        let metric_type = mf.get_field_type();
+
+        // カウンタのファミリー名は `_total` を含まず、サンプル名にのみ付ける
+        let name = match metric_type {
+            MetricType::COUNTER => mf.get_name().strip_suffix("_total").unwrap_or(mf.get_name()),
+            _ => mf.get_name(),
+        };
+
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+
+        let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+        if let Some(unit) = KNOWN_UNITS
+            .iter()
+            .find(|unit| name.ends_with(&format!("_{}", unit)))
+        {
+            let _ = writeln!(out, "# UNIT {} {}", name, unit);
+        }
+        if !mf.get_help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", name, escape(mf.get_help()));
+        }
+
+        for m in mf.get_metric() {
+            match metric_type {
+                MetricType::COUNTER => {
+                    write_sample(&mut out, name, "_total", m, None, m.get_counter().get_value());
+                }
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, "", m, None, m.get_gauge().get_value());
+                }
+                MetricType::UNTYPED => {
+                    write_sample(&mut out, name, "", m, None, m.get_untyped().get_value());
+                }
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    let mut inf_seen = false;
+                    for b in h.get_bucket() {
+                        let upper_bound = b.get_upper_bound();
+                        if upper_bound.is_infinite() && upper_bound.is_sign_positive() {
+                            inf_seen = true;
+                        }
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            m,
+                            Some(("le", &format_float(upper_bound))),
+                            b.get_cumulative_count() as f64,
+                        );
+                    }
+                    if !inf_seen {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            m,
+                            Some(("le", "+Inf")),
+                            h.get_sample_count() as f64,
+                        );
+                    }
+                    write_sample(&mut out, name, "_count", m, None, h.get_sample_count() as f64);
+                    write_sample(&mut out, name, "_sum", m, None, h.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "",
+                            m,
+                            Some(("quantile", &format_float(q.get_quantile()))),
+                            q.get_value(),
+                        );
+                    }
+                    write_sample(&mut out, name, "_count", m, None, s.get_sample_count() as f64);
+                    write_sample(&mut out, name, "_sum", m, None, s.get_sample_sum());
+                }
+            }
+        }
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+/// 1サンプル分の行を書き出す
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    out.push_str(suffix);
+
+    let mut labels: Vec<String> = metric
+        .get_label()
+        .iter()
+        .map(|pair| format!("{}=\"{}\"", pair.get_name(), escape(pair.get_value())))
+        .collect();
+    if let Some((label_name, label_value)) = additional_label {
+        labels.push(format!("{}=\"{}\"", label_name, escape(label_value)));
+    }
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+
+    let _ = write!(out, " {}", format_float(value));
+
+    // OpenMetricsのタイムスタンプは秒単位
+    let timestamp_ms = metric.get_timestamp_ms();
+    if timestamp_ms != 0 {
+        let _ = write!(out, " {}", timestamp_ms as f64 / 1000.0);
+    }
+    out.push('\n');
+}
+
+/// OpenMetricsの正規形に従って浮動小数点数を文字列化する
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value.is_sign_positive() { "+Inf" } else { "-Inf" }.to_string()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// ラベル値とHELP文字列のエスケープ
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Counter, CounterVec, Histogram, HistogramOpts, Opts, Registry};
+
+    fn encode(registry: &Registry) -> String {
+        encode_openmetrics(&registry.gather())
+    }
+
+    #[test]
+    fn counters_are_declared_without_total_and_sampled_with_it() {
+        let registry = Registry::new();
+        let counter = Counter::new("packets_total", "処理したパケット数").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc_by(3.0);
+
+        let out = encode(&registry);
+        assert!(out.contains("# TYPE packets counter\n"));
+        assert!(out.contains("# HELP packets 処理したパケット数\n"));
+        assert!(out.contains("\npackets_total 3.0\n"));
+        assert!(!out.contains("# TYPE packets_total"));
+    }
+
+    #[test]
+    fn unit_is_taken_from_the_name_suffix() {
+        let registry = Registry::new();
+        let counter = Counter::new("tx_bytes_total", "送信バイト数").unwrap();
+        let gauge = prometheus::Gauge::new("uptime_seconds", "稼働時間").unwrap();
+        let no_unit = prometheus::Gauge::new("connections", "接続数").unwrap();
+        registry.register(Box::new(counter)).unwrap();
+        registry.register(Box::new(gauge)).unwrap();
+        registry.register(Box::new(no_unit)).unwrap();
+
+        let out = encode(&registry);
+        assert!(out.contains("# UNIT tx_bytes bytes\n"));
+        assert!(out.contains("# UNIT uptime_seconds seconds\n"));
+        assert!(!out.contains("# UNIT connections"));
+    }
+
+    #[test]
+    fn histograms_end_with_an_inf_bucket_count_and_sum() {
+        let registry = Registry::new();
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("rtt_seconds", "往復時間").buckets(vec![0.1, 1.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(0.05);
+        histogram.observe(5.0);
+
+        let out = encode(&registry);
+        assert!(out.contains("# TYPE rtt_seconds histogram\n"));
+        assert!(out.contains("rtt_seconds_bucket{le=\"0.1\"} 1.0\n"));
+        assert!(out.contains("rtt_seconds_bucket{le=\"1.0\"} 1.0\n"));
+        assert!(out.contains("rtt_seconds_bucket{le=\"+Inf\"} 2.0\n"));
+        assert!(out.contains("rtt_seconds_count 2.0\n"));
+        assert!(out.contains("rtt_seconds_sum 5.05\n"));
+        assert_eq!(out.matches("le=\"+Inf\"").count(), 1);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let registry = Registry::new();
+        let counter =
+            CounterVec::new(Opts::new("requests_total", "リクエスト数"), &["path"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["a\"b\\c\nd"]).inc();
+
+        let out = encode(&registry);
+        assert!(out.contains("requests_total{path=\"a\\\"b\\\\c\\nd\"} 1.0\n"));
+    }
+
+    #[test]
+    fn output_ends_with_eof() {
+        assert_eq!(encode_openmetrics(&[]), "# EOF\n");
+
+        let registry = Registry::new();
+        let gauge = prometheus::Gauge::new("connections", "接続数").unwrap();
+        registry.register(Box::new(gauge)).unwrap();
+        let out = encode(&registry);
+        assert!(out.ends_with("connections 0.0\n# EOF\n"));
+        assert_eq!(out.matches("# EOF").count(), 1);
+    }
+}
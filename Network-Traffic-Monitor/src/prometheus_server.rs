@@ -1,22 +1,50 @@
-use crate::stats::IpStatsMap;
-use http_body_util::Full;
+use crate::auth::bearer_token_matches;
+use crate::capture::{format_bps, NetworkMetrics, PacketLossEvent};
+use crate::gzip::accepts_gzip;
+use crate::ring::PacketRingHandle;
+use crate::openmetrics::{accepts_openmetrics, encode_openmetrics, OPENMETRICS_FORMAT};
+use crate::stats::{IpStatsMap, MetricsSnapshot};
+use crate::timeseries::{QueryRequest, SearchRequest, TimeSeriesHandle};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper::body::Bytes;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
+use futures_util::{SinkExt, StreamExt};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{error, info, warn};
 use prometheus::{Registry, TextEncoder};
+use serde::Serialize;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// `POST /search` / `POST /query` で受け付ける本文の上限（Grafanaのリクエストは数KiB程度）
+const MAX_JSON_REQUEST_BODY: usize = 64 * 1024;
 
 // グローバルネットワークメトリクス（capture.rsから共有）
-static NETWORK_METRICS: std::sync::OnceLock<Arc<Mutex<crate::capture::NetworkMetrics>>> =
+static NETWORK_METRICS: std::sync::OnceLock<Arc<Mutex<NetworkMetrics>>> =
     std::sync::OnceLock::new();
 static IP_STATS: std::sync::OnceLock<IpStatsMap> = std::sync::OnceLock::new();
+static LOSS_EVENTS: std::sync::OnceLock<broadcast::Sender<PacketLossEvent>> =
+    std::sync::OnceLock::new();
+static CAPTURE_ALIVE: std::sync::OnceLock<Arc<AtomicBool>> = std::sync::OnceLock::new();
+static PACKET_RING: std::sync::OnceLock<PacketRingHandle> = std::sync::OnceLock::new();
+static TIME_SERIES: std::sync::OnceLock<TimeSeriesHandle> = std::sync::OnceLock::new();
+static METRICS_TOKEN: std::sync::OnceLock<String> = std::sync::OnceLock::new();
 
-pub fn set_network_metrics(metrics: Arc<Mutex<crate::capture::NetworkMetrics>>) {
+pub fn set_network_metrics(metrics: Arc<Mutex<NetworkMetrics>>) {
     let _ = NETWORK_METRICS.set(metrics);
 }
 
@@ -24,6 +52,43 @@ pub fn set_ip_stats(stats: IpStatsMap) {
     let _ = IP_STATS.set(stats);
 }
 
+pub fn set_loss_events(sender: broadcast::Sender<PacketLossEvent>) {
+    let _ = LOSS_EVENTS.set(sender);
+}
+
+pub fn set_capture_alive(alive: Arc<AtomicBool>) {
+    let _ = CAPTURE_ALIVE.set(alive);
+}
+
+pub fn set_packet_ring(ring: PacketRingHandle) {
+    let _ = PACKET_RING.set(ring);
+}
+
+pub fn set_time_series(store: TimeSeriesHandle) {
+    let _ = TIME_SERIES.set(store);
+}
+
+pub fn set_metrics_token(token: String) {
+    let _ = METRICS_TOKEN.set(token);
+}
+
+/// キャプチャと共有しているメトリクス（キャプチャの開始前はNone）
+pub fn network_metrics() -> Option<Arc<Mutex<NetworkMetrics>>> {
+    NETWORK_METRICS.get().cloned()
+}
+
+/// キャプチャと共有しているIP統計（キャプチャの開始前はNone）
+pub fn ip_stats() -> Option<IpStatsMap> {
+    IP_STATS.get().cloned()
+}
+
+/// キャプチャスレッドが動作中かどうか（キャプチャと連携していない場合は常にtrue）
+fn capture_is_alive() -> bool {
+    CAPTURE_ALIVE
+        .get()
+        .is_none_or(|alive| alive.load(Ordering::SeqCst))
+}
+
 // メトリクス構造体
 #[derive(Clone)]
 struct AppMetrics {
@@ -37,6 +102,10 @@ impl AppMetrics {
         AppMetrics { registry }
     }
 
+    fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.registry.gather()
+    }
+
     fn export(&self) -> String {
         let encoder = TextEncoder::new();
         let metric_families = self.registry.gather();
@@ -44,16 +113,486 @@ impl AppMetrics {
     }
 }
 
+/// AcceptヘッダでOpenMetrics形式が要求されているか
+fn wants_openmetrics(req: &Request<hyper::body::Incoming>) -> bool {
+    req.headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(accepts_openmetrics)
+        .unwrap_or(false)
+}
+
+/// `--metrics-token` が設定されている場合に、一致するBearerトークンが送られているか
+///
+/// `/health` はロードバランサーなどの死活監視に使われるため、トークンなしで応答する。
+fn is_authorized(req: &Request<hyper::body::Incoming>) -> bool {
+    let Some(token) = METRICS_TOKEN.get() else {
+        return true;
+    };
+    let authorization = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    req.uri().path() == "/health" || bearer_token_matches(authorization, token)
+}
+
+/// Accept-Encodingヘッダでgzipが受け付けられているか
+fn wants_gzip(req: &Request<hyper::body::Incoming>) -> bool {
+    req.headers()
+        .get(hyper::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(accepts_gzip)
+        .unwrap_or(false)
+}
+
+/// `/metrics` のレスポンスを作成する（クライアントが受け付ける場合はgzipで圧縮する）
+fn metrics_response(content_type: &str, body: String, gzip: bool) -> Response<Full<Bytes>> {
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .header(hyper::header::VARY, "Accept-Encoding");
+    if gzip {
+        match crate::gzip::compress(body.as_bytes()) {
+            Ok(compressed) => {
+                return builder
+                    .header(hyper::header::CONTENT_ENCODING, "gzip")
+                    .body(Full::new(Bytes::from(compressed)))
+                    .unwrap();
+            }
+            Err(e) => warn!("Failed to gzip metrics, sending uncompressed: {}", e),
+        }
+    }
+    builder.body(Full::new(Bytes::from(body))).unwrap()
+}
+
+/// `/ws/events` へのWebSocketアップグレード要求を処理する
+///
+/// 101レスポンスを返した後、アップグレード完了を待つタスクで購読を開始する。
+fn websocket_events(mut req: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+    let is_upgrade = req
+        .headers()
+        .get(hyper::header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    let accept_key = req
+        .headers()
+        .get(hyper::header::SEC_WEBSOCKET_KEY)
+        .map(|key| derive_accept_key(key.as_bytes()));
+
+    let accept_key = match (is_upgrade, accept_key) {
+        (true, Some(accept_key)) => accept_key,
+        _ => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from("Expected a WebSocket upgrade request")))
+                .unwrap()
+        }
+    };
+
+    let receiver = match LOSS_EVENTS.get() {
+        Some(sender) => sender.subscribe(),
+        None => {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Full::new(Bytes::from("Packet capture is not running")))
+                .unwrap()
+        }
+    };
+
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                    .await;
+                stream_loss_events(ws, receiver).await;
+            }
+            Err(e) => error!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(hyper::header::UPGRADE, "websocket")
+        .header(hyper::header::CONNECTION, "Upgrade")
+        .header(hyper::header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+/// 新しく検出されたロスイベントをJSONとして1クライアントへ送り続ける
+async fn stream_loss_events<S>(
+    mut ws: WebSocketStream<S>,
+    mut receiver: broadcast::Receiver<PacketLossEvent>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    let json = match serde_json::to_string(&event) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            error!("Failed to serialize loss event: {}", e);
+                            continue;
+                        }
+                    };
+                    if ws.send(Message::text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket subscriber lagged, {} loss events dropped", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = ws.next() => match message {
+                // クライアントからの切断またはエラーで終了（Pingへの応答はtungsteniteが行う）
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    let _ = ws.close(None).await;
+}
+
+/// `/connections` で返す1接続分の状態
+#[derive(Debug, Serialize)]
+struct ConnectionSummary {
+    connection: String,
+    expected_seq: u32,
+    total_packets: u64,
+    lost_packets: u64,
+    idle_secs: f64,
+}
+
+/// `/connections` の並び順
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionSort {
+    Connection,
+    Loss,
+    Packets,
+    Idle,
+}
+
+/// `?sort=loss&limit=50` 形式のクエリを解釈する
+fn parse_connections_query(query: Option<&str>) -> Result<(ConnectionSort, Option<usize>), String> {
+    let mut sort = ConnectionSort::Connection;
+    let mut limit = None;
+
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "sort" => {
+                sort = match value {
+                    "connection" => ConnectionSort::Connection,
+                    "loss" => ConnectionSort::Loss,
+                    "packets" => ConnectionSort::Packets,
+                    "idle" => ConnectionSort::Idle,
+                    _ => return Err(format!("unknown sort key: {}", value)),
+                }
+            }
+            "limit" => {
+                limit = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid limit: {}", value))?,
+                )
+            }
+            _ => {}
+        }
+    }
+
+    Ok((sort, limit))
+}
+
+/// アクティブなTCP接続の状態をJSONで返す
+fn connections_response(query: Option<&str>) -> Response<Full<Bytes>> {
+    let (sort, limit) = match parse_connections_query(query) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from(e)))
+                .unwrap();
+        }
+    };
+
+    // ロックは状態のコピー中のみ保持し、ソートとシリアライズはロック外で行う
+    let mut connections: Vec<ConnectionSummary> = match NETWORK_METRICS.get() {
+        Some(network_metrics) => match network_metrics.lock() {
+            Ok(network_metrics) => {
+                let now = std::time::Instant::now();
+                network_metrics
+                    .tcp_connection_states
+                    .iter()
+                    .map(|(connection, state)| ConnectionSummary {
+                        connection: connection.clone(),
+                        expected_seq: state.expected_seq,
+                        total_packets: state.total_packets,
+                        lost_packets: state.lost_packets,
+                        idle_secs: now.duration_since(state.last_active).as_secs_f64(),
+                    })
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+
+    match sort {
+        ConnectionSort::Connection => connections.sort_by(|a, b| a.connection.cmp(&b.connection)),
+        ConnectionSort::Loss => connections.sort_by_key(|c| std::cmp::Reverse(c.lost_packets)),
+        ConnectionSort::Packets => connections.sort_by_key(|c| std::cmp::Reverse(c.total_packets)),
+        ConnectionSort::Idle => connections.sort_by(|a, b| b.idle_secs.total_cmp(&a.idle_secs)),
+    }
+    if let Some(limit) = limit {
+        connections.truncate(limit);
+    }
+
+    match serde_json::to_string(&connections) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(json)))
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to serialize connections: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::new(Bytes::from("Internal Server Error")))
+                .unwrap()
+        }
+    }
+}
+
+/// リングバッファに残っている直近のパケットを古い順にJSONで返す
+fn dump_response() -> Response<Full<Bytes>> {
+    let packets = match PACKET_RING.get() {
+        // ロックはコピー中のみ保持し、キャプチャスレッドを止めないようにする
+        Some(ring) => match ring.lock() {
+            Ok(ring) => ring.snapshot(),
+            Err(_) => Vec::new(),
+        },
+        None => {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Full::new(Bytes::from("Packet capture is not running")))
+                .unwrap()
+        }
+    };
+
+    match serde_json::to_string(&packets) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(json)))
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to serialize packet ring: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::new(Bytes::from("Internal Server Error")))
+                .unwrap()
+        }
+    }
+}
+
+/// 現在のレート・接続数・ロス率と上位のIPを固定幅のテキスト表で返す（`curl` での確認用）
+fn summary_response() -> Response<Full<Bytes>> {
+    let (Some(network_metrics), Some(ip_stats)) = (NETWORK_METRICS.get(), IP_STATS.get()) else {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Full::new(Bytes::from("Packet capture is not running")))
+            .unwrap();
+    };
+
+    // 2つのロックを同時に保持しないよう、IP統計は先にコピーする
+    let ip_stats = ip_stats
+        .lock()
+        .map(|stats| stats.clone())
+        .unwrap_or_default();
+    // ロックは値の複製中のみ保持し、整形はロック外で行う
+    let snapshot = match network_metrics.lock() {
+        Ok(network_metrics) => network_metrics.snapshot(&ip_stats),
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::new(Bytes::from("Internal Server Error")))
+                .unwrap()
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Full::new(Bytes::from(summary_table(&snapshot))))
+        .unwrap()
+}
+
+/// `/summary` 用の固定幅のテキスト表
+fn summary_table(snapshot: &MetricsSnapshot) -> String {
+    let mut table = String::new();
+    table.push_str(&format!(
+        "{:<20} {}\n",
+        "Total TX:",
+        format_bps(snapshot.total_tx_bytes_rate * 8.0)
+    ));
+    table.push_str(&format!(
+        "{:<20} {}\n",
+        "Total RX:",
+        format_bps(snapshot.total_rx_bytes_rate * 8.0)
+    ));
+    table.push_str(&format!(
+        "{:<20} {}\n",
+        "Active connections:", snapshot.active_connections
+    ));
+    table.push_str(&format!(
+        "{:<20} {:.2}%\n",
+        "Packet loss:", snapshot.packet_loss_percentage
+    ));
+
+    table.push_str(&format!(
+        "\n{:<39} {:>15} {:>15}\n",
+        "IP", "TX bytes", "RX bytes"
+    ));
+    for talker in &snapshot.top_talkers {
+        table.push_str(&format!(
+            "{:<39} {:>15} {:>15}\n",
+            talker.ip.to_string(),
+            talker.tx_bytes,
+            talker.rx_bytes
+        ));
+    }
+    table
+}
+
+/// `POST /search` の本文を解釈し、名前に `target` を含む系列名をJSONで返す
+///
+/// Grafanaは本文なしで呼ぶこともあるため、その場合は全系列を返す。
+fn search_json(store: &TimeSeriesHandle, body: &[u8]) -> Result<String, String> {
+    let request: SearchRequest = if body.is_empty() {
+        SearchRequest::default()
+    } else {
+        serde_json::from_slice(body).map_err(|e| format!("invalid search request: {}", e))?
+    };
+    let names = match store.lock() {
+        Ok(store) => store.search(&request.target),
+        Err(_) => Vec::new(),
+    };
+    serde_json::to_string(&names).map_err(|e| e.to_string())
+}
+
+/// `POST /query` の本文を解釈し、要求された範囲の時系列をJSONで返す
+fn query_json(store: &TimeSeriesHandle, body: &[u8]) -> Result<String, String> {
+    let request: QueryRequest =
+        serde_json::from_slice(body).map_err(|e| format!("invalid query request: {}", e))?;
+    // ロックは点のコピー中のみ保持し、シリアライズはロック外で行う
+    let series = match store.lock() {
+        Ok(store) => store.query(&request),
+        Err(_) => Vec::new(),
+    };
+    serde_json::to_string(&series).map_err(|e| e.to_string())
+}
+
+/// GrafanaのSimpleJSON/Infinityデータソース向けの `POST /search` と `POST /query` に応答する
+async fn json_datasource_response(req: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+    let Some(store) = TIME_SERIES.get() else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from(
+                "JSON datasource is disabled, start with --json-datasource-retention",
+            )))
+            .unwrap();
+    };
+
+    let search = req.uri().path() == "/search";
+    let body = match Limited::new(req.into_body(), MAX_JSON_REQUEST_BODY).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Full::new(Bytes::from(format!(
+                    "Request body exceeds {} bytes",
+                    MAX_JSON_REQUEST_BODY
+                ))))
+                .unwrap()
+        }
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from(format!(
+                    "Failed to read request body: {}",
+                    e
+                ))))
+                .unwrap()
+        }
+    };
+    let result = if search {
+        search_json(store, &body)
+    } else {
+        query_json(store, &body)
+    };
+
+    match result {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(json)))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Full::new(Bytes::from(e)))
+            .unwrap(),
+    }
+}
+
 // HTTPハンドラー
 async fn handle_request(
     req: Request<hyper::body::Incoming>,
     metrics: Arc<AppMetrics>,
 ) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
+    if !is_authorized(&req) {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+            .body(Full::new(Bytes::from("Unauthorized")))
+            .unwrap());
+    }
+    if req.method() == Method::GET && req.uri().path() == "/ws/events" {
+        return Ok(websocket_events(req));
+    }
+    if req.method() == Method::POST && matches!(req.uri().path(), "/search" | "/query") {
+        return Ok(json_datasource_response(req).await);
+    }
+
+    let gzip = wants_gzip(&req);
     let response = match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => Response::builder()
             .status(StatusCode::OK)
             .body(Full::new(Bytes::from("Hello, Prometheus!")))
             .unwrap(),
+        (&Method::GET, "/metrics") if wants_openmetrics(&req) => {
+            // OpenMetrics形式では `# EOF` を1回だけ出力するため、全ファミリーをまとめてエンコード
+            let mut metric_families = metrics.gather();
+            if let Some(network_metrics) = NETWORK_METRICS.get() {
+                if let Ok(network_metrics) = network_metrics.lock() {
+                    metric_families.extend(network_metrics.gather());
+                }
+            }
+
+            metrics_response(
+                OPENMETRICS_FORMAT,
+                encode_openmetrics(&metric_families),
+                gzip,
+            )
+        }
         (&Method::GET, "/metrics") => {
             // アプリケーションメトリクスを取得
             let app_metrics_output = metrics.export();
@@ -92,16 +631,19 @@ async fn handle_request(
             combined_metrics.push_str(&network_metrics_output);
             combined_metrics.push_str(&ip_stats_output);
 
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "text/plain; version=0.0.4")
-                .body(Full::new(Bytes::from(combined_metrics)))
-                .unwrap()
+            metrics_response("text/plain; version=0.0.4", combined_metrics, gzip)
         }
-        (&Method::GET, "/health") => Response::builder()
+        (&Method::GET, "/connections") => connections_response(req.uri().query()),
+        (&Method::GET, "/dump") => dump_response(),
+        (&Method::GET, "/summary") => summary_response(),
+        (&Method::GET, "/health") if capture_is_alive() => Response::builder()
             .status(StatusCode::OK)
             .body(Full::new(Bytes::from("OK")))
             .unwrap(),
+        (&Method::GET, "/health") => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Full::new(Bytes::from("Capture thread is not running")))
+            .unwrap(),
         _ => Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Full::new(Bytes::from("Not Found")))
@@ -140,6 +682,7 @@ pub async fn start_prometheus_server(
                                 .serve_connection(io, service_fn(move |req| {
                                     handle_request(req, metrics.clone())
                                 }))
+                                .with_upgrades()
                                 .await
                             {
                                 eprintln!("Error serving connection: {:?}", err);
@@ -162,3 +705,194 @@ pub async fn start_prometheus_server(
 
     Ok(())
 }
+
+/// Pushgatewayへの送信設定
+#[derive(Debug, Clone)]
+pub struct PushgatewayConfig {
+    /// PushgatewayのベースURL（例: http://pushgateway:9091）
+    pub url: String,
+    /// グルーピングキーとして使うジョブ名
+    pub job: String,
+    /// 送信間隔（秒）
+    pub interval_secs: u64,
+}
+
+/// Pushgatewayへの送信に使うHTTPクライアント（送信ごとに作らず使い回す）
+pub type PushClient = Client<HttpConnector, Full<Bytes>>;
+
+/// Pushgatewayの `/metrics/job/<job>` のURL（ジョブ名はパスの1セグメントとしてパーセントエンコードする）
+fn push_url(pushgateway_url: &str, job: &str) -> String {
+    let mut url = format!("{}/metrics/job/", pushgateway_url.trim_end_matches('/'));
+    for byte in job.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            url.push(byte as char);
+        } else {
+            url.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    url
+}
+
+/// `NetworkMetrics::export()` の出力をPushgatewayの `/metrics/job/<job>` にPOSTする
+///
+/// エクスポートはこの関数の呼び出し時点で同期的に行うため、メトリクスのロックは
+/// 返されたFutureをawaitする前に解放できる。
+pub fn push_metrics(
+    client: &PushClient,
+    pushgateway_url: &str,
+    job: &str,
+    metrics: &NetworkMetrics,
+) -> impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> {
+    let body = metrics.export();
+    let url = push_url(pushgateway_url, job);
+    let client = client.clone();
+
+    async move {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&url)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(body)))?;
+
+        let response = client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("Pushgateway returned {} for {}", response.status(), url).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// 一定間隔でPushgatewayへメトリクスを送信し続ける
+pub async fn push_metrics_periodically(
+    metrics: Arc<Mutex<NetworkMetrics>>,
+    config: PushgatewayConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!(
+        "Pushing metrics to {} (job: {}) every {}s",
+        config.url, config.job, config.interval_secs
+    );
+
+    let client: PushClient = Client::builder(TokioExecutor::new()).build_http();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let push = match metrics.lock() {
+                    Ok(metrics) => push_metrics(&client, &config.url, &config.job, &metrics),
+                    Err(_) => continue,
+                };
+
+                if let Err(e) = push.await {
+                    error!("Failed to push metrics to Pushgateway: {}", e);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Pushgateway pusher received shutdown signal");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::TalkerSnapshot;
+
+    #[test]
+    fn push_url_percent_encodes_the_job() {
+        assert_eq!(
+            push_url("http://pushgateway:9091/", "network-traffic-monitor"),
+            "http://pushgateway:9091/metrics/job/network-traffic-monitor"
+        );
+        assert_eq!(
+            push_url("http://pushgateway:9091", "edge/router 1"),
+            "http://pushgateway:9091/metrics/job/edge%2Frouter%201"
+        );
+    }
+
+    #[test]
+    fn connections_query_defaults_to_connection_order_without_limit() {
+        assert_eq!(parse_connections_query(None), Ok((ConnectionSort::Connection, None)));
+        assert_eq!(parse_connections_query(Some("")), Ok((ConnectionSort::Connection, None)));
+    }
+
+    #[test]
+    fn connections_query_accepts_every_sort_key() {
+        for (value, sort) in [
+            ("connection", ConnectionSort::Connection),
+            ("loss", ConnectionSort::Loss),
+            ("packets", ConnectionSort::Packets),
+            ("idle", ConnectionSort::Idle),
+        ] {
+            let query = format!("sort={}", value);
+            assert_eq!(parse_connections_query(Some(&query)), Ok((sort, None)));
+        }
+        assert_eq!(
+            parse_connections_query(Some("sort=packets&limit=50")),
+            Ok((ConnectionSort::Packets, Some(50)))
+        );
+    }
+
+    #[test]
+    fn connections_query_parses_the_limit() {
+        assert_eq!(parse_connections_query(Some("limit=10")), Ok((ConnectionSort::Connection, Some(10))));
+        assert_eq!(parse_connections_query(Some("limit=0")), Ok((ConnectionSort::Connection, Some(0))));
+        // 知らないパラメータは無視する
+        assert_eq!(
+            parse_connections_query(Some("format=json&limit=3")),
+            Ok((ConnectionSort::Connection, Some(3)))
+        );
+    }
+
+    #[test]
+    fn connections_query_rejects_invalid_values() {
+        assert!(parse_connections_query(Some("limit=abc")).is_err());
+        assert!(parse_connections_query(Some("limit=-1")).is_err());
+        assert!(parse_connections_query(Some("limit=")).is_err());
+        assert_eq!(
+            parse_connections_query(Some("sort=bytes")),
+            Err("unknown sort key: bytes".to_string())
+        );
+        assert!(parse_connections_query(Some("sort=")).is_err());
+    }
+
+    #[test]
+    fn summary_table_lists_totals_and_top_talkers() {
+        let snapshot = MetricsSnapshot {
+            total_tx_bytes_rate: 125_000.0,
+            total_rx_bytes_rate: 250.0,
+            packet_loss_percentage: 1.5,
+            active_connections: 3,
+            top_talkers: vec![
+                TalkerSnapshot {
+                    ip: "192.168.1.10".parse().unwrap(),
+                    tx_bytes: 5000,
+                    rx_bytes: 100,
+                },
+                TalkerSnapshot {
+                    ip: "2001:db8:1::10".parse().unwrap(),
+                    tx_bytes: 100,
+                    rx_bytes: 3000,
+                },
+            ],
+        };
+
+        let table = summary_table(&snapshot);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "Total TX:            1.00 Mbps");
+        assert_eq!(lines[1], "Total RX:            2.00 Kbps");
+        assert_eq!(lines[2], "Active connections:  3");
+        assert_eq!(lines[3], "Packet loss:         1.50%");
+        assert!(lines[5].starts_with("IP "));
+        assert!(lines[6].starts_with("192.168.1.10 "));
+        assert!(lines[6].ends_with("           5000             100"));
+        assert!(lines[7].starts_with("2001:db8:1::10 "));
+        assert_eq!(lines.len(), 8);
+        assert!(lines[5..].iter().all(|line| line.len() == lines[5].len()));
+    }
+}
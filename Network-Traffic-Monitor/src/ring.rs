@@ -0,0 +1,73 @@
+use crate::capture::PacketInfo;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// `--capture-ring` を指定しない場合に保持するパケット数
+pub const DEFAULT_CAPTURE_RING: usize = 4096;
+
+/// キャプチャスレッドとHTTPサーバーで共有するリングバッファ
+pub type PacketRingHandle = Arc<Mutex<PacketRing>>;
+
+/// 直近のパケット情報を一定数だけ保持するリングバッファ（`/dump` で出力する）
+///
+/// 満杯の場合は最も古いパケットから捨てる。容量0の場合は何も保持しない。
+#[derive(Debug)]
+pub struct PacketRing {
+    capacity: usize,
+    packets: VecDeque<PacketInfo>,
+}
+
+impl PacketRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            packets: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, packet: PacketInfo) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.packets.len() == self.capacity {
+            self.packets.pop_front();
+        }
+        self.packets.push_back(packet);
+    }
+
+    /// 保持しているパケットを古い順にコピーする（シリアライズはロックの外で行う）
+    pub fn snapshot(&self) -> Vec<PacketInfo> {
+        self.packets.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(size: u64) -> PacketInfo {
+        PacketInfo {
+            protocol: "TCP".to_string(),
+            size,
+            src_ip: None,
+            dst_ip: None,
+            src_port: None,
+            dst_port: None,
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn oldest_packets_are_evicted_when_full() {
+        let mut ring = PacketRing::new(3);
+        for size in 1..=5 {
+            ring.push(packet(size));
+        }
+        let sizes: Vec<u64> = ring.snapshot().iter().map(|p| p.size).collect();
+        assert_eq!(sizes, vec![3, 4, 5]);
+
+        let mut disabled = PacketRing::new(0);
+        disabled.push(packet(1));
+        assert!(disabled.snapshot().is_empty());
+    }
+}
@@ -26,6 +26,20 @@ pub struct TrafficStatistics {
     pub previous_total: ProtocolStats,
     /// 最後のリセット時刻
     pub last_reset_time: chrono::DateTime<chrono::Utc>,
+    /// 全接続のパケットロス率（%）
+    #[serde(default)]
+    pub packet_loss_percentage: f64,
+}
+
+impl TrafficStatistics {
+    /// `last_reset_time` から `end_time` までの `previous_total` との差分で合計のレートを求める
+    pub fn update_total_rates(&mut self) {
+        let elapsed_secs = (self.end_time - self.last_reset_time)
+            .to_std()
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+        let previous = self.previous_total.clone();
+        self.total.update_rates(&previous, elapsed_secs);
+    }
 }
 
 /// プロトコル別の統計情報
@@ -41,6 +55,29 @@ pub struct ProtocolStats {
     pub bytes_per_second: f64,
 }
 
+impl ProtocolStats {
+    /// パケット1つ分を加算
+    pub fn record(&mut self, bytes: u64) {
+        self.packet_count += 1;
+        self.byte_count += bytes;
+    }
+
+    /// `previous` からの増分を `elapsed_secs` 秒で割って秒あたりのレートを求める
+    ///
+    /// 経過時間が0以下の場合と、カウンタが巻き戻った場合はレートを0とする。
+    pub fn update_rates(&mut self, previous: &ProtocolStats, elapsed_secs: f64) {
+        if elapsed_secs <= 0.0 {
+            self.packets_per_second = 0.0;
+            self.bytes_per_second = 0.0;
+            return;
+        }
+        self.packets_per_second =
+            self.packet_count.saturating_sub(previous.packet_count) as f64 / elapsed_secs;
+        self.bytes_per_second =
+            self.byte_count.saturating_sub(previous.byte_count) as f64 / elapsed_secs;
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct IpStats {
     pub tx_bytes: u64,
@@ -50,3 +87,96 @@ pub struct IpStats {
 }
 
 pub type IpStatsMap = Arc<Mutex<HashMap<IpAddr, IpStats>>>;
+
+/// `bytes` の多い順に上位 `limit` 件のIPとそのバイト数を返す
+pub fn top_ips(
+    ip_stats: &HashMap<IpAddr, IpStats>,
+    bytes: impl Fn(&IpStats) -> u64,
+    limit: usize,
+) -> HashMap<IpAddr, u64> {
+    ranked_ips(ip_stats, bytes, limit).into_iter().collect()
+}
+
+/// `top_ips` と同じ基準で、多い順に並べたまま返す（同じバイト数の場合はIPの昇順）
+pub fn ranked_ips(
+    ip_stats: &HashMap<IpAddr, IpStats>,
+    bytes: impl Fn(&IpStats) -> u64,
+    limit: usize,
+) -> Vec<(IpAddr, u64)> {
+    let mut ips: Vec<_> = ip_stats
+        .iter()
+        .map(|(ip, stats)| (*ip, bytes(stats)))
+        .filter(|(_, bytes)| *bytes > 0)
+        .collect();
+    ips.sort_by_key(|(ip, bytes)| (std::cmp::Reverse(*bytes), *ip));
+    ips.truncate(limit);
+    ips
+}
+
+/// `NetworkMetrics::snapshot` で1回のロックでまとめて取り出した現在の値
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    /// 全ローカルIPの送信バイト数レート合計（bytes/sec）
+    pub total_tx_bytes_rate: f64,
+    /// 全ローカルIPの受信バイト数レート合計（bytes/sec）
+    pub total_rx_bytes_rate: f64,
+    /// 全接続のパケットロス率（%）
+    pub packet_loss_percentage: f64,
+    /// 最近パケットを観測したTCP接続数
+    pub active_connections: usize,
+    /// 送受信バイト数の合計が多い順のIP
+    pub top_talkers: Vec<TalkerSnapshot>,
+}
+
+/// IPごとの送受信バイト数
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TalkerSnapshot {
+    pub ip: IpAddr,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_computed_from_the_previous_snapshot() {
+        let start = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let previous = ProtocolStats {
+            packet_count: 100,
+            byte_count: 150_000,
+            ..Default::default()
+        };
+        let mut statistics = TrafficStatistics {
+            start_time: start,
+            end_time: start + chrono::Duration::seconds(4),
+            interface: "eth0".to_string(),
+            total: ProtocolStats {
+                packet_count: 300,
+                byte_count: 450_000,
+                ..Default::default()
+            },
+            protocols: HashMap::new(),
+            source_ips: HashMap::new(),
+            destination_ips: HashMap::new(),
+            ports: HashMap::new(),
+            previous_total: previous.clone(),
+            last_reset_time: start,
+            packet_loss_percentage: 0.0,
+        };
+
+        statistics.update_total_rates();
+        assert_eq!(statistics.total.packets_per_second, 50.0);
+        assert_eq!(statistics.total.bytes_per_second, 75_000.0);
+
+        // 経過時間0・カウンタの巻き戻りではレートを0とする
+        let mut reset = ProtocolStats::default();
+        reset.update_rates(&previous, 1.0);
+        assert_eq!(reset.bytes_per_second, 0.0);
+        statistics.total.update_rates(&previous, 0.0);
+        assert_eq!(statistics.total.packets_per_second, 0.0);
+    }
+}
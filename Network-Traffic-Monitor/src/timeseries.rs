@@ -0,0 +1,236 @@
+use chrono::{DateTime, Utc};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 記録タスクとHTTPサーバーで共有する時系列ストア
+pub type TimeSeriesHandle = Arc<Mutex<TimeSeriesStore>>;
+
+/// `POST /search` のリクエスト（`target` を含む名前の系列を返す）
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchRequest {
+    #[serde(default)]
+    pub target: String,
+}
+
+/// `POST /query` のリクエスト（SimpleJSONデータソースの形式）
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: QueryRange,
+    pub targets: Vec<QueryTarget>,
+    /// 1系列あたりに返す点数の上限（超える場合は間引く）
+    #[serde(default)]
+    pub max_data_points: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryTarget {
+    pub target: String,
+}
+
+/// `POST /query` で返す1系列（点は `[値, UNIXミリ秒]`）
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TimeSeries {
+    pub target: String,
+    pub datapoints: Vec<[f64; 2]>,
+}
+
+/// Grafanaから直接参照できるよう、直近のメトリクスの値を保持する時系列ストア
+///
+/// 系列はメトリクス名（ラベルがある場合は `name{label="value"}`）ごとに持ち、
+/// `retention` より古い点は記録のたびに捨てる。
+#[derive(Debug)]
+pub struct TimeSeriesStore {
+    retention: Duration,
+    series: BTreeMap<String, VecDeque<(i64, f64)>>,
+}
+
+impl TimeSeriesStore {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            series: BTreeMap::new(),
+        }
+    }
+
+    /// カウンター・ゲージの現在値（ヒストグラムは `_count` と `_sum`）を `timestamp` の点として記録する
+    pub fn record(&mut self, families: &[MetricFamily], timestamp: DateTime<Utc>) {
+        let timestamp_ms = timestamp.timestamp_millis();
+        for family in families {
+            let name = family.get_name();
+            for metric in family.get_metric() {
+                let mut push = |name: &str, value: f64| {
+                    self.series
+                        .entry(series_name(name, metric))
+                        .or_default()
+                        .push_back((timestamp_ms, value));
+                };
+                match family.get_field_type() {
+                    MetricType::COUNTER => push(name, metric.get_counter().get_value()),
+                    MetricType::GAUGE => push(name, metric.get_gauge().get_value()),
+                    MetricType::UNTYPED => push(name, metric.get_untyped().get_value()),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        push(
+                            &format!("{}_count", name),
+                            histogram.get_sample_count() as f64,
+                        );
+                        push(&format!("{}_sum", name), histogram.get_sample_sum());
+                    }
+                    MetricType::SUMMARY => {}
+                }
+            }
+        }
+
+        let oldest = timestamp_ms.saturating_sub(self.retention.as_millis() as i64);
+        self.series.retain(|_, points| {
+            while points.front().is_some_and(|(time, _)| *time < oldest) {
+                points.pop_front();
+            }
+            !points.is_empty()
+        });
+    }
+
+    /// 名前に `filter` を含む系列の名前（名前順）
+    pub fn search(&self, filter: &str) -> Vec<String> {
+        self.series
+            .keys()
+            .filter(|name| name.contains(filter))
+            .cloned()
+            .collect()
+    }
+
+    /// 要求された各系列の、範囲内の点を返す（存在しない系列は空の系列になる）
+    pub fn query(&self, request: &QueryRequest) -> Vec<TimeSeries> {
+        let from = request.range.from.timestamp_millis();
+        let to = request.range.to.timestamp_millis();
+        request
+            .targets
+            .iter()
+            .map(|target| {
+                let points: Vec<[f64; 2]> = self
+                    .series
+                    .get(&target.target)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(time, _)| (from..=to).contains(time))
+                    .map(|(time, value)| [*value, *time as f64])
+                    .collect();
+                TimeSeries {
+                    target: target.target.clone(),
+                    datapoints: thin(points, request.max_data_points),
+                }
+            })
+            .collect()
+    }
+}
+
+/// ラベルを含む系列名（Prometheusのテキスト形式と同じ書き方）
+fn series_name(name: &str, metric: &Metric) -> String {
+    let labels: Vec<String> = metric
+        .get_label()
+        .iter()
+        .map(|label| format!("{}=\"{}\"", label.get_name(), label.get_value()))
+        .collect();
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, labels.join(","))
+    }
+}
+
+/// 点数が `max_points` を超える場合に、等間隔に間引く（最新の点は常に残す）
+fn thin(points: Vec<[f64; 2]>, max_points: Option<usize>) -> Vec<[f64; 2]> {
+    let Some(max_points) = max_points.filter(|max| *max > 0 && points.len() > *max) else {
+        return points;
+    };
+    let step = points.len().div_ceil(max_points);
+    let last = points.len() - 1;
+    points
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| (last - i).is_multiple_of(step))
+        .map(|(_, point)| point)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use prometheus::{Gauge, GaugeVec, Opts, Registry};
+
+    #[test]
+    fn recorded_metrics_are_searched_and_queried_within_the_retention() {
+        let registry = Registry::new();
+        let loss = Gauge::new("packet_loss_percentage", "loss").unwrap();
+        let rate =
+            GaugeVec::new(Opts::new("local_ip_tx_bytes_rate", "rate"), &["local_ip"]).unwrap();
+        registry.register(Box::new(loss.clone())).unwrap();
+        registry.register(Box::new(rate.clone())).unwrap();
+
+        let start = Utc.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap();
+        let at = |secs: i64| start + chrono::Duration::seconds(secs);
+        let mut store = TimeSeriesStore::new(Duration::from_secs(3));
+        for secs in 0..5 {
+            loss.set(secs as f64);
+            rate.with_label_values(&["10.0.0.1"])
+                .set(100.0 * secs as f64);
+            store.record(&registry.gather(), at(secs));
+        }
+
+        assert_eq!(
+            store.search("tx"),
+            vec!["local_ip_tx_bytes_rate{local_ip=\"10.0.0.1\"}".to_string()]
+        );
+        assert_eq!(store.search("").len(), 2);
+
+        let request: QueryRequest = serde_json::from_str(
+            r#"{
+                "range": {"from": "2026-10-14T12:00:00.000Z", "to": "2026-10-14T12:00:03.000Z"},
+                "targets": [{"target": "packet_loss_percentage", "refId": "A"}, {"target": "missing"}],
+                "maxDataPoints": 500
+            }"#,
+        )
+        .unwrap();
+        // 保持期間の3秒より古い0秒の点は捨てられている
+        assert_eq!(
+            store.query(&request),
+            vec![
+                TimeSeries {
+                    target: "packet_loss_percentage".to_string(),
+                    datapoints: vec![
+                        [1.0, at(1).timestamp_millis() as f64],
+                        [2.0, at(2).timestamp_millis() as f64],
+                        [3.0, at(3).timestamp_millis() as f64],
+                    ],
+                },
+                TimeSeries {
+                    target: "missing".to_string(),
+                    datapoints: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn points_beyond_max_data_points_are_thinned() {
+        let points: Vec<[f64; 2]> = (0..10).map(|i| [i as f64, i as f64]).collect();
+        let values = |points: Vec<[f64; 2]>| points.iter().map(|p| p[0]).collect::<Vec<_>>();
+        assert_eq!(
+            values(thin(points.clone(), Some(4))),
+            vec![0.0, 3.0, 6.0, 9.0]
+        );
+        assert_eq!(values(thin(points.clone(), None)).len(), 10);
+        assert_eq!(values(thin(points, Some(20))).len(), 10);
+    }
+}
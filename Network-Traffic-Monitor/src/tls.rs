@@ -0,0 +1,152 @@
+/// TLSとして解析するTCPポート
+pub const TLS_PORT: u16 = 443;
+
+/// TLSレコードのContent Type: Handshake
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// ハンドシェイクの種別: ClientHello
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+/// 拡張の種別: server_name
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+/// server_nameリストのエントリ種別: host_name
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// 境界チェック付きでバイト列を先頭から読み進めるカーソル
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// 1バイトの長さに続く可変長フィールド
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    /// 2バイトの長さに続く可変長フィールド
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+/// TCPペイロードの先頭をTLS ClientHelloとして解析し、SNIのホスト名を返す
+///
+/// セグメント境界を跨いだ再構築は行わないため、ClientHelloが1つ目のデータ
+/// セグメントに収まらずserver_name拡張まで届かない場合はNoneになる。
+pub fn parse_tls_client_hello(payload: &[u8]) -> Option<String> {
+    let mut record = Reader::new(payload);
+    if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    let _record_version = record.u16()?;
+    let _record_len = record.u16()?;
+
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let _handshake_len = record.u24()?;
+
+    // client_version, random, session_id, cipher_suites, compression_methods
+    record.take(2 + 32)?;
+    record.vec_u8()?;
+    record.vec_u16()?;
+    record.vec_u8()?;
+
+    let mut extensions = Reader::new(record.vec_u16()?);
+    while let Some(extension_type) = extensions.u16() {
+        let data = extensions.vec_u16()?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader::new(Reader::new(data).vec_u16()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.vec_u16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let host = std::str::from_utf8(name).ok()?;
+                return (!host.is_empty()).then(|| host.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// server_name拡張のみを持つ最小限のClientHelloを組み立てる
+    fn client_hello(host: &str) -> Vec<u8> {
+        let mut server_name = vec![NAME_TYPE_HOST_NAME];
+        server_name.extend((host.len() as u16).to_be_bytes());
+        server_name.extend(host.as_bytes());
+
+        let mut extension = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+        extension.extend(((server_name.len() + 2) as u16).to_be_bytes());
+        extension.extend((server_name.len() as u16).to_be_bytes());
+        extension.extend(server_name);
+
+        // 先頭にSNI以外の拡張（supported_versions）を置く
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend(extension);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]);
+        body.push(0); // session_id
+        body.extend([0x00, 0x02, 0x13, 0x01]); // cipher_suites
+        body.extend([0x01, 0x00]); // compression_methods
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn extracts_sni_from_client_hello() {
+        let payload = client_hello("Example.COM");
+        assert_eq!(
+            parse_tls_client_hello(&payload),
+            Some("example.com".to_string())
+        );
+
+        // 途中で切れたClientHelloや非TLSのペイロードは無視する
+        assert_eq!(parse_tls_client_hello(&payload[..payload.len() - 4]), None);
+        assert_eq!(parse_tls_client_hello(b"GET / HTTP/1.1\r\n"), None);
+    }
+}
@@ -0,0 +1,387 @@
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::Packet;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+#[cfg(target_os = "linux")]
+use std::ptr::{self, NonNull};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+/// 受信待ちのタイムアウト（libpcapの読み取りタイムアウトと揃え、計測時間の判定に戻れるようにする）
+#[cfg(target_os = "linux")]
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// リングの1ブロックの大きさ（カーネルはパケットをブロック単位でまとめてユーザー空間に渡す）
+#[cfg(target_os = "linux")]
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// `--buffer-size` を指定しない場合のリング全体の大きさ
+#[cfg(target_os = "linux")]
+const DEFAULT_RING_SIZE: usize = 32 << 20;
+
+/// TPACKET_V3のフレームの大きさ（V3のフレームは可変長で、この値はリングの設定の検証にのみ使われる）
+#[cfg(target_os = "linux")]
+const FRAME_SIZE: usize = 2048;
+
+/// パケットが少ない場合に、ブロックが埋まるのを待たずにユーザー空間へ渡すまでの時間（ミリ秒）
+#[cfg(target_os = "linux")]
+const BLOCK_TIMEOUT_MS: u32 = 100;
+
+/// AF_PACKETソケットのTPACKET_V3受信リング（`--engine afpacket`）
+///
+/// カーネルがmmapした共有メモリのブロックにフレームを書き込み、ユーザー空間はブロックを読み終えたら返す。
+/// フレームごとのシステムコールやコピーがなく、受信待ちはブロックが届くまでのpollのみになる。
+#[cfg(target_os = "linux")]
+pub struct Ring {
+    socket: OwnedFd,
+    map: NonNull<u8>,
+    block_count: usize,
+    snaplen: usize,
+    // 次に読む（または読み出し中の）ブロック
+    block: usize,
+    // 読み出し中のブロック内の次のパケットの位置と、残りのパケット数
+    cursor: Option<(usize, u32)>,
+}
+
+// リングのメモリはこの構造体だけが所有し、読み出しには `&mut self` が必要なため別スレッドへ渡せる
+#[cfg(target_os = "linux")]
+unsafe impl Send for Ring {}
+
+/// インターフェースをAF_PACKETのTPACKET_V3リングで開く（`--engine afpacket`）
+///
+/// `snaplen` を超える部分は切り捨て、リングの大きさは `buffer_size`（`--buffer-size`）をブロック単位に切り捨てた値とする。
+/// `promiscuous` がfalseの場合はこのホスト宛て・発のフレームのみを受け取る（`--no-promisc`）。
+#[cfg(target_os = "linux")]
+pub fn open(interface: &str, snaplen: usize, promiscuous: bool, buffer_size: Option<usize>) -> Result<Ring, Box<dyn std::error::Error>> {
+    use crate::interface::InterfaceNotFound;
+
+    let interfaces = pnet::datalink::interfaces();
+    let device = interfaces
+        .iter()
+        .find(|iface| iface.name == interface)
+        .ok_or_else(|| InterfaceNotFound::new(Some(interface), interfaces.iter().map(|iface| iface.name.clone())))?;
+
+    let block_count = (buffer_size.unwrap_or(DEFAULT_RING_SIZE) / BLOCK_SIZE).max(1);
+    Ring::new(device.index, snaplen, promiscuous, block_count)
+        .map_err(|e| format!("インターフェース '{}' のAF_PACKETリングを作成できません: {}", interface, e).into())
+}
+
+#[cfg(target_os = "linux")]
+impl Ring {
+    fn new(ifindex: u32, snaplen: usize, promiscuous: bool, block_count: usize) -> io::Result<Self> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::c_int::from(protocol)) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        set_option(&socket, libc::PACKET_VERSION, &(libc::tpacket_versions::TPACKET_V3 as libc::c_int))?;
+        let request = libc::tpacket_req3 {
+            tp_block_size: BLOCK_SIZE as u32,
+            tp_block_nr: block_count as u32,
+            tp_frame_size: FRAME_SIZE as u32,
+            tp_frame_nr: (BLOCK_SIZE / FRAME_SIZE * block_count) as u32,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        set_option(&socket, libc::PACKET_RX_RING, &request)?;
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                BLOCK_SIZE * block_count,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                socket.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // ここから先のエラーではDropでリングを解放する
+        let ring = Ring {
+            socket,
+            map: NonNull::new(map.cast()).ok_or_else(|| io::Error::other("mmapがnullを返しました"))?,
+            block_count,
+            snaplen,
+            block: 0,
+            cursor: None,
+        };
+
+        if promiscuous {
+            let membership = libc::packet_mreq {
+                mr_ifindex: ifindex as libc::c_int,
+                mr_type: libc::PACKET_MR_PROMISC as libc::c_ushort,
+                mr_alen: 0,
+                mr_address: [0; 8],
+            };
+            set_option(&ring.socket, libc::PACKET_ADD_MEMBERSHIP, &membership)?;
+        }
+
+        let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as libc::c_ushort;
+        address.sll_protocol = protocol;
+        address.sll_ifindex = ifindex as libc::c_int;
+        let result = unsafe {
+            libc::bind(
+                ring.socket.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ring)
+    }
+
+    /// 次のフレームを返す（`READ_TIMEOUT` の間に届かなければ `TimedOut` のエラー）
+    ///
+    /// 返したフレームは次の呼び出しまで有効で、ブロックの最後のフレームを読んだ後の呼び出しでブロックをカーネルに返す。
+    pub fn next(&mut self) -> io::Result<&[u8]> {
+        let frame = loop {
+            if let Some((offset, remaining)) = self.cursor {
+                if remaining == 0 {
+                    self.release_block();
+                    continue;
+                }
+                match read_packet(self.current_block(), offset, self.snaplen) {
+                    Ok((next_offset, frame)) => {
+                        self.cursor = Some((next_offset, remaining - 1));
+                        break frame;
+                    }
+                    Err(e) => {
+                        // 壊れたブロックは読み飛ばしてカーネルに返す
+                        self.release_block();
+                        return Err(e);
+                    }
+                }
+            }
+
+            if self.block_status().load(Ordering::Acquire) & libc::TP_STATUS_USER == 0 {
+                self.wait()?;
+                continue;
+            }
+            let header = unsafe { ptr::read(ptr::addr_of!((*self.block_desc()).hdr.bh1)) };
+            self.cursor = Some((header.offset_to_first_pkt as usize, header.num_pkts));
+        };
+        Ok(&self.current_block()[frame])
+    }
+
+    fn block_desc(&self) -> *mut libc::tpacket_block_desc {
+        unsafe { self.map.as_ptr().add(self.block * BLOCK_SIZE).cast() }
+    }
+
+    /// ブロックの所有者（カーネルかユーザー空間か）を示す状態。カーネルと共有するためアトミックに読み書きする
+    fn block_status(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(ptr::addr_of_mut!((*self.block_desc()).hdr.bh1.block_status)) }
+    }
+
+    /// ユーザー空間が所有している読み出し中のブロック
+    fn current_block(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.map.as_ptr().add(self.block * BLOCK_SIZE), BLOCK_SIZE) }
+    }
+
+    fn release_block(&mut self) {
+        self.block_status().store(libc::TP_STATUS_KERNEL, Ordering::Release);
+        self.block = (self.block + 1) % self.block_count;
+        self.cursor = None;
+    }
+
+    /// 次のブロックが届くまで待つ。インターフェースの消失などのソケットのエラーはそのまま返す
+    fn wait(&self) -> io::Result<()> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN | libc::POLLERR,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, READ_TIMEOUT.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            return if error.kind() == io::ErrorKind::Interrupted { Ok(()) } else { Err(error) };
+        }
+        if ready == 0 {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if poll_fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            return Err(self.take_socket_error());
+        }
+        Ok(())
+    }
+
+    /// ソケットに記録されたエラー（SO_ERROR）を取り出す
+    fn take_socket_error(&self) -> io::Error {
+        let mut error: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut error as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        match (result, error) {
+            (0, 0) => io::Error::other("AF_PACKETソケットが閉じられました"),
+            (0, error) => io::Error::from_raw_os_error(error),
+            _ => io::Error::last_os_error(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map.as_ptr().cast(), BLOCK_SIZE * self.block_count);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_option<T>(socket: &OwnedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_PACKET,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// ブロック内の `offset` にあるパケットのヘッダを読み、次のパケットの位置とフレームの範囲を返す
+///
+/// ヘッダやフレームがブロックの外を指す場合はエラーにする。フレームは `snaplen` で切り詰める。
+#[cfg(target_os = "linux")]
+fn read_packet(block: &[u8], offset: usize, snaplen: usize) -> io::Result<(usize, Range<usize>)> {
+    let header_end = offset.checked_add(std::mem::size_of::<libc::tpacket3_hdr>());
+    if header_end.is_none_or(|end| end > block.len()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "パケットのヘッダがブロックの外を指しています"));
+    }
+    let header = unsafe { ptr::read_unaligned(block.as_ptr().add(offset).cast::<libc::tpacket3_hdr>()) };
+    let start = offset + usize::from(header.tp_mac);
+    let end = start + (header.tp_snaplen as usize).min(snaplen);
+    if end > block.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "フレームがブロックの外を指しています"));
+    }
+    Ok((offset + header.tp_next_offset as usize, start..end))
+}
+
+/// AF_PACKETはLinuxのみのため、ほかのOSでは値を作れない
+#[cfg(not(target_os = "linux"))]
+pub enum Ring {}
+
+#[cfg(not(target_os = "linux"))]
+impl Ring {
+    pub fn next(&mut self) -> io::Result<&[u8]> {
+        match *self {}
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open(_interface: &str, _snaplen: usize, _promiscuous: bool, _buffer_size: Option<usize>) -> Result<Ring, Box<dyn std::error::Error>> {
+    Err("--engine afpacket はLinuxでのみ利用できます".into())
+}
+
+/// 受信待ちがタイムアウトしただけのエラーか（libpcapの `TimeoutExpired` に相当）
+pub fn is_timeout(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::TimedOut
+}
+
+/// libpcapの `tcp` フィルタ（IPv4・IPv6のTCP）に相当する判定
+///
+/// AF_PACKETソケットにはBPFを設定しないため、パケット数を数える前にユーザー空間で絞り込む。
+pub fn is_tcp_frame(frame: &[u8]) -> bool {
+    let Some(ethernet) = EthernetPacket::new(frame) else {
+        return false;
+    };
+    match ethernet.get_ethertype() {
+        EtherTypes::Ipv4 => Ipv4Packet::new(ethernet.payload())
+            .is_some_and(|ipv4| ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Tcp),
+        EtherTypes::Ipv6 => Ipv6Packet::new(ethernet.payload())
+            .is_some_and(|ipv6| ipv6.get_next_header() == IpNextHeaderProtocols::Tcp),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+
+    fn ipv4_frame(protocol: pnet::packet::ip::IpNextHeaderProtocol) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20];
+        MutableEthernetPacket::new(&mut frame).unwrap().set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_next_level_protocol(protocol);
+        frame
+    }
+
+    #[test]
+    fn only_tcp_frames_pass_the_userspace_filter() {
+        assert!(is_tcp_frame(&ipv4_frame(IpNextHeaderProtocols::Tcp)));
+        assert!(!is_tcp_frame(&ipv4_frame(IpNextHeaderProtocols::Udp)));
+
+        // ARPや途中で切れたフレームは数えない
+        let mut arp = ipv4_frame(IpNextHeaderProtocols::Tcp);
+        MutableEthernetPacket::new(&mut arp).unwrap().set_ethertype(EtherTypes::Arp);
+        assert!(!is_tcp_frame(&arp));
+        assert!(!is_tcp_frame(&[0u8; 10]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn packets_are_walked_within_a_ring_block() {
+        // カーネルと同じ形で、ブロック内に2つのパケット（フレームはヘッダの80バイト後ろ）を並べる
+        let mut block = vec![0u8; 4096];
+        let write_header = |block: &mut [u8], offset: usize, next_offset: u32, snaplen: u32| {
+            let header = libc::tpacket3_hdr {
+                tp_next_offset: next_offset,
+                tp_sec: 0,
+                tp_nsec: 0,
+                tp_snaplen: snaplen,
+                tp_len: snaplen,
+                tp_status: libc::TP_STATUS_USER,
+                tp_mac: 80,
+                tp_net: 94,
+                hv1: libc::tpacket_hdr_variant1 { tp_rxhash: 0, tp_vlan_tci: 0, tp_vlan_tpid: 0, tp_padding: 0 },
+                tp_padding: [0; 8],
+            };
+            unsafe { ptr::write_unaligned(block.as_mut_ptr().add(offset).cast(), header) };
+        };
+        write_header(&mut block, 48, 1024, 1000);
+        write_header(&mut block, 1072, 0, 60);
+
+        assert_eq!(read_packet(&block, 48, 65536).unwrap(), (1072, 128..1128));
+        // snaplenを超える部分は切り捨てる
+        assert_eq!(read_packet(&block, 48, 54).unwrap(), (1072, 128..182));
+        assert_eq!(read_packet(&block, 1072, 65536).unwrap(), (1072, 1152..1212));
+
+        // ブロックの外を指すヘッダ・フレームはエラーにする
+        assert!(read_packet(&block, 4090, 65536).is_err());
+        write_header(&mut block, 3000, 0, 2000);
+        assert!(read_packet(&block, 3000, 65536).is_err());
+    }
+}
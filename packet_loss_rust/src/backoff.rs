@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+
+/// 失敗するごとに倍にし、上限で止まる待ち時間
+///
+/// キャプチャのバックエンド（libpcap・AF_PACKET）の再接続で同じ間隔を使う。
+#[derive(Debug)]
+pub struct Backoff {
+    delay: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { delay: initial, max }
+    }
+
+    /// 次に待つ時間
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// 失敗を記録し、待ち時間を倍にする（上限を超えない）
+    pub fn fail(&mut self) {
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    /// 待ち時間だけ眠る（`deadline` を過ぎる場合はそこまで）。`deadline` に達した場合はfalseを返す
+    pub fn sleep(&self, deadline: Option<Instant>) -> bool {
+        let wait = match deadline {
+            Some(deadline) => self.delay.min(deadline.saturating_duration_since(Instant::now())),
+            None => self.delay,
+        };
+        std::thread::sleep(wait);
+        deadline.is_none_or(|deadline| Instant::now() < deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        let mut waits = Vec::new();
+        for _ in 0..7 {
+            backoff.fail();
+            waits.push(backoff.delay().as_secs());
+        }
+        assert_eq!(waits, [2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn sleep_stops_at_the_deadline() {
+        let backoff = Backoff::new(Duration::from_secs(30), Duration::from_secs(30));
+        assert!(!backoff.sleep(Some(Instant::now())));
+    }
+}
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use std::fmt::Debug;
+use std::time::Instant;
+
+/// 現在時刻を提供するクロック
+///
+/// 検出ロジックが直接 `Utc::now()` / `Instant::now()` を呼ばないようにすることで、
+/// テストから時刻を制御できるようにする。
+pub trait Clock: Debug + Send + Sync {
+    /// 現在のUTC時刻
+    fn now_utc(&self) -> DateTime<Utc>;
+    /// 現在の単調時刻
+    fn now_instant(&self) -> Instant;
+}
+
+/// システム時刻を返す通常のクロック
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// テスト用の手動で進めるクロック
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: std::sync::Mutex<(DateTime<Utc>, Instant)>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        let start_utc = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        Self {
+            now: std::sync::Mutex::new((start_utc, Instant::now())),
+        }
+    }
+
+    /// 両方の時刻を同じだけ進める
+    pub fn advance(&self, duration: std::time::Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += chrono::Duration::from_std(duration).unwrap();
+        now.1 += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.now.lock().unwrap().0
+    }
+
+    fn now_instant(&self) -> Instant {
+        self.now.lock().unwrap().1
+    }
+}
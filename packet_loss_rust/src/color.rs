@@ -0,0 +1,79 @@
+use clap::ValueEnum;
+use std::io::IsTerminal;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// `--color` で選ぶコンソール出力の色付け
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// 標準出力が端末で、環境変数 `NO_COLOR` が設定されていない場合のみ色を付ける
+    Auto,
+    /// 常に色を付ける
+    Always,
+    /// 色を付けない
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self, is_terminal: bool, no_color: bool) -> bool {
+        match self {
+            Self::Auto => is_terminal && !no_color,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// コンソール出力の色付け（無効の場合は文字列をそのまま返し、パイプ先のログを汚さない）
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Palette {
+    enabled: bool,
+}
+
+impl Palette {
+    /// `choice` と標準出力が端末かどうか、`NO_COLOR`（空文字列は未設定と同じ）から色付けの有無を決める
+    pub fn detect(choice: ColorChoice) -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        Self {
+            enabled: choice.enabled(std::io::stdout().is_terminal(), no_color),
+        }
+    }
+
+    /// ロスなどの件数（0なら緑、それ以外は赤）
+    pub fn count(&self, value: u64) -> String {
+        let color = if value == 0 { GREEN } else { RED };
+        self.paint(&value.to_string(), color)
+    }
+
+    fn paint(&self, text: &str, color: &str) -> String {
+        if self.enabled {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_colors_only_interactive_output() {
+        assert!(ColorChoice::Auto.enabled(true, false));
+        assert!(!ColorChoice::Auto.enabled(false, false));
+        assert!(!ColorChoice::Auto.enabled(true, true));
+        assert!(ColorChoice::Always.enabled(false, true));
+        assert!(!ColorChoice::Never.enabled(true, false));
+    }
+
+    #[test]
+    fn loss_counts_are_red_unless_zero() {
+        let palette = Palette { enabled: true };
+        assert_eq!(palette.count(0), "\x1b[32m0\x1b[0m");
+        assert_eq!(palette.count(3), "\x1b[31m3\x1b[0m");
+        assert_eq!(Palette::default().count(3), "3");
+    }
+}
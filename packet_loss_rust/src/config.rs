@@ -0,0 +1,210 @@
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, Parser};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// `--config` の引数のID（`Args` のフィールド名）
+const CONFIG_ARG: &str = "config";
+
+/// コマンドライン引数を解釈する（`--config` を指定した場合は `F` として読んだTOMLファイルの値で補う）
+///
+/// 解釈できない場合は `Parser::parse` と同じくエラーを表示して終了する。
+pub fn parse<T: Parser, F: DeserializeOwned + Serialize>() -> T {
+    try_parse_from::<T, F>(std::env::args_os()).unwrap_or_else(|e| e.exit())
+}
+
+/// `argv` を解釈し、`--config` のファイルにあるキーのうちコマンドラインで指定していないものを補う
+///
+/// ファイルは `F`（`#[serde(deny_unknown_fields)]` で不明なキーを拒否する）として読み、
+/// その値をコマンドラインと同じ引数に変換してから解釈するため、値の検証や既定値はCLIと共通になる。
+pub fn try_parse_from<T: Parser, F: DeserializeOwned + Serialize>(
+    argv: impl IntoIterator<Item = impl Into<OsString>>,
+) -> Result<T, clap::Error> {
+    let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+    let mut command = T::command();
+    // 必須の引数はファイル側にある場合もあるため、この時点ではエラーにしない
+    let cli = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)?;
+    let Some(path) = cli.get_one::<PathBuf>(CONFIG_ARG) else {
+        return T::try_parse_from(argv);
+    };
+
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        command.error(
+            ErrorKind::Io,
+            format!("設定ファイル {} を読み込めません: {}", path.display(), e),
+        )
+    })?;
+    let invalid = |e: &dyn std::fmt::Display| {
+        T::command().error(
+            ErrorKind::ValueValidation,
+            format!("設定ファイル {} が不正です: {}", path.display(), e),
+        )
+    };
+    let file: F = toml::from_str(&contents).map_err(|e| invalid(&e))?;
+    // 未指定（`None`）のキーは含まれない
+    let entries = toml::Table::try_from(file).map_err(|e| invalid(&e))?;
+
+    let mut file_args = Vec::new();
+    for (key, value) in entries {
+        let id = key.as_str();
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id && id != CONFIG_ARG)
+        else {
+            return Err(command.error(
+                ErrorKind::UnknownArgument,
+                format!("設定ファイルのキー {} に対応する引数がありません", key),
+            ));
+        };
+        // コマンドラインで指定した値を優先する
+        if cli.value_source(id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, toml::Value::Boolean(enabled)) => {
+                if enabled {
+                    file_args.push(OsString::from(format!("--{}", long)));
+                }
+            }
+            (ArgAction::SetTrue, _) => {
+                return Err(command.error(
+                    ErrorKind::InvalidValue,
+                    format!("設定ファイルの {} は true か false で指定してください", key),
+                ));
+            }
+            // `--focus <IP1> <IP2>` のように1回で複数の値を取る引数には、配列をまとめて渡す
+            (_, toml::Value::Array(values))
+                if arg
+                    .get_num_args()
+                    .is_some_and(|range| range.min_values() > 1) =>
+            {
+                file_args.push(OsString::from(format!("--{}", long)));
+                for value in values {
+                    let value = scalar_arg(&key, value).map_err(|e| invalid(&e))?;
+                    file_args.push(OsString::from(value));
+                }
+            }
+            (_, toml::Value::Array(values)) => {
+                for value in values {
+                    let value = scalar_arg(&key, value).map_err(|e| invalid(&e))?;
+                    file_args.push(OsString::from(format!("--{}={}", long, value)));
+                }
+            }
+            (_, value) => {
+                let value = scalar_arg(&key, value).map_err(|e| invalid(&e))?;
+                file_args.push(OsString::from(format!("--{}={}", long, value)));
+            }
+        }
+    }
+
+    // ファイルの値をプログラム名の直後に置き、残りはコマンドラインの順のまま解釈する
+    let mut merged = argv;
+    let rest = merged.split_off(merged.len().min(1));
+    merged.extend(file_args);
+    merged.extend(rest);
+    let mut matches = command.try_get_matches_from_mut(merged)?;
+    T::from_arg_matches_mut(&mut matches)
+}
+
+/// 文字列・数値・真偽値を、コマンドラインに渡すのと同じ文字列に変換する
+fn scalar_arg(key: &str, value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!("{} に対応していない値です", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[derive(Parser, Debug)]
+    struct TestArgs {
+        #[arg(short, long)]
+        interface: String,
+        #[arg(long, default_value = "1")]
+        stats_interval: u64,
+        #[arg(long)]
+        verbose: bool,
+        #[arg(long = "exclude-port")]
+        exclude_ports: Vec<u16>,
+        #[arg(long, num_args = 2)]
+        pair: Vec<String>,
+        #[arg(long)]
+        config: Option<PathBuf>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(deny_unknown_fields)]
+    struct TestConfig {
+        interface: Option<String>,
+        stats_interval: Option<u64>,
+        verbose: Option<bool>,
+        exclude_ports: Option<Vec<u16>>,
+        pair: Option<Vec<String>>,
+    }
+
+    fn write_config(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    fn parse_with(file: &NamedTempFile, extra: &[&str]) -> Result<TestArgs, clap::Error> {
+        let mut argv = vec!["monitor", "--config", file.path().to_str().unwrap()];
+        argv.extend(extra);
+        try_parse_from::<TestArgs, TestConfig>(argv)
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let file = write_config(
+            "# 監視設定\ninterface = \"eth0\"\nstats_interval = 5 # 秒\nverbose = true\nexclude_ports = [22, 9090]\npair = [\"a\", \"b\"]\n",
+        );
+
+        let args = parse_with(&file, &[]).unwrap();
+        assert_eq!(args.interface, "eth0");
+        assert_eq!(args.stats_interval, 5);
+        assert!(args.verbose);
+        assert_eq!(args.exclude_ports, vec![22, 9090]);
+        assert_eq!(args.pair, vec!["a", "b"]);
+
+        let args = parse_with(&file, &["-i", "eth1", "--exclude-port", "443"]).unwrap();
+        assert_eq!(args.interface, "eth1");
+        assert_eq!(args.stats_interval, 5);
+        assert_eq!(args.exclude_ports, vec![443]);
+
+        // --config がなければ通常どおり
+        assert!(try_parse_from::<TestArgs, TestConfig>(["monitor"]).is_err());
+    }
+
+    #[test]
+    fn invalid_or_unknown_keys_are_rejected() {
+        for contents in [
+            "interface = \"eth0\"\nstats_interval = -1\n",
+            "interface = \"eth0\"\nstats_intervall = 1\n",
+            "interface = \"eth0\"\nverbose = \"yes\"\n",
+            "interface = \"eth0\"\ninterface = \"eth1\"\n",
+            "[capture]\ninterface = \"eth0\"\n",
+        ] {
+            let file = write_config(contents);
+            assert!(parse_with(&file, &[]).is_err(), "{}", contents);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+
+/// 逆引き結果を保持する件数の上限（超えた場合はキャッシュを空にしてやり直す）
+pub const DEFAULT_CACHE_CAPACITY: usize = 4096;
+/// 1件の逆引きを待つ時間の上限
+pub const DEFAULT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+/// 逆引き待ちのキューの長さ（溢れた分は次の表示で改めて依頼する）
+const QUEUE_CAPACITY: usize = 256;
+/// 同時に実行する逆引きの上限
+///
+/// タイムアウトしても実行中の逆引きは止められないため、応答しないDNSサーバーで
+/// ブロッキングプールのスレッドが積み上がらないよう、終わるまで枠を返さない。
+const MAX_IN_FLIGHT: usize = 4;
+
+#[derive(Debug, Clone)]
+enum CacheEntry {
+    Pending,
+    Resolved(String),
+    Failed,
+}
+
+/// IPアドレスからホスト名への逆引きキャッシュ（`--resolve-dns`）
+///
+/// 逆引きはバックグラウンドで同時に `MAX_IN_FLIGHT` 件まで行い、参照側はキャッシュを見るだけで待たない。
+/// 解決前や失敗したアドレスにはホスト名を返さない。
+#[derive(Debug, Clone)]
+pub struct ReverseDnsCache {
+    entries: Arc<Mutex<HashMap<IpAddr, CacheEntry>>>,
+    capacity: usize,
+    requests: mpsc::Sender<IpAddr>,
+}
+
+impl ReverseDnsCache {
+    /// キャッシュと逆引きを行うタスクを作成する（tokioのランタイム内で呼ぶ）
+    pub fn spawn(capacity: usize, timeout: Duration) -> Self {
+        let (cache, mut requests) = Self::new(capacity);
+        let entries = cache.entries.clone();
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+        tokio::spawn(async move {
+            while let Some(ip) = requests.recv().await {
+                let Ok(permit) = in_flight.clone().acquire_owned().await else {
+                    break;
+                };
+                let entries = entries.clone();
+                tokio::spawn(async move {
+                    let lookup = tokio::task::spawn_blocking(move || {
+                        let hostname = dns_lookup::lookup_addr(&ip).ok();
+                        drop(permit);
+                        hostname
+                    });
+                    let entry = match tokio::time::timeout(timeout, lookup).await {
+                        Ok(Ok(Some(hostname))) => CacheEntry::Resolved(hostname),
+                        Ok(_) => CacheEntry::Failed,
+                        Err(_) => {
+                            debug!("逆引きがタイムアウトしました: {}", ip);
+                            CacheEntry::Failed
+                        }
+                    };
+                    entries.lock().unwrap().insert(ip, entry);
+                });
+            }
+        });
+        cache
+    }
+
+    fn new(capacity: usize) -> (Self, mpsc::Receiver<IpAddr>) {
+        let (requests, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let cache = Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            capacity: capacity.max(1),
+            requests,
+        };
+        (cache, receiver)
+    }
+
+    /// 指定したアドレスを解決済みにしたキャッシュ（逆引きは行わない）
+    #[cfg(test)]
+    pub fn with_resolved(resolved: &[(IpAddr, &str)]) -> Self {
+        let (cache, _) = Self::new(DEFAULT_CACHE_CAPACITY);
+        cache.entries.lock().unwrap().extend(
+            resolved
+                .iter()
+                .map(|(ip, hostname)| (*ip, CacheEntry::Resolved(hostname.to_string()))),
+        );
+        cache
+    }
+
+    /// 解決済みのホスト名を返す。未解決の場合は逆引きを依頼してNoneを返す
+    pub fn hostname(&self, ip: IpAddr) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&ip) {
+            Some(CacheEntry::Resolved(hostname)) => return Some(hostname.clone()),
+            Some(CacheEntry::Pending | CacheEntry::Failed) => return None,
+            None => {}
+        }
+        if entries.len() >= self.capacity {
+            entries.clear();
+        }
+        if self.requests.try_send(ip).is_ok() {
+            entries.insert(ip, CacheEntry::Pending);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_are_queued_once_and_never_block() {
+        let (cache, mut requests) = ReverseDnsCache::new(2);
+        let ip: IpAddr = "203.0.113.10".parse().unwrap();
+
+        // 未解決のアドレスは待たずにNoneを返し、逆引きは1回だけ依頼する
+        assert_eq!(cache.hostname(ip), None);
+        assert_eq!(cache.hostname(ip), None);
+        assert_eq!(requests.try_recv(), Ok(ip));
+        assert!(requests.try_recv().is_err());
+
+        cache.entries.lock().unwrap().insert(ip, CacheEntry::Resolved("edge.example.net".to_string()));
+        assert_eq!(cache.hostname(ip).as_deref(), Some("edge.example.net"));
+
+        // 上限に達したら古い結果を捨てる
+        cache.hostname("203.0.113.11".parse().unwrap());
+        cache.hostname("203.0.113.12".parse().unwrap());
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    }
+}
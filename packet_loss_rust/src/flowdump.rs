@@ -0,0 +1,110 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// CSVのヘッダ（列名はpandasなどで読み込んだときの列名になる）
+const HEADER: &str = "timestamp,connection,packets,bytes,missing,duplicate,out_of_order,last_seen";
+
+/// フローダンプの1行（接続の1つの向きの状態）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowRecord {
+    // 向きのある接続のキー（送信元IP:ポート-宛先IP:ポート）
+    pub connection: String,
+    pub packets: u64,
+    // ペイロードのバイト数
+    pub bytes: u64,
+    pub missing: u64,
+    pub duplicate: u64,
+    pub out_of_order: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// `--flow-dump` のパスのファイル名に時刻を入れる（例: flows.csv → flows-20261014T120000Z.csv）
+///
+/// ダンプごとに別のファイルになるため、古いファイルはそのまま残り、時刻順に並ぶ。
+pub fn timestamped_path(template: &Path, timestamp: DateTime<Utc>) -> PathBuf {
+    let stem = template
+        .file_stem()
+        .map_or_else(|| "flows".into(), |stem| stem.to_string_lossy());
+    let extension = template
+        .extension()
+        .map_or_else(|| "csv".into(), |extension| extension.to_string_lossy());
+    template.with_file_name(format!(
+        "{}-{}.{}",
+        stem,
+        timestamp.format("%Y%m%dT%H%M%SZ"),
+        extension
+    ))
+}
+
+/// フロー表をCSVファイルに書き出す
+///
+/// 書き込み途中のファイルを読み込まれないよう、一時ファイルに書いてから名前を変える。
+/// 接続のキーはIPv4アドレスとポートのみでカンマや引用符を含まないため、値はエスケープしない。
+pub fn write_csv(path: &Path, timestamp: DateTime<Utc>, records: &[FlowRecord]) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    writeln!(writer, "{}", HEADER)?;
+    let timestamp = timestamp.to_rfc3339_opts(SecondsFormat::Millis, true);
+    for record in records {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{}",
+            timestamp,
+            record.connection,
+            record.packets,
+            record.bytes,
+            record.missing,
+            record.duplicate,
+            record.out_of_order,
+            record
+                .last_seen
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+        )?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn flow_table_is_written_to_a_timestamped_csv() {
+        let timestamp = Utc.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap();
+        assert_eq!(
+            timestamped_path(Path::new("/var/log/flows.csv"), timestamp),
+            PathBuf::from("/var/log/flows-20261014T120000Z.csv")
+        );
+        assert_eq!(
+            timestamped_path(Path::new("dump"), timestamp),
+            PathBuf::from("dump-20261014T120000Z.csv")
+        );
+
+        let dir = std::env::temp_dir().join(format!("flowdump-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = timestamped_path(&dir.join("flows.csv"), timestamp);
+        let record = FlowRecord {
+            connection: "203.0.113.10:443-198.51.100.20:50000".to_string(),
+            packets: 3,
+            bytes: 2920,
+            missing: 1,
+            duplicate: 0,
+            out_of_order: 2,
+            last_seen: timestamp - chrono::Duration::milliseconds(250),
+        };
+        write_csv(&path, timestamp, &[record]).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "timestamp,connection,packets,bytes,missing,duplicate,out_of_order,last_seen\n\
+             2026-10-14T12:00:00.000Z,203.0.113.10:443-198.51.100.20:50000,3,2920,1,0,2,2026-10-14T11:59:59.750Z\n"
+        );
+        assert!(!path.with_extension("partial").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use maxminddb::{path, Reader};
+use std::net::IpAddr;
+use std::path::Path;
+
+/// 国・ASNが判別できなかった場合のラベル値
+const UNKNOWN: &str = "unknown";
+
+/// MaxMind形式（.mmdb）のデータベースを使ったIPアドレスの国・ASN判定
+///
+/// Country/City系とASN系のどちらのデータベースでも使えるよう、両方のフィールドを
+/// 参照し、存在しないものは `unknown` として扱う。
+#[derive(Debug)]
+pub struct GeoIpDb {
+    reader: Reader<Vec<u8>>,
+}
+
+/// 1つのIPアドレスに対するルックアップ結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoIpInfo {
+    pub country: String,
+    pub asn: String,
+}
+
+impl GeoIpDb {
+    /// データベースファイルを読み込む（起動時に1回だけ呼ぶ）
+    pub fn open(db_path: &Path) -> Result<Self, maxminddb::MaxMindDbError> {
+        Ok(Self {
+            reader: Reader::open_readfile(db_path)?,
+        })
+    }
+
+    /// IPアドレスの国コードとAS番号を取得
+    pub fn lookup(&self, ip: IpAddr) -> GeoIpInfo {
+        let result = match self.reader.lookup(ip) {
+            Ok(result) => result,
+            Err(_) => return GeoIpInfo::unknown(),
+        };
+
+        let country = result
+            .decode_path::<String>(&path!["country", "iso_code"])
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| UNKNOWN.to_string());
+        let asn = result
+            .decode_path::<u32>(&path!["autonomous_system_number"])
+            .ok()
+            .flatten()
+            .map(|asn| format!("AS{}", asn))
+            .unwrap_or_else(|| UNKNOWN.to_string());
+
+        GeoIpInfo { country, asn }
+    }
+}
+
+impl GeoIpInfo {
+    fn unknown() -> Self {
+        Self {
+            country: UNKNOWN.to_string(),
+            asn: UNKNOWN.to_string(),
+        }
+    }
+}
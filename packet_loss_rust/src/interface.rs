@@ -0,0 +1,237 @@
+use pnet::datalink::{MacAddr, NetworkInterface};
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
+use std::fmt;
+
+/// 監視するインターフェースの指定（`--interface` / `--interface-index` / `--interface-mac`）
+///
+/// 名前は再起動や環境によって変わるため、インデックスやMACアドレスでも指定できる。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceSelector {
+    pub name: Option<String>,
+    pub index: Option<u32>,
+    pub mac: Option<MacAddr>,
+}
+
+impl InterfaceSelector {
+    /// 名前・インデックス・MACアドレスの順に一致するインターフェースを探し、その名前を返す
+    ///
+    /// pcapのデバイスは名前でしか開けないため、インデックスとMACアドレスはpnetの一覧から名前に変換する。
+    pub fn resolve(&self, interfaces: &[NetworkInterface]) -> Result<String, InterfaceNotFound> {
+        let by_name = || {
+            let name = self.name.as_ref()?;
+            interfaces.iter().find(|iface| &iface.name == name)
+        };
+        let by_index = || {
+            let index = self.index?;
+            interfaces.iter().find(|iface| iface.index == index)
+        };
+        let by_mac = || {
+            let mac = self.mac?;
+            interfaces.iter().find(|iface| iface.mac == Some(mac))
+        };
+        if let Some(interface) = by_name().or_else(by_index).or_else(by_mac) {
+            return Ok(interface.name.clone());
+        }
+
+        let mut tried = Vec::new();
+        if let Some(index) = self.index {
+            tried.push(format!("インデックス {}", index));
+        }
+        if let Some(mac) = self.mac {
+            tried.push(format!("MAC {}", mac));
+        }
+        Err(InterfaceNotFound {
+            tried,
+            ..InterfaceNotFound::new(
+                self.name.as_deref(),
+                interfaces.iter().map(|iface| iface.name.clone()),
+            )
+        })
+    }
+}
+
+/// インターフェースの最初のIPv4アドレスとネットマスク（同じネットワークセグメントのアドレスをローカルとして扱う）
+///
+/// パケットごとにインターフェースを列挙しないよう、起動時に1回だけ呼び出す。
+pub fn ipv4_network(interfaces: &[NetworkInterface], name: &str) -> Option<Ipv4Network> {
+    interfaces
+        .iter()
+        .find(|iface| iface.name == name)?
+        .ips
+        .iter()
+        .find_map(|ip| match ip {
+            IpNetwork::V4(network) => Some(*network),
+            IpNetwork::V6(_) => None,
+        })
+}
+
+/// 指定したインターフェースが見つからないエラー（利用可能なインターフェースと、近い名前の候補を示す）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceNotFound {
+    name: Option<String>,
+    // 名前以外に試した条件（インデックス・MACアドレス）
+    tried: Vec<String>,
+    available: Vec<String>,
+}
+
+impl InterfaceNotFound {
+    pub fn new(name: Option<&str>, available: impl IntoIterator<Item = String>) -> Self {
+        let mut available: Vec<String> = available.into_iter().collect();
+        available.sort();
+        available.dedup();
+        Self {
+            name: name.map(str::to_string),
+            tried: Vec::new(),
+            available,
+        }
+    }
+
+    /// 編集距離が最も近いインターフェース名（離れすぎている場合は提案しない）
+    pub fn suggestion(&self) -> Option<&str> {
+        let name = self.name.as_deref()?;
+        let max_distance = (name.chars().count() / 3).max(2);
+        self.available
+            .iter()
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate.as_str())
+    }
+}
+
+impl fmt::Display for InterfaceNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.tried.is_empty()) {
+            (Some(name), true) => write!(f, "インターフェース '{}' が見つかりません", name)?,
+            (name, _) => {
+                let tried: Vec<String> = name
+                    .iter()
+                    .map(|name| format!("名前 '{}'", name))
+                    .chain(self.tried.iter().cloned())
+                    .collect();
+                write!(
+                    f,
+                    "インターフェース（{}）が見つかりません",
+                    tried.join("、")
+                )?;
+            }
+        }
+        if self.available.is_empty() {
+            write!(
+                f,
+                "（利用可能なインターフェースがありません。権限を確認してください）"
+            )?;
+        } else {
+            write!(f, "（利用可能: {}）", self.available.join(", "))?;
+        }
+        if let Some(suggestion) = self.suggestion() {
+            write!(f, "。{} のことですか？", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InterfaceNotFound {}
+
+/// 2つの文字列のレーベンシュタイン距離
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found(name: &str) -> InterfaceNotFound {
+        InterfaceNotFound::new(
+            Some(name),
+            ["lo", "ens19", "docker0", "ens18"].map(String::from),
+        )
+    }
+
+    #[test]
+    fn closest_interface_is_suggested() {
+        assert_eq!(edit_distance("ens19", "ens18"), 1);
+        assert_eq!(edit_distance("eth0", "ens19"), 4);
+        assert_eq!(edit_distance("", "lo"), 2);
+
+        assert_eq!(not_found("enss19").suggestion(), Some("ens19"));
+        assert_eq!(not_found("dokcer0").suggestion(), Some("docker0"));
+        assert_eq!(not_found("wlp3s0").suggestion(), None);
+        assert_eq!(
+            not_found("ens180").to_string(),
+            "インターフェース 'ens180' が見つかりません（利用可能: docker0, ens18, ens19, lo）。ens18 のことですか？"
+        );
+        assert_eq!(
+            not_found("wlp3s0").to_string(),
+            "インターフェース 'wlp3s0' が見つかりません（利用可能: docker0, ens18, ens19, lo）"
+        );
+    }
+
+    #[test]
+    fn interfaces_are_resolved_by_name_then_index_then_mac() {
+        let interface = |name: &str, index: u32, mac: Option<MacAddr>| NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac,
+            ips: Vec::new(),
+            flags: 0,
+        };
+        let mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0x00, 0x00, 0x02);
+        let interfaces = [interface("lo", 1, None), interface("enp3s0", 2, Some(mac))];
+
+        let selector = InterfaceSelector {
+            name: Some("ens19".to_string()),
+            index: Some(2),
+            mac: None,
+        };
+        assert_eq!(selector.resolve(&interfaces), Ok("enp3s0".to_string()));
+        let selector = InterfaceSelector {
+            mac: Some(mac),
+            ..Default::default()
+        };
+        assert_eq!(selector.resolve(&interfaces), Ok("enp3s0".to_string()));
+        // 名前が一致すればインデックスより優先する
+        let selector = InterfaceSelector {
+            name: Some("lo".to_string()),
+            index: Some(2),
+            mac: None,
+        };
+        assert_eq!(selector.resolve(&interfaces), Ok("lo".to_string()));
+
+        let selector = InterfaceSelector {
+            name: Some("enp3s1".to_string()),
+            index: Some(7),
+            mac: Some(MacAddr::new(0xaa, 0xbb, 0xcc, 0x00, 0x00, 0x09)),
+        };
+        assert_eq!(
+            selector.resolve(&interfaces).unwrap_err().to_string(),
+            "インターフェース（名前 'enp3s1'、インデックス 7、MAC aa:bb:cc:00:00:09）が見つかりません（利用可能: enp3s0, lo）。enp3s0 のことですか？"
+        );
+    }
+
+    #[test]
+    fn interface_network_is_the_first_ipv4_address() {
+        let interfaces = [NetworkInterface {
+            name: "enp3s0".to_string(),
+            description: String::new(),
+            index: 2,
+            mac: None,
+            ips: vec!["2001:db8::10/64".parse().unwrap(), "192.0.2.10/24".parse().unwrap()],
+            flags: 0,
+        }];
+        assert_eq!(ipv4_network(&interfaces, "enp3s0"), Some("192.0.2.10/24".parse().unwrap()));
+        assert_eq!(ipv4_network(&interfaces, "ens19"), None);
+    }
+}
@@ -1,18 +1,51 @@
-use clap::Parser;
-use pcap::{Capture, Device};
+mod afpacket;
+mod backoff;
+mod clock;
+mod color;
+mod config;
+mod dns;
+mod flowdump;
+mod geoip;
+mod interface;
+mod metricsjson;
+mod route;
+mod scan;
+mod worker;
+
+use backoff::Backoff;
+use clap::{Parser, ValueEnum};
+use clock::{Clock, SystemClock};
+use color::{ColorChoice, Palette};
+use dns::ReverseDnsCache;
+use flowdump::FlowRecord;
+use geoip::GeoIpDb;
+#[cfg(feature = "pcap")]
+use interface::InterfaceNotFound;
+use interface::InterfaceSelector;
+use route::RouteChangeDetector;
+use scan::ScanDetector;
+use worker::WorkerPool;
+#[cfg(feature = "pcap")]
+use pcap::{Activated, Capture, Device};
+use pnet::datalink::MacAddr;
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::tcp::TcpPacket;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket};
 use pnet::packet::Packet;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(feature = "pcap")]
+use std::path::Path;
+use std::path::PathBuf;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use log::{info, warn};
-use prometheus::{Gauge, Registry, TextEncoder};
+use log::{debug, info, warn};
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, Opts, Registry, TextEncoder};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode};
 use std::convert::Infallible;
@@ -21,23 +54,296 @@ use std::convert::Infallible;
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// ネットワークインターフェース名
-    #[arg(short, long)]
-    interface: String,
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["interface_index", "interface_mac", "list_interfaces", "benchmark", "pcap_fifo", "pcap_stdin"]
+    )]
+    interface: Option<String>,
+    
+    /// インターフェースをインデックスで指定する（--interface と併用した場合はその名前が見つからないときに使う）
+    #[arg(long, value_name = "INDEX")]
+    interface_index: Option<u32>,
+    
+    /// インターフェースをMACアドレス（例: aa:bb:cc:dd:ee:ff）で指定する（名前・インデックスの次に試す）
+    #[arg(long, value_name = "MAC")]
+    interface_mac: Option<MacAddr>,
+    
+    /// 利用可能なインターフェースの一覧を表示して終了
+    #[arg(long)]
+    list_interfaces: bool,
+    
+    /// pcapファイルを最大速度で処理し、スループットとメモリ使用量をJSONで出力して終了
+    /// （--interface を指定するとそのインターフェースのローカル判定を使う）
+    #[arg(long, value_name = "PCAP")]
+    benchmark: Option<PathBuf>,
+    
+    /// ライブキャプチャの代わりに名前付きパイプからpcap形式のストリームを読み込む
+    /// （キャプチャ権限を持つサイドカーから受け取る場合など。--interface はローカル判定にのみ使う）
+    #[arg(long, value_name = "PATH", conflicts_with = "pcap_stdin")]
+    pcap_fifo: Option<PathBuf>,
+    
+    /// ライブキャプチャの代わりに標準入力からpcap形式のストリームを読み込む
+    #[arg(long)]
+    pcap_stdin: bool,
     
     /// 統計出力間隔（秒）
     #[arg(short, long, default_value = "1")]
     stats_interval: u64,
     
+    /// パケットレートに応じて統計出力間隔を変える（多いと短く、少ないと長くする）。--stats-interval は初期値になる
+    #[arg(long)]
+    adaptive_interval: bool,
+    
+    /// --adaptive-interval で短くする下限（ミリ秒）
+    #[arg(long, value_name = "MS", default_value = "250", value_parser = clap::value_parser!(u64).range(1..))]
+    min_stats_interval: u64,
+    
+    /// --adaptive-interval で長くする上限（秒）
+    #[arg(long, value_name = "SECONDS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    max_stats_interval: u64,
+    
+    /// 指定した秒数でキャプチャを終了し、最終サマリを表示する（0で無期限）
+    #[arg(long, default_value = "0")]
+    duration: u64,
+    
+    /// 指定したパケット数を処理したらキャプチャを終了し、最終サマリを表示する（--duration と併用した場合は先に達した方で終了）
+    #[arg(short = 'c', long, value_name = "PACKETS", value_parser = clap::value_parser!(u64).range(1..))]
+    count: Option<u64>,
+    
     /// 詳細なログを出力
     #[arg(short, long)]
     verbose: bool,
     
+    /// tracingでログを出力する（接続キー付きのspanで特定の接続に絞り込める。RUST_LOGでフィルタ指定可）
+    #[arg(long)]
+    tracing: bool,
+    
     /// Prometheusメトリクス用のHTTPポート
     #[arg(short, long, default_value = "59122")]
     prometheus_port: u16,
+    
+    /// グローバル通信の国・ASN判定に使うMaxMind形式のデータベース（.mmdb）
+    #[arg(long)]
+    geoip_db: Option<PathBuf>,
+    
+    /// 送信元IP別のメトリクスに逆引きしたホスト名の `hostname` ラベルを付ける
+    ///
+    /// 逆引きはバックグラウンドで行い、解決するまではラベルを空にする。
+    #[arg(long)]
+    resolve_dns: bool,
+    
+    /// コンソール出力の色付け（autoは標準出力が端末で `NO_COLOR` が未設定の場合のみ）
+    #[arg(long, value_enum, value_name = "WHEN", default_value = "auto")]
+    color: ColorChoice,
+    
+    /// コンソール出力に色を付けない（`--color never` と同じ）
+    #[arg(long, conflicts_with = "color")]
+    no_color: bool,
+    
+    /// 統計間隔ごとのコンソール表示を行わない（メトリクスの更新とログ出力は続ける）
+    #[arg(long)]
+    quiet: bool,
+    
+    /// スキャン検出のウィンドウ（秒）
+    #[arg(long, default_value = "10")]
+    scan_window: u64,
+    
+    /// ウィンドウ内で未完了のSYN宛先（IP・ポート）がこの数以上ならスキャンとみなす
+    #[arg(long, default_value = "100")]
+    scan_threshold: usize,
+    
+    /// 同じフローのパケット間でIPv4のTTLがこの数を超えて変わったら経路変化の疑いとして数える
+    #[arg(long, value_name = "HOPS", default_value = "2")]
+    ttl_change_threshold: u8,
+    
+    /// 欠損とみなすシーケンスギャップの上限（バイト）。未指定時は受信側のウィンドウサイズから決める
+    #[arg(long)]
+    max_plausible_gap: Option<u32>,
+    
+    /// ロス解析をN接続に1つに間引く（例: 1:10）。パケット数・バイト数は全パケットで集計する
+    #[arg(long, value_name = "1:N", default_value = "1:1", value_parser = parse_sample_ratio)]
+    sample: u32,
+    
+    /// このポートを送信元・宛先とするパケットを解析から除外する（複数指定可）
+    #[arg(long = "exclude-port", value_name = "PORT")]
+    exclude_ports: Vec<u16>,
+    
+    /// このIPアドレスを送信元・宛先とするパケットを解析から除外する（複数指定可）
+    #[arg(long = "exclude-ip", value_name = "IP")]
+    exclude_ips: Vec<IpAddr>,
+    
+    /// プライベートアドレスでもグローバルとして集計する範囲（複数指定可、VPNやオーバーレイ越しの通信など）
+    #[arg(long = "global-subnet", value_name = "CIDR")]
+    global_subnets: Vec<IpNetwork>,
+    
+    /// ローカルとして扱う範囲を追加する（複数指定可）。--global-subnet と重なる場合はプレフィックスの長い方を優先する
+    #[arg(long = "local-subnet", value_name = "CIDR")]
+    local_subnets: Vec<IpNetwork>,
+    
+    /// この2つのホスト間の通信（どちら向きも）だけを解析・表示し、それ以外のパケットは解析前に除外する
+    #[arg(long, num_args = 2, value_names = ["IP1", "IP2"])]
+    focus: Vec<IpAddr>,
+    
+    /// ライブキャプチャのバックエンド（afpacketはLinuxのみ。pcapストリームの読み込みには使えない）
+    #[cfg_attr(feature = "pcap", arg(long, value_enum, default_value = "pcap"))]
+    #[cfg_attr(not(feature = "pcap"), arg(long, value_enum, default_value = "afpacket"))]
+    engine: CaptureEngine,
+    
+    /// 1パケットあたりのキャプチャ長（バイト）。ロス検出にはヘッダのみで十分なため小さくできる
+    #[arg(long, default_value = "65536")]
+    snaplen: i32,
+    
+    /// カーネルのキャプチャバッファサイズ（バイト）。未指定時はlibpcapの既定値
+    #[arg(long)]
+    buffer_size: Option<i32>,
+    
+    /// ロス解析を行うワーカースレッド数（0でキャプチャスレッドが解析する）。キャプチャはヘッダの読み取りのみを行い、接続ごとに決まったワーカーへ渡す
+    #[arg(long, value_name = "N", default_value = "0")]
+    workers: usize,
+    
+    /// ワーカーごとのキューに溜められるパケット数（満杯の場合は捨てて worker_queue_dropped_total に数える）
+    #[arg(long, value_name = "PACKETS", default_value = "65536", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    worker_queue: usize,
+    
+    /// プロミスキャスモードを無効にし、このホスト宛て・発のトラフィックのみをキャプチャする
+    #[arg(long)]
+    no_promisc: bool,
+    
+    /// 最後のパケットからこの秒数以内の接続をアクティブとして数える
+    #[arg(long, value_name = "SECONDS", default_value = "10")]
+    active_window: u64,
+    
+    /// 同じ接続・種類の連続したロスイベントをこの時間（ミリ秒）内なら1つにまとめる（0でまとめない）
+    #[arg(long, value_name = "MS", default_value = "0")]
+    coalesce_window_ms: u64,
+    
+    /// 接続の向きごとに最初のこのパケット数の間はロスイベントを報告しない（0で無効。warmup_suppressed_total に数える）
+    #[arg(long, value_name = "N", default_value = "0")]
+    warmup_packets: u64,
+    
+    /// 欠損ギャップのヒストグラムのバケット（バイト、昇順のカンマ区切り）
+    #[arg(long, value_name = "BYTES", value_delimiter = ',', default_value = "1,5,10,50,100,500,1000,5000")]
+    gap_buckets: Vec<f64>,
+    
+    /// 全メトリクス名の先頭に付ける名前空間（例: edge1 で edge1_tcp_monitor_...）。複数インスタンスの併用時に使う
+    #[arg(long, value_name = "PREFIX", value_parser = parse_metric_prefix)]
+    metric_prefix: Option<String>,
+    
+    /// アクティブな接続の向きごとの状態を定期的に書き出すCSVのパス（ファイル名に時刻が入る。例: flows.csv → flows-20261014T120000Z.csv）
+    #[arg(long, value_name = "PATH")]
+    flow_dump: Option<PathBuf>,
+    
+    /// `--flow-dump` の書き出し間隔（秒）
+    #[arg(long, value_name = "SECONDS", default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    flow_dump_interval: u64,
+    
+    /// 引数の値を読み込むTOMLファイル（キーはフィールド名、例: stats_interval = 5）。コマンドラインの指定が優先される
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// `--config` のTOMLファイルの内容（キーは `Args` のフィールド名で、すべて省略できる）
+///
+/// 値はコマンドラインで指定したのと同じように `Args` で解釈するため、検証や既定値は共通になる。不明なキーはエラーにする。
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    interface: Option<String>,
+    interface_index: Option<u32>,
+    interface_mac: Option<String>,
+    list_interfaces: Option<bool>,
+    benchmark: Option<PathBuf>,
+    pcap_fifo: Option<PathBuf>,
+    pcap_stdin: Option<bool>,
+    stats_interval: Option<u64>,
+    adaptive_interval: Option<bool>,
+    min_stats_interval: Option<u64>,
+    max_stats_interval: Option<u64>,
+    duration: Option<u64>,
+    count: Option<u64>,
+    verbose: Option<bool>,
+    tracing: Option<bool>,
+    prometheus_port: Option<u16>,
+    geoip_db: Option<PathBuf>,
+    resolve_dns: Option<bool>,
+    color: Option<String>,
+    no_color: Option<bool>,
+    quiet: Option<bool>,
+    scan_window: Option<u64>,
+    scan_threshold: Option<usize>,
+    ttl_change_threshold: Option<u8>,
+    max_plausible_gap: Option<u32>,
+    sample: Option<String>,
+    exclude_ports: Option<Vec<u16>>,
+    exclude_ips: Option<Vec<String>>,
+    global_subnets: Option<Vec<String>>,
+    local_subnets: Option<Vec<String>>,
+    focus: Option<Vec<String>>,
+    engine: Option<String>,
+    snaplen: Option<i32>,
+    buffer_size: Option<i32>,
+    workers: Option<usize>,
+    worker_queue: Option<usize>,
+    no_promisc: Option<bool>,
+    active_window: Option<u64>,
+    coalesce_window_ms: Option<u64>,
+    warmup_packets: Option<u64>,
+    gap_buckets: Option<Vec<f64>>,
+    metric_prefix: Option<String>,
+    flow_dump: Option<PathBuf>,
+    flow_dump_interval: Option<u64>,
+}
+
+/// `--engine` で選ぶキャプチャのバックエンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CaptureEngine {
+    /// libpcap（BPFフィルタに対応。pcap機能を無効にしたビルドでは使えない）
+    Pcap,
+    /// AF_PACKETのTPACKET_V3リング（libpcapに依存しない）
+    Afpacket,
+}
+
+/// `--pcap-fifo` / `--pcap-stdin` で指定されたpcapストリームのパス（libpcapは "-" を標準入力として扱う）
+fn pcap_stream_path(args: &Args) -> Option<PathBuf> {
+    if args.pcap_stdin {
+        Some(PathBuf::from("-"))
+    } else {
+        args.pcap_fifo.clone()
+    }
+}
+
+/// `--gap-buckets` のバケットと `--metric-prefix` でメトリクスを作成する
+fn metrics_from_args(args: &Args) -> Result<PrometheusMetrics, String> {
+    PrometheusMetrics::new(&args.gap_buckets, args.metric_prefix.as_deref(), args.resolve_dns)
+        .map_err(|e| format!("--gap-buckets が不正です: {}", e))
+}
+
+/// `--metric-prefix` を検証する（末尾の `_` は区切りとして付け直すため取り除く）
+fn parse_metric_prefix(value: &str) -> Result<String, String> {
+    let prefix = value.trim_end_matches('_');
+    let mut chars = prefix.chars();
+    let valid = chars.next().is_none_or(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if valid {
+        Ok(prefix.to_string())
+    } else {
+        Err(format!("メトリクス名に使えない文字が含まれています: {}", value))
+    }
+}
+
+/// `--sample` の `1:N` 形式を解釈してNを返す
+fn parse_sample_ratio(value: &str) -> Result<u32, String> {
+    let n = value
+        .strip_prefix("1:")
+        .ok_or_else(|| format!("1:N の形式で指定してください: {}", value))?;
+    match n.parse::<u32>() {
+        Ok(n) if n >= 1 => Ok(n),
+        _ => Err(format!("Nには1以上の整数を指定してください: {}", value)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct TcpConnection {
     src_ip: String,
     dst_ip: String,
@@ -53,15 +359,82 @@ struct PacketLossEvent {
     received_seq: u32,
     gap_size: u32,
     loss_type: PacketLossType,
+    // まとめたイベントの数（`--coalesce-window-ms` を指定しない場合は常に1）
+    count: u32,
+    // まとめたイベントのギャップの範囲
+    min_gap_size: u32,
+    max_gap_size: u32,
+    // 最後にまとめたイベントの時刻
+    last_timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl PacketLossEvent {
+    fn new(
+        timestamp: DateTime<Utc>,
+        connection: TcpConnection,
+        expected_seq: u32,
+        received_seq: u32,
+        gap_size: u32,
+        loss_type: PacketLossType,
+    ) -> Self {
+        Self {
+            timestamp,
+            connection,
+            expected_seq,
+            received_seq,
+            gap_size,
+            loss_type,
+            count: 1,
+            min_gap_size: gap_size,
+            max_gap_size: gap_size,
+            last_timestamp: timestamp,
+        }
+    }
+    
+    /// 同じ種類で、最後にまとめたイベントから `window` 以内のイベントならこのイベントに加える
+    fn absorb(&mut self, event: &PacketLossEvent, window: Duration) -> bool {
+        let elapsed = (event.timestamp - self.last_timestamp).to_std().unwrap_or_default();
+        if event.loss_type != self.loss_type || elapsed > window {
+            return false;
+        }
+        self.count += event.count;
+        self.received_seq = event.received_seq;
+        self.min_gap_size = self.min_gap_size.min(event.min_gap_size);
+        self.max_gap_size = self.max_gap_size.max(event.max_gap_size);
+        self.last_timestamp = self.last_timestamp.max(event.last_timestamp);
+        true
+    }
+}
+
+/// ロスイベントを追加する（`window` が0でなければ、同じ接続の直前のイベントにまとめられる場合はまとめる）
+fn push_loss_event(events: &mut Vec<PacketLossEvent>, event: PacketLossEvent, window: Duration) {
+    if !window.is_zero() {
+        let previous = events.iter_mut().rev().find(|previous| previous.connection == event.connection);
+        if previous.is_some_and(|previous| previous.absorb(&event, window)) {
+            return;
+        }
+    }
+    events.push(event);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 enum PacketLossType {
     MissingSequence,    // シーケンス番号の欠損
     DuplicateSequence,  // 重複パケット（再送の可能性）
     OutOfOrder,         // 順序が乱れたパケット
 }
 
+impl PacketLossType {
+    /// 向き別ロスカウンタの `type` ラベル
+    fn label(&self) -> &'static str {
+        match self {
+            PacketLossType::MissingSequence => "missing",
+            PacketLossType::DuplicateSequence => "duplicate",
+            PacketLossType::OutOfOrder => "out_of_order",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct PrometheusMetrics {
     registry: Registry,
@@ -72,14 +445,112 @@ struct PrometheusMetrics {
     packet_loss_out_of_order_gauge: Gauge,
     window_shrink_gauge: Gauge,
     
+    // 直近 `LOSS_WINDOW_SECS` 秒の1秒あたりのロスイベント数（統計表示間隔ごとに更新）
+    loss_rate_1m_gauge: Gauge,
+    
+    // 検出時に加算する単調増加カウンタ（統計表示間隔のリセットとは独立）
+    packet_loss_missing_counter: Counter,
+    packet_loss_duplicate_counter: Counter,
+    packet_loss_out_of_order_counter: Counter,
+    window_shrink_counter: Counter,
+    
+    // 受信側の重複ACK数と、高速再送のきっかけになる3回目の重複ACK数
+    dup_acks_counter: Counter,
+    fast_retransmit_triggers_counter: Counter,
+    
+    // 逆方向で観測した最大のシーケンス番号を超えるACKの数（なりすまし・計測の不具合の兆候）
+    invalid_acks_counter: Counter,
+    
+    // アイドル状態の接続へのキープアライブ（重複・順序違いとは数えない）
+    keepalives_counter: Counter,
+    
+    // 接続の観測開始直後（`--warmup-packets`）のため報告しなかったロスイベント数
+    warmup_suppressed_counter: Counter,
+    
+    // データ送信側がリモート（受信経路）かローカル（送信経路）かで分けたロスイベント数
+    packet_loss_inbound_counter: CounterVec,
+    packet_loss_outbound_counter: CounterVec,
+    
+    // サービス側のポート別のロスイベント数（エフェメラルポート同士の接続は `other`）
+    packet_loss_port_counter: CounterVec,
+    
     // その他のゲージメトリクス
     active_connections_gauge: Gauge,
     current_window_size_gauge: Gauge,
+    
+    // 国・ASN別のグローバル通信バイト数（GeoIPデータベース指定時のみ）
+    global_connection_bytes_counter: CounterVec,
+    
+    // スキャンの疑いがある送信元（値は未完了のSYN宛先数）
+    scan_suspected_sources_gauge: GaugeVec,
+    
+    // TCP解析をスキップしたIPv4フラグメント数
+    ipv4_fragments_skipped_counter: Counter,
+    
+    // IPv4・TCPのヘッダが欠けているためTCP解析をスキップしたパケット数
+    truncated_packets_counter: Counter,
+    
+    // ECNによる輻輳通知（IPヘッダのCEマークと、SYN以外のTCPのECE/CWRフラグ）
+    ecn_ce_marked_counter: Counter,
+    tcp_ece_counter: Counter,
+    tcp_cwr_counter: Counter,
+    
+    // 同じフローでTTLがしきい値を超えて変わった回数（経路変化・なりすましの疑い）
+    route_change_suspected_counter: Counter,
+    
+    // キャプチャエラー後にインターフェースを開き直した回数
+    capture_reconnects_counter: Counter,
+    
+    // ワーカーのキューが満杯で解析せずに捨てたパケット数
+    worker_queue_dropped_counter: Counter,
+    
+    // FIN/RSTまたはアイドルで終了した接続の継続時間
+    connection_duration_histogram: Histogram,
+    
+    // アイドルで削除した接続状態の数と、削除後に残っている接続状態の数
+    connections_reaped_counter: Counter,
+    connection_map_size_gauge: Gauge,
+    
+    // 片方向のみFINを観測した接続の数
+    half_closed_gauge: Gauge,
+    
+    // 順序違いのパケットが期待位置から何セグメント遅れていたか
+    reorder_distance_histogram: Histogram,
+    
+    // 欠損と確定したシーケンスギャップの大きさ（バイト）
+    packet_loss_gap_histogram: Histogram,
+    
+    // ACKが進んだ時点で送信側から見た未確認のバイト数（実効的な輻輳ウィンドウの推定）
+    inflight_bytes_histogram: Histogram,
+    
+    // 統計間隔ごとの、接続の向きの `BURST_BUCKET` あたりの最大ペイロードバイト数
+    connection_burst_bytes_histogram: Histogram,
+    
+    // 最後のデータセグメントから、それを確認するACKまでの時間
+    ack_delay_histogram: Histogram,
+    
+    // 統計間隔ごとの、接続の向きの帯域幅遅延積（平均スループット×ハンドシェイクの往復時間）
+    bdp_bytes_histogram: Histogram,
+    
+    // 統計間隔内のロスイベント数を、接続のリモート側の/24ネットワーク別に合計したもの
+    path_loss_gauge: GaugeVec,
+    // 同じ間隔内にロスのあった接続の数（ひとつの接続だけのロスと、多くの接続に共通するロスを区別する）
+    path_loss_connections_gauge: GaugeVec,
+    
+    // 起動時刻（UNIX秒）と起動からの経過秒数
+    start_time_gauge: Gauge,
+    uptime_gauge: Gauge,
 }
 
 impl PrometheusMetrics {
-    fn new() -> Result<Self, prometheus::Error> {
-        let registry = Registry::new();
+    /// 欠損ギャップのヒストグラムを `gap_buckets` で作成する（昇順でない場合はエラー）
+    /// `metric_prefix` を指定すると全メトリクス名の先頭に `<prefix>_` を付ける（空文字列は付けない）
+    /// `hostname_label` を指定すると送信元IP別の系列に逆引きした `hostname` ラベルを付ける（`--resolve-dns`）
+    fn new(gap_buckets: &[f64], metric_prefix: Option<&str>, hostname_label: bool) -> Result<Self, prometheus::Error> {
+        let registry = match metric_prefix.filter(|prefix| !prefix.is_empty()) {
+            Some(prefix) => Registry::new_custom(Some(prefix.to_string()), None)?,
+            None => Registry::new(),
+        };
         
         let global_tcp_packets_gauge = Gauge::new(
             "tcp_monitor_global_tcp_packets_per_second",
@@ -106,9 +577,83 @@ impl PrometheusMetrics {
             "Number of TCP window shrink events per second"
         )?;
         
+        let loss_rate_1m_gauge = Gauge::new(
+            "tcp_monitor_loss_rate_1m",
+            "Packet loss events per second averaged over the last 60 seconds"
+        )?;
+        
+        let packet_loss_missing_counter = Counter::new(
+            "tcp_monitor_packet_loss_missing_total",
+            "Total number of missing sequence packet loss events"
+        )?;
+        
+        let packet_loss_duplicate_counter = Counter::new(
+            "tcp_monitor_packet_loss_duplicate_total",
+            "Total number of duplicate packet loss events"
+        )?;
+        
+        let packet_loss_out_of_order_counter = Counter::new(
+            "tcp_monitor_packet_loss_out_of_order_total",
+            "Total number of out-of-order packet loss events"
+        )?;
+        
+        let window_shrink_counter = Counter::new(
+            "tcp_monitor_window_shrink_total",
+            "Total number of TCP window shrink events"
+        )?;
+        
+        let dup_acks_counter = Counter::new(
+            "tcp_monitor_dup_acks_total",
+            "Number of duplicate pure ACKs observed from receivers"
+        )?;
+        
+        let fast_retransmit_triggers_counter = Counter::new(
+            "tcp_monitor_fast_retransmit_triggers_total",
+            "Number of times a receiver sent the third duplicate ACK that triggers fast retransmit"
+        )?;
+        
+        let invalid_acks_counter = Counter::new(
+            "tcp_monitor_invalid_acks_total",
+            "Number of ACKs acknowledging data beyond the highest sequence number observed from the peer"
+        )?;
+        
+        let keepalives_counter = Counter::new(
+            "tcp_monitor_keepalives_total",
+            "Number of TCP keepalive probes observed on idle connections"
+        )?;
+        
+        let warmup_suppressed_counter = Counter::new(
+            "tcp_monitor_warmup_suppressed_total",
+            "Number of loss events not reported because the connection direction was within its first --warmup-packets packets"
+        )?;
+        
+        let packet_loss_inbound_counter = CounterVec::new(
+            Opts::new(
+                "tcp_monitor_packet_loss_inbound_total",
+                "Packet loss events on data sent by a remote host, by loss type"
+            ),
+            &["type"]
+        )?;
+        
+        let packet_loss_outbound_counter = CounterVec::new(
+            Opts::new(
+                "tcp_monitor_packet_loss_outbound_total",
+                "Packet loss events on data sent by a local host, by loss type"
+            ),
+            &["type"]
+        )?;
+        
+        let packet_loss_port_counter = CounterVec::new(
+            Opts::new(
+                "tcp_monitor_packet_loss_total",
+                "Packet loss events by the server-side port of the connection"
+            ),
+            &["port"]
+        )?;
+        
         let active_connections_gauge = Gauge::new(
             "tcp_monitor_active_connections",
-            "Number of active TCP connections"
+            "Number of TCP connections seen within the activity window"
         )?;
         
         let current_window_size_gauge = Gauge::new(
@@ -116,14 +661,205 @@ impl PrometheusMetrics {
             "Current TCP window size"
         )?;
         
+        let global_connection_bytes_counter = CounterVec::new(
+            Opts::new(
+                "global_connection_bytes_total",
+                "Bytes of global TCP traffic by source country and ASN"
+            ),
+            &["country", "asn"]
+        )?;
+        
+        let scan_suspected_sources_gauge = GaugeVec::new(
+            Opts::new(
+                "scan_suspected_sources",
+                "Distinct unanswered SYN targets per source IP exceeding the scan threshold"
+            ),
+            if hostname_label { &["source_ip", "hostname"] } else { &["source_ip"] }
+        )?;
+        
+        let ipv4_fragments_skipped_counter = Counter::new(
+            "ipv4_fragments_skipped_total",
+            "Number of IPv4 fragments skipped before TCP analysis"
+        )?;
+        
+        let truncated_packets_counter = Counter::new(
+            "truncated_packets_total",
+            "Number of TCP packets skipped because the captured bytes do not hold complete, consistent IPv4 and TCP headers"
+        )?;
+        
+        let ecn_ce_marked_counter = Counter::new(
+            "ecn_ce_marked_total",
+            "Number of TCP packets whose IPv4 ECN field carries the Congestion Experienced codepoint"
+        )?;
+        
+        let tcp_ece_counter = Counter::new(
+            "tcp_ece_total",
+            "Number of non-SYN TCP segments with the ECE flag set"
+        )?;
+        
+        let tcp_cwr_counter = Counter::new(
+            "tcp_cwr_total",
+            "Number of non-SYN TCP segments with the CWR flag set"
+        )?;
+        
+        let route_change_suspected_counter = Counter::new(
+            "route_change_suspected_total",
+            "Number of times the IPv4 TTL of a flow shifted by more than the threshold between packets"
+        )?;
+        
+        let capture_reconnects_counter = Counter::new(
+            "capture_reconnects_total",
+            "Number of times the capture interface was reopened after a capture error"
+        )?;
+        
+        let worker_queue_dropped_counter = Counter::new(
+            "worker_queue_dropped_total",
+            "Number of captured packets dropped without analysis because the worker queue was full"
+        )?;
+        
+        let connections_reaped_counter = Counter::new(
+            "tcp_monitor_connections_reaped_total",
+            "Number of idle TCP connection states removed by the periodic cleanup"
+        )?;
+        
+        let connection_map_size_gauge = Gauge::new(
+            "tcp_monitor_connection_map_size",
+            "Number of TCP connection states held after the last periodic cleanup"
+        )?;
+        
+        let half_closed_gauge = Gauge::new(
+            "tcp_monitor_half_closed",
+            "Number of TCP connections with a FIN seen in only one direction after the last periodic cleanup"
+        )?;
+        
+        let connection_duration_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_connection_duration_seconds",
+                "Lifetime of TCP connections ended by FIN/RST or idle eviction"
+            )
+            .buckets(CONNECTION_DURATION_BUCKETS.to_vec())
+        )?;
+        
+        let reorder_distance_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_monitor_reorder_distance",
+                "Distance in segments between the expected and received sequence of out-of-order packets"
+            )
+            .buckets(REORDER_DISTANCE_BUCKETS.to_vec())
+        )?;
+        
+        let inflight_bytes_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_monitor_inflight_bytes",
+                "Bytes sent but not yet acknowledged, sampled whenever the receiver's ACK advances"
+            )
+            .buckets(INFLIGHT_BYTES_BUCKETS.to_vec())
+        )?;
+        
+        let connection_burst_bytes_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_monitor_connection_burst_bytes",
+                "Largest number of payload bytes a connection direction sent within 100ms, observed once per stats interval"
+            )
+            .buckets(BURST_BYTES_BUCKETS.to_vec())
+        )?;
+        
+        let ack_delay_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_monitor_ack_delay_seconds",
+                "Receiver delay between the last data segment of a connection direction and the first ACK covering it, excluding the handshake RTT from the capture point to the receiver"
+            )
+            .buckets(ACK_DELAY_BUCKETS.to_vec())
+        )?;
+        
+        // 未確認のバイト数やウィンドウと比べられるよう、同じバケットを使う
+        let bdp_bytes_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_monitor_bdp_bytes",
+                "Bandwidth-delay product of connection directions (throughput over the stats interval times the handshake RTT), observed once per stats interval"
+            )
+            .buckets(INFLIGHT_BYTES_BUCKETS.to_vec())
+        )?;
+        
+        let path_loss_gauge = GaugeVec::new(
+            Opts::new(
+                "tcp_monitor_path_loss",
+                "Loss events within the stats interval summed over connections by the remote /24 network"
+            ),
+            &["remote_net"]
+        )?;
+        
+        let path_loss_connections_gauge = GaugeVec::new(
+            Opts::new(
+                "tcp_monitor_path_loss_connections",
+                "Distinct connections with loss events within the stats interval by the remote /24 network"
+            ),
+            &["remote_net"]
+        )?;
+        
+        let packet_loss_gap_histogram = Histogram::with_opts(
+            HistogramOpts::new(
+                "tcp_monitor_packet_loss_gap",
+                "Size in bytes of sequence gaps confirmed as packet loss"
+            )
+            .buckets(gap_buckets.to_vec())
+        )?;
+        
+        let start_time_gauge = Gauge::new(
+            "tcp_monitor_start_time_seconds",
+            "Start time of the monitor since unix epoch in seconds"
+        )?;
+        
+        let uptime_gauge = Gauge::new(
+            "tcp_monitor_uptime_seconds",
+            "Seconds elapsed since the monitor started"
+        )?;
+        
         // メトリクスを登録
         registry.register(Box::new(global_tcp_packets_gauge.clone()))?;
         registry.register(Box::new(packet_loss_missing_gauge.clone()))?;
         registry.register(Box::new(packet_loss_duplicate_gauge.clone()))?;
         registry.register(Box::new(packet_loss_out_of_order_gauge.clone()))?;
         registry.register(Box::new(window_shrink_gauge.clone()))?;
+        registry.register(Box::new(loss_rate_1m_gauge.clone()))?;
+        registry.register(Box::new(packet_loss_missing_counter.clone()))?;
+        registry.register(Box::new(packet_loss_duplicate_counter.clone()))?;
+        registry.register(Box::new(packet_loss_out_of_order_counter.clone()))?;
+        registry.register(Box::new(window_shrink_counter.clone()))?;
+        registry.register(Box::new(dup_acks_counter.clone()))?;
+        registry.register(Box::new(fast_retransmit_triggers_counter.clone()))?;
+        registry.register(Box::new(invalid_acks_counter.clone()))?;
+        registry.register(Box::new(keepalives_counter.clone()))?;
+        registry.register(Box::new(warmup_suppressed_counter.clone()))?;
+        registry.register(Box::new(packet_loss_inbound_counter.clone()))?;
+        registry.register(Box::new(packet_loss_outbound_counter.clone()))?;
+        registry.register(Box::new(packet_loss_port_counter.clone()))?;
         registry.register(Box::new(active_connections_gauge.clone()))?;
         registry.register(Box::new(current_window_size_gauge.clone()))?;
+        registry.register(Box::new(global_connection_bytes_counter.clone()))?;
+        registry.register(Box::new(scan_suspected_sources_gauge.clone()))?;
+        registry.register(Box::new(ipv4_fragments_skipped_counter.clone()))?;
+        registry.register(Box::new(truncated_packets_counter.clone()))?;
+        registry.register(Box::new(ecn_ce_marked_counter.clone()))?;
+        registry.register(Box::new(tcp_ece_counter.clone()))?;
+        registry.register(Box::new(tcp_cwr_counter.clone()))?;
+        registry.register(Box::new(route_change_suspected_counter.clone()))?;
+        registry.register(Box::new(capture_reconnects_counter.clone()))?;
+        registry.register(Box::new(worker_queue_dropped_counter.clone()))?;
+        registry.register(Box::new(connection_duration_histogram.clone()))?;
+        registry.register(Box::new(connections_reaped_counter.clone()))?;
+        registry.register(Box::new(connection_map_size_gauge.clone()))?;
+        registry.register(Box::new(half_closed_gauge.clone()))?;
+        registry.register(Box::new(reorder_distance_histogram.clone()))?;
+        registry.register(Box::new(packet_loss_gap_histogram.clone()))?;
+        registry.register(Box::new(inflight_bytes_histogram.clone()))?;
+        registry.register(Box::new(connection_burst_bytes_histogram.clone()))?;
+        registry.register(Box::new(ack_delay_histogram.clone()))?;
+        registry.register(Box::new(bdp_bytes_histogram.clone()))?;
+        registry.register(Box::new(path_loss_gauge.clone()))?;
+        registry.register(Box::new(path_loss_connections_gauge.clone()))?;
+        registry.register(Box::new(start_time_gauge.clone()))?;
+        registry.register(Box::new(uptime_gauge.clone()))?;
         
         Ok(PrometheusMetrics {
             registry,
@@ -132,10 +868,59 @@ impl PrometheusMetrics {
             packet_loss_duplicate_gauge,
             packet_loss_out_of_order_gauge,
             window_shrink_gauge,
+            loss_rate_1m_gauge,
+            packet_loss_missing_counter,
+            packet_loss_duplicate_counter,
+            packet_loss_out_of_order_counter,
+            window_shrink_counter,
+            dup_acks_counter,
+            fast_retransmit_triggers_counter,
+            invalid_acks_counter,
+            keepalives_counter,
+            warmup_suppressed_counter,
+            packet_loss_inbound_counter,
+            packet_loss_outbound_counter,
+            packet_loss_port_counter,
             active_connections_gauge,
             current_window_size_gauge,
+            global_connection_bytes_counter,
+            scan_suspected_sources_gauge,
+            ipv4_fragments_skipped_counter,
+            truncated_packets_counter,
+            ecn_ce_marked_counter,
+            tcp_ece_counter,
+            tcp_cwr_counter,
+            route_change_suspected_counter,
+            capture_reconnects_counter,
+            worker_queue_dropped_counter,
+            connection_duration_histogram,
+            connections_reaped_counter,
+            connection_map_size_gauge,
+            half_closed_gauge,
+            reorder_distance_histogram,
+            packet_loss_gap_histogram,
+            inflight_bytes_histogram,
+            connection_burst_bytes_histogram,
+            ack_delay_histogram,
+            bdp_bytes_histogram,
+            path_loss_gauge,
+            path_loss_connections_gauge,
+            start_time_gauge,
+            uptime_gauge,
         })
     }
+    
+    /// ロスイベントをサービス側のポート別と、接続のデータ送信側の向きで加算する（向きが未判定の接続は向き別に数えない）
+    fn inc_loss_breakdown(&self, connection: &TcpConnection, local_sender: Option<bool>, loss_type: &PacketLossType) {
+        self.packet_loss_port_counter.with_label_values(&[&service_port_label(connection)]).inc();
+        
+        let counter = match local_sender {
+            Some(true) => &self.packet_loss_outbound_counter,
+            Some(false) => &self.packet_loss_inbound_counter,
+            None => return,
+        };
+        counter.with_label_values(&[loss_type.label()]).inc();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -144,11 +929,186 @@ struct ConnectionState {
     last_ack: u32,
     expected_seq: u32,
     packet_count: u64,
+    // この向きで送られたペイロードのバイト数
+    byte_count: u64,
+    // 統計間隔内にこの向きで送られたペイロードのバイト数（帯域幅遅延積の計算用）
+    interval_bytes: u64,
     loss_events: Vec<PacketLossEvent>,
     out_of_order_count: u32,
     duplicate_count: u32,
     last_seen: DateTime<Utc>,
-    last_window_size: u16,
+    // 直前のウィンドウサイズ（ウィンドウスケール適用後）
+    last_window_size: u32,
+    // この向きのパケットで広告された最大ウィンドウサイズ（逆方向のデータ量の上限になる）
+    max_window_size: u32,
+    // この向きで観測した最大のペイロード長（順序違いの距離をセグメント数に換算する）
+    max_segment_len: u32,
+    // この向きのSYNで広告されたMSS（逆方向から送られるセグメントの上限）
+    mss: Option<u16>,
+    // この向きのSYNで広告されたウィンドウスケール（両方向のSYNに含まれる場合のみ有効）
+    window_scale: Option<u8>,
+    // 受信側（逆方向）の純粋なACKで到達が確認されたシーケンス番号
+    acked_seq: Option<u32>,
+    // acked_seqに対する重複ACKの連続回数
+    duplicate_ack_count: u32,
+    // 受信側のACKで到達も欠損もまだ確認できていないギャップ
+    pending_gaps: Vec<PacketLossEvent>,
+    // 最後に送られた新しいデータの末尾のシーケンスと観測時刻（ACKまでの遅延の計測用、再送でクリアする）
+    unacked_data: Option<(u32, Instant)>,
+    // この向きの送信元がローカルネットワークか（向き別のロス集計用、最初のパケットの処理後に判定）
+    local_sender: Option<bool>,
+    // 統計間隔内の微小バーストの計測
+    burst: BurstMeter,
+}
+
+/// 微小バーストを数える時間の区切り（秒単位のメトリクスでは平均されて見えなくなる長さ）
+const BURST_BUCKET: Duration = Duration::from_millis(100);
+
+/// バーストのヒストグラムのバケット（バイト）。1セグメントから100ミリ秒で約1Gbpsを超える量まで
+const BURST_BYTES_BUCKETS: &[f64] = &[
+    1460.0, 14600.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+/// 接続の向きごとに `BURST_BUCKET` 内のバイト数・パケット数を数え、統計間隔内の最大を保持する
+///
+/// 区切りは間隔内の最初のパケットから始まり、`BURST_BUCKET` が過ぎた後の最初のパケットで次の区切りに移る。
+#[derive(Debug, Clone)]
+struct BurstMeter {
+    bucket_start: Instant,
+    bucket_bytes: u64,
+    bucket_packets: u64,
+    // 統計間隔内で最大だった区切りのバイト数とパケット数
+    max_bytes: u64,
+    max_packets: u64,
+}
+
+impl BurstMeter {
+    fn new(now: Instant) -> Self {
+        Self {
+            bucket_start: now,
+            bucket_bytes: 0,
+            bucket_packets: 0,
+            max_bytes: 0,
+            max_packets: 0,
+        }
+    }
+    
+    fn record(&mut self, now: Instant, payload_len: u32) {
+        if now.duration_since(self.bucket_start) >= BURST_BUCKET {
+            self.close_bucket();
+            self.bucket_start = now;
+        }
+        self.bucket_bytes += payload_len as u64;
+        self.bucket_packets += 1;
+    }
+    
+    fn close_bucket(&mut self) {
+        if self.bucket_bytes > self.max_bytes {
+            self.max_bytes = self.bucket_bytes;
+            self.max_packets = self.bucket_packets;
+        }
+        self.bucket_bytes = 0;
+        self.bucket_packets = 0;
+    }
+    
+    /// 統計間隔内の最大のバースト（バイト数, パケット数）を返して次の間隔の計測を始める（データがなければNone）
+    fn take_interval_max(&mut self, now: Instant) -> Option<(u64, u64)> {
+        self.close_bucket();
+        self.bucket_start = now;
+        let max = (self.max_bytes > 0).then_some((self.max_bytes, self.max_packets));
+        self.max_bytes = 0;
+        self.max_packets = 0;
+        max
+    }
+}
+
+/// 1つの接続の両方向の状態（`TcpConnection::canonical_key` で1つにまとめる）
+#[derive(Debug, Clone)]
+struct ConnectionEntry {
+    // 正規化したキーの向き（端点の小さい側から送られたパケット）の状態
+    forward: Option<ConnectionState>,
+    // 逆方向の状態
+    reverse: Option<ConnectionState>,
+    // 最初のパケットを観測した時刻（継続時間ヒストグラム用）
+    created_at: Instant,
+    // FIN/RSTによる終了の段階（Open以外は継続時間を記録済み）
+    phase: ConnectionPhase,
+    // ハンドシェイクから求める往復時間
+    handshake: HandshakeRtt,
+}
+
+/// ハンドシェイクから求める往復時間の計測の段階
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeRtt {
+    /// 計測できない（SYNを観測していない、またはSYN・SYN-ACKが再送された）
+    Unknown,
+    /// クライアントのSYNを観測した（`client_forward` はSYNを送った向き）
+    SynSent { client_forward: bool, syn_at: Instant },
+    /// サーバーのSYN-ACKを観測し、ハンドシェイクを完了するACKを待っている
+    SynAckSeen { client_forward: bool, syn_at: Instant, syn_ack_at: Instant },
+    /// モニタからサーバー（SYNからSYN-ACKまで）とクライアント（SYN-ACKからACKまで）への往復時間
+    Measured { client_forward: bool, server_rtt: Duration, client_rtt: Duration },
+}
+
+impl HandshakeRtt {
+    /// クライアントとサーバーの間の往復時間
+    fn rtt(&self) -> Option<Duration> {
+        match *self {
+            HandshakeRtt::Measured { server_rtt, client_rtt, .. } => Some(server_rtt + client_rtt),
+            _ => None,
+        }
+    }
+    
+    /// モニタから、`forward` の向きにパケットを送る端点までの往復時間
+    fn rtt_to_sender(&self, forward: bool) -> Option<Duration> {
+        match *self {
+            HandshakeRtt::Measured { client_forward, server_rtt, client_rtt } => {
+                Some(if forward == client_forward { client_rtt } else { server_rtt })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// FIN/RSTによる接続の終了の段階
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionPhase {
+    Open,
+    /// 片方の向きのみFINを観測した（`fin_forward` はFINを送った向き）
+    Closing { fin_forward: bool },
+    /// 両方向のFIN、またはRSTを観測した
+    Closed,
+}
+
+impl ConnectionEntry {
+    fn new(created_at: Instant) -> Self {
+        Self {
+            forward: None,
+            reverse: None,
+            created_at,
+            phase: ConnectionPhase::Open,
+            handshake: HandshakeRtt::Unknown,
+        }
+    }
+    
+    /// 指定した向きの状態（その向きのパケットをまだ観測していない場合はNone）
+    fn direction(&self, forward: bool) -> Option<&ConnectionState> {
+        if forward { self.forward.as_ref() } else { self.reverse.as_ref() }
+    }
+    
+    fn direction_slot(&mut self, forward: bool) -> &mut Option<ConnectionState> {
+        if forward { &mut self.forward } else { &mut self.reverse }
+    }
+    
+    /// どちらかの向きで最後にパケットを観測した時刻
+    fn last_seen(&self) -> DateTime<Utc> {
+        [&self.forward, &self.reverse]
+            .into_iter()
+            .flatten()
+            .map(|state| state.last_seen)
+            .max()
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
 }
 
 #[derive(Debug)]
@@ -156,7 +1116,8 @@ struct GlobalStats {
     total_packets: u64,
     tcp_packets: u64,
     global_tcp_packets: u64,
-    connection_states: HashMap<String, ConnectionState>,
+    // 正規化したキーごとの接続状態（両方向で1エントリ）
+    connection_states: HashMap<String, ConnectionEntry>,
     packet_loss_events: Vec<PacketLossEvent>,
     window_shrink_events: u32,
     start_time: Instant,
@@ -169,16 +1130,114 @@ struct GlobalStats {
     packet_loss_duplicate_per_second: u32,
     packet_loss_out_of_order_per_second: u32,
     window_shrink_per_second: u32,
+
+    // 時刻の取得元（テストではモッククロックを注入する）
+    clock: Arc<dyn Clock>,
+    
+    // グローバル通信の国・ASN判定用データベース（任意、ワーカーのシャードと共有する）
+    geoip: Option<Arc<GeoIpDb>>,
+    
+    // SYNスキャンの検出器
+    scan_detector: ScanDetector,
+    
+    // TTLの変化による経路変化の検出器
+    route_detector: RouteChangeDetector,
+    
+    // 欠損とみなすシーケンスギャップの上限（Noneの場合は観測したウィンドウサイズから決める）
+    max_plausible_gap: Option<u32>,
+    
+    // ロス解析を行う接続の割合（N接続に1つ、1で全接続）
+    sample_rate: u32,
+    
+    // 解析前に除外するIPアドレス・ポート
+    exclusions: PacketExclusions,
+    
+    // ローカル・グローバルの判定を上書きする範囲
+    subnet_overrides: SubnetOverrides,
+    
+    // 監視するインターフェースのIPv4ネットワーク（起動時に1回だけ取得する）
+    interface_network: Option<Ipv4Network>,
+    
+    // 最後のパケットからこの時間内の接続をアクティブとして数える
+    active_window: Duration,
+    
+    // 表示用のホスト名の逆引きキャッシュ（`--resolve-dns` を指定した場合のみ）
+    reverse_dns: Option<ReverseDnsCache>,
+    
+    // コンソール出力の色付け（`--color`）
+    palette: Palette,
+    
+    // 統計間隔ごとのコンソール表示を行わない（`--quiet`）
+    quiet: bool,
+    
+    // 統計表示ごとのロスイベント数を1秒単位で保持する直近1分のリング
+    loss_window: LossWindow,
+    
+    // 同じ接続・種類の連続したロスイベントを1つにまとめる時間（0でまとめない）
+    coalesce_window: Duration,
+    
+    // 接続の向きごとにロスイベントを報告しない最初のパケット数（0で無効）
+    warmup_packets: u64,
+    
+    // `--workers` のワーカーごとの統計（接続の状態を分け持ち、統計間隔ごとにここへ集約する）
+    worker_shards: Vec<Arc<Mutex<GlobalStats>>>,
 }
 
 impl Default for GlobalStats {
     fn default() -> Self {
-        let now = Instant::now();
-        let prometheus_metrics = PrometheusMetrics::new().expect("Failed to create Prometheus metrics");
-        
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl GlobalStats {
+    /// 接続のこの向きの状態
+    fn direction_state_mut(&mut self, connection: &TcpConnection) -> Option<&mut ConnectionState> {
+        self.connection_states
+            .get_mut(&connection.canonical_key())?
+            .direction_slot(connection.is_forward())
+            .as_mut()
+    }
+    
+    /// 指定したクロックを使う統計情報を作成
+    fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let prometheus_metrics = PrometheusMetrics::new(DEFAULT_GAP_BUCKETS, None, false).expect("Failed to create Prometheus metrics");
+        Self::with_metrics(clock, prometheus_metrics)
+    }
+    
+    /// 指定したクロックとメトリクスを使う統計情報を作成
+    fn with_metrics(clock: Arc<dyn Clock>, prometheus_metrics: PrometheusMetrics) -> Self {
+        prometheus_metrics.start_time_gauge.set(clock.now_utc().timestamp_millis() as f64 / 1000.0);
+        Self::empty(clock, prometheus_metrics)
+    }
+    
+    /// `--workers` のワーカー1つ分の統計を作成
+    ///
+    /// 解析の設定・クロック・メトリクスは共有し、接続の状態とカウンタは空から始める。
+    /// 検出器はこの時点では空のため、設定ごと複製する。
+    fn worker_shard(&self) -> Self {
         Self {
-            total_packets: 0,
-            tcp_packets: 0,
+            start_time: self.start_time,
+            last_reset_time: self.last_reset_time,
+            geoip: self.geoip.clone(),
+            scan_detector: self.scan_detector.clone(),
+            route_detector: self.route_detector.clone(),
+            max_plausible_gap: self.max_plausible_gap,
+            sample_rate: self.sample_rate,
+            exclusions: self.exclusions.clone(),
+            subnet_overrides: self.subnet_overrides.clone(),
+            interface_network: self.interface_network,
+            active_window: self.active_window,
+            coalesce_window: self.coalesce_window,
+            warmup_packets: self.warmup_packets,
+            ..Self::empty(Arc::clone(&self.clock), self.prometheus_metrics.clone())
+        }
+    }
+    
+    fn empty(clock: Arc<dyn Clock>, prometheus_metrics: PrometheusMetrics) -> Self {
+        let now = clock.now_instant();
+        Self {
+            total_packets: 0,
+            tcp_packets: 0,
             global_tcp_packets: 0,
             connection_states: HashMap::new(),
             packet_loss_events: Vec::new(),
@@ -191,7 +1250,75 @@ impl Default for GlobalStats {
             packet_loss_duplicate_per_second: 0,
             packet_loss_out_of_order_per_second: 0,
             window_shrink_per_second: 0,
+            clock,
+            geoip: None,
+            scan_detector: ScanDetector::default(),
+            route_detector: RouteChangeDetector::default(),
+            max_plausible_gap: None,
+            sample_rate: 1,
+            exclusions: PacketExclusions::default(),
+            subnet_overrides: SubnetOverrides::default(),
+            interface_network: None,
+            active_window: DEFAULT_ACTIVE_WINDOW,
+            reverse_dns: None,
+            palette: Palette::default(),
+            quiet: false,
+            loss_window: LossWindow::default(),
+            coalesce_window: Duration::ZERO,
+            warmup_packets: 0,
+            worker_shards: Vec::new(),
+        }
+    }
+}
+
+/// 1回の統計間隔で検出されたイベント数
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct IntervalCounts {
+    missing: u32,
+    duplicate: u32,
+    out_of_order: u32,
+    window_shrink: u32,
+}
+
+impl IntervalCounts {
+    fn total_loss(&self) -> u32 {
+        self.missing + self.duplicate + self.out_of_order
+    }
+}
+
+/// ロスイベント数を起動からの経過秒ごとのバケットに分けて、直近 `LOSS_WINDOW_SECS` 秒分だけ保持するリング
+///
+/// 統計表示の間隔が1秒より長い場合も、その間のイベントは表示した時刻のバケットにまとめて入る。
+#[derive(Debug, Default)]
+struct LossWindow {
+    buckets: VecDeque<u64>,
+    // 最後のバケットの経過秒
+    current_second: u64,
+}
+
+impl LossWindow {
+    /// 経過秒 `second` のバケットに `losses` を加える（間の秒は0件のバケットとして進める）
+    fn record(&mut self, second: u64, losses: u64) {
+        let steps = second.saturating_sub(self.current_second).min(LOSS_WINDOW_SECS as u64);
+        for _ in 0..steps {
+            if self.buckets.len() == LOSS_WINDOW_SECS {
+                self.buckets.pop_front();
+            }
+            self.buckets.push_back(0);
+        }
+        self.current_second = self.current_second.max(second);
+        match self.buckets.back_mut() {
+            Some(bucket) => *bucket += losses,
+            None => self.buckets.push_back(losses),
+        }
+    }
+    
+    /// 保持している秒数で平均した1秒あたりのロスイベント数（起動直後は経過した秒数で割る）
+    fn rate(&self) -> f64 {
+        if self.buckets.is_empty() {
+            return 0.0;
         }
+        self.buckets.iter().sum::<u64>() as f64 / self.buckets.len() as f64
     }
 }
 
@@ -203,6 +1330,43 @@ impl TcpConnection {
     fn reverse_key(&self) -> String {
         format!("{}:{}-{}:{}", self.dst_ip, self.dst_port, self.src_ip, self.src_port)
     }
+    
+    /// 両方向で同じになるキー（端点を順序付け、小さい側を送信元として表す）
+    fn canonical_key(&self) -> String {
+        if self.is_forward() { self.key() } else { self.reverse_key() }
+    }
+    
+    /// この向きが正規化したキーの向き（送信元の端点の方が小さい）か
+    fn is_forward(&self) -> bool {
+        (&self.src_ip, self.src_port) <= (&self.dst_ip, self.dst_port)
+    }
+    
+    /// 逆方向の接続
+    fn reversed(&self) -> TcpConnection {
+        TcpConnection {
+            src_ip: self.dst_ip.clone(),
+            dst_ip: self.src_ip.clone(),
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+        }
+    }
+    
+    /// `--sample 1:N` でロス解析の対象にするかどうか
+    ///
+    /// パケット単位で間引くと解析対象の接続すべてにシーケンスの欠けが生じるため、
+    /// 接続（両方向）単位で決定的に1/Nを選ぶ。同じ接続の全パケットは常に同じ結果になる。
+    fn is_sampled(&self, sample_rate: u32) -> bool {
+        if sample_rate <= 1 {
+            return true;
+        }
+        
+        let forward = (self.src_ip.as_str(), self.src_port);
+        let backward = (self.dst_ip.as_str(), self.dst_port);
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        forward.min(backward).hash(&mut hasher);
+        forward.max(backward).hash(&mut hasher);
+        hasher.finish().is_multiple_of(sample_rate as u64)
+    }
 }
 
 /// IPアドレスがプライベート（ローカル）アドレスかどうかを判定
@@ -215,43 +1379,74 @@ fn is_private_ip(ip_str: &str) -> bool {
     }
 }
 
+/// `target` が `local` と同じネットワークセグメント（`netmask` で区切った範囲）にあるか
+///
+/// ネットワークアドレスとブロードキャストアドレスも範囲に含める。
+fn in_same_subnet(target: Ipv4Addr, local: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+    let netmask_u32 = u32::from(netmask);
+    u32::from(target) & netmask_u32 == u32::from(local) & netmask_u32
+}
+
 /// インターフェースの設定に基づいてIPアドレスがローカルネットワークかどうかを判定
-fn is_local_ip_with_interface(ip_str: &str, interface_name: &str) -> bool {
+fn is_local_ip_with_interface(ip_str: &str, interface_network: Option<Ipv4Network>, overrides: &SubnetOverrides) -> bool {
+    // 運用者が指定した範囲は組み込みの判定より優先する
+    if let Some(local) = ip_str.parse().ok().and_then(|ip| overrides.is_local(ip)) {
+        return local;
+    }
+    
     // まず基本的なプライベートアドレス判定
     if is_private_ip(ip_str) {
         return true;
     }
 
-    // インターフェース情報を取得してローカルネットワーク範囲を確認
-    if let Ok(devices) = Device::list() {
-        if let Some(device) = devices.into_iter().find(|d| d.name == interface_name) {
-            if let Some(addr) = device.addresses.iter().find(|a| a.addr.is_ipv4()) {
-                if let (std::net::IpAddr::V4(local_ip), Some(std::net::IpAddr::V4(netmask))) = (addr.addr, addr.netmask) {
-                    if let Ok(target_ip) = ip_str.parse::<Ipv4Addr>() {
-                        // ローカルネットワークの範囲を計算
-                        let local_ip_u32 = u32::from(local_ip);
-                        let netmask_u32 = u32::from(netmask);
-                        let network_u32 = local_ip_u32 & netmask_u32;
-                        
-                        let target_ip_u32 = u32::from(target_ip);
-                        let target_network_u32 = target_ip_u32 & netmask_u32;
-                        
-                        // 同じネットワークセグメントかどうか判定
-                        if network_u32 == target_network_u32 {
-                            return true;
-                        }
-                    }
-                }
-            }
-        }
+    // 起動時に取得したインターフェースのネットワーク範囲を確認
+    if let (Some(network), Ok(target_ip)) = (interface_network, ip_str.parse::<Ipv4Addr>()) {
+        return in_same_subnet(target_ip, network.ip(), network.mask());
     }
 
     false
 }
 
+/// `--global-subnet` / `--local-subnet` で指定された、ローカル・グローバルの判定を上書きする範囲
+#[derive(Debug, Clone, Default)]
+struct SubnetOverrides {
+    global: Vec<IpNetwork>,
+    local: Vec<IpNetwork>,
+}
+
+impl SubnetOverrides {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            global: args.global_subnets.clone(),
+            local: args.local_subnets.clone(),
+        }
+    }
+    
+    /// 指定された範囲に含まれる場合にローカルかどうかを返す（どちらにも含まれない場合はNone）
+    ///
+    /// 両方に含まれる場合は、`10.0.0.0/8` をグローバルにしつつ `10.1.0.0/16` をローカルに残せるよう
+    /// プレフィックスの長い方を優先し、同じ長さならローカルとする。
+    fn is_local(&self, ip: IpAddr) -> Option<bool> {
+        let longest = |networks: &[IpNetwork]| {
+            networks.iter().filter(|network| network.contains(ip)).map(|network| network.prefix()).max()
+        };
+        match (longest(&self.global), longest(&self.local)) {
+            (Some(global), Some(local)) => Some(local >= global),
+            (global, local) => local.map(|_| true).or(global.map(|_| false)),
+        }
+    }
+}
+
+/// IPv4アドレスがRFC 6598のキャリアグレードNAT共有アドレス（100.64.0.0/10）かどうかを判定
+fn is_shared_cgnat_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && (octets[1] & 0b1100_0000) == 0b0100_0000
+}
+
 /// IPv4アドレスがプライベートアドレスかどうかを判定
 fn is_private_ipv4(ip: Ipv4Addr) -> bool {
     ip.is_private()
+        || is_shared_cgnat_ipv4(ip)
         || ip.is_loopback()
         || ip.is_link_local()
         || ip.is_multicast()
@@ -259,9 +1454,9 @@ fn is_private_ipv4(ip: Ipv4Addr) -> bool {
         || ip.is_documentation()
 }
 
-/// 両方のIPアドレスがグローバルIPかどうかを判定（インターフェース情報を考慮）
-fn is_global_connection_with_interface(src_ip: &str, dst_ip: &str, interface_name: &str) -> bool {
-    !is_local_ip_with_interface(src_ip, interface_name) && !is_local_ip_with_interface(dst_ip, interface_name)
+/// 両方のIPアドレスがグローバルIPかどうかを判定（インターフェース情報と `--global-subnet` / `--local-subnet` を考慮）
+fn is_global_connection_with_interface(src_ip: &str, dst_ip: &str, interface_network: Option<Ipv4Network>, overrides: &SubnetOverrides) -> bool {
+    !is_local_ip_with_interface(src_ip, interface_network, overrides) && !is_local_ip_with_interface(dst_ip, interface_network, overrides)
 }
 
 /// 両方のIPアドレスがグローバルIPかどうかを判定（従来の方法）
@@ -269,138 +1464,802 @@ fn is_global_connection(src_ip: &str, dst_ip: &str) -> bool {
     !is_private_ip(src_ip) && !is_private_ip(dst_ip)
 }
 
+/// 欠損とみなすシーケンスギャップの既定の下限（バイト）
+///
+/// 送信側は受信側のウィンドウを超えて先行送信できないため、それより大きなギャップは
+/// 実際の欠損ではなく、途中から観測し始めた接続・シーケンスのリセット・破損した
+/// パケットなどによるものとみなして無視する。
+const DEFAULT_MAX_PLAUSIBLE_GAP: u32 = 1_000_000;
+
+/// 受信側のウィンドウの何倍までのギャップを欠損として扱うか
+const WINDOW_GAP_FACTOR: u32 = 2;
+
+/// `--max-plausible-gap` 未指定時のギャップ上限
+///
+/// 受信側が広告したウィンドウの `WINDOW_GAP_FACTOR` 倍とし、既定値を下回らないようにする。
+fn derived_max_plausible_gap(receiver_window: Option<u32>) -> u32 {
+    receiver_window
+        .map_or(0, |window| window.saturating_mul(WINDOW_GAP_FACTOR))
+        .max(DEFAULT_MAX_PLAUSIBLE_GAP)
+}
+
+/// 未確認バイト数のヒストグラムのバケット（バイト）。1セグメント程度からBDPの大きなリンクまで4倍刻み
+const INFLIGHT_BYTES_BUCKETS: &[f64] = &[
+    1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+/// ACK遅延のヒストグラムのバケット（秒）。LAN内の即時ACKから、遅延ACKのタイマー（40〜200ミリ秒）を超える遅延まで
+const ACK_DELAY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.005, 0.01, 0.02, 0.04, 0.1, 0.2, 0.5, 1.0];
+
+/// 最後に送られたデータの末尾までを `ack_num` が確認した場合に、受信側のACKの遅延を返す
+///
+/// モニタで見えるデータからACKまでの時間には、モニタから受信側までの往復（`path_rtt`、ハンドシェイクで計測）が含まれるため差し引く。
+/// 途中までのACKでは遅延ACKの挙動が分からないため計測せず、1つのデータにつき1回だけ計測する。
+fn take_ack_delay(data: &mut ConnectionState, ack_num: u32, now: Instant, path_rtt: Duration) -> Option<Duration> {
+    let (end, sent_at) = data.unacked_data?;
+    if seq_before(ack_num, end) {
+        return None;
+    }
+    data.unacked_data = None;
+    Some(now.saturating_duration_since(sent_at).saturating_sub(path_rtt))
+}
+
+/// 送信済みの最大シーケンス（`sent_end`）と受信側の最大ACKから未確認のバイト数を求める
+///
+/// 受信側がモニタの観測していないデータまでACKしている場合や、受信側のウィンドウから
+/// あり得ない大きさになる場合（途中から観測した接続など）はNoneとする。
+fn bytes_in_flight(sent_end: u32, acked: u32, receiver_window: u32) -> Option<u32> {
+    if seq_before(sent_end, acked) {
+        return None;
+    }
+    let inflight = sent_end.wrapping_sub(acked);
+    (inflight <= derived_max_plausible_gap(Some(receiver_window))).then_some(inflight)
+}
+
+/// シーケンス番号 `a` が `b` より後か（RFC 1982のシリアル番号演算で2^32の折り返しを考慮）
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// シーケンス番号 `a` が `b` より前か（RFC 1982のシリアル番号演算で2^32の折り返しを考慮）
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// セグメントが消費するシーケンス番号の長さ（SYNとFINはペイロードとは別にそれぞれ1つ消費する）
+///
+/// TCP Fast OpenのようにSYNにデータを載せる接続では、これを数えないと次のセグメントを1バイトの欠損と誤検出する。
+fn sequence_length(payload_len: u32, flags: u8) -> u32 {
+    let syn = (flags & TcpFlags::SYN != 0) as u32;
+    let fin = (flags & TcpFlags::FIN != 0) as u32;
+    payload_len.wrapping_add(syn + fin)
+}
+
+/// 最後のパケットからこの時間が経過した接続の状態を削除する
+const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 両方向のFINまたはRSTを観測した接続は、最後のパケットからこの時間で削除する
+///
+/// TIME_WAITと同じく、遅れて届いた最後のACKや再送されたFINで状態が作り直されない程度の長さにする。
+const CLOSED_LINGER: Duration = Duration::from_secs(5);
+
+/// 直前のパケットからこの時間以上空いたセグメントのみキープアライブとみなす
+const KEEPALIVE_MIN_IDLE: Duration = Duration::from_secs(1);
+
+/// キープアライブのプローブか（次に送るシーケンスの1つ前を指す、空または1バイトのセグメント）
+///
+/// 送信側は確認応答済みの最後の1バイトを再送するため、重複や順序違いと区別する。
+fn is_keepalive_probe(seq_num: u32, payload_len: u32, expected_seq: u32, idle: Duration) -> bool {
+    payload_len <= 1 && seq_num == expected_seq.wrapping_sub(1) && idle >= KEEPALIVE_MIN_IDLE
+}
+
+/// Linuxの既定のエフェメラルポート範囲の開始。これ以上のポートはクライアント側とみなす
+const EPHEMERAL_PORT_START: u16 = 32768;
+
+/// `tcp_monitor_packet_loss_total` の `port` ラベル
+///
+/// 小さい方のポートをサービス側とし、両方がエフェメラルポートの場合は系列数を抑えるため `other` にまとめる。
+fn service_port_label(connection: &TcpConnection) -> String {
+    let port = connection.src_port.min(connection.dst_port);
+    if port < EPHEMERAL_PORT_START {
+        port.to_string()
+    } else {
+        "other".to_string()
+    }
+}
+
+/// `tcp_monitor_loss_rate_1m` で平均する秒数
+const LOSS_WINDOW_SECS: usize = 60;
+
+/// アクティブな接続とみなす、最後のパケットからの既定の時間。`--active-window` の既定値と同じ
+const DEFAULT_ACTIVE_WINDOW: Duration = Duration::from_secs(10);
+
+/// 接続の継続時間ヒストグラムのバケット（秒）。短いAPI呼び出しから長時間のストリーミングまでを区別する
+const CONNECTION_DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// 欠損ギャップのヒストグラムの既定のバケット（バイト）。`--gap-buckets` の既定値と同じ
+const DEFAULT_GAP_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0];
+
+/// FIN/RSTで接続の終了の段階を進め、最初のFIN/RSTで継続時間を記録する
+///
+/// 閉じた後のACKや再送されたFINで状態が作り直されて短い接続として記録されないよう、
+/// 状態はすぐには削除しない。両方向のFINまたはRSTの後は `CLOSED_LINGER`、
+/// 片方向のみのFINはアイドル時間の経過後に削除する。
+fn finish_connection(stats: &mut GlobalStats, connection: &TcpConnection, reset: bool) {
+    let now = stats.clock.now_instant();
+    let forward = connection.is_forward();
+    if let Some(entry) = stats.connection_states.get_mut(&connection.canonical_key()) {
+        if entry.phase == ConnectionPhase::Open {
+            stats
+                .prometheus_metrics
+                .connection_duration_histogram
+                .observe(now.duration_since(entry.created_at).as_secs_f64());
+        }
+        entry.phase = match entry.phase {
+            _ if reset => ConnectionPhase::Closed,
+            ConnectionPhase::Open => ConnectionPhase::Closing { fin_forward: forward },
+            ConnectionPhase::Closing { fin_forward } if fin_forward != forward => ConnectionPhase::Closed,
+            // 再送されたFIN
+            phase => phase,
+        };
+    }
+}
+
+/// SYN・SYN-ACK・ACKの観測時刻から接続の往復時間を求める
+///
+/// クライアントのSYNからハンドシェイクを完了するACKまでの時間は、キャプチャ地点の位置にかかわらず
+/// クライアントとサーバーの間の往復時間になる。SYNやSYN-ACKが再送された場合は、
+/// どちらの送信への応答か分からないため計測しない。
+fn observe_handshake(stats: &mut GlobalStats, connection: &TcpConnection, syn: bool, ack: bool) {
+    let now = stats.clock.now_instant();
+    let forward = connection.is_forward();
+    let Some(entry) = stats.connection_states.get_mut(&connection.canonical_key()) else {
+        return;
+    };
+    // この向きの最初のパケット（状態の作成直後）のSYNのみ計測を始める
+    let first_packet = entry.direction(forward).is_some_and(|state| state.packet_count == 1);
+    entry.handshake = match entry.handshake {
+        HandshakeRtt::Unknown if syn && !ack && first_packet => HandshakeRtt::SynSent { client_forward: forward, syn_at: now },
+        HandshakeRtt::SynSent { client_forward, syn_at } if syn && ack && forward != client_forward => {
+            HandshakeRtt::SynAckSeen { client_forward, syn_at, syn_ack_at: now }
+        }
+        HandshakeRtt::SynAckSeen { client_forward, syn_at, syn_ack_at } if !syn && ack && forward == client_forward => {
+            HandshakeRtt::Measured {
+                client_forward,
+                server_rtt: syn_ack_at.saturating_duration_since(syn_at),
+                client_rtt: now.saturating_duration_since(syn_ack_at),
+            }
+        }
+        HandshakeRtt::SynSent { .. } | HandshakeRtt::SynAckSeen { .. } if syn => HandshakeRtt::Unknown,
+        handshake => handshake,
+    };
+}
+
+/// 統計間隔ごとに、往復時間が分かっている接続の向きの帯域幅遅延積を記録する
+///
+/// 帯域幅は統計間隔内の平均スループットとし、データのなかった向きは記録しない。
+fn observe_bandwidth_delay_products(stats: &mut GlobalStats, now: Instant) {
+    let interval = now.duration_since(stats.last_reset_time.max(stats.start_time)).as_secs_f64();
+    let histogram = &stats.prometheus_metrics.bdp_bytes_histogram;
+    for entry in stats.connection_states.values_mut() {
+        let rtt = entry.handshake.rtt();
+        for state in [&mut entry.forward, &mut entry.reverse].into_iter().flatten() {
+            let bytes = std::mem::take(&mut state.interval_bytes);
+            if let Some(rtt) = rtt.filter(|_| bytes > 0 && interval > 0.0) {
+                histogram.observe(bytes as f64 / interval * rtt.as_secs_f64());
+            }
+        }
+    }
+}
+
+/// リモート側のアドレスを集約するネットワークのプレフィックス長
+const PATH_LOSS_PREFIX_LEN: u8 = 24;
+
+/// IPv4アドレスを含む `PATH_LOSS_PREFIX_LEN` のネットワーク（例: 1.2.3.4 → 1.2.3.0/24）
+fn remote_network(ip: &str) -> Option<String> {
+    let ip: Ipv4Addr = ip.parse().ok()?;
+    let network = Ipv4Network::new(ip, PATH_LOSS_PREFIX_LEN).ok()?;
+    Some(format!("{}/{}", network.network(), PATH_LOSS_PREFIX_LEN))
+}
+
+/// リモート側のネットワークごとのロスイベント数と、ロスのあった接続のキー
+type PathLoss = HashMap<String, (u64, HashSet<String>)>;
+
+/// 統計間隔内のロスイベントとロスのあった接続を、接続のリモート側のネットワーク別に `loss_per_network` へ集計する
+///
+/// 同じネットワークへの多くの接続で同時に起きるロスは、アプリケーションではなく共通の経路の問題を示す。
+/// イベント数だけではひとつの接続の大量のロスと区別できないため、接続も数える。
+/// どちらの端点がローカルか判定できていない接続は数えない。
+fn tally_path_loss(stats: &GlobalStats, loss_per_network: &mut PathLoss) {
+    for event in &stats.packet_loss_events {
+        let connection = &event.connection;
+        let local_sender = stats
+            .connection_states
+            .get(&connection.canonical_key())
+            .and_then(|entry| entry.direction(connection.is_forward()))
+            .and_then(|state| state.local_sender);
+        let remote_ip = match local_sender {
+            Some(true) => &connection.dst_ip,
+            Some(false) => &connection.src_ip,
+            None => continue,
+        };
+        if let Some(network) = remote_network(remote_ip) {
+            let (events, connections) = loss_per_network.entry(network).or_default();
+            *events += u64::from(event.count);
+            connections.insert(connection.canonical_key());
+        }
+    }
+}
+
+/// ネットワーク別のロスのゲージを置き換える（ロスのなかったネットワークの系列は削除する）
+fn set_path_loss_gauges(metrics: &PrometheusMetrics, loss_per_network: &PathLoss) {
+    let gauge = &metrics.path_loss_gauge;
+    let connections_gauge = &metrics.path_loss_connections_gauge;
+    gauge.reset();
+    connections_gauge.reset();
+    for (network, (events, connections)) in loss_per_network {
+        gauge.with_label_values(&[network]).set(*events as f64);
+        connections_gauge.with_label_values(&[network]).set(connections.len() as f64);
+    }
+}
+
+/// 統計間隔ごとに、接続の向きごとの最大のバーストをヒストグラムに記録する
+fn observe_connection_bursts(stats: &mut GlobalStats, now: Instant) {
+    let histogram = &stats.prometheus_metrics.connection_burst_bytes_histogram;
+    for (key, entry) in stats.connection_states.iter_mut() {
+        for state in [&mut entry.forward, &mut entry.reverse].into_iter().flatten() {
+            if let Some((bytes, packets)) = state.burst.take_interval_max(now) {
+                debug!("バースト: {} で100ミリ秒に {} バイト / {} パケット", key, bytes, packets);
+                histogram.observe(bytes as f64);
+            }
+        }
+    }
+}
+
+/// アイドル状態の接続と閉じた接続を削除し、FIN/RSTで記録されていなかった接続の継続時間を記録する
+///
+/// FIN/RSTを観測した接続もここで削除されるため、削除数にはそれらも含む。
+fn evict_idle_connections(stats: &mut GlobalStats) {
+    let now = stats.clock.now_instant();
+    let now_utc = stats.clock.now_utc();
+    let histogram = &stats.prometheus_metrics.connection_duration_histogram;
+    let reaped_counter = &stats.prometheus_metrics.connections_reaped_counter;
+    
+    stats.connection_states.retain(|_, entry| {
+        let idle = (now_utc - entry.last_seen()).to_std().unwrap_or_default();
+        let timeout = if entry.phase == ConnectionPhase::Closed { CLOSED_LINGER } else { CONNECTION_IDLE_TIMEOUT };
+        if idle < timeout {
+            return true;
+        }
+        reaped_counter.inc();
+        if entry.phase == ConnectionPhase::Open {
+            // 継続時間は最後のパケットまでとし、アイドル時間は含めない
+            let lifetime = now.duration_since(entry.created_at).saturating_sub(idle);
+            histogram.observe(lifetime.as_secs_f64());
+        }
+        false
+    });
+}
+
+/// 片方向のみFINを観測した接続の数
+fn half_closed_connections(stats: &GlobalStats) -> usize {
+    stats
+        .connection_states
+        .values()
+        .filter(|entry| matches!(entry.phase, ConnectionPhase::Closing { .. }))
+        .count()
+}
+
+/// 最後のパケットが `active_window` 以内の接続の数
+///
+/// アイドルで削除されるまでの接続も含む `connection_states` の件数とは区別する。
+fn count_active_connections(stats: &GlobalStats) -> usize {
+    let now_utc = stats.clock.now_utc();
+    stats
+        .connection_states
+        .values()
+        .filter(|entry| (now_utc - entry.last_seen()).to_std().unwrap_or_default() <= stats.active_window)
+        .count()
+}
+
+/// 統計間隔ごとに全体の統計とワーカーのシャードを走査して合計した値
+#[derive(Debug, Default)]
+struct ShardTally {
+    connections: usize,
+    half_closed: usize,
+    active: usize,
+    path_loss: PathLoss,
+    // 送信元ごとのハンドシェイクが完了していない宛先
+    unanswered_syns: HashMap<String, HashSet<(String, u16)>>,
+}
+
+/// 統計間隔ごとに、1つのシャード（`--workers` を使わない場合は全体の統計）の接続の状態を走査する
+///
+/// 接続ごとのヒストグラムはここで記録し、シャードをまたいで合計する値は `tally` に加える。
+/// アイドル状態の接続の削除もここで行う。
+fn tally_shard(stats: &mut GlobalStats, now: Instant, tally: &mut ShardTally) {
+    stats.route_detector.prune(now);
+    stats.scan_detector.collect_unanswered(now, &mut tally.unanswered_syns);
+    observe_connection_bursts(stats, now);
+    observe_bandwidth_delay_products(stats, now);
+    tally_path_loss(stats, &mut tally.path_loss);
+    evict_idle_connections(stats);
+    tally.connections += stats.connection_states.len();
+    tally.half_closed += half_closed_connections(stats);
+    tally.active += count_active_connections(stats);
+}
+
+/// ワーカーのシャードのパケット数・統計間隔のカウンタ・ロスイベントを全体の統計に移す
+fn merge_worker_shard(stats: &mut GlobalStats, shard: &mut GlobalStats) {
+    stats.total_packets += std::mem::take(&mut shard.total_packets);
+    stats.tcp_packets += std::mem::take(&mut shard.tcp_packets);
+    stats.global_tcp_packets += std::mem::take(&mut shard.global_tcp_packets);
+    stats.packet_loss_events.append(&mut shard.packet_loss_events);
+    stats.window_shrink_events += std::mem::take(&mut shard.window_shrink_events);
+    stats.global_tcp_packets_per_second += std::mem::take(&mut shard.global_tcp_packets_per_second);
+    stats.packet_loss_missing_per_second += std::mem::take(&mut shard.packet_loss_missing_per_second);
+    stats.packet_loss_duplicate_per_second += std::mem::take(&mut shard.packet_loss_duplicate_per_second);
+    stats.packet_loss_out_of_order_per_second += std::mem::take(&mut shard.packet_loss_out_of_order_per_second);
+    stats.window_shrink_per_second += std::mem::take(&mut shard.window_shrink_per_second);
+}
+
+/// `--flow-dump` に書き出す、アクティブな接続の向きごとの状態（接続のキー順）
+fn flow_records(stats: &GlobalStats) -> Vec<FlowRecord> {
+    let now_utc = stats.clock.now_utc();
+    let mut records = Vec::new();
+    for (key, entry) in &stats.connection_states {
+        if (now_utc - entry.last_seen()).to_std().unwrap_or_default() > stats.active_window {
+            continue;
+        }
+        // 正規化したキーは forward の向き（IPv4のみのため '-' はアドレス内に現れない）
+        let Some((lower, upper)) = key.split_once('-') else {
+            continue;
+        };
+        let directions = [(&entry.forward, key.clone()), (&entry.reverse, format!("{}-{}", upper, lower))];
+        for (state, connection) in directions {
+            let Some(state) = state else {
+                continue;
+            };
+            let missing = state
+                .loss_events
+                .iter()
+                .filter(|event| event.loss_type == PacketLossType::MissingSequence)
+                .map(|event| u64::from(event.count))
+                .sum();
+            records.push(FlowRecord {
+                connection,
+                packets: state.packet_count,
+                bytes: state.byte_count,
+                missing,
+                duplicate: u64::from(state.duplicate_count),
+                out_of_order: u64::from(state.out_of_order_count),
+                last_seen: state.last_seen,
+            });
+        }
+    }
+    records.sort_by(|a, b| a.connection.cmp(&b.connection));
+    records
+}
+
+/// `--flow-dump` の間隔ごとにフロー表をCSVに書き出す
+///
+/// ロックを持つのは状態を取り出す間だけで、ファイルへの書き込みはブロッキング用のスレッドで行う。
+async fn run_flow_dump(stats: Arc<Mutex<GlobalStats>>, template: PathBuf, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // 最初のtickはすぐに完了するため、1間隔分のデータが溜まってから書き出す
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let (timestamp, records) = {
+            let stats_guard = stats.lock().unwrap();
+            let mut records = flow_records(&stats_guard);
+            for shard in &stats_guard.worker_shards {
+                records.extend(flow_records(&shard.lock().unwrap()));
+            }
+            records.sort_by(|a, b| a.connection.cmp(&b.connection));
+            (stats_guard.clock.now_utc(), records)
+        };
+        let path = flowdump::timestamped_path(&template, timestamp);
+        let result = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || flowdump::write_csv(&path, timestamp, &records)
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => debug!("フロー表を書き出しました: {}", path.display()),
+            Ok(Err(e)) => warn!("フロー表を書き出せません（{}）: {}", path.display(), e),
+            Err(e) => warn!("フロー表の書き出しタスクが失敗しました: {}", e),
+        }
+    }
+}
+
+/// `--adaptive-interval` で1回の統計出力あたりに目標とするパケット数
+///
+/// この2倍を超えると間隔を半分にし、半分を下回ると倍にする（間で止めて頻繁に変わらないようにする）。
+const ADAPTIVE_TARGET_PACKETS: u64 = 10_000;
+
+/// `--adaptive-interval` の統計出力間隔の範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AdaptiveInterval {
+    min: Duration,
+    max: Duration,
+}
+
+impl AdaptiveInterval {
+    fn from_args(args: &Args) -> Self {
+        let min = Duration::from_millis(args.min_stats_interval);
+        let max = Duration::from_secs(args.max_stats_interval);
+        // 下限が上限を超える指定は上限に揃える
+        Self { min: min.min(max), max }
+    }
+    
+    /// 直前の間隔に処理したパケット数から次の間隔を決める
+    fn next(&self, current: Duration, packets: u64) -> Duration {
+        let next = if packets > ADAPTIVE_TARGET_PACKETS * 2 {
+            current / 2
+        } else if packets < ADAPTIVE_TARGET_PACKETS / 2 {
+            current * 2
+        } else {
+            current
+        };
+        next.clamp(self.min, self.max)
+    }
+}
+
+/// ウィンドウスケールの最大値（RFC 7323）
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// SYN/SYN-ACKのMSSとウィンドウスケールのオプションを取り出す
+fn syn_options(tcp_packet: &TcpPacket) -> (Option<u16>, Option<u8>) {
+    let mut mss = None;
+    let mut window_scale = None;
+    for option in tcp_packet.get_options_iter() {
+        let number = option.get_number();
+        match option.payload() {
+            [high, low] if number == TcpOptionNumbers::MSS => {
+                mss = Some(u16::from_be_bytes([*high, *low])).filter(|mss| *mss > 0);
+            }
+            [shift] if number == TcpOptionNumbers::WSCALE => {
+                window_scale = Some((*shift).min(MAX_WINDOW_SCALE));
+            }
+            _ => {}
+        }
+    }
+    (mss, window_scale)
+}
+
+/// 順序違いの距離ヒストグラムのバケット（セグメント数）
+///
+/// 1〜2セグメントの入れ替わりはマルチパスのリンクでは普通に起こるため、
+/// それより深い入れ替わりと区別できるようにする。
+const REORDER_DISTANCE_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 8.0, 16.0, 32.0, 64.0];
+
+/// 期待していた位置から何セグメント遅れて届いたか（端数は切り上げ、最低1）
+fn reorder_distance(bytes_behind: u32, segment_len: u32) -> u32 {
+    bytes_behind.div_ceil(segment_len.max(1)).max(1)
+}
+
+/// 保留中のギャップを欠損と確定させる重複ACKの回数（高速再送のしきい値と同じ）
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+
+/// 確定したシーケンス欠損を接続・全体の統計とメトリクスに記録する
+fn record_missing_sequence(stats: &mut GlobalStats, connection: &TcpConnection, loss_event: PacketLossEvent) {
+    debug!(
+        "シーケンス欠損: 期待値 {} 受信 {} ギャップ {}",
+        loss_event.expected_seq, loss_event.received_seq, loss_event.gap_size
+    );
+    let mut local_sender = None;
+    let gap_size = loss_event.gap_size;
+    let coalesce_window = stats.coalesce_window;
+    if let Some(state) = stats.direction_state_mut(connection) {
+        push_loss_event(&mut state.loss_events, loss_event.clone(), coalesce_window);
+        local_sender = state.local_sender;
+    }
+    push_loss_event(&mut stats.packet_loss_events, loss_event, coalesce_window);
+    
+    // 1秒間の計測値を更新
+    stats.packet_loss_missing_per_second += 1;
+    stats.prometheus_metrics.packet_loss_missing_counter.inc();
+    stats.prometheus_metrics.packet_loss_gap_histogram.observe(gap_size as f64);
+    stats.prometheus_metrics.inc_loss_breakdown(connection, local_sender, &PacketLossType::MissingSequence);
+}
+
+/// 純粋なACKを逆方向（データ送信側）の接続に反映し、保留中のギャップを判定する
+///
+/// 受信側がギャップの終端以降までACKしていれば、データは再送なしで届いており
+/// モニタ側での取りこぼしとみなして破棄する。ギャップの始端に対する重複ACKが
+/// `DUPLICATE_ACK_THRESHOLD` 回続いた場合は受信側が欠けたデータを待っているため
+/// 欠損と確定させる。
+fn correlate_reverse_ack(connection: &TcpConnection, ack_num: u32, stats: &mut GlobalStats) {
+    let data = connection.reversed();
+    // メトリクスを同時に更新するため、GlobalStats全体ではなく接続状態のみを借用する
+    let Some(state) = stats
+        .connection_states
+        .get_mut(&data.canonical_key())
+        .and_then(|entry| entry.direction_slot(data.is_forward()).as_mut())
+    else {
+        return;
+    };
+    
+    let mut confirmed = Vec::new();
+    match state.acked_seq {
+        Some(acked) if ack_num == acked => {
+            state.duplicate_ack_count += 1;
+            stats.prometheus_metrics.dup_acks_counter.inc();
+            // 3回目の重複ACKで送信側は高速再送を行う（それ以降の重複ACKでは数えない）
+            if state.duplicate_ack_count == DUPLICATE_ACK_THRESHOLD {
+                stats.prometheus_metrics.fast_retransmit_triggers_counter.inc();
+            }
+            if state.duplicate_ack_count >= DUPLICATE_ACK_THRESHOLD {
+                let (lost, pending): (Vec<_>, Vec<_>) = state
+                    .pending_gaps
+                    .drain(..)
+                    .partition(|gap| gap.expected_seq == ack_num);
+                state.pending_gaps = pending;
+                confirmed = lost;
+            }
+        }
+        Some(acked) if !seq_after(ack_num, acked) => return,
+        _ => {
+            state.acked_seq = Some(ack_num);
+            state.duplicate_ack_count = 0;
+            state.pending_gaps.retain(|gap| {
+                let delivered = !seq_before(ack_num, gap.received_seq);
+                if delivered {
+                    debug!("ACKで到達を確認: ギャップ {}-{} は取りこぼし", gap.expected_seq, gap.received_seq);
+                }
+                !delivered
+            });
+        }
+    }
+    
+    for loss_event in confirmed {
+        record_missing_sequence(stats, &data, loss_event);
+    }
+}
+
+/// ACKが逆方向（データ送信側）で観測した最大のシーケンス番号を超えているか
+///
+/// 送られていないデータへのACKは、なりすましたパケットか計測の不具合を示す。
+/// `expected_seq` はSYN・FINが消費するシーケンス番号も含むため、FINへのACKもそのまま比べられる。
+/// 逆方向のパケットを観測していない接続（非対称ルーティングなど）は判定しない。
+fn is_invalid_ack(stats: &GlobalStats, connection: &TcpConnection, ack_num: u32) -> bool {
+    let data = connection.reversed();
+    stats
+        .connection_states
+        .get(&data.canonical_key())
+        .and_then(|entry| entry.direction(data.is_forward()))
+        .is_some_and(|state| seq_after(ack_num, state.expected_seq))
+}
+
 /// パケットロスとウィンドウサイズの縮小を検出する
+#[tracing::instrument(level = "debug", skip_all, fields(connection = %connection.key()))]
 fn detect_packet_loss_and_window_shrink(
     connection: &TcpConnection,
     seq_num: u32,
     ack_num: u32,
     payload_len: u32,
     window_size: u16,
+    flags: u8,
     stats: &mut GlobalStats,
 ) {
-    let connection_key = connection.key();
+    let now = stats.clock.now_utc();
+    let forward = connection.is_forward();
+    let segment_end = seq_num.wrapping_add(sequence_length(payload_len, flags));
+    
+    // 両方向で共有する接続のエントリを取得または作成
+    let now_instant = stats.clock.now_instant();
+    let entry = stats
+        .connection_states
+        .entry(connection.canonical_key())
+        .or_insert_with(|| ConnectionEntry::new(now_instant));
+    let is_new_connection = entry.direction(forward).is_none();
     
-    // 接続状態を取得または作成
-    let state = stats.connection_states.entry(connection_key.clone()).or_insert_with(|| {
+    // 受信側（逆方向）が広告したウィンドウからギャップの上限を決める
+    let reverse = entry.direction(!forward);
+    let receiver_window = reverse.map(|reverse| reverse.max_window_size);
+    let peer_window_scale = reverse.and_then(|reverse| reverse.window_scale);
+    let peer_mss = reverse.and_then(|reverse| reverse.mss);
+    let max_plausible_gap = stats
+        .max_plausible_gap
+        .unwrap_or_else(|| derived_max_plausible_gap(receiver_window));
+    
+    // この向きの状態を取得または作成
+    let state = entry.direction_slot(forward).get_or_insert_with(|| {
         ConnectionState {
             last_seq: seq_num,
             last_ack: ack_num,
-            expected_seq: seq_num.wrapping_add(payload_len.max(1)),
+            expected_seq: segment_end,
             packet_count: 0,
+            byte_count: 0,
+            interval_bytes: 0,
             loss_events: Vec::new(),
             out_of_order_count: 0,
             duplicate_count: 0,
-            last_seen: Utc::now(),
-            last_window_size: window_size,
+            last_seen: now,
+            last_window_size: window_size as u32,
+            max_window_size: window_size as u32,
+            max_segment_len: payload_len,
+            mss: None,
+            window_scale: None,
+            acked_seq: None,
+            duplicate_ack_count: 0,
+            pending_gaps: Vec::new(),
+            unacked_data: None,
+            local_sender: None,
+            burst: BurstMeter::new(now_instant),
         }
     });
     
     state.packet_count += 1;
-    state.last_seen = Utc::now();
+    state.byte_count += u64::from(payload_len);
+    state.interval_bytes += u64::from(payload_len);
+    if payload_len > 0 {
+        state.burst.record(now_instant, payload_len);
+    }
+    let idle = (now - state.last_seen).to_std().unwrap_or_default();
+    state.last_seen = now;
+    // 途中から観測した接続では期待する次のシーケンスが推測のため、最初のパケットではロスを報告しない
+    let warming_up = state.packet_count <= stats.warmup_packets;
+    
+    // ウィンドウスケールは両方向のSYNで合意された場合のみ適用する
+    // （SYN自体のウィンドウはスケールされないが、オプションはSYNの処理後に記録される）
+    let window_shift = peer_window_scale.and(state.window_scale).unwrap_or(0);
+    let window_size = (window_size as u32) << window_shift;
     
     // ウィンドウサイズの縮小検出
     if state.last_window_size > 0 && window_size < state.last_window_size {
         let shrink_ratio = (state.last_window_size - window_size) as f64 / state.last_window_size as f64;
         if shrink_ratio > 0.3 { // 30%以上の縮小を検出
+            debug!("ウィンドウサイズの縮小: {} -> {}", state.last_window_size, window_size);
             stats.window_shrink_events += 1;
             stats.window_shrink_per_second += 1;
+            stats.prometheus_metrics.window_shrink_counter.inc();
         }
     }
     state.last_window_size = window_size;
+    state.max_window_size = state.max_window_size.max(window_size);
     
     // 現在のウィンドウサイズを更新
     stats.prometheus_metrics.current_window_size_gauge.set(window_size as f64);
     
-    // ペイロードがある場合のみシーケンス番号分析を行う
-    if payload_len > 0 {
+    // 再送で埋まったことで欠損と確定した保留中のギャップ
+    let mut confirmed_gap = None;
+    
+    state.max_segment_len = state.max_segment_len.max(payload_len);
+    if is_new_connection && payload_len > 0 {
+        state.unacked_data = Some((state.expected_seq, now_instant));
+    }
+    
+    // キープアライブを除き、ペイロードがある場合のみシーケンス番号分析を行う
+    // （最初のセグメントは状態の初期化に使うため比較対象がない）
+    if !is_new_connection && is_keepalive_probe(seq_num, payload_len, state.expected_seq, idle) {
+        debug!("キープアライブ: シーケンス {}", seq_num);
+        stats.prometheus_metrics.keepalives_counter.inc();
+    } else if payload_len > 0 && !is_new_connection {
         if seq_num == state.expected_seq {
             state.last_seq = seq_num;
-            state.expected_seq = seq_num.wrapping_add(payload_len);
-        } else if seq_num > state.expected_seq {
+            state.expected_seq = segment_end;
+            state.unacked_data = Some((state.expected_seq, now_instant));
+        } else if seq_after(seq_num, state.expected_seq) {
             let gap_size = seq_num.wrapping_sub(state.expected_seq);
             
-            if gap_size > 0 && gap_size < 1000000 {
-                let loss_event = PacketLossEvent {
-                    timestamp: Utc::now(),
-                    connection: connection.clone(),
-                    expected_seq: state.expected_seq,
-                    received_seq: seq_num,
+            if gap_size > 0 && gap_size < max_plausible_gap && warming_up {
+                debug!("ウォームアップ中のギャップを無視: 期待値 {} 受信 {}", state.expected_seq, seq_num);
+                stats.prometheus_metrics.warmup_suppressed_counter.inc();
+            } else if gap_size > 0 && gap_size < max_plausible_gap {
+                let loss_event = PacketLossEvent::new(
+                    now,
+                    connection.clone(),
+                    state.expected_seq,
+                    seq_num,
                     gap_size,
-                    loss_type: PacketLossType::MissingSequence,
-                };
+                    PacketLossType::MissingSequence,
+                );
                 
-                state.loss_events.push(loss_event.clone());
-                stats.packet_loss_events.push(loss_event);
-                
-                // 1秒間の計測値を更新
-                stats.packet_loss_missing_per_second += 1;
+                match state.acked_seq {
+                    // 受信側が既にギャップの先までACKしている場合はモニタ側の取りこぼし
+                    Some(acked) if !seq_before(acked, seq_num) => {
+                        debug!("ACK済みのギャップ: 期待値 {} 受信 {}", state.expected_seq, seq_num);
+                    }
+                    // 受信側のACKを観測している接続では、到達か欠損かが分かるまで保留する
+                    Some(_) => state.pending_gaps.push(loss_event),
+                    // 非対称ルーティング等でACKが見えない接続は直ちに欠損とする
+                    None => confirmed_gap = Some(loss_event),
+                }
             }
             
             state.last_seq = seq_num;
-            state.expected_seq = seq_num.wrapping_add(payload_len);
-        } else if seq_num < state.expected_seq {
-            if seq_num == state.last_seq {
+            state.expected_seq = segment_end;
+            state.unacked_data = Some((state.expected_seq, now_instant));
+        } else if seq_before(seq_num, state.expected_seq) {
+            // 再送があるとACKが元の送信と再送のどちらへのものか区別できない
+            state.unacked_data = None;
+            let filled_gap = state.pending_gaps.iter().position(|gap| {
+                !seq_before(seq_num, gap.expected_seq) && seq_before(seq_num, gap.received_seq)
+            });
+            if let Some(index) = filled_gap {
+                // 保留中のギャップへの再送は、そのギャップが実際に欠損していたことを示す
+                confirmed_gap = Some(state.pending_gaps.remove(index));
+            } else if warming_up {
+                debug!("ウォームアップ中の重複・順序違いを無視: 期待値 {} 受信 {}", state.expected_seq, seq_num);
+                stats.prometheus_metrics.warmup_suppressed_counter.inc();
+            } else if seq_num == state.last_seq {
+                debug!("重複パケット: シーケンス {}", seq_num);
                 state.duplicate_count += 1;
                 
-                let loss_event = PacketLossEvent {
-                    timestamp: Utc::now(),
-                    connection: connection.clone(),
-                    expected_seq: state.expected_seq,
-                    received_seq: seq_num,
-                    gap_size: 0,
-                    loss_type: PacketLossType::DuplicateSequence,
-                };
+                let loss_event = PacketLossEvent::new(
+                    now,
+                    connection.clone(),
+                    state.expected_seq,
+                    seq_num,
+                    0,
+                    PacketLossType::DuplicateSequence,
+                );
                 
-                state.loss_events.push(loss_event.clone());
-                stats.packet_loss_events.push(loss_event);
+                push_loss_event(&mut state.loss_events, loss_event.clone(), stats.coalesce_window);
+                push_loss_event(&mut stats.packet_loss_events, loss_event, stats.coalesce_window);
                 
                 // 1秒間の計測値を更新
                 stats.packet_loss_duplicate_per_second += 1;
+                stats.prometheus_metrics.packet_loss_duplicate_counter.inc();
+                stats.prometheus_metrics.inc_loss_breakdown(connection, state.local_sender, &PacketLossType::DuplicateSequence);
             } else {
+                let segment_len = peer_mss.map_or(state.max_segment_len, u32::from);
+                let distance = reorder_distance(state.expected_seq.wrapping_sub(seq_num), segment_len);
+                debug!("順序違い: 期待値 {} 受信 {} 距離 {} セグメント", state.expected_seq, seq_num, distance);
                 state.out_of_order_count += 1;
+                stats.prometheus_metrics.reorder_distance_histogram.observe(distance as f64);
                 
-                let loss_event = PacketLossEvent {
-                    timestamp: Utc::now(),
-                    connection: connection.clone(),
-                    expected_seq: state.expected_seq,
-                    received_seq: seq_num,
-                    gap_size: state.expected_seq.wrapping_sub(seq_num),
-                    loss_type: PacketLossType::OutOfOrder,
-                };
+                let loss_event = PacketLossEvent::new(
+                    now,
+                    connection.clone(),
+                    state.expected_seq,
+                    seq_num,
+                    state.expected_seq.wrapping_sub(seq_num),
+                    PacketLossType::OutOfOrder,
+                );
                 
-                state.loss_events.push(loss_event.clone());
-                stats.packet_loss_events.push(loss_event);
+                push_loss_event(&mut state.loss_events, loss_event.clone(), stats.coalesce_window);
+                push_loss_event(&mut stats.packet_loss_events, loss_event, stats.coalesce_window);
                 
                 // 1秒間の計測値を更新
                 stats.packet_loss_out_of_order_per_second += 1;
+                stats.prometheus_metrics.packet_loss_out_of_order_counter.inc();
+                stats.prometheus_metrics.inc_loss_breakdown(connection, state.local_sender, &PacketLossType::OutOfOrder);
             }
         }
     }
     
-    if ack_num > state.last_ack {
+    let ack_advanced = seq_after(ack_num, state.last_ack);
+    if ack_advanced {
         state.last_ack = ack_num;
     }
     
-    // 最後にアクティブ接続数を更新
-    let active_connections_count = stats.connection_states.len();
-    stats.prometheus_metrics.active_connections_gauge.set(active_connections_count as f64);
+    // ACKが進んだら、逆方向（データ送信側）の未確認のバイト数を記録する
+    if ack_advanced {
+        let receiver_window = state.max_window_size;
+        let sent_end = entry.direction(!forward).map(|data| data.expected_seq);
+        if let Some(inflight) = sent_end.and_then(|sent_end| bytes_in_flight(sent_end, ack_num, receiver_window)) {
+            stats.prometheus_metrics.inflight_bytes_histogram.observe(inflight as f64);
+        }
+        // ハンドシェイクを観測していない接続では受信側までの往復を差し引けないため、計測しない
+        if let Some(path_rtt) = entry.handshake.rtt_to_sender(forward) {
+            if let Some(delay) = entry.direction_slot(!forward).as_mut().and_then(|data| take_ack_delay(data, ack_num, now_instant, path_rtt)) {
+                stats.prometheus_metrics.ack_delay_histogram.observe(delay.as_secs_f64());
+            }
+        }
+    }
+    
+    if let Some(loss_event) = confirmed_gap {
+        record_missing_sequence(stats, connection, loss_event);
+    }
 }
 
-fn process_tcp_packet(
-    tcp_packet: &TcpPacket,
-    src_ip: String,
-    dst_ip: String,
-    stats: &Arc<Mutex<GlobalStats>>,
-    interface_name: &str,
-) {
-    let src_port = tcp_packet.get_source();
-    let dst_port = tcp_packet.get_destination();
-    let window_size = tcp_packet.get_window();
-    
-    // TCP シーケンス番号とACK番号を取得
-    let seq_num = tcp_packet.get_sequence();
-    let ack_num = tcp_packet.get_acknowledgement();
-    let payload_len = tcp_packet.payload().len() as u32;
+fn process_tcp_packet(segment: &TcpSegment, stats: &mut GlobalStats) {
+    let TcpSegment { src_port, dst_port, window_size, seq_num, ack_num, payload_len, segment_len, ttl, .. } = *segment;
+    let src_ip = segment.src_ip.to_string();
+    let dst_ip = segment.dst_ip.to_string();
     
     let connection = TcpConnection {
         src_ip: src_ip.clone(),
@@ -409,99 +2268,592 @@ fn process_tcp_packet(
         dst_port,
     };
     
-    let mut stats_guard = stats.lock().unwrap();
-    stats_guard.tcp_packets += 1;
+    let flags = segment.flags;
+    let syn = flags & TcpFlags::SYN != 0;
+    let ack = flags & TcpFlags::ACK != 0;
+    
+    stats.tcp_packets += 1;
+    
+    // SYNスキャンの検出
+    let now = stats.clock.now_instant();
+    stats.scan_detector.observe(&src_ip, &dst_ip, dst_port, syn, ack, now);
+    
+    // TTLの変化による経路変化の検出
+    if stats.route_detector.observe(&src_ip, &connection.key(), ttl, now) {
+        stats.prometheus_metrics.route_change_suspected_counter.inc();
+    }
     
     // インターフェース情報を考慮したグローバル接続判定を使用
-    if is_global_connection_with_interface(&src_ip, &dst_ip, interface_name) {
-        stats_guard.global_tcp_packets += 1;
-        stats_guard.global_tcp_packets_per_second += 1;
+    if is_global_connection_with_interface(&src_ip, &dst_ip, stats.interface_network, &stats.subnet_overrides) {
+        stats.global_tcp_packets += 1;
+        stats.global_tcp_packets_per_second += 1;
+        
+        // 送信元IPの国・ASNでバイト数を集計
+        if let (Some(geoip), Ok(ip)) = (&stats.geoip, src_ip.parse::<IpAddr>()) {
+            let info = geoip.lookup(ip);
+            stats
+                .prometheus_metrics
+                .global_connection_bytes_counter
+                .with_label_values(&[&info.country, &info.asn])
+                .inc_by(segment_len as f64);
+        }
     }
     
-    // パケットロス検出とウィンドウサイズの縮小検出
-    detect_packet_loss_and_window_shrink(&connection, seq_num, ack_num, payload_len, window_size, &mut stats_guard);
-}
-
-fn process_packet(packet_data: &[u8], stats: &Arc<Mutex<GlobalStats>>, interface_name: &str) {
-    let mut stats_guard = stats.lock().unwrap();
-    stats_guard.total_packets += 1;
-    drop(stats_guard);
-    
-    if let Some(ethernet) = EthernetPacket::new(packet_data) {
-        if ethernet.get_ethertype() == EtherTypes::Ipv4 {
-            if let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) {
-                if ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Tcp {
-                    if let Some(tcp) = TcpPacket::new(ipv4.payload()) {
-                        let src_ip = ipv4.get_source().to_string();
-                        let dst_ip = ipv4.get_destination().to_string();
-                        process_tcp_packet(&tcp, src_ip, dst_ip, stats, interface_name);
-                    }
-                }
+    // パケットロス検出とウィンドウサイズの縮小検出（サンプリング時は対象の接続のみ）
+    if connection.is_sampled(stats.sample_rate) {
+        detect_packet_loss_and_window_shrink(&connection, seq_num, ack_num, payload_len, window_size, flags, stats);
+        
+        // データ送信側の向きは接続の向きごとに1回だけ判定する
+        if stats.direction_state_mut(&connection).is_some_and(|state| state.local_sender.is_none()) {
+            let local_sender = is_local_ip_with_interface(&src_ip, stats.interface_network, &stats.subnet_overrides);
+            if let Some(state) = stats.direction_state_mut(&connection) {
+                state.local_sender = Some(local_sender);
+            }
+        }
+        
+        if syn || ack {
+            observe_handshake(stats, &connection, syn, ack);
+        }
+        
+        // ハンドシェイク時のオプションを記録する（SYNの再送では上書きする）
+        if syn {
+            if let Some(state) = stats.direction_state_mut(&connection) {
+                state.mss = segment.mss;
+                state.window_scale = segment.window_scale;
             }
         }
+        
+        if ack && is_invalid_ack(stats, &connection, ack_num) {
+            debug!("送られていないデータへのACK: {}", ack_num);
+            stats.prometheus_metrics.invalid_acks_counter.inc();
+        }
+        
+        // 純粋なACKは逆方向のデータの到達確認に使う
+        if ack && !syn && payload_len == 0 {
+            correlate_reverse_ack(&connection, ack_num, stats);
+        }
+        
+        if flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+            finish_connection(stats, &connection, flags & TcpFlags::RST != 0);
+        }
     }
 }
 
-fn print_statistics(stats: &Arc<Mutex<GlobalStats>>) {
-    let mut stats_guard = stats.lock().unwrap();
-    let current_time = Instant::now();
-    
-    // 1秒間のパケットロス統計をカウント
-    let mut missing_count = 0;
-    let mut duplicate_count = 0;
-    let mut out_of_order_count = 0;
+/// IPv4パケットがフラグメントかどうかを判定
+///
+/// 再構築は行わないため、フラグメント化されたセグメントをTCPとして解析すると
+/// 不正なシーケンス番号を読んでしまう。先頭フラグメント（MFフラグあり）も対象とする。
+fn is_ipv4_fragment(ipv4: &Ipv4Packet) -> bool {
+    ipv4.get_flags() & Ipv4Flags::MoreFragments != 0 || ipv4.get_fragment_offset() != 0
+}
+
+/// IPv4ヘッダとTCPヘッダがキャプチャされたバイト列に収まり、長さのフィールドが矛盾していないか
+///
+/// snaplenでペイロードが切り詰められていてもセグメント長はTotal Lengthから求めるため問題ないが、
+/// ヘッダ自体が欠けている、またはIHL・データオフセットが不正なパケットを解析すると
+/// でたらめなシーケンス番号を読み、存在しないロスを検出してしまう。
+fn has_complete_tcp_headers(ipv4: &Ipv4Packet) -> bool {
+    let captured = ipv4.packet();
+    let header_len = ipv4.get_header_length() as usize * 4;
+    if header_len < Ipv4Packet::minimum_packet_size() || header_len > captured.len() {
+        return false;
+    }
+    // TSO等でTotal Lengthが0のパケットはキャプチャされた長さで代用する（tcp_segment_lenと同じ）
+    let total_length = match ipv4.get_total_length() as usize {
+        0 => captured.len(),
+        total_length => total_length,
+    };
+    if total_length < header_len + TcpPacket::minimum_packet_size() {
+        return false;
+    }
+    // Ethernetの最小フレーム長のパディングはTotal Lengthの外にあるため除く
+    let tcp = &captured[header_len..captured.len().min(total_length)];
+    let Some(tcp) = TcpPacket::new(tcp) else {
+        return false;
+    };
+    let tcp_header_len = tcp.get_data_offset() as usize * 4;
+    tcp_header_len >= TcpPacket::minimum_packet_size()
+        && tcp_header_len <= total_length - header_len
+        && tcp_header_len <= tcp.packet().len()
+}
+
+/// IPヘッダから求めたTCPセグメント（ヘッダ＋ペイロード）の実際の長さ
+///
+/// キャプチャされたバイト数はsnaplenで切り詰められている可能性があるため使わない。
+/// TSO等でTotal Lengthが0のパケットはキャプチャされた長さで代用する。
+fn tcp_segment_len(ipv4: &Ipv4Packet) -> u32 {
+    let total_length = ipv4.get_total_length() as u32;
+    if total_length == 0 {
+        return ipv4.payload().len() as u32;
+    }
+    total_length.saturating_sub(ipv4.get_header_length() as u32 * 4)
+}
+
+/// `--exclude-ip` / `--exclude-port` で指定された、解析前に除外するパケットの条件
+#[derive(Debug, Clone, Default)]
+struct PacketExclusions {
+    ips: Vec<IpAddr>,
+    ports: Vec<u16>,
+    // `--focus` の2つのホスト（指定時はこの2台の間以外のパケットをすべて除外する）
+    focus: Option<[IpAddr; 2]>,
+}
+
+impl PacketExclusions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            ips: args.exclude_ips.clone(),
+            ports: args.exclude_ports.clone(),
+            focus: <[IpAddr; 2]>::try_from(args.focus.as_slice()).ok(),
+        }
+    }
     
-    // 最後のリセット時刻以降のイベントのみカウント
-    let reset_time = stats_guard.last_reset_time;
-    for event in &stats_guard.packet_loss_events {
-        let event_elapsed = current_time.duration_since(stats_guard.start_time);
-        let event_time = stats_guard.start_time + Duration::from_secs(event_elapsed.as_secs());
+    /// 送信元・宛先のいずれかのIPアドレスまたはTCPポートが除外対象か、`--focus` の2台の間の通信でないか
+    ///
+    /// 自分のSSHセッションやスクレイプの通信など、BPFより役割で指定しやすいものを除外する。
+    fn matches(&self, packet_data: &[u8]) -> bool {
+        if self.ips.is_empty() && self.ports.is_empty() && self.focus.is_none() {
+            return false;
+        }
         
-        if event_time >= reset_time {
-            match event.loss_type {
-                PacketLossType::MissingSequence => missing_count += 1,
-                PacketLossType::DuplicateSequence => duplicate_count += 1,
-                PacketLossType::OutOfOrder => out_of_order_count += 1,
+        // --focus の指定時は、2台の間の通信と判定できないパケットも除外する
+        let unparsed = self.focus.is_some();
+        let Some(ethernet) = EthernetPacket::new(packet_data) else {
+            return unparsed;
+        };
+        if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+            return unparsed;
+        }
+        let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) else {
+            return unparsed;
+        };
+        
+        let ips = [IpAddr::V4(ipv4.get_source()), IpAddr::V4(ipv4.get_destination())];
+        if let Some([a, b]) = self.focus {
+            if ips != [a, b] && ips != [b, a] {
+                return true;
             }
         }
-    }
-    
-    // 1秒間の統計を表示（シンプルに）
-    println!("\n=== 1秒間の統計 ===");
-    println!("時刻: {}", Utc::now().format("%Y-%m-%d %H:%M:%S UTC"));
-    println!("パケット欠損: {} 回", missing_count);
-    println!("重複パケット: {} 回", duplicate_count);
-    println!("順序乱れ: {} 回", out_of_order_count);
-    println!("ウィンドウサイズ縮小: {} 回", stats_guard.window_shrink_events);
-    println!("総パケットロス: {} 回", missing_count + duplicate_count + out_of_order_count);
-    
-    // Prometheusメトリクスを1秒間の計測値で更新
-    stats_guard.prometheus_metrics.global_tcp_packets_gauge.set(stats_guard.global_tcp_packets_per_second as f64);
-    stats_guard.prometheus_metrics.packet_loss_missing_gauge.set(stats_guard.packet_loss_missing_per_second as f64);
-    stats_guard.prometheus_metrics.packet_loss_duplicate_gauge.set(stats_guard.packet_loss_duplicate_per_second as f64);
-    stats_guard.prometheus_metrics.packet_loss_out_of_order_gauge.set(stats_guard.packet_loss_out_of_order_per_second as f64);
-    stats_guard.prometheus_metrics.window_shrink_gauge.set(stats_guard.window_shrink_per_second as f64);
+        if ips.iter().any(|ip| self.ips.contains(ip)) {
+            return true;
+        }
+        
+        // ポートは先頭フラグメントのTCPヘッダからのみ取得できる
+        if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp || ipv4.get_fragment_offset() != 0 {
+            return false;
+        }
+        TcpPacket::new(ipv4.payload()).is_some_and(|tcp| {
+            self.ports.contains(&tcp.get_source()) || self.ports.contains(&tcp.get_destination())
+        })
+    }
+}
+
+fn process_packet(packet_data: &[u8], stats: &Arc<Mutex<GlobalStats>>) {
+    // 1パケットにつきロックは1回だけ取る（ワーカーがシャードをロックするのと同じ）
+    let mut stats_guard = stats.lock().unwrap();
+    // 除外対象のパケットはどのカウンタにも数えない
+    if stats_guard.exclusions.matches(packet_data) {
+        return;
+    }
+    analyze_packet(CapturedPacket::parse(packet_data), &mut stats_guard);
+}
+
+/// キャプチャしたパケットを解析に渡す（`--workers` の指定時はワーカーのキューを経由する）
+struct PacketDispatcher {
+    stats: Arc<Mutex<GlobalStats>>,
+    workers: Option<Workers>,
+}
+
+/// `--workers` のワーカーと、キャプチャスレッドがロックを取らずに使う除外条件・カウンタ
+struct Workers {
+    pool: WorkerPool<CapturedPacket>,
+    exclusions: PacketExclusions,
+    dropped_counter: Counter,
+}
+
+impl PacketDispatcher {
+    fn new(stats: &Arc<Mutex<GlobalStats>>, args: &Args) -> std::io::Result<Self> {
+        let workers = if args.workers == 0 {
+            None
+        } else {
+            let mut stats_guard = stats.lock().unwrap();
+            let exclusions = stats_guard.exclusions.clone();
+            let dropped_counter = stats_guard.prometheus_metrics.worker_queue_dropped_counter.clone();
+            // ワーカーごとに自分だけがロックするシャードを持たせ、統計間隔ごとに全体の統計へ集約する
+            let pool = WorkerPool::spawn(args.workers, args.worker_queue, |_| {
+                let shard = Arc::new(Mutex::new(stats_guard.worker_shard()));
+                stats_guard.worker_shards.push(Arc::clone(&shard));
+                move |packet| analyze_packet(packet, &mut shard.lock().unwrap())
+            })?;
+            Some(Workers { pool, exclusions, dropped_counter })
+        };
+        Ok(Self {
+            stats: Arc::clone(stats),
+            workers,
+        })
+    }
+    
+    /// 1フレームを解析する、またはヘッダを読み取ってワーカーのキューに入れる
+    fn dispatch(&self, packet_data: &[u8]) {
+        let Some(workers) = &self.workers else {
+            process_packet(packet_data, &self.stats);
+            return;
+        };
+        if workers.exclusions.matches(packet_data) {
+            return;
+        }
+        let packet = CapturedPacket::parse(packet_data);
+        if !workers.pool.dispatch(packet.shard(), packet) {
+            workers.dropped_counter.inc();
+        }
+    }
+    
+    /// ワーカーのキューに残っているパケットを解析し終えるまで待つ
+    fn finish(self) {
+        if let Some(workers) = self.workers {
+            workers.pool.finish();
+        }
+    }
+}
+
+/// キャプチャしたフレームから取り出した、解析に必要なヘッダの値
+///
+/// 所有権を持つため、キャプチャスレッドで作ってワーカーに渡せる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CapturedPacket {
+    /// IPv4のTCP以外のフレーム（総パケット数のみ数える）
+    Other,
+    /// 再構築を行わないIPv4フラグメント
+    Fragment,
+    /// ヘッダが欠けている、または長さのフィールドが矛盾しているTCPパケット
+    Truncated,
+    Tcp(TcpSegment),
+}
+
+/// TCPセグメントのヘッダの値
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TcpSegment {
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    ttl: u8,
+    ecn: u8,
+    src_port: u16,
+    dst_port: u16,
+    seq_num: u32,
+    ack_num: u32,
+    window_size: u16,
+    flags: u8,
+    // IPヘッダから求めたセグメント長（TCPヘッダを含む）
+    segment_len: u32,
+    // snaplenで切り詰められていてもシーケンス計算が狂わないよう、ヘッダの長さから求める
+    payload_len: u32,
+    // SYN/SYN-ACKで広告されたMSSとウィンドウスケール
+    mss: Option<u16>,
+    window_scale: Option<u8>,
+}
+
+impl CapturedPacket {
+    fn parse(packet_data: &[u8]) -> Self {
+        let Some(ethernet) = EthernetPacket::new(packet_data) else {
+            return Self::Other;
+        };
+        if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+            return Self::Other;
+        }
+        let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) else {
+            return Self::Other;
+        };
+        if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+            return Self::Other;
+        }
+        if is_ipv4_fragment(&ipv4) {
+            return Self::Fragment;
+        }
+        if !has_complete_tcp_headers(&ipv4) {
+            return Self::Truncated;
+        }
+        let Some(tcp) = TcpPacket::new(ipv4.payload()) else {
+            return Self::Truncated;
+        };
+        
+        let segment_len = tcp_segment_len(&ipv4);
+        let flags = tcp.get_flags();
+        let (mss, window_scale) = if flags & TcpFlags::SYN != 0 { syn_options(&tcp) } else { (None, None) };
+        Self::Tcp(TcpSegment {
+            src_ip: ipv4.get_source(),
+            dst_ip: ipv4.get_destination(),
+            ttl: ipv4.get_ttl(),
+            ecn: ipv4.get_ecn(),
+            src_port: tcp.get_source(),
+            dst_port: tcp.get_destination(),
+            seq_num: tcp.get_sequence(),
+            ack_num: tcp.get_acknowledgement(),
+            window_size: tcp.get_window(),
+            flags,
+            segment_len,
+            payload_len: segment_len.saturating_sub(tcp.get_data_offset() as u32 * 4),
+            mss,
+            window_scale,
+        })
+    }
+    
+    /// ワーカーを選ぶ値（同じ接続は両方向とも同じ値になり、接続内の順序が保たれて接続の状態も1つのワーカーのシャードに収まる）
+    fn shard(&self) -> u64 {
+        let Self::Tcp(segment) = self else {
+            return 0;
+        };
+        let forward = (segment.src_ip, segment.src_port);
+        let backward = (segment.dst_ip, segment.dst_port);
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        forward.min(backward).hash(&mut hasher);
+        forward.max(backward).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// 除外の判定を済ませたパケットを数え、TCPセグメントを解析する
+fn analyze_packet(packet: CapturedPacket, stats: &mut GlobalStats) {
+    stats.total_packets += 1;
+    match packet {
+        CapturedPacket::Other => {}
+        CapturedPacket::Fragment => stats.prometheus_metrics.ipv4_fragments_skipped_counter.inc(),
+        CapturedPacket::Truncated => stats.prometheus_metrics.truncated_packets_counter.inc(),
+        CapturedPacket::Tcp(segment) => {
+            record_ecn(stats, &segment);
+            process_tcp_packet(&segment, stats);
+        }
+    }
+}
+
+/// IPv4ヘッダのECNフィールドのCE（Congestion Experienced）コードポイント
+const ECN_CE: u8 = 0b11;
+
+/// ECNによる輻輳通知を数える（ロスより先に現れる輻輳の兆候）
+///
+/// SYN/SYN-ACKのECE・CWRはECNを使うかどうかのネゴシエーションなので数えない。
+fn record_ecn(stats: &GlobalStats, segment: &TcpSegment) {
+    let ce_marked = segment.ecn == ECN_CE;
+    let flags = segment.flags;
+    let congestion_flags = if flags & TcpFlags::SYN != 0 { 0 } else { flags & (TcpFlags::ECE | TcpFlags::CWR) };
+    if !ce_marked && congestion_flags == 0 {
+        return;
+    }
+    
+    let metrics = &stats.prometheus_metrics;
+    if ce_marked {
+        metrics.ecn_ce_marked_counter.inc();
+    }
+    if congestion_flags & TcpFlags::ECE != 0 {
+        metrics.tcp_ece_counter.inc();
+    }
+    if congestion_flags & TcpFlags::CWR != 0 {
+        metrics.tcp_cwr_counter.inc();
+    }
+}
+
+/// 最後のリセット時刻以降に発生したイベントを種類別に数える
+fn collect_interval_counts(stats: &GlobalStats, current_time: Instant) -> IntervalCounts {
+    let window = current_time.duration_since(stats.last_reset_time.max(stats.start_time));
+    let window_start = stats.clock.now_utc() - chrono::Duration::from_std(window).unwrap_or_default();
+    
+    let mut counts = IntervalCounts {
+        window_shrink: stats.window_shrink_events,
+        ..Default::default()
+    };
+    
+    for event in &stats.packet_loss_events {
+        if event.timestamp >= window_start {
+            match event.loss_type {
+                PacketLossType::MissingSequence => counts.missing += event.count,
+                PacketLossType::DuplicateSequence => counts.duplicate += event.count,
+                PacketLossType::OutOfOrder => counts.out_of_order += event.count,
+            }
+        }
+    }
+    
+    counts
+}
+
+/// `hostname` ラベルの値（`--resolve-dns` を指定していない場合はNoneでラベル自体がなく、未解決の場合は空文字列）
+fn hostname_label(stats: &GlobalStats, ip: &str) -> Option<String> {
+    let cache = stats.reverse_dns.as_ref()?;
+    Some(ip.parse().ok().and_then(|ip| cache.hostname(ip)).unwrap_or_default())
+}
+
+/// コンソールに表示するIPアドレス（`--resolve-dns` で解決済みの場合は `ip (ホスト名)`）
+fn display_ip(stats: &GlobalStats, ip: &str) -> String {
+    match hostname_label(stats, ip) {
+        Some(hostname) if !hostname.is_empty() => format!("{} ({})", ip, hostname),
+        _ => ip.to_string(),
+    }
+}
+
+/// 統計間隔ごとのコンソール表示に並べる、ロスの多い接続の件数
+const CONSOLE_TOP_CONNECTIONS: usize = 5;
+
+/// 統計間隔ごとのコンソール表示のうち、IPアドレスを含む行
+///
+/// 間隔内のロスが多い接続（上位 `CONSOLE_TOP_CONNECTIONS` 件）と、スキャンの疑いがある送信元を表示する。
+fn per_ip_console_lines(stats: &GlobalStats, suspected_sources: &[(String, usize)]) -> Vec<String> {
+    let mut loss_per_connection: HashMap<String, (&TcpConnection, u64)> = HashMap::new();
+    for event in &stats.packet_loss_events {
+        let (_, losses) = loss_per_connection.entry(event.connection.key()).or_insert((&event.connection, 0));
+        *losses += u64::from(event.count);
+    }
+    let mut connections: Vec<_> = loss_per_connection.into_iter().collect();
+    connections.sort_by(|(a_key, (_, a)), (b_key, (_, b))| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+    
+    let palette = stats.palette;
+    let mut lines = Vec::new();
+    for (_, (connection, losses)) in connections.into_iter().take(CONSOLE_TOP_CONNECTIONS) {
+        lines.push(format!(
+            "ロス: {}:{} -> {}:{} {} 回",
+            display_ip(stats, &connection.src_ip),
+            connection.src_port,
+            display_ip(stats, &connection.dst_ip),
+            connection.dst_port,
+            palette.count(losses)
+        ));
+    }
+    for (source_ip, targets) in suspected_sources {
+        lines.push(format!("スキャンの疑い: {} （未完了の宛先 {} 件）", display_ip(stats, source_ip), targets));
+    }
+    lines
+}
+
+fn print_statistics(stats: &Arc<Mutex<GlobalStats>>) {
+    let mut stats_guard = stats.lock().unwrap();
+    let current_time = stats_guard.clock.now_instant();
+    
+    // 接続の状態を走査し、ワーカーのシャードのカウンタとロスイベントを集約する
+    // （ワーカーは自分のシャードだけをロックするため、ロックは常に全体→シャードの順に取る）
+    let mut tally = ShardTally::default();
+    tally_shard(&mut stats_guard, current_time, &mut tally);
+    for shard in stats_guard.worker_shards.clone() {
+        let mut shard = shard.lock().unwrap();
+        tally_shard(&mut shard, current_time, &mut tally);
+        merge_worker_shard(&mut stats_guard, &mut shard);
+        reset_interval_stats(&mut shard, current_time);
+    }
+    
+    // 最後のリセット時刻以降のパケットロス統計をカウント
+    let counts = collect_interval_counts(&stats_guard, current_time);
+    
+    // スキャンの疑いがある送信元（コンソール表示とゲージで使う）
+    let mut suspected_sources = stats_guard.scan_detector.suspected_sources(&tally.unanswered_syns);
+    suspected_sources.sort();
+    
+    // 1秒間の統計を表示（シンプルに、`--quiet` では表示しない）
+    if !stats_guard.quiet {
+        println!("\n=== 1秒間の統計 ===");
+        println!("時刻: {}", stats_guard.clock.now_utc().format("%Y-%m-%d %H:%M:%S UTC"));
+        let palette = stats_guard.palette;
+        println!("パケット欠損: {} 回", palette.count(counts.missing as u64));
+        println!("重複パケット: {} 回", palette.count(counts.duplicate as u64));
+        println!("順序乱れ: {} 回", palette.count(counts.out_of_order as u64));
+        println!("ウィンドウサイズ縮小: {} 回", palette.count(counts.window_shrink as u64));
+        println!("総パケットロス: {} 回", palette.count(counts.total_loss() as u64));
+        for line in per_ip_console_lines(&stats_guard, &suspected_sources) {
+            println!("{}", line);
+        }
+    }
+    
+    // Prometheusメトリクスを毎秒の値で更新（統計間隔が1秒でない場合は経過秒数で割る）
+    let interval_secs = current_time.duration_since(stats_guard.last_reset_time.max(stats_guard.start_time)).as_secs_f64();
+    let per_second = |count: f64| if interval_secs > 0.0 { count / interval_secs } else { count };
+    stats_guard.prometheus_metrics.global_tcp_packets_gauge.set(per_second(stats_guard.global_tcp_packets_per_second as f64));
+    stats_guard.prometheus_metrics.packet_loss_missing_gauge.set(per_second(stats_guard.packet_loss_missing_per_second as f64));
+    stats_guard.prometheus_metrics.packet_loss_duplicate_gauge.set(per_second(stats_guard.packet_loss_duplicate_per_second as f64));
+    stats_guard.prometheus_metrics.packet_loss_out_of_order_gauge.set(per_second(stats_guard.packet_loss_out_of_order_per_second as f64));
+    stats_guard.prometheus_metrics.window_shrink_gauge.set(per_second(stats_guard.window_shrink_per_second as f64));
+    let elapsed_second = current_time.duration_since(stats_guard.start_time).as_secs();
+    stats_guard.loss_window.record(elapsed_second, counts.total_loss() as u64);
+    let loss_rate = stats_guard.loss_window.rate();
+    stats_guard.prometheus_metrics.loss_rate_1m_gauge.set(loss_rate);
+    stats_guard.prometheus_metrics.uptime_gauge.set(current_time.duration_since(stats_guard.start_time).as_secs_f64());
+    
+    // スキャンの疑いがある送信元を更新（期限切れの送信元は系列ごと削除）
+    let scan_gauge = &stats_guard.prometheus_metrics.scan_suspected_sources_gauge;
+    scan_gauge.reset();
+    for (source_ip, targets) in &suspected_sources {
+        let gauge = match hostname_label(&stats_guard, source_ip) {
+            Some(hostname) => scan_gauge.with_label_values(&[source_ip, &hostname]),
+            None => scan_gauge.with_label_values(&[source_ip]),
+        };
+        gauge.set(*targets as f64);
+    }
+    
+    set_path_loss_gauges(&stats_guard.prometheus_metrics, &tally.path_loss);
+    let metrics = &stats_guard.prometheus_metrics;
+    metrics.connection_map_size_gauge.set(tally.connections as f64);
+    metrics.half_closed_gauge.set(tally.half_closed as f64);
+    metrics.active_connections_gauge.set(tally.active as f64);
+    reset_interval_stats(&mut stats_guard, current_time);
+}
+
+/// キャプチャ終了時に起動からの累計を表示
+fn print_final_summary(stats: &Arc<Mutex<GlobalStats>>) {
+    let mut stats_guard = stats.lock().unwrap();
+    let mut connections = stats_guard.connection_states.len();
+    for shard in stats_guard.worker_shards.clone() {
+        let mut shard = shard.lock().unwrap();
+        merge_worker_shard(&mut stats_guard, &mut shard);
+        connections += shard.connection_states.len();
+    }
+    let metrics = &stats_guard.prometheus_metrics;
     
-    // 統計をリセット
-    stats_guard.packet_loss_events.clear();
-    stats_guard.window_shrink_events = 0;
-    stats_guard.global_tcp_packets_per_second = 0;
-    stats_guard.packet_loss_missing_per_second = 0;
-    stats_guard.packet_loss_duplicate_per_second = 0;
-    stats_guard.packet_loss_out_of_order_per_second = 0;
-    stats_guard.window_shrink_per_second = 0;
-    stats_guard.last_reset_time = current_time;
+    println!("\n=== 最終サマリ ===");
+    println!("計測時間: {:.1} 秒", stats_guard.clock.now_instant().duration_since(stats_guard.start_time).as_secs_f64());
+    println!("総パケット数: {}", stats_guard.total_packets);
+    println!("TCPパケット数: {}", stats_guard.tcp_packets);
+    println!("グローバルTCPパケット数: {}", stats_guard.global_tcp_packets);
+    println!("接続数: {}", connections);
+    let palette = stats_guard.palette;
+    println!("パケット欠損: {} 回", palette.count(metrics.packet_loss_missing_counter.get() as u64));
+    println!("重複パケット: {} 回", palette.count(metrics.packet_loss_duplicate_counter.get() as u64));
+    println!("順序乱れ: {} 回", palette.count(metrics.packet_loss_out_of_order_counter.get() as u64));
+    println!("ウィンドウサイズ縮小: {} 回", palette.count(metrics.window_shrink_counter.get() as u64));
+}
+
+/// 統計間隔ごとのカウンタをリセット
+///
+/// コンソール表示用の値のみを対象とし、Prometheusの単調増加カウンタには触れない。
+/// これによりスクレイプ間隔と `--stats-interval` が異なっても取りこぼしや二重計上が起きない。
+fn reset_interval_stats(stats: &mut GlobalStats, current_time: Instant) {
+    stats.packet_loss_events.clear();
+    stats.window_shrink_events = 0;
+    stats.global_tcp_packets_per_second = 0;
+    stats.packet_loss_missing_per_second = 0;
+    stats.packet_loss_duplicate_per_second = 0;
+    stats.packet_loss_out_of_order_per_second = 0;
+    stats.window_shrink_per_second = 0;
+    stats.last_reset_time = current_time;
 }
 
 // Prometheusメトリクスを提供するHTTPサーバー
 async fn metrics_handler(
-    _req: Request<Body>,
+    req: Request<Body>,
     stats: Arc<Mutex<GlobalStats>>,
 ) -> Result<Response<Body>, Infallible> {
     let stats_guard = stats.lock().unwrap();
     let encoder = TextEncoder::new();
     let metric_families = stats_guard.prometheus_metrics.registry.gather();
     
+    // スクレイパー以外（スクリプトやダッシュボード）向けに、同じメトリクスをJSONでも返す
+    if req.uri().path() == "/metrics/json" {
+        let families = metricsjson::metric_families(&metric_families);
+        let response = match serde_json::to_string(&families) {
+            Ok(body) => Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+            Err(_) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Error encoding metrics"))
+                .unwrap(),
+        };
+        return Ok(response);
+    }
+    
     match encoder.encode_to_string(&metric_families) {
         Ok(metrics_string) => {
             let response = Response::builder()
@@ -543,12 +2895,351 @@ async fn start_prometheus_server(port: u16, stats: Arc<Mutex<GlobalStats>>) -> R
     Ok(())
 }
 
+/// tracingベースのロガーを初期化する
+///
+/// `log` クレートのマクロによる出力もtracing-logのブリッジ経由で同じsubscriberに流れるため、
+/// 接続ごとのspan内で出力されたログには接続キーが付与される。
+fn init_tracing(verbose: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let default_level = if verbose { "debug" } else { "info" };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default_level));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .try_init()
+        .map_err(|e| format!("tracingの初期化に失敗しました: {}", e))?;
+    Ok(())
+}
+
+/// キャプチャに設定するBPFフィルタ
+#[cfg(feature = "pcap")]
+const CAPTURE_FILTER: &str = "tcp";
+
+/// キャプチャエラー後に最初の再接続を試みるまでの待ち時間（失敗するごとに倍にする）
+const CAPTURE_RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// 再接続の待ち時間の上限
+const CAPTURE_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// pcap機能を無効にしてビルドした場合に、libpcapが必要な指定に返すエラー
+#[cfg(not(feature = "pcap"))]
+const PCAP_DISABLED: &str = "pcap機能を無効にしてビルドしたため、--engine pcap・--pcap-fifo・--pcap-stdin・--benchmark は使えません（--engine afpacket を指定してください）";
+
+/// インターフェースを名前で探してライブキャプチャを開く（フィルタは呼び出し側で設定する）
+#[cfg(feature = "pcap")]
+fn open_device_capture(interface: &str, args: &Args) -> Result<Capture<dyn Activated>, Box<dyn std::error::Error>> {
+    // pcap デバイスの取得
+    let mut devices = Device::list()?;
+    let Some(index) = devices.iter().position(|d| d.name == interface) else {
+        return Err(InterfaceNotFound::new(Some(interface), devices.into_iter().map(|d| d.name)).into());
+    };
+    let device = devices.swap_remove(index);
+    
+    info!("デバイス: {} を開いています", device.name);
+    
+    // キャプチャの開始
+    let mut cap = Capture::from_device(device)?
+        .promisc(!args.no_promisc)
+        .snaplen(args.snaplen)
+        .timeout(1000);
+    if let Some(buffer_size) = args.buffer_size {
+        cap = cap.buffer_size(buffer_size);
+    }
+    let cap = cap.open()?;
+    if args.no_promisc {
+        info!("プロミスキャスモードを無効にしてキャプチャします");
+    }
+    match args.buffer_size {
+        Some(buffer_size) => info!("snaplen: {} バイト, バッファサイズ: {} バイト", args.snaplen, buffer_size),
+        None => info!("snaplen: {} バイト, バッファサイズ: libpcapの既定値", args.snaplen),
+    }
+    Ok(cap.into())
+}
+
+/// インターフェースを開き直せるまで待ち時間を延ばしながら再接続を試みる
+///
+/// `open` はキャプチャのバックエンドごとの開き方で、計測時間の終了までに開き直せなかった場合はNoneを返す。
+fn reconnect_capture<T>(
+    interface: &str,
+    deadline: Option<Instant>,
+    mut open: impl FnMut() -> Result<T, Box<dyn std::error::Error>>,
+) -> Option<T> {
+    let mut backoff = Backoff::new(CAPTURE_RECONNECT_INITIAL_BACKOFF, CAPTURE_RECONNECT_MAX_BACKOFF);
+    loop {
+        if !backoff.sleep(deadline) {
+            return None;
+        }
+        
+        match open() {
+            Ok(cap) => {
+                info!("インターフェース '{}' でキャプチャを再開しました", interface);
+                return Some(cap);
+            }
+            Err(e) => {
+                backoff.fail();
+                warn!("インターフェース '{}' を開けません: {}。{} 秒後に再試行します", interface, e, backoff.delay().as_secs());
+            }
+        }
+    }
+}
+
+/// `--engine` で開いたライブキャプチャ、またはpcapストリーム
+enum CaptureSource {
+    #[cfg(feature = "pcap")]
+    Pcap(Capture<dyn Activated>),
+    AfPacket(afpacket::Ring),
+}
+
+/// `--snaplen` をAF_PACKETで切り詰める長さに変換する（負の値はフレーム全体を受け取る）
+fn afpacket_buffer_len(snaplen: i32) -> usize {
+    usize::try_from(snaplen).unwrap_or(65536)
+}
+
+/// `--snaplen` / `--no-promisc` / `--buffer-size` に従ってAF_PACKETのリングを開く
+fn open_afpacket(interface: &str, args: &Args) -> Result<afpacket::Ring, Box<dyn std::error::Error>> {
+    let ring_size = args.buffer_size.and_then(|size| usize::try_from(size).ok());
+    afpacket::open(interface, afpacket_buffer_len(args.snaplen), !args.no_promisc, ring_size)
+}
+
+/// `--count` のパケット数を処理し終えたか
+fn reached_packet_count(processed: u64, count: Option<u64>) -> bool {
+    let reached = count.is_some_and(|count| processed >= count);
+    if reached {
+        info!("指定されたパケット数（{}）を処理しました", processed);
+    }
+    reached
+}
+
+/// libpcapのキャプチャからパケットを読み、計測時間の終了・指定したパケット数・ストリームの終端のいずれかまで処理する
+#[cfg(feature = "pcap")]
+fn run_pcap_capture(
+    mut cap: Capture<dyn Activated>,
+    interface: &str,
+    args: &Args,
+    dispatcher: &PacketDispatcher,
+    deadline: Option<Instant>,
+    is_stream: bool,
+) {
+    let mut processed = 0;
+    loop {
+        // 読み取りタイムアウト（1秒）ごとにも判定されるため、パケットが来なくても終了できる
+        // （pcapストリームにはタイムアウトがないため、次のパケットの到着時に判定する）
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("指定された計測時間に達しました");
+            break;
+        }
+        
+        match cap.next_packet() {
+            Ok(packet) => {
+                dispatcher.dispatch(packet.data);
+                processed += 1;
+                if reached_packet_count(processed, args.count) {
+                    break;
+                }
+            }
+            Err(pcap::Error::TimeoutExpired) => {
+                // タイムアウトは正常、続行
+                continue;
+            }
+            Err(pcap::Error::NoMorePackets) => {
+                // pcapストリームの送り手が終了した
+                info!("pcapストリームが終了しました");
+                break;
+            }
+            Err(e) if is_stream => {
+                // 途中で切れたストリームは読み進められないため終了する
+                warn!("pcapストリームの読み込みに失敗しました: {}", e);
+                break;
+            }
+            Err(e) => {
+                // インターフェースの消失（USB NICの抜去やVMの移行など）は開き直すまで回復しない
+                warn!("パケットキャプチャエラー: {}。インターフェースを開き直します", e);
+                let reopened = reconnect_capture(interface, deadline, || {
+                    let mut cap = open_device_capture(interface, args)?;
+                    cap.filter(CAPTURE_FILTER, true)?;
+                    Ok(cap)
+                });
+                match reopened {
+                    Some(reopened) => {
+                        cap = reopened;
+                        dispatcher.stats.lock().unwrap().prometheus_metrics.capture_reconnects_counter.inc();
+                    }
+                    None => {
+                        info!("指定された計測時間に達しました");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// AF_PACKETのリングからフレームを読み、計測時間の終了か指定したパケット数まで処理する
+///
+/// BPFを使わないため、libpcapの `tcp` フィルタと同じ条件で先に絞り込んでから `process_packet` に渡す。
+fn run_afpacket_capture(
+    mut rx: afpacket::Ring,
+    interface: &str,
+    args: &Args,
+    dispatcher: &PacketDispatcher,
+    deadline: Option<Instant>,
+) {
+    let mut processed = 0;
+    loop {
+        // 読み取りタイムアウト（1秒）ごとにも判定されるため、パケットが来なくても終了できる
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("指定された計測時間に達しました");
+            break;
+        }
+        
+        match rx.next() {
+            Ok(frame) => {
+                if afpacket::is_tcp_frame(frame) {
+                    dispatcher.dispatch(frame);
+                    processed += 1;
+                    if reached_packet_count(processed, args.count) {
+                        break;
+                    }
+                }
+            }
+            Err(e) if afpacket::is_timeout(&e) => continue,
+            Err(e) => {
+                warn!("パケットキャプチャエラー: {}。インターフェースを開き直します", e);
+                let reopened = reconnect_capture(interface, deadline, || open_afpacket(interface, args));
+                match reopened {
+                    Some(reopened) => {
+                        rx = reopened;
+                        dispatcher.stats.lock().unwrap().prometheus_metrics.capture_reconnects_counter.inc();
+                    }
+                    None => {
+                        info!("指定された計測時間に達しました");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `--benchmark` の計測結果
+#[cfg(feature = "pcap")]
+#[derive(Debug, Serialize)]
+struct BenchmarkReport {
+    file: String,
+    packets: u64,
+    tcp_packets: u64,
+    connections: usize,
+    elapsed_secs: f64,
+    packets_per_sec: f64,
+    /// 最大常駐メモリ（/proc/self/status のVmHWM、取得できない環境ではnull）
+    peak_rss_kb: Option<u64>,
+}
+
+/// pcapファイルの全パケット（`--count` の指定があればそのパケット数まで）を通常のキャプチャと同じ `process_packet` に最大速度で流す
+#[cfg(feature = "pcap")]
+fn run_benchmark(pcap_path: &Path, interface_network: Option<Ipv4Network>, args: &Args) -> Result<BenchmarkReport, Box<dyn std::error::Error>> {
+    let mut cap = Capture::from_file(pcap_path)?;
+    let stats = Arc::new(Mutex::new(GlobalStats {
+        scan_detector: ScanDetector::new(Duration::from_secs(args.scan_window), args.scan_threshold),
+        route_detector: RouteChangeDetector::new(args.ttl_change_threshold),
+        max_plausible_gap: args.max_plausible_gap,
+        sample_rate: args.sample,
+        exclusions: PacketExclusions::from_args(args),
+        subnet_overrides: SubnetOverrides::from_args(args),
+        active_window: Duration::from_secs(args.active_window),
+        coalesce_window: Duration::from_millis(args.coalesce_window_ms),
+        warmup_packets: args.warmup_packets,
+        interface_network,
+        ..GlobalStats::with_metrics(Arc::new(SystemClock), metrics_from_args(args)?)
+    }));
+    
+    info!("ベンチマークを開始します: {}", pcap_path.display());
+    let started = Instant::now();
+    let mut processed = 0;
+    loop {
+        match cap.next_packet() {
+            Ok(packet) => {
+                process_packet(packet.data, &stats);
+                processed += 1;
+                if reached_packet_count(processed, args.count) {
+                    break;
+                }
+            }
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let elapsed_secs = started.elapsed().as_secs_f64();
+    
+    let stats_guard = stats.lock().unwrap();
+    Ok(BenchmarkReport {
+        file: pcap_path.display().to_string(),
+        packets: stats_guard.total_packets,
+        tcp_packets: stats_guard.tcp_packets,
+        connections: stats_guard.connection_states.len(),
+        elapsed_secs,
+        packets_per_sec: if elapsed_secs > 0.0 { stats_guard.total_packets as f64 / elapsed_secs } else { 0.0 },
+        peak_rss_kb: peak_rss_kb(),
+    })
+}
+
+/// プロセスの最大常駐メモリ（KiB）をLinuxの /proc から取得
+#[cfg(feature = "pcap")]
+fn peak_rss_kb() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// 利用可能なインターフェースを名前・説明・アドレス・状態フラグ付きで表示
+///
+/// `--engine afpacket` のみでも使えるよう、libpcapではなくpnetの一覧を使う。
+fn list_interfaces() {
+    for interface in pnet::datalink::interfaces() {
+        let mut flags = Vec::new();
+        if interface.is_up() {
+            flags.push("UP");
+        }
+        if interface.is_running() {
+            flags.push("RUNNING");
+        }
+        if interface.is_loopback() {
+            flags.push("LOOPBACK");
+        }
+        
+        println!("{} [{}]", interface.name, flags.join(" "));
+        if !interface.description.is_empty() {
+            println!("    説明: {}", interface.description);
+        }
+        // `--interface-index` / `--interface-mac` で指定するためのインデックスとMACアドレス
+        println!("    インデックス: {}", interface.index);
+        if let Some(mac) = interface.mac {
+            println!("    MAC: {}", mac);
+        }
+        for address in &interface.ips {
+            println!("    アドレス: {} ネットマスク: {}", address.ip(), address.mask());
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let args: Args = config::parse::<Args, ConfigFile>();
+    
+    if args.list_interfaces {
+        list_interfaces();
+        return Ok(());
+    }
     
     // ログレベルの設定
-    if args.verbose {
+    if args.tracing {
+        init_tracing(args.verbose)?;
+    } else if args.verbose {
         env_logger::Builder::from_default_env()
             .filter_level(log::LevelFilter::Debug)
             .init();
@@ -558,53 +3249,111 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .init();
     }
     
+    // ローカルネットワークの判定に使うインターフェースのアドレスは、ここで1回だけ取得する
+    let interfaces = pnet::datalink::interfaces();
+    #[cfg(feature = "pcap")]
+    if let Some(pcap_path) = &args.benchmark {
+        let interface_network = args.interface.as_deref().and_then(|name| interface::ipv4_network(&interfaces, name));
+        let report = run_benchmark(pcap_path, interface_network, &args)?;
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(());
+    }
+    #[cfg(not(feature = "pcap"))]
+    if args.benchmark.is_some() {
+        return Err(PCAP_DISABLED.into());
+    }
+    // clapにより --list-interfaces / --benchmark / pcapストリーム以外では必須
+    let stream_path = pcap_stream_path(&args);
+    let interface = if args.interface_index.is_some() || args.interface_mac.is_some() {
+        // pcapのデバイスは名前で開くため、pnetのインターフェース一覧から名前に変換する
+        let selector = InterfaceSelector {
+            name: args.interface.clone(),
+            index: args.interface_index,
+            mac: args.interface_mac,
+        };
+        selector.resolve(&interfaces)?
+    } else {
+        match (&args.interface, &stream_path) {
+            (Some(interface), _) => interface.clone(),
+            (None, Some(_)) => String::new(),
+            (None, None) => return Err("インターフェース名が指定されていません".into()),
+        }
+    };
+    
     info!("TCP Window Size Monitor & パケットロス検出 を開始します");
     // 自分のIPアドレスとサブネットマスクを取得
-    if let Some(device) = Device::list()?.into_iter().find(|d| d.name == args.interface) {
-        if let Some(addr) = device.addresses.iter().find(|a| a.addr.is_ipv4()) {
-            if let (std::net::IpAddr::V4(ip), Some(std::net::IpAddr::V4(netmask))) = (addr.addr, addr.netmask) {
-                info!("自分のIPアドレス: {}", ip);
-                info!("サブネットマスク: {}", netmask);
-
-                let ip_u32 = u32::from(ip);
-                let netmask_u32 = u32::from(netmask);
-                let network_u32 = ip_u32 & netmask_u32;
-                let broadcast_u32 = network_u32 | !netmask_u32;
-
-                let network_ip = Ipv4Addr::from(network_u32);
-                let broadcast_ip = Ipv4Addr::from(broadcast_u32);
-                
-                info!("IPアドレス範囲: {} - {}", network_ip, broadcast_ip);
-            }
-        }
+    let interface_network = interface::ipv4_network(&interfaces, &interface);
+    if let Some(network) = interface_network {
+        info!("自分のIPアドレス: {}", network.ip());
+        info!("サブネットマスク: {}", network.mask());
+        info!("IPアドレス範囲: {} - {}", network.network(), network.broadcast());
     }
-    info!("インターフェース: {}", args.interface);
+    info!("インターフェース: {}", interface);
     info!("対象: グローバルIP間のTCP通信のみ");
+    if let [a, b] = args.focus.as_slice() {
+        info!("対象を {} と {} の間の通信に絞り込みます", a, b);
+    }
     
-    // pcap デバイスの取得
-    let device = Device::list()?
-        .into_iter()
-        .find(|d| d.name == args.interface)
-        .ok_or_else(|| format!("インターフェース '{}' が見つかりません", args.interface))?;
-    
-    info!("デバイス: {} を開いています", device.name);
-    
-    // キャプチャの開始
-    let mut cap = Capture::from_device(device)?
-        .promisc(true)
-        .snaplen(65536)
-        .timeout(1000)
-        .open()?;
-    
-    // TCPフィルタを設定
-    let filter = "tcp".to_string();
+    let capture = match (&stream_path, args.engine) {
+        (Some(_), CaptureEngine::Afpacket) => {
+            return Err("--engine afpacket は --pcap-fifo / --pcap-stdin と併用できません".into());
+        }
+        #[cfg(feature = "pcap")]
+        (Some(path), CaptureEngine::Pcap) => {
+            // 送り手がグローバルヘッダを書き込むまでここでブロックする
+            info!("pcapストリーム: {} を開いています", path.display());
+            let mut cap: Capture<dyn Activated> = Capture::from_file(path)
+                .map_err(|e| format!("pcapストリーム '{}' を開けません: {}", path.display(), e))?
+                .into();
+            cap.filter(CAPTURE_FILTER, true)?;
+            info!("フィルタを設定しました: {}", CAPTURE_FILTER);
+            CaptureSource::Pcap(cap)
+        }
+        #[cfg(feature = "pcap")]
+        (None, CaptureEngine::Pcap) => {
+            let mut cap = open_device_capture(&interface, &args)?;
+            cap.filter(CAPTURE_FILTER, true)?;
+            info!("フィルタを設定しました: {}", CAPTURE_FILTER);
+            CaptureSource::Pcap(cap)
+        }
+        #[cfg(not(feature = "pcap"))]
+        (_, CaptureEngine::Pcap) => return Err(PCAP_DISABLED.into()),
+        (None, CaptureEngine::Afpacket) => {
+            info!("AF_PACKETでデバイス: {} を開いています", interface);
+            CaptureSource::AfPacket(open_afpacket(&interface, &args)?)
+        }
+    };
     
-    cap.filter(&filter, true)?;
-    info!("フィルタを設定しました: {}", filter);
+    // GeoIPデータベースの読み込み（起動時に1回のみ）
+    let geoip = match &args.geoip_db {
+        Some(path) => {
+            let db = GeoIpDb::open(path)
+                .map_err(|e| format!("GeoIPデータベース '{}' を読み込めません: {}", path.display(), e))?;
+            info!("GeoIPデータベースを読み込みました: {}", path.display());
+            Some(Arc::new(db))
+        }
+        None => None,
+    };
     
     let stats = Arc::new(Mutex::new(GlobalStats {
         start_time: Instant::now(),
-        ..Default::default()
+        geoip,
+        scan_detector: ScanDetector::new(Duration::from_secs(args.scan_window), args.scan_threshold),
+        route_detector: RouteChangeDetector::new(args.ttl_change_threshold),
+        max_plausible_gap: args.max_plausible_gap,
+        sample_rate: args.sample,
+        exclusions: PacketExclusions::from_args(&args),
+        subnet_overrides: SubnetOverrides::from_args(&args),
+        interface_network,
+        active_window: Duration::from_secs(args.active_window),
+        reverse_dns: args
+            .resolve_dns
+            .then(|| ReverseDnsCache::spawn(dns::DEFAULT_CACHE_CAPACITY, dns::DEFAULT_LOOKUP_TIMEOUT)),
+        palette: Palette::detect(if args.no_color { ColorChoice::Never } else { args.color }),
+        quiet: args.quiet,
+        coalesce_window: Duration::from_millis(args.coalesce_window_ms),
+        warmup_packets: args.warmup_packets,
+        ..GlobalStats::with_metrics(Arc::new(SystemClock), metrics_from_args(&args)?)
     }));
     
     let stats_clone_for_stats = Arc::clone(&stats);
@@ -619,35 +3368,1538 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
     
-    // 統計表示用のタスク
+    // 統計表示用のタスク（--adaptive-interval ではパケットレートに応じて間隔を変える）
+    let adaptive = args.adaptive_interval.then(|| AdaptiveInterval::from_args(&args));
     let _stats_task = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(stats_interval));
+        let mut period = Duration::from_secs(stats_interval);
+        if let Some(adaptive) = &adaptive {
+            period = period.clamp(adaptive.min, adaptive.max);
+        }
+        let mut interval = tokio::time::interval(period);
+        let mut last_total_packets = 0;
         
         loop {
             interval.tick().await;
             print_statistics(&stats_clone_for_stats);
+            
+            let Some(adaptive) = &adaptive else {
+                continue;
+            };
+            let total_packets = stats_clone_for_stats.lock().unwrap().total_packets;
+            let next = adaptive.next(period, total_packets - last_total_packets);
+            last_total_packets = total_packets;
+            if next != period {
+                debug!("統計出力間隔を変更します: {:?} -> {:?}", period, next);
+                period = next;
+                interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            }
         }
     });
     
+    // フロー表の定期的な書き出し
+    if let Some(flow_dump) = args.flow_dump.clone() {
+        info!("フロー表を {} 秒ごとに書き出します: {}", args.flow_dump_interval, flow_dump.display());
+        tokio::spawn(run_flow_dump(Arc::clone(&stats), flow_dump, Duration::from_secs(args.flow_dump_interval)));
+    }
+    
     // パケットキャプチャのメインループ
     info!("パケットキャプチャを開始します...");
+    let deadline = (args.duration > 0).then(|| Instant::now() + Duration::from_secs(args.duration));
+    if deadline.is_some() {
+        info!("{} 秒後にキャプチャを終了します", args.duration);
+    }
+    if let Some(count) = args.count {
+        info!("{} パケットを処理したらキャプチャを終了します", count);
+    }
     
-    loop {
-        match cap.next_packet() {
-            Ok(packet) => {
-                process_packet(packet.data, &stats, &args.interface);
-            }
-            Err(pcap::Error::TimeoutExpired) => {
-                // タイムアウトは正常、続行
-                continue;
-            }
-            Err(e) => {
-                warn!("パケットキャプチャエラー: {}", e);
-                continue;
-            }
-        }
+    let dispatcher = PacketDispatcher::new(&stats, &args)?;
+    if args.workers > 0 {
+        info!("{} 個のワーカースレッドでロス解析を行います", args.workers);
     }
+    match capture {
+        #[cfg(feature = "pcap")]
+        CaptureSource::Pcap(cap) => run_pcap_capture(cap, &interface, &args, &dispatcher, deadline, stream_path.is_some()),
+        CaptureSource::AfPacket(rx) => run_afpacket_capture(rx, &interface, &args, &dispatcher, deadline),
+    }
+    dispatcher.finish();
     
+    print_final_summary(&stats);
     info!("監視を終了しました");
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn test_connection() -> TcpConnection {
+        TcpConnection {
+            src_ip: "203.0.113.10".to_string(),
+            dst_ip: "198.51.100.20".to_string(),
+            src_port: 443,
+            dst_port: 50000,
+        }
+    }
+
+    fn direction_state<'a>(stats: &'a GlobalStats, connection: &TcpConnection) -> &'a ConnectionState {
+        stats.connection_states[&connection.canonical_key()]
+            .direction(connection.is_forward())
+            .unwrap()
+    }
+
+    fn stats_with_mock_clock() -> (Arc<MockClock>, GlobalStats) {
+        let clock = Arc::new(MockClock::new());
+        let stats = GlobalStats::with_clock(clock.clone());
+        (clock, stats)
+    }
+
+    /// Ethernet(14) + IPv4(20) + TCP(20) + ペイロードのフレーム
+    fn tcp_frame(src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        use pnet::packet::ethernet::MutableEthernetPacket;
+        use pnet::packet::ipv4::MutableIpv4Packet;
+        use pnet::packet::tcp::MutableTcpPacket;
+
+        let mut frame = vec![0u8; 54 + payload.len()];
+        MutableEthernetPacket::new(&mut frame).unwrap().set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(40 + payload.len() as u16);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ipv4.set_source(src);
+        ipv4.set_destination(dst);
+        let mut tcp = MutableTcpPacket::new(&mut frame[34..]).unwrap();
+        tcp.set_source(sport);
+        tcp.set_destination(dport);
+        tcp.set_sequence(seq);
+        tcp.set_data_offset(5);
+        tcp.set_flags(flags);
+        tcp.set_window(65535);
+        tcp.set_payload(payload);
+        frame
+    }
+
+    #[test]
+    fn subnet_matching_uses_the_interface_netmask() {
+        let ip = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+
+        // /24
+        assert!(in_same_subnet(ip("203.0.113.200"), ip("203.0.113.10"), ip("255.255.255.0")));
+        assert!(in_same_subnet(ip("203.0.113.255"), ip("203.0.113.10"), ip("255.255.255.0")));
+        assert!(!in_same_subnet(ip("203.0.114.10"), ip("203.0.113.10"), ip("255.255.255.0")));
+
+        // /16
+        assert!(in_same_subnet(ip("198.51.7.1"), ip("198.51.100.20"), ip("255.255.0.0")));
+        assert!(!in_same_subnet(ip("198.52.100.20"), ip("198.51.100.20"), ip("255.255.0.0")));
+
+        // /30（ポイントツーポイントリンク）: 4アドレスの境界をまたがない
+        assert!(in_same_subnet(ip("192.0.2.7"), ip("192.0.2.5"), ip("255.255.255.252")));
+        assert!(!in_same_subnet(ip("192.0.2.8"), ip("192.0.2.5"), ip("255.255.255.252")));
+        assert!(!in_same_subnet(ip("192.0.2.3"), ip("192.0.2.5"), ip("255.255.255.252")));
+
+        // /32は自分自身のみ、/0はすべてのアドレス
+        assert!(in_same_subnet(ip("192.0.2.5"), ip("192.0.2.5"), ip("255.255.255.255")));
+        assert!(!in_same_subnet(ip("192.0.2.6"), ip("192.0.2.5"), ip("255.255.255.255")));
+        assert!(in_same_subnet(ip("8.8.8.8"), ip("192.0.2.5"), ip("0.0.0.0")));
+    }
+
+    #[test]
+    fn engine_defaults_to_pcap() {
+        // pcap機能を無効にしたビルドではafpacketが既定になる
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        let default_engine = if cfg!(feature = "pcap") { CaptureEngine::Pcap } else { CaptureEngine::Afpacket };
+        assert_eq!(args.engine, default_engine);
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--engine", "afpacket"]).unwrap();
+        assert_eq!(args.engine, CaptureEngine::Afpacket);
+        assert!(Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--engine", "netmap"]).is_err());
+    }
+    
+    #[test]
+    fn afpacket_snaplen_of_minus_one_takes_whole_frames() {
+        assert_eq!(afpacket_buffer_len(-1), 65536);
+        assert_eq!(afpacket_buffer_len(128), 128);
+    }
+    
+    #[test]
+    fn promiscuous_mode_is_on_unless_disabled() {
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        assert!(!args.no_promisc);
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--no-promisc"]).unwrap();
+        assert!(args.no_promisc);
+    }
+    
+    #[test]
+    fn packet_count_stops_the_capture() {
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        assert_eq!(args.count, None);
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "-c", "1000", "--duration", "60"]).unwrap();
+        assert_eq!(args.count, Some(1000));
+        assert!(Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--count", "0"]).is_err());
+        
+        assert!(!reached_packet_count(999, args.count));
+        assert!(reached_packet_count(1000, args.count));
+        assert!(!reached_packet_count(u64::MAX, None));
+    }
+
+    #[test]
+    fn config_file_supplies_required_interface() {
+        use std::io::Write;
+        
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"interface = \"eth0\"\ngap_buckets = [1, 10, 100]\nresolve_dns = true\ncolor = \"never\"\n").unwrap();
+        let config = file.path().to_str().unwrap();
+        
+        let args: Args = config::try_parse_from::<Args, ConfigFile>(["tcp_window_monitor", "--config", config]).unwrap();
+        assert_eq!(args.interface.as_deref(), Some("eth0"));
+        assert_eq!(args.gap_buckets, vec![1.0, 10.0, 100.0]);
+        assert!(args.resolve_dns);
+        assert_eq!(args.color, ColorChoice::Never);
+        
+        let args: Args = config::try_parse_from::<Args, ConfigFile>(["tcp_window_monitor", "--config", config, "-i", "eth1", "--color", "always"]).unwrap();
+        assert_eq!(args.interface.as_deref(), Some("eth1"));
+        assert_eq!(args.color, ColorChoice::Always);
+    }
+    
+    #[test]
+    fn pcap_stream_replaces_interface_requirement() {
+        assert!(Args::try_parse_from(["tcp_window_monitor"]).is_err());
+        assert!(Args::try_parse_from(["tcp_window_monitor", "--pcap-stdin", "--pcap-fifo", "/tmp/p"]).is_err());
+
+        let args = Args::try_parse_from(["tcp_window_monitor", "--pcap-stdin"]).unwrap();
+        assert_eq!(pcap_stream_path(&args), Some(PathBuf::from("-")));
+        let args = Args::try_parse_from(["tcp_window_monitor", "--pcap-fifo", "/run/capture.fifo"]).unwrap();
+        assert_eq!(pcap_stream_path(&args), Some(PathBuf::from("/run/capture.fifo")));
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        assert_eq!(pcap_stream_path(&args), None);
+    }
+
+    #[test]
+    fn loss_rate_is_averaged_over_the_last_minute() {
+        let mut window = LossWindow::default();
+        assert_eq!(window.rate(), 0.0);
+        
+        // 起動直後は経過した秒数で平均する
+        window.record(1, 30);
+        window.record(2, 0);
+        assert_eq!(window.rate(), 15.0);
+        
+        // 1分の間の2回のバースト
+        window.record(30, 60);
+        window.record(60, 0);
+        assert_eq!(window.rate(), 1.5);
+        
+        // 最初のバーストが窓から外れる
+        window.record(61, 0);
+        assert_eq!(window.rate(), 1.0);
+        assert_eq!(window.buckets.len(), LOSS_WINDOW_SECS);
+        
+        // 1分以上イベントがなければ0に戻る
+        window.record(200, 0);
+        assert_eq!(window.rate(), 0.0);
+        assert_eq!(window.buckets.len(), LOSS_WINDOW_SECS);
+    }
+    
+    #[test]
+    fn adaptive_interval_follows_the_packet_rate_within_bounds() {
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--adaptive-interval"]).unwrap();
+        let adaptive = AdaptiveInterval::from_args(&args);
+        assert_eq!(adaptive, AdaptiveInterval { min: Duration::from_millis(250), max: Duration::from_secs(10) });
+        let second = Duration::from_secs(1);
+
+        assert_eq!(adaptive.next(second, ADAPTIVE_TARGET_PACKETS * 3), Duration::from_millis(500));
+        assert_eq!(adaptive.next(Duration::from_millis(300), ADAPTIVE_TARGET_PACKETS * 3), Duration::from_millis(250));
+        // 目標の半分から2倍の間では変えない
+        assert_eq!(adaptive.next(second, ADAPTIVE_TARGET_PACKETS), second);
+        assert_eq!(adaptive.next(second, ADAPTIVE_TARGET_PACKETS * 2), second);
+        assert_eq!(adaptive.next(second, 0), Duration::from_secs(2));
+        assert_eq!(adaptive.next(Duration::from_secs(8), 0), Duration::from_secs(10));
+
+        let args = Args::try_parse_from([
+            "tcp_window_monitor", "-i", "eth0", "--adaptive-interval", "--min-stats-interval", "5000", "--max-stats-interval", "2",
+        ])
+        .unwrap();
+        assert_eq!(AdaptiveInterval::from_args(&args).min, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn start_time_and_uptime_gauges() {
+        let (clock, stats) = stats_with_mock_clock();
+        assert_eq!(stats.prometheus_metrics.start_time_gauge.get(), 1_704_067_200.0);
+
+        let stats = Arc::new(Mutex::new(stats));
+        clock.advance(Duration::from_millis(2500));
+        print_statistics(&stats);
+        assert_eq!(stats.lock().unwrap().prometheus_metrics.uptime_gauge.get(), 2.5);
+        
+        // --quiet でも表示以外の更新は行う
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--quiet"]).unwrap();
+        stats.lock().unwrap().quiet = args.quiet;
+        clock.advance(Duration::from_millis(500));
+        print_statistics(&stats);
+        assert_eq!(stats.lock().unwrap().prometheus_metrics.uptime_gauge.get(), 3.0);
+    }
+
+    #[test]
+    fn per_second_gauges_are_divided_by_the_interval() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        stats.global_tcp_packets_per_second = 500;
+        stats.packet_loss_missing_per_second = 10;
+        stats.window_shrink_per_second = 5;
+
+        // --adaptive-interval などで5秒間隔になった場合も毎秒の値を出す
+        let stats = Arc::new(Mutex::new(stats));
+        clock.advance(Duration::from_secs(5));
+        print_statistics(&stats);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.prometheus_metrics.global_tcp_packets_gauge.get(), 100.0);
+        assert_eq!(stats.prometheus_metrics.packet_loss_missing_gauge.get(), 2.0);
+        assert_eq!(stats.prometheus_metrics.window_shrink_gauge.get(), 1.0);
+    }
+
+    #[test]
+    fn keepalive_probes_are_not_duplicates() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        // 1バイトのプローブを繰り返しても、重複・順序違いには数えない
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(75));
+            detect_packet_loss_and_window_shrink(&connection, 1099, 1, 1, 65535, 0, &mut stats);
+        }
+        clock.advance(Duration::from_secs(75));
+        detect_packet_loss_and_window_shrink(&connection, 1099, 1, 0, 65535, 0, &mut stats);
+
+        let metrics = &stats.prometheus_metrics;
+        assert_eq!(metrics.keepalives_counter.get(), 4.0);
+        assert_eq!(metrics.packet_loss_duplicate_counter.get(), 0.0);
+        assert_eq!(metrics.packet_loss_out_of_order_counter.get(), 0.0);
+        assert_eq!(direction_state(&stats, &connection).expected_seq, 1100);
+
+        // 直前のパケットから間がない場合は通常の順序違いとして扱う
+        detect_packet_loss_and_window_shrink(&connection, 1099, 1, 1, 65535, 0, &mut stats);
+        assert_eq!(stats.prometheus_metrics.keepalives_counter.get(), 4.0);
+        assert_eq!(stats.prometheus_metrics.packet_loss_out_of_order_counter.get(), 1.0);
+    }
+
+    #[test]
+    fn syn_with_data_consumes_one_extra_sequence_number() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        // TFO の SYN+データ: SYN の 1 と 100 バイトで次は 1101 から
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, TcpFlags::SYN, &mut stats);
+        assert_eq!(direction_state(&stats, &connection).expected_seq, 1101);
+        detect_packet_loss_and_window_shrink(&connection, 1101, 1, 100, 65535, TcpFlags::ACK, &mut stats);
+
+        let metrics = &stats.prometheus_metrics;
+        assert_eq!(metrics.packet_loss_missing_counter.get(), 0.0);
+        assert_eq!(metrics.packet_loss_out_of_order_counter.get(), 0.0);
+        assert_eq!(direction_state(&stats, &connection).expected_seq, 1201);
+    }
+
+    #[test]
+    fn fin_consumes_one_sequence_number() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, TcpFlags::ACK, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1100, 1, 100, 65535, TcpFlags::FIN | TcpFlags::ACK, &mut stats);
+        assert_eq!(direction_state(&stats, &connection).expected_seq, 1201);
+
+        // FIN の再送は重複として数え、期待シーケンスは戻さない
+        detect_packet_loss_and_window_shrink(&connection, 1100, 1, 100, 65535, TcpFlags::FIN | TcpFlags::ACK, &mut stats);
+        let metrics = &stats.prometheus_metrics;
+        assert_eq!(metrics.packet_loss_missing_counter.get(), 0.0);
+        assert_eq!(metrics.packet_loss_duplicate_counter.get(), 1.0);
+        assert_eq!(direction_state(&stats, &connection).expected_seq, 1201);
+    }
+
+    #[test]
+    fn active_gauge_counts_recently_seen_connections() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let quiet = test_connection();
+        let busy = TcpConnection {
+            dst_port: 50001,
+            ..test_connection()
+        };
+
+        detect_packet_loss_and_window_shrink(&quiet, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&busy, 1000, 1, 100, 65535, 0, &mut stats);
+        clock.advance(DEFAULT_ACTIVE_WINDOW + Duration::from_secs(1));
+        detect_packet_loss_and_window_shrink(&busy, 1100, 1, 100, 65535, 0, &mut stats);
+        // 接続数のゲージはパケットごとではなく統計出力ごとに更新する
+        assert_eq!(stats.prometheus_metrics.active_connections_gauge.get(), 0.0);
+        assert_eq!(stats.prometheus_metrics.connection_map_size_gauge.get(), 0.0);
+
+        // 静かな接続はアイドルで削除されるまで状態に残るが、アクティブには数えない
+        let stats = Arc::new(Mutex::new(stats));
+        print_statistics(&stats);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.prometheus_metrics.active_connections_gauge.get(), 1.0);
+        assert_eq!(stats.prometheus_metrics.connection_map_size_gauge.get(), 2.0);
+    }
+
+    #[test]
+    fn loss_is_split_by_data_sender_direction() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        stats.direction_state_mut(&connection).unwrap().local_sender = Some(false);
+        // 欠損と重複はリモートからのデータ（受信経路）として数える
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        stats.direction_state_mut(&connection).unwrap().local_sender = Some(true);
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+
+        let metrics = &stats.prometheus_metrics;
+        let inbound = |kind: &str| metrics.packet_loss_inbound_counter.with_label_values(&[kind]).get();
+        let outbound = |kind: &str| metrics.packet_loss_outbound_counter.with_label_values(&[kind]).get();
+        assert_eq!(inbound("missing"), 1.0);
+        assert_eq!(inbound("duplicate"), 1.0);
+        assert_eq!(outbound("missing"), 0.0);
+        assert_eq!(outbound("duplicate"), 1.0);
+    }
+    
+    #[test]
+    fn largest_burst_per_interval_is_observed() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+        let histogram = stats.prometheus_metrics.connection_burst_bytes_histogram.clone();
+        
+        // 100ミリ秒以内に10セグメント、その後は1セグメントずつ
+        let mut seq = 1000;
+        for _ in 0..10 {
+            detect_packet_loss_and_window_shrink(&connection, seq, 1, 1460, 65535, 0, &mut stats);
+            seq += 1460;
+            clock.advance(Duration::from_millis(5));
+        }
+        for _ in 0..3 {
+            clock.advance(Duration::from_millis(200));
+            detect_packet_loss_and_window_shrink(&connection, seq, 1, 1460, 65535, 0, &mut stats);
+            seq += 1460;
+        }
+        // 逆方向の純粋なACKはバーストに含めない
+        detect_packet_loss_and_window_shrink(&connection.reversed(), 1, seq, 0, 65535, 0, &mut stats);
+        
+        observe_connection_bursts(&mut stats, clock.now_instant());
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 14600.0);
+        
+        // 次の間隔は新たに数え、データのない間隔は記録しない
+        detect_packet_loss_and_window_shrink(&connection, seq, 1, 100, 65535, 0, &mut stats);
+        observe_connection_bursts(&mut stats, clock.now_instant());
+        observe_connection_bursts(&mut stats, clock.now_instant());
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 14700.0);
+    }
+    
+    #[test]
+    fn loss_is_attributed_to_the_service_port() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let web = test_connection();
+        let database = TcpConnection {
+            src_ip: "198.51.100.20".to_string(),
+            dst_ip: "192.0.2.30".to_string(),
+            src_port: 41000,
+            dst_port: 5432,
+        };
+        let peer_to_peer = TcpConnection {
+            src_port: 40000,
+            dst_port: 50000,
+            ..test_connection()
+        };
+        
+        for connection in [&web, &database, &peer_to_peer] {
+            detect_packet_loss_and_window_shrink(connection, 1000, 1, 100, 65535, 0, &mut stats);
+            detect_packet_loss_and_window_shrink(connection, 1300, 1, 100, 65535, 0, &mut stats);
+        }
+        // 逆向きのデータも同じサービスのロスとして数える
+        detect_packet_loss_and_window_shrink(&web.reversed(), 5000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&web.reversed(), 5100, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&web.reversed(), 5100, 1, 100, 65535, 0, &mut stats);
+        
+        let port = |label: &str| stats.prometheus_metrics.packet_loss_port_counter.with_label_values(&[label]).get();
+        assert_eq!(port("443"), 2.0);
+        assert_eq!(port("5432"), 1.0);
+        assert_eq!(port("other"), 1.0);
+        assert_eq!(port("50000"), 0.0);
+    }
+
+    #[test]
+    fn flow_records_cover_both_directions_of_active_connections() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let idle = TcpConnection {
+            src_port: 40000,
+            ..test_connection()
+        };
+        detect_packet_loss_and_window_shrink(&idle, 1, 1, 10, 65535, 0, &mut stats);
+        clock.advance(stats.active_window + Duration::from_secs(1));
+        
+        let web = test_connection();
+        detect_packet_loss_and_window_shrink(&web, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&web, 1300, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&web.reversed(), 5000, 1, 40, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&web.reversed(), 5000, 1, 40, 65535, 0, &mut stats);
+        
+        // 最後のパケットが --active-window より前の接続は書き出さない
+        let records = flow_records(&stats);
+        let summary: Vec<(&str, u64, u64, u64, u64)> = records
+            .iter()
+            .map(|record| (record.connection.as_str(), record.packets, record.bytes, record.missing, record.duplicate))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("198.51.100.20:50000-203.0.113.10:443", 2, 80, 0, 1),
+                ("203.0.113.10:443-198.51.100.20:50000", 2, 200, 1, 0),
+            ]
+        );
+        assert!(records.iter().all(|record| record.last_seen == stats.clock.now_utc()));
+    }
+    
+    #[test]
+    fn metric_prefix_is_prepended_to_every_name() {
+        assert_eq!(parse_metric_prefix("edge1_"), Ok("edge1".to_string()));
+        assert!(parse_metric_prefix("1edge").is_err());
+        assert!(parse_metric_prefix("edge-1").is_err());
+
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--metric-prefix", "edge1"]).unwrap();
+        let names: Vec<String> = metrics_from_args(&args)
+            .unwrap()
+            .registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert!(names.contains(&"edge1_tcp_monitor_start_time_seconds".to_string()));
+        assert!(names.contains(&"edge1_capture_reconnects_total".to_string()));
+        assert!(names.iter().all(|name| name.starts_with("edge1_")));
+
+        // 未指定の場合は従来どおりの名前
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        let metrics = metrics_from_args(&args).unwrap();
+        assert!(metrics.registry.gather().iter().any(|family| family.get_name() == "tcp_monitor_start_time_seconds"));
+    }
+
+    #[test]
+    fn scan_targets_are_merged_across_worker_shards() {
+        let (clock, stats) = stats_with_mock_clock();
+        let stats = Arc::new(Mutex::new(GlobalStats {
+            scan_detector: ScanDetector::new(Duration::from_secs(10), 2),
+            ..stats
+        }));
+        // 同じ送信元のSYNが別々のワーカーのシャードに記録される
+        {
+            let mut stats_guard = stats.lock().unwrap();
+            for dst_port in [22, 23] {
+                let mut shard = stats_guard.worker_shard();
+                shard.scan_detector.observe("203.0.113.10", "198.51.100.20", dst_port, true, false, clock.now_instant());
+                stats_guard.worker_shards.push(Arc::new(Mutex::new(shard)));
+            }
+        }
+        print_statistics(&stats);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.prometheus_metrics.scan_suspected_sources_gauge.with_label_values(&["203.0.113.10"]).get(), 2.0);
+    }
+
+    #[test]
+    fn console_lines_show_resolved_hostnames() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        let suspected_sources = [("192.0.2.7".to_string(), 120)];
+        
+        // `--resolve-dns` を指定していない場合はIPアドレスのみ
+        assert_eq!(
+            per_ip_console_lines(&stats, &suspected_sources),
+            [
+                "ロス: 203.0.113.10:443 -> 198.51.100.20:50000 1 回",
+                "スキャンの疑い: 192.0.2.7 （未完了の宛先 120 件）",
+            ]
+        );
+        
+        // 解決済みのアドレスはホスト名を併記し、未解決のアドレスはIPアドレスのまま表示する
+        stats.reverse_dns = Some(ReverseDnsCache::with_resolved(&[
+            ("203.0.113.10".parse().unwrap(), "edge.example.net"),
+            ("192.0.2.7".parse().unwrap(), "scanner.example.org"),
+        ]));
+        assert_eq!(
+            per_ip_console_lines(&stats, &suspected_sources),
+            [
+                "ロス: 203.0.113.10 (edge.example.net):443 -> 198.51.100.20:50000 1 回",
+                "スキャンの疑い: 192.0.2.7 (scanner.example.org) （未完了の宛先 120 件）",
+            ]
+        );
+    }
+
+    #[test]
+    fn hostname_label_only_exists_with_resolve_dns() {
+        let stats = GlobalStats::with_metrics(Arc::new(MockClock::new()), PrometheusMetrics::new(DEFAULT_GAP_BUCKETS, None, false).unwrap());
+        assert_eq!(hostname_label(&stats, "203.0.113.10"), None);
+        let gauge = &stats.prometheus_metrics.scan_suspected_sources_gauge;
+        assert!(gauge.get_metric_with_label_values(&["203.0.113.10"]).is_ok());
+        assert!(gauge.get_metric_with_label_values(&["203.0.113.10", ""]).is_err());
+        
+        let metrics = PrometheusMetrics::new(DEFAULT_GAP_BUCKETS, None, true).unwrap();
+        assert!(metrics.scan_suspected_sources_gauge.get_metric_with_label_values(&["203.0.113.10", ""]).is_ok());
+    }
+    
+    #[test]
+    fn gap_buckets_are_configurable() {
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        assert_eq!(args.gap_buckets, DEFAULT_GAP_BUCKETS);
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--gap-buckets", "1,10,100,1000,10000"]).unwrap();
+        assert_eq!(args.gap_buckets, [1.0, 10.0, 100.0, 1000.0, 10000.0]);
+        assert!(PrometheusMetrics::new(&[100.0, 10.0], None, false).is_err());
+
+        let mut stats = GlobalStats::with_metrics(Arc::new(MockClock::new()), metrics_from_args(&args).unwrap());
+        let connection = test_connection();
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 7100, 1, 100, 65535, 0, &mut stats);
+
+        let histogram = stats.prometheus_metrics.packet_loss_gap_histogram.clone();
+        let metric = prometheus::core::Collector::collect(&histogram)[0].get_metric()[0].get_histogram().clone();
+        assert_eq!(metric.get_sample_count(), 1);
+        assert_eq!(metric.get_sample_sum(), 6000.0);
+        let counts: Vec<u64> = metric.get_bucket().iter().map(|bucket| bucket.get_cumulative_count()).collect();
+        assert_eq!(counts, [0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn prometheus_counters_survive_interval_resets() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        // 欠損 → 統計リセット → 重複 → 統計リセット → 欠損とウィンドウ縮小
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_secs(1));
+        reset_interval_stats(&mut stats, clock.now_instant());
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_secs(1));
+        reset_interval_stats(&mut stats, clock.now_instant());
+        detect_packet_loss_and_window_shrink(&connection, 2000, 1, 100, 1000, 0, &mut stats);
+
+        let metrics = &stats.prometheus_metrics;
+        assert_eq!(metrics.packet_loss_missing_counter.get(), 2.0);
+        assert_eq!(metrics.packet_loss_duplicate_counter.get(), 1.0);
+        assert_eq!(metrics.packet_loss_out_of_order_counter.get(), 0.0);
+        assert_eq!(metrics.window_shrink_counter.get(), 1.0);
+
+        // コンソール用の値は直近の間隔分のみ
+        let counts = collect_interval_counts(&stats, clock.now_instant());
+        assert_eq!(counts.missing, 1);
+        assert_eq!(counts.duplicate, 0);
+        assert_eq!(counts.window_shrink, 1);
+    }
+
+    #[test]
+    fn segment_len_ignores_snaplen_truncation() {
+        use pnet::packet::ipv4::MutableIpv4Packet;
+
+        // 20バイトのIPヘッダ + 20バイトのTCPヘッダ + 1460バイトのペイロードのうち、
+        // 先頭の20バイトのTCPヘッダまでしかキャプチャされていないパケット
+        let mut buffer = [0u8; 40];
+        let mut ipv4 = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(1500);
+
+        assert_eq!(tcp_segment_len(&ipv4.to_immutable()), 1480);
+    }
+
+    #[test]
+    fn sequence_wraparound_is_not_loss() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        // 2^32の直前から開始し、折り返しを跨いで順番通りに送る
+        let mut seq = u32::MAX - 2000;
+        for _ in 0..10 {
+            detect_packet_loss_and_window_shrink(&connection, seq, 1, 500, 65535, 0, &mut stats);
+            seq = seq.wrapping_add(500);
+        }
+
+        assert!(stats.packet_loss_events.is_empty());
+        assert_eq!(stats.prometheus_metrics.packet_loss_missing_counter.get(), 0.0);
+
+        // 折り返し後の欠損は通常どおり検出する
+        detect_packet_loss_and_window_shrink(&connection, seq.wrapping_add(500), 1, 500, 65535, 0, &mut stats);
+        assert_eq!(stats.packet_loss_events.len(), 1);
+        assert_eq!(stats.packet_loss_events[0].gap_size, 500);
+    }
+
+    #[test]
+    fn consecutive_losses_are_coalesced_within_the_window() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        stats.coalesce_window = Duration::from_millis(100);
+        let connection = test_connection();
+        
+        // 50ミリ秒ごとの欠損は、直前のイベントから100ミリ秒以内なので1つにまとまり続ける
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        for seq in [1300, 1500, 1900] {
+            clock.advance(Duration::from_millis(50));
+            detect_packet_loss_and_window_shrink(&connection, seq, 1, 100, 65535, 0, &mut stats);
+        }
+        assert_eq!(stats.packet_loss_events.len(), 1);
+        let event = &stats.packet_loss_events[0];
+        assert_eq!(event.count, 3);
+        assert_eq!((event.min_gap_size, event.max_gap_size), (100, 300));
+        assert_eq!(event.expected_seq, 1100);
+        assert_eq!(event.last_timestamp - event.timestamp, chrono::Duration::milliseconds(100));
+        assert_eq!(direction_state(&stats, &connection).loss_events.len(), 1);
+        
+        // 種類が違うイベントや、間隔が空いたイベントは別に記録する
+        detect_packet_loss_and_window_shrink(&connection, 1900, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(150));
+        detect_packet_loss_and_window_shrink(&connection, 2100, 1, 100, 65535, 0, &mut stats);
+        assert_eq!(stats.packet_loss_events.len(), 3);
+        
+        // 間隔内の件数とカウンタはまとめる前と同じ
+        let counts = collect_interval_counts(&stats, clock.now_instant());
+        assert_eq!((counts.missing, counts.duplicate), (4, 1));
+        assert_eq!(stats.prometheus_metrics.packet_loss_missing_counter.get(), 4.0);
+    }
+    
+    #[test]
+    fn gap_ceiling_is_configurable() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        // 既定の上限では2MBのギャップは欠損とみなさない
+        detect_packet_loss_and_window_shrink(&connection, 0, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 2_000_100, 1, 100, 65535, 0, &mut stats);
+        assert!(stats.packet_loss_events.is_empty());
+
+        stats.max_plausible_gap = Some(4_000_000);
+        detect_packet_loss_and_window_shrink(&connection, 4_000_200, 1, 100, 65535, 0, &mut stats);
+        assert_eq!(stats.packet_loss_events.len(), 1);
+        assert_eq!(stats.packet_loss_events[0].gap_size, 2_000_000);
+    }
+
+    #[test]
+    fn acks_beyond_the_highest_sent_sequence_are_invalid() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let data = test_connection();
+        let ack = data.reversed();
+        
+        // 逆方向のデータを観測していない間は判定しない
+        assert!(!is_invalid_ack(&stats, &ack, 5000));
+        
+        detect_packet_loss_and_window_shrink(&data, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&data, 1100, 1, 100, 65535, 0, &mut stats);
+        assert!(!is_invalid_ack(&stats, &ack, 1200));
+        assert!(is_invalid_ack(&stats, &ack, 1201));
+        assert!(is_invalid_ack(&stats, &ack, 900_000));
+        // 順序違いで古いセグメントが届いても、最大のシーケンス番号は下がらない
+        detect_packet_loss_and_window_shrink(&data, 1000, 1, 100, 65535, 0, &mut stats);
+        assert!(!is_invalid_ack(&stats, &ack, 1200));
+        
+        // データとFINのセグメントへのACKは、FINが消費するシーケンス番号の分だけ先まで正当
+        detect_packet_loss_and_window_shrink(&data, 1200, 1, 100, 65535, TcpFlags::FIN, &mut stats);
+        assert!(!is_invalid_ack(&stats, &ack, 1301));
+        assert!(is_invalid_ack(&stats, &ack, 1302));
+    }
+    
+    #[test]
+    fn reverse_acks_resolve_pending_gaps() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let data = test_connection();
+        let ack = TcpConnection {
+            src_ip: data.dst_ip.clone(),
+            dst_ip: data.src_ip.clone(),
+            src_port: data.dst_port,
+            dst_port: data.src_port,
+        };
+
+        detect_packet_loss_and_window_shrink(&data, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&ack, 1, 1100, 0, 65535, 0, &mut stats);
+        correlate_reverse_ack(&ack, 1100, &mut stats);
+
+        // 1100-1200を取りこぼしたが、受信側は再送なしで1300までACKした
+        detect_packet_loss_and_window_shrink(&data, 1200, 1, 100, 65535, 0, &mut stats);
+        assert!(stats.packet_loss_events.is_empty());
+        correlate_reverse_ack(&ack, 1300, &mut stats);
+        assert!(direction_state(&stats, &data).pending_gaps.is_empty());
+
+        // 1300-1400が失われ、受信側は1300への重複ACKを返し続ける
+        detect_packet_loss_and_window_shrink(&data, 1400, 1, 100, 65535, 0, &mut stats);
+        for _ in 0..DUPLICATE_ACK_THRESHOLD {
+            correlate_reverse_ack(&ack, 1300, &mut stats);
+        }
+        assert_eq!(stats.packet_loss_events.len(), 1);
+        assert_eq!(stats.packet_loss_events[0].expected_seq, 1300);
+        // 高速再送のきっかけは3回目の重複ACKでのみ数える
+        correlate_reverse_ack(&ack, 1300, &mut stats);
+        assert_eq!(stats.prometheus_metrics.dup_acks_counter.get(), 4.0);
+        assert_eq!(stats.prometheus_metrics.fast_retransmit_triggers_counter.get(), 1.0);
+
+        // 1500-1600が失われ、再送が観測されたら欠損と確定する（順序違いには数えない）
+        detect_packet_loss_and_window_shrink(&data, 1600, 1, 100, 65535, 0, &mut stats);
+        assert_eq!(stats.packet_loss_events.len(), 1);
+        detect_packet_loss_and_window_shrink(&data, 1500, 1, 100, 65535, 0, &mut stats);
+        assert_eq!(stats.prometheus_metrics.packet_loss_missing_counter.get(), 2.0);
+        assert_eq!(stats.prometheus_metrics.packet_loss_out_of_order_counter.get(), 0.0);
+    }
+
+    #[test]
+    fn ack_delay_is_measured_from_the_last_data_segment() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let client = test_connection();
+        let server = client.reversed();
+        let send = |stats: &mut GlobalStats, connection: &TcpConnection, seq: u32, ack: u32, flags: u8| {
+            detect_packet_loss_and_window_shrink(connection, seq, ack, 0, 65535, flags, stats);
+            observe_handshake(stats, connection, flags & TcpFlags::SYN != 0, flags & TcpFlags::ACK != 0);
+        };
+
+        // モニタからサーバーまで5ミリ秒、クライアントまで1ミリ秒の往復
+        send(&mut stats, &client, 999, 0, TcpFlags::SYN);
+        clock.advance(Duration::from_millis(5));
+        send(&mut stats, &server, 4999, 1000, TcpFlags::SYN | TcpFlags::ACK);
+        clock.advance(Duration::from_millis(1));
+        send(&mut stats, &client, 1000, 5000, TcpFlags::ACK);
+        detect_packet_loss_and_window_shrink(&client, 1000, 5000, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(40));
+        detect_packet_loss_and_window_shrink(&server, 5000, 1100, 0, 65535, 0, &mut stats);
+
+        // 遅延ACKで2セグメントをまとめて確認する場合は、最後のセグメントから計測する
+        detect_packet_loss_and_window_shrink(&client, 1100, 5000, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(10));
+        detect_packet_loss_and_window_shrink(&client, 1200, 5000, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(20));
+        // 途中までのACKは計測しない
+        detect_packet_loss_and_window_shrink(&server, 5000, 1200, 0, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(20));
+        detect_packet_loss_and_window_shrink(&server, 5000, 1300, 0, 65535, 0, &mut stats);
+
+        // 再送されたデータへのACKは、どちらの送信に対するものか分からないため計測しない
+        detect_packet_loss_and_window_shrink(&client, 1300, 5000, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&client, 1300, 5000, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(200));
+        detect_packet_loss_and_window_shrink(&server, 5000, 1400, 0, 65535, 0, &mut stats);
+
+        // サーバーまでの往復の5ミリ秒を差し引く
+        let histogram = &stats.prometheus_metrics.ack_delay_histogram;
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert!((histogram.get_sample_sum() - 0.07).abs() < 1e-9);
+
+        // ハンドシェイクを観測していない接続は往復を差し引けないため計測しない
+        let unseen = TcpConnection {
+            dst_port: 50001,
+            ..test_connection()
+        };
+        detect_packet_loss_and_window_shrink(&unseen.reversed(), 5000, 1000, 0, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&unseen, 1000, 5000, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(40));
+        detect_packet_loss_and_window_shrink(&unseen.reversed(), 5000, 1100, 0, 65535, 0, &mut stats);
+        assert_eq!(stats.prometheus_metrics.ack_delay_histogram.get_sample_count(), 2);
+    }
+
+    #[test]
+    fn bdp_combines_interval_throughput_with_the_handshake_rtt() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let start = clock.now_instant();
+        let client = test_connection();
+        let server = client.reversed();
+        let retried = TcpConnection {
+            dst_port: 50001,
+            ..test_connection()
+        };
+        let send = |stats: &mut GlobalStats, connection: &TcpConnection, seq: u32, ack: u32, len: u32, flags: u8| {
+            detect_packet_loss_and_window_shrink(connection, seq, ack, len, 65535, flags, stats);
+            observe_handshake(stats, connection, flags & TcpFlags::SYN != 0, flags & TcpFlags::ACK != 0);
+        };
+
+        send(&mut stats, &client, 1000, 0, 0, TcpFlags::SYN);
+        send(&mut stats, &retried, 1000, 0, 0, TcpFlags::SYN);
+        clock.advance(Duration::from_millis(30));
+        send(&mut stats, &server, 5000, 1001, 0, TcpFlags::SYN | TcpFlags::ACK);
+        // SYNの再送があると計測しない
+        send(&mut stats, &retried, 1000, 0, 0, TcpFlags::SYN);
+        send(&mut stats, &retried.reversed(), 5000, 1001, 0, TcpFlags::SYN | TcpFlags::ACK);
+        clock.advance(Duration::from_millis(20));
+        send(&mut stats, &client, 1001, 5001, 0, TcpFlags::ACK);
+        send(&mut stats, &retried, 1001, 5001, 0, TcpFlags::ACK);
+        let handshake = |stats: &GlobalStats, connection: &TcpConnection| stats.connection_states[&connection.canonical_key()].handshake;
+        assert_eq!(handshake(&stats, &client).rtt(), Some(Duration::from_millis(50)));
+        assert_eq!(handshake(&stats, &client).rtt_to_sender(server.is_forward()), Some(Duration::from_millis(30)));
+        assert_eq!(handshake(&stats, &client).rtt_to_sender(client.is_forward()), Some(Duration::from_millis(20)));
+        assert_eq!(handshake(&stats, &retried), HandshakeRtt::Unknown);
+
+        for i in 0..10u32 {
+            send(&mut stats, &client, 1001 + i * 1000, 5001, 1000, TcpFlags::ACK);
+            send(&mut stats, &retried, 1001 + i * 1000, 5001, 1000, TcpFlags::ACK);
+        }
+        clock.advance(Duration::from_secs(1) - clock.now_instant().duration_since(start));
+        observe_bandwidth_delay_products(&mut stats, clock.now_instant());
+
+        // 1秒間に10000バイト × 50ミリ秒。データのない向きと往復時間の分からない接続は記録しない
+        let histogram = &stats.prometheus_metrics.bdp_bytes_histogram;
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!((histogram.get_sample_sum() - 500.0).abs() < 1e-6);
+        assert_eq!(direction_state(&stats, &client).interval_bytes, 0);
+    }
+
+    #[test]
+    fn loss_is_aggregated_by_the_remote_network() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let inbound = test_connection();
+        let inbound_neighbour = TcpConnection {
+            src_ip: "203.0.113.77".to_string(),
+            ..test_connection()
+        };
+        let outbound = TcpConnection {
+            src_ip: "198.51.100.20".to_string(),
+            dst_ip: "192.0.2.5".to_string(),
+            src_port: 50001,
+            dst_port: 443,
+        };
+        let undetermined = TcpConnection {
+            src_ip: "198.18.0.1".to_string(),
+            ..test_connection()
+        };
+        let duplicate = |stats: &mut GlobalStats, connection: &TcpConnection, local_sender: Option<bool>, times: u32| {
+            detect_packet_loss_and_window_shrink(connection, 1000, 1, 100, 65535, 0, stats);
+            stats.direction_state_mut(connection).unwrap().local_sender = local_sender;
+            for _ in 0..times {
+                detect_packet_loss_and_window_shrink(connection, 1000, 1, 100, 65535, 0, stats);
+            }
+        };
+        duplicate(&mut stats, &inbound, Some(false), 2);
+        duplicate(&mut stats, &inbound_neighbour, Some(false), 1);
+        duplicate(&mut stats, &outbound, Some(true), 1);
+        duplicate(&mut stats, &undetermined, None, 1);
+        let mut loss_per_network = PathLoss::new();
+        tally_path_loss(&stats, &mut loss_per_network);
+        set_path_loss_gauges(&stats.prometheus_metrics, &loss_per_network);
+
+        // リモートからのデータは送信元、ローカルからのデータは宛先のネットワークで集計する
+        let gauge = &stats.prometheus_metrics.path_loss_gauge;
+        assert_eq!(gauge.with_label_values(&["203.0.113.0/24"]).get(), 3.0);
+        assert_eq!(gauge.with_label_values(&["192.0.2.0/24"]).get(), 1.0);
+        // 同じ接続のロスは何回あっても1接続として数える
+        let connections_gauge = &stats.prometheus_metrics.path_loss_connections_gauge;
+        assert_eq!(connections_gauge.with_label_values(&["203.0.113.0/24"]).get(), 2.0);
+        assert_eq!(connections_gauge.with_label_values(&["192.0.2.0/24"]).get(), 1.0);
+        assert_eq!(remote_network("10.1.2.255").as_deref(), Some("10.1.2.0/24"));
+        assert_eq!(remote_network("::1"), None);
+
+        // ロスのなかった間隔では系列を残さない
+        let current_time = stats.clock.now_instant();
+        reset_interval_stats(&mut stats, current_time);
+        let mut loss_per_network = PathLoss::new();
+        tally_path_loss(&stats, &mut loss_per_network);
+        set_path_loss_gauges(&stats.prometheus_metrics, &loss_per_network);
+        let families = stats.prometheus_metrics.registry.gather();
+        for name in ["tcp_monitor_path_loss", "tcp_monitor_path_loss_connections"] {
+            let path_loss = families.iter().find(|family| family.get_name() == name);
+            assert!(path_loss.is_none_or(|family| family.get_metric().is_empty()));
+        }
+    }
+
+    #[test]
+    fn inflight_bytes_are_sampled_when_the_ack_advances() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let client = test_connection();
+        let server = client.reversed();
+
+        detect_packet_loss_and_window_shrink(&server, 5000, 1000, 0, 65535, 0, &mut stats);
+        for i in 0..5u32 {
+            detect_packet_loss_and_window_shrink(&client, 1000 + i * 100, 5000, 100, 65535, 0, &mut stats);
+        }
+        // 1500まで送信済みで1200までACKされた時点では300バイトが未確認
+        detect_packet_loss_and_window_shrink(&server, 5000, 1200, 0, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&server, 5000, 1500, 0, 65535, 0, &mut stats);
+        // ACKが進まない重複ACKでは記録しない
+        detect_packet_loss_and_window_shrink(&server, 5000, 1500, 0, 65535, 0, &mut stats);
+
+        let histogram = &stats.prometheus_metrics.inflight_bytes_histogram;
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 300.0);
+
+        // 観測していないデータまでのACKや、ウィンドウからあり得ない値は使わない
+        assert_eq!(bytes_in_flight(1500, 1600, 65535), None);
+        assert_eq!(bytes_in_flight(u32::MAX / 4, 0, 65535), None);
+    }
+
+    #[test]
+    fn both_directions_share_one_entry() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let client = test_connection();
+        let server = client.reversed();
+        assert_eq!(client.canonical_key(), server.canonical_key());
+        assert_ne!(client.is_forward(), server.is_forward());
+
+        detect_packet_loss_and_window_shrink(&client, 1000, 5000, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_secs(1));
+        detect_packet_loss_and_window_shrink(&server, 5000, 1100, 200, 32768, 0, &mut stats);
+
+        // 1つのエントリに向きごとの状態を持つ
+        assert_eq!(stats.connection_states.len(), 1);
+        assert_eq!(direction_state(&stats, &client).expected_seq, 1100);
+        assert_eq!(direction_state(&stats, &server).expected_seq, 5200);
+        assert_eq!(direction_state(&stats, &server).max_window_size, 32768);
+
+        // 両方向のFINでも継続時間は接続につき1回だけ記録する
+        finish_connection(&mut stats, &client, false);
+        finish_connection(&mut stats, &server, false);
+        let histogram = &stats.prometheus_metrics.connection_duration_histogram;
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 1.0);
+        assert_eq!(count_active_connections(&stats), 1);
+    }
+
+    #[test]
+    fn connection_duration_is_recorded_once() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let closed = test_connection();
+        let idle = TcpConnection {
+            dst_port: 50001,
+            ..test_connection()
+        };
+
+        detect_packet_loss_and_window_shrink(&closed, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&idle, 1000, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_secs(2));
+        detect_packet_loss_and_window_shrink(&idle, 1100, 1, 100, 65535, 0, &mut stats);
+        finish_connection(&mut stats, &closed, false);
+        finish_connection(&mut stats, &closed, false);
+
+        let histogram = stats.prometheus_metrics.connection_duration_histogram.clone();
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 2.0);
+
+        // アイドルで削除された接続は最後のパケットまでの時間を記録する
+        clock.advance(CONNECTION_IDLE_TIMEOUT);
+        evict_idle_connections(&mut stats);
+        assert!(stats.connection_states.is_empty());
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 4.0);
+        // FIN済みの接続も状態はアイドルで削除されるため、削除数に含まれる
+        assert_eq!(stats.prometheus_metrics.connections_reaped_counter.get(), 2.0);
+    }
+
+    #[test]
+    fn closed_connections_are_reaped_after_the_linger() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let both_fin = test_connection();
+        let reset = TcpConnection {
+            dst_port: 50001,
+            ..test_connection()
+        };
+        let half_closed = TcpConnection {
+            dst_port: 50002,
+            ..test_connection()
+        };
+        for connection in [&both_fin, &reset, &half_closed] {
+            detect_packet_loss_and_window_shrink(connection, 1000, 1, 100, 65535, 0, &mut stats);
+            detect_packet_loss_and_window_shrink(&connection.reversed(), 1, 1100, 0, 65535, 0, &mut stats);
+        }
+
+        finish_connection(&mut stats, &both_fin, false);
+        finish_connection(&mut stats, &both_fin.reversed(), false);
+        finish_connection(&mut stats, &reset, true);
+        // 同じ向きのFINの再送では閉じない
+        finish_connection(&mut stats, &half_closed, false);
+        finish_connection(&mut stats, &half_closed, false);
+        let phase = |stats: &GlobalStats, connection: &TcpConnection| stats.connection_states[&connection.canonical_key()].phase;
+        assert_eq!(phase(&stats, &both_fin), ConnectionPhase::Closed);
+        assert_eq!(phase(&stats, &reset), ConnectionPhase::Closed);
+        assert_eq!(phase(&stats, &half_closed), ConnectionPhase::Closing { fin_forward: half_closed.is_forward() });
+
+        // 閉じた接続はアイドルのタイムアウトを待たずに削除する
+        clock.advance(CLOSED_LINGER);
+        evict_idle_connections(&mut stats);
+        assert_eq!(stats.connection_states.len(), 1);
+        assert!(stats.connection_states.contains_key(&half_closed.canonical_key()));
+        assert_eq!(stats.prometheus_metrics.connections_reaped_counter.get(), 2.0);
+        assert_eq!(half_closed_connections(&stats), 1);
+        // 継続時間は最初のFIN/RSTで1回ずつ記録済み
+        assert_eq!(stats.prometheus_metrics.connection_duration_histogram.get_sample_count(), 3);
+
+        clock.advance(CONNECTION_IDLE_TIMEOUT);
+        evict_idle_connections(&mut stats);
+        assert!(stats.connection_states.is_empty());
+        assert_eq!(half_closed_connections(&stats), 0);
+    }
+
+    #[test]
+    fn reorder_distance_is_measured_in_segments() {
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        for i in 0..6u32 {
+            detect_packet_loss_and_window_shrink(&connection, 1000 + i * 100, 1, 100, 65535, 0, &mut stats);
+        }
+        // 期待値1600に対し、2セグメント分と4セグメント分遅れて届く
+        // （短いセグメントでも距離は観測した最大のセグメント長で換算する）
+        detect_packet_loss_and_window_shrink(&connection, 1450, 1, 50, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1200, 1, 100, 65535, 0, &mut stats);
+
+        let histogram = &stats.prometheus_metrics.reorder_distance_histogram;
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 2.0 + 4.0);
+        assert_eq!(reorder_distance(1, 1460), 1);
+        assert_eq!(reorder_distance(1460 * 3, 1460), 3);
+    }
+
+    #[test]
+    fn window_scale_applies_after_both_syns() {
+        use pnet::packet::tcp::{MutableTcpPacket, TcpOption};
+
+        // MSS 1460、ウィンドウスケール7のオプションを持つSYN
+        let mut buffer = [0u8; 32];
+        let mut syn = MutableTcpPacket::new(&mut buffer).unwrap();
+        syn.set_data_offset(8);
+        syn.set_options(&[TcpOption::mss(1460), TcpOption::wscale(7), TcpOption::nop()]);
+        assert_eq!(syn_options(&syn.to_immutable()), (Some(1460), Some(7)));
+
+        let (_clock, mut stats) = stats_with_mock_clock();
+        let client = test_connection();
+        let server = TcpConnection {
+            src_ip: client.dst_ip.clone(),
+            dst_ip: client.src_ip.clone(),
+            src_port: client.dst_port,
+            dst_port: client.src_port,
+        };
+        for connection in [&client, &server] {
+            detect_packet_loss_and_window_shrink(connection, 0, 0, 0, 65535, 0, &mut stats);
+            let state = stats.direction_state_mut(connection).unwrap();
+            state.window_scale = Some(7);
+        }
+
+        // 生の値が65535→1000でも、スケール後は128000バイトと比較する
+        detect_packet_loss_and_window_shrink(&client, 1, 1, 0, 1000, 0, &mut stats);
+        assert_eq!(direction_state(&stats, &client).last_window_size, 1000 << 7);
+        assert_eq!(stats.prometheus_metrics.window_shrink_counter.get(), 0.0);
+        detect_packet_loss_and_window_shrink(&client, 1, 1, 0, 500, 0, &mut stats);
+        assert_eq!(stats.prometheus_metrics.window_shrink_counter.get(), 1.0);
+    }
+
+    #[test]
+    fn derived_gap_ceiling_follows_receiver_window() {
+        assert_eq!(derived_max_plausible_gap(None), DEFAULT_MAX_PLAUSIBLE_GAP);
+        assert_eq!(derived_max_plausible_gap(Some(65535)), DEFAULT_MAX_PLAUSIBLE_GAP);
+        assert_eq!(derived_max_plausible_gap(Some(8 << 20)), 16 << 20);
+    }
+
+    #[test]
+    fn sampling_keeps_both_directions_of_a_connection() {
+        let forward = test_connection();
+        let backward = TcpConnection {
+            src_ip: forward.dst_ip.clone(),
+            dst_ip: forward.src_ip.clone(),
+            src_port: forward.dst_port,
+            dst_port: forward.src_port,
+        };
+
+        for sample_rate in 1..20 {
+            assert_eq!(forward.is_sampled(sample_rate), backward.is_sampled(sample_rate));
+        }
+        assert!(forward.is_sampled(1));
+        assert_eq!(parse_sample_ratio("1:10"), Ok(10));
+        assert!(parse_sample_ratio("1:0").is_err());
+        assert!(parse_sample_ratio("10").is_err());
+    }
+
+    #[test]
+    fn excluded_packets_are_not_counted() {
+        use pnet::packet::ethernet::MutableEthernetPacket;
+        use pnet::packet::ipv4::MutableIpv4Packet;
+
+        // SSHパケット
+        let frame = tcp_frame(Ipv4Addr::new(203, 0, 113, 10), Ipv4Addr::new(198, 51, 100, 20), 50000, 22, 0, 0, &[]);
+
+        let by_port = PacketExclusions { ports: vec![22], ..Default::default() };
+        let by_ip = PacketExclusions { ips: vec!["198.51.100.20".parse().unwrap()], ..Default::default() };
+        let other = PacketExclusions { ips: vec!["192.0.2.1".parse().unwrap()], ports: vec![443], focus: None };
+        assert!(by_port.matches(&frame));
+        assert!(by_ip.matches(&frame));
+        assert!(!other.matches(&frame));
+        assert!(!PacketExclusions::default().matches(&frame));
+
+        let (_clock, mut stats) = stats_with_mock_clock();
+        stats.exclusions = by_port;
+        let stats = Arc::new(Mutex::new(stats));
+        process_packet(&frame, &stats);
+        assert_eq!(stats.lock().unwrap().total_packets, 0);
+        assert_eq!(stats.lock().unwrap().tcp_packets, 0);
+        
+        // --focus は2台の間の通信をどちら向きも残し、それ以外と非IPv4のパケットを除外する
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--focus", "198.51.100.20", "203.0.113.10"]).unwrap();
+        let focus = PacketExclusions::from_args(&args);
+        assert!(!focus.matches(&frame));
+        let between = |src: Ipv4Addr, dst: Ipv4Addr| {
+            let mut packet = frame.clone();
+            let mut ipv4 = MutableIpv4Packet::new(&mut packet[14..]).unwrap();
+            ipv4.set_source(src);
+            ipv4.set_destination(dst);
+            packet
+        };
+        assert!(!focus.matches(&between(Ipv4Addr::new(198, 51, 100, 20), Ipv4Addr::new(203, 0, 113, 10))));
+        assert!(focus.matches(&between(Ipv4Addr::new(198, 51, 100, 20), Ipv4Addr::new(192, 0, 2, 1))));
+        let mut arp = frame.clone();
+        MutableEthernetPacket::new(&mut arp).unwrap().set_ethertype(EtherTypes::Arp);
+        assert!(focus.matches(&arp));
+        assert!(Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--focus", "198.51.100.20"]).is_err());
+    }
+
+    #[test]
+    fn packets_with_truncated_headers_are_skipped() {
+        use pnet::packet::ipv4::MutableIpv4Packet;
+        use pnet::packet::tcp::MutableTcpPacket;
+        
+        // 1000バイトのペイロードを持つセグメントを、ヘッダまで（54バイト）に切り詰める
+        let frame = |ihl: u8, data_offset: u8| {
+            let mut frame = tcp_frame(Ipv4Addr::new(203, 0, 113, 10), Ipv4Addr::new(198, 51, 100, 20), 443, 50000, 0, TcpFlags::ACK, &[0; 1000]);
+            frame.truncate(54);
+            MutableIpv4Packet::new(&mut frame[14..]).unwrap().set_header_length(ihl);
+            MutableTcpPacket::new(&mut frame[34..]).unwrap().set_data_offset(data_offset);
+            frame
+        };
+        
+        let (_clock, stats) = stats_with_mock_clock();
+        let stats = Arc::new(Mutex::new(stats));
+        // snaplenでペイロードだけが切り詰められたパケットは解析する
+        process_packet(&frame(5, 5), &stats);
+        // TCPヘッダの途中で切れたパケット、IHLが最小値未満、オプションがキャプチャ範囲を超えるもの
+        process_packet(&frame(5, 5)[..44], &stats);
+        process_packet(&frame(4, 5), &stats);
+        process_packet(&frame(5, 8), &stats);
+        
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.total_packets, 4);
+        assert_eq!(stats.tcp_packets, 1);
+        assert_eq!(stats.prometheus_metrics.truncated_packets_counter.get(), 3.0);
+    }
+    
+    #[test]
+    fn worker_pool_analyses_connections_like_the_capture_thread() {
+        // ペイロード100バイトのセグメント
+        let frame = |dst_port: u16, seq: u32| {
+            tcp_frame(Ipv4Addr::new(203, 0, 113, 10), Ipv4Addr::new(198, 51, 100, 20), 443, dst_port, seq, TcpFlags::ACK, &[0; 100])
+        };
+        
+        // 同じ接続の両方向は同じワーカーに渡る
+        let CapturedPacket::Tcp(segment) = CapturedPacket::parse(&frame(50000, 1000)) else {
+            panic!("TCPセグメントとして読み取れませんでした");
+        };
+        let reverse = TcpSegment {
+            src_ip: segment.dst_ip,
+            dst_ip: segment.src_ip,
+            src_port: segment.dst_port,
+            dst_port: segment.src_port,
+            ..segment.clone()
+        };
+        assert_eq!(CapturedPacket::Tcp(segment).shard(), CapturedPacket::Tcp(reverse).shard());
+        
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--workers", "2"]).unwrap();
+        assert_eq!(args.worker_queue, 65536);
+        assert!(Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--worker-queue", "0"]).is_err());
+        let (_clock, stats) = stats_with_mock_clock();
+        let stats = Arc::new(Mutex::new(stats));
+        let dispatcher = PacketDispatcher::new(&stats, &args).unwrap();
+        // 2つの接続がそれぞれ1セグメントずつ欠損する
+        for dst_port in [50000, 50001] {
+            for seq in [1000, 1100, 1300] {
+                dispatcher.dispatch(&frame(dst_port, seq));
+            }
+        }
+        dispatcher.dispatch(&frame(50000, 1400)[..44]);
+        dispatcher.finish();
+        
+        // 接続の状態はワーカーのシャードに分かれ、カウンタは統計出力で全体の統計に集約する
+        {
+            let stats = stats.lock().unwrap();
+            assert!(stats.connection_states.is_empty());
+            assert_eq!(stats.total_packets, 0);
+            let shard_connections: Vec<usize> = stats.worker_shards.iter().map(|shard| shard.lock().unwrap().connection_states.len()).collect();
+            assert_eq!(shard_connections.iter().sum::<usize>(), 2);
+        }
+        print_statistics(&stats);
+        let stats = stats.lock().unwrap();
+        let metrics = &stats.prometheus_metrics;
+        assert_eq!(stats.total_packets, 7);
+        assert_eq!(stats.tcp_packets, 6);
+        assert_eq!(metrics.connection_map_size_gauge.get(), 2.0);
+        assert_eq!(metrics.packet_loss_missing_counter.get(), 2.0);
+        assert_eq!(metrics.truncated_packets_counter.get(), 1.0);
+        assert_eq!(metrics.worker_queue_dropped_counter.get(), 0.0);
+    }
+    
+    #[test]
+    fn ecn_marks_are_counted_outside_the_handshake() {
+        use pnet::packet::ipv4::MutableIpv4Packet;
+        
+        let frame = |ecn: u8, flags: u8| {
+            let mut frame = tcp_frame(Ipv4Addr::new(203, 0, 113, 10), Ipv4Addr::new(198, 51, 100, 20), 443, 50000, 0, flags, &[]);
+            MutableIpv4Packet::new(&mut frame[14..]).unwrap().set_ecn(ecn);
+            frame
+        };
+        
+        let (_clock, stats) = stats_with_mock_clock();
+        let stats = Arc::new(Mutex::new(stats));
+        // ECNのネゴシエーション（SYN+ECE+CWR、SYN-ACK+ECE）
+        process_packet(&frame(0, TcpFlags::SYN | TcpFlags::ECE | TcpFlags::CWR), &stats);
+        process_packet(&frame(0, TcpFlags::SYN | TcpFlags::ACK | TcpFlags::ECE), &stats);
+        // 経路上でCEマークされたセグメントと、それに応える受信側のECE・送信側のCWR
+        process_packet(&frame(ECN_CE, TcpFlags::ACK), &stats);
+        process_packet(&frame(0b10, TcpFlags::ACK | TcpFlags::ECE), &stats);
+        process_packet(&frame(0b10, TcpFlags::ACK | TcpFlags::CWR), &stats);
+        
+        let stats = stats.lock().unwrap();
+        let metrics = &stats.prometheus_metrics;
+        assert_eq!(metrics.ecn_ce_marked_counter.get(), 1.0);
+        assert_eq!(metrics.tcp_ece_counter.get(), 1.0);
+        assert_eq!(metrics.tcp_cwr_counter.get(), 1.0);
+    }
+    
+    #[test]
+    fn ttl_shifts_within_a_flow_suggest_a_route_change() {
+        use pnet::packet::ipv4::MutableIpv4Packet;
+        
+        let frame = |ttl: u8, src_port: u16| {
+            let mut frame = tcp_frame(Ipv4Addr::new(203, 0, 113, 10), Ipv4Addr::new(198, 51, 100, 20), src_port, 50000, 0, TcpFlags::ACK, &[]);
+            MutableIpv4Packet::new(&mut frame[14..]).unwrap().set_ttl(ttl);
+            frame
+        };
+        
+        let (_clock, stats) = stats_with_mock_clock();
+        let stats = Arc::new(Mutex::new(stats));
+        // しきい値（2ホップ）以内の揺れは数えない
+        for ttl in [54, 54, 53, 55] {
+            process_packet(&frame(ttl, 443), &stats);
+        }
+        // 経路が変わり、ホップ数が大きく減った
+        process_packet(&frame(60, 443), &stats);
+        process_packet(&frame(60, 443), &stats);
+        // 同じ送信元でも別のフローのTTLとは比較しない
+        process_packet(&frame(54, 8443), &stats);
+        
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.prometheus_metrics.route_change_suspected_counter.get(), 1.0);
+    }
+    
+    #[test]
+    fn cgnat_range_is_not_global() {
+        assert!(!is_private_ipv4(Ipv4Addr::new(100, 63, 255, 255)));
+        assert!(is_private_ipv4(Ipv4Addr::new(100, 64, 0, 0)));
+        assert!(is_private_ipv4(Ipv4Addr::new(100, 100, 1, 1)));
+        assert!(is_private_ipv4(Ipv4Addr::new(100, 127, 255, 255)));
+        assert!(!is_private_ipv4(Ipv4Addr::new(100, 128, 0, 0)));
+    }
+
+    #[test]
+    fn subnet_overrides_take_precedence_over_private_ranges() {
+        let args = Args::try_parse_from([
+            "tcp_window_monitor", "-i", "eth0",
+            "--global-subnet", "10.0.0.0/8",
+            "--local-subnet", "10.1.0.0/16",
+            "--local-subnet", "192.0.2.0/24",
+        ])
+        .unwrap();
+        let overrides = SubnetOverrides::from_args(&args);
+        assert_eq!(overrides.is_local("10.2.3.4".parse().unwrap()), Some(false));
+        // 重なる範囲はプレフィックスの長い方を優先する
+        assert_eq!(overrides.is_local("10.1.2.3".parse().unwrap()), Some(true));
+        assert_eq!(overrides.is_local("192.0.2.1".parse().unwrap()), Some(true));
+        assert_eq!(overrides.is_local("192.168.1.1".parse().unwrap()), None);
+
+        // トンネル越しのプライベートアドレス同士の通信をグローバルとして数える
+        assert!(is_global_connection_with_interface("10.2.3.4", "10.3.0.1", None, &overrides));
+        assert!(!is_global_connection_with_interface("10.2.3.4", "10.1.0.1", None, &overrides));
+        assert!(!is_global_connection_with_interface("10.2.3.4", "10.3.0.1", None, &SubnetOverrides::default()));
+        assert!(Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--global-subnet", "10.0.0.0/33"]).is_err());
+    }
+
+    #[test]
+    fn in_order_stream_has_no_loss_events() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        for i in 0..5u32 {
+            detect_packet_loss_and_window_shrink(&connection, 1000 + i * 100, 1, 100, 65535, 0, &mut stats);
+            clock.advance(Duration::from_millis(100));
+        }
+
+        let counts = collect_interval_counts(&stats, clock.now_instant());
+        assert_eq!(counts, IntervalCounts::default());
+        assert_eq!(direction_state(&stats, &connection).last_seen, clock.now_utc() - chrono::Duration::milliseconds(100));
+    }
+
+    #[test]
+    fn loss_is_suppressed_during_the_warmup_packets() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+        let args = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0", "--warmup-packets", "3"]).unwrap();
+        stats.warmup_packets = args.warmup_packets;
+
+        // 最初の3パケットのギャップと重複は期待値の更新のみ行う
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        assert!(stats.packet_loss_events.is_empty());
+        assert_eq!(direction_state(&stats, &connection).expected_seq, 1400);
+        assert_eq!(stats.prometheus_metrics.warmup_suppressed_counter.get(), 2.0);
+
+        detect_packet_loss_and_window_shrink(&connection, 1600, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&connection, 1600, 1, 100, 65535, 0, &mut stats);
+        let counts = collect_interval_counts(&stats, clock.now_instant());
+        assert_eq!((counts.missing, counts.duplicate), (1, 1));
+        assert_eq!(stats.packet_loss_events[0].expected_seq, 1400);
+        assert_eq!(stats.prometheus_metrics.warmup_suppressed_counter.get(), 2.0);
+
+        // ウォームアップは向きごとに数える
+        let reverse = connection.reversed();
+        detect_packet_loss_and_window_shrink(&reverse, 5000, 1, 100, 65535, 0, &mut stats);
+        detect_packet_loss_and_window_shrink(&reverse, 5500, 1, 100, 65535, 0, &mut stats);
+        assert_eq!(stats.prometheus_metrics.warmup_suppressed_counter.get(), 3.0);
+        let default = Args::try_parse_from(["tcp_window_monitor", "-i", "eth0"]).unwrap();
+        assert_eq!(default.warmup_packets, 0);
+    }
+
+    #[test]
+    fn interval_counts_follow_the_mock_clock() {
+        let (clock, mut stats) = stats_with_mock_clock();
+        let connection = test_connection();
+
+        // 1つ目の間隔：欠損1回
+        detect_packet_loss_and_window_shrink(&connection, 1000, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(200));
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(800));
+
+        let counts = collect_interval_counts(&stats, clock.now_instant());
+        assert_eq!(counts.missing, 1);
+        assert_eq!(counts.total_loss(), 1);
+        assert_eq!(stats.packet_loss_events[0].timestamp, clock.now_utc() - chrono::Duration::milliseconds(800));
+        reset_interval_stats(&mut stats, clock.now_instant());
+
+        // 2つ目の間隔：重複1回のみ
+        clock.advance(Duration::from_millis(500));
+        detect_packet_loss_and_window_shrink(&connection, 1300, 1, 100, 65535, 0, &mut stats);
+        clock.advance(Duration::from_millis(500));
+
+        let counts = collect_interval_counts(&stats, clock.now_instant());
+        assert_eq!(counts.missing, 0);
+        assert_eq!(counts.duplicate, 1);
+        assert_eq!(stats.last_reset_time + Duration::from_secs(1), clock.now_instant());
+    }
+
+    /// 合成したTCPセグメントをライブキャプチャなしで検出器に直接流し込むハーネス
+    mod synthetic {
+        use super::*;
+
+        /// 1つの接続（データ側の向き）に対するシナリオ
+        struct Scenario {
+            clock: Arc<MockClock>,
+            stats: GlobalStats,
+            connection: TcpConnection,
+        }
+
+        /// シナリオ実行後のロス種別ごとのPrometheusカウンタ
+        #[derive(Debug, PartialEq)]
+        struct LossCounters {
+            missing: f64,
+            duplicate: f64,
+            out_of_order: f64,
+        }
+
+        impl Scenario {
+            fn new() -> Self {
+                let (clock, stats) = stats_with_mock_clock();
+                Self { clock, stats, connection: test_connection() }
+            }
+
+            /// (シーケンス番号, ペイロード長) のセグメントを10ミリ秒間隔で順に送る
+            fn send(&mut self, segments: &[(u32, u32)]) -> &mut Self {
+                for &(seq, len) in segments {
+                    detect_packet_loss_and_window_shrink(&self.connection, seq, 1, len, 65535, 0, &mut self.stats);
+                    self.clock.advance(Duration::from_millis(10));
+                }
+                self
+            }
+
+            /// 記録された (種別, 期待値, 受信値) の一覧
+            fn events(&self) -> Vec<(&'static str, u32, u32)> {
+                self.stats
+                    .packet_loss_events
+                    .iter()
+                    .map(|event| (event.loss_type.label(), event.expected_seq, event.received_seq))
+                    .collect()
+            }
+
+            fn counters(&self) -> LossCounters {
+                let metrics = &self.stats.prometheus_metrics;
+                LossCounters {
+                    missing: metrics.packet_loss_missing_counter.get(),
+                    duplicate: metrics.packet_loss_duplicate_counter.get(),
+                    out_of_order: metrics.packet_loss_out_of_order_counter.get(),
+                }
+            }
+
+            fn assert_counters(&self, missing: f64, duplicate: f64, out_of_order: f64) {
+                assert_eq!(self.counters(), LossCounters { missing, duplicate, out_of_order });
+            }
+        }
+
+        #[test]
+        fn clean_stream() {
+            let mut scenario = Scenario::new();
+            scenario.send(&[(1000, 100), (1100, 100), (1200, 100), (1300, 100)]);
+
+            assert!(scenario.events().is_empty());
+            scenario.assert_counters(0.0, 0.0, 0.0);
+            assert_eq!(direction_state(&scenario.stats, &scenario.connection).expected_seq, 1400);
+        }
+
+        #[test]
+        fn single_loss() {
+            let mut scenario = Scenario::new();
+            scenario.send(&[(1000, 100), (1100, 100), (1300, 100), (1400, 100)]);
+
+            assert_eq!(scenario.events(), vec![("missing", 1200, 1300)]);
+            assert_eq!(scenario.stats.packet_loss_events[0].gap_size, 100);
+            scenario.assert_counters(1.0, 0.0, 0.0);
+        }
+
+        #[test]
+        fn reorder() {
+            let mut scenario = Scenario::new();
+            // 1100が1200の後に届く。ACKが見えない接続ではギャップを直ちに欠損とし、
+            // 遅れて届いたセグメントは順序違いとして数える
+            scenario.send(&[(1000, 100), (1200, 100), (1100, 100), (1300, 100)]);
+
+            assert_eq!(scenario.events(), vec![("missing", 1100, 1200), ("out_of_order", 1300, 1100)]);
+            scenario.assert_counters(1.0, 0.0, 1.0);
+        }
+
+        #[test]
+        fn duplicate() {
+            let mut scenario = Scenario::new();
+            scenario.send(&[(1000, 100), (1100, 100), (1100, 100), (1200, 100)]);
+
+            assert_eq!(scenario.events(), vec![("duplicate", 1200, 1100)]);
+            scenario.assert_counters(0.0, 1.0, 0.0);
+        }
+
+        #[test]
+        fn wraparound() {
+            let mut scenario = Scenario::new();
+            let start = u32::MAX - 150;
+            let segments: Vec<(u32, u32)> = (0..5).map(|i| (start.wrapping_add(i * 100), 100)).collect();
+            scenario.send(&segments);
+
+            assert!(scenario.events().is_empty());
+            scenario.assert_counters(0.0, 0.0, 0.0);
+
+            // 折り返し後に1セグメント欠けた
+            scenario.send(&[(start.wrapping_add(600), 100)]);
+            assert_eq!(scenario.events(), vec![("missing", start.wrapping_add(500), start.wrapping_add(600))]);
+            scenario.assert_counters(1.0, 0.0, 0.0);
+        }
+    }
+}
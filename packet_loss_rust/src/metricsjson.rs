@@ -0,0 +1,173 @@
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// `GET /metrics/json` で返すメトリクスファミリー
+#[derive(Debug, Serialize, PartialEq)]
+pub struct JsonMetricFamily {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub help: String,
+    pub samples: Vec<JsonSample>,
+}
+
+/// 1つのサンプル（ヒストグラムはテキスト形式と同じく `_bucket` / `_sum` / `_count` に展開する）
+#[derive(Debug, Serialize, PartialEq)]
+pub struct JsonSample {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+/// `Registry::gather` の結果をJSONに変換できる形にする
+pub fn metric_families(families: &[MetricFamily]) -> Vec<JsonMetricFamily> {
+    families
+        .iter()
+        .map(|family| {
+            let name = family.get_name();
+            let kind = match family.get_field_type() {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "untyped",
+            };
+            let samples = family
+                .get_metric()
+                .iter()
+                .flat_map(|metric| samples(name, family.get_field_type(), metric))
+                .collect();
+            JsonMetricFamily {
+                name: name.to_string(),
+                kind,
+                help: family.get_help().to_string(),
+                samples,
+            }
+        })
+        .collect()
+}
+
+fn samples(name: &str, kind: MetricType, metric: &Metric) -> Vec<JsonSample> {
+    let labels: BTreeMap<String, String> = metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+        .collect();
+    let sample = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+        let mut labels = labels.clone();
+        labels.extend(extra.map(|(key, value)| (key.to_string(), value)));
+        JsonSample {
+            name: format!("{}{}", name, suffix),
+            labels,
+            value,
+        }
+    };
+    match kind {
+        MetricType::COUNTER => vec![sample("", None, metric.get_counter().get_value())],
+        MetricType::GAUGE => vec![sample("", None, metric.get_gauge().get_value())],
+        MetricType::UNTYPED => vec![sample("", None, metric.get_untyped().get_value())],
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let mut samples: Vec<JsonSample> = histogram
+                .get_bucket()
+                .iter()
+                .map(|bucket| {
+                    let le = bucket.get_upper_bound().to_string();
+                    sample(
+                        "_bucket",
+                        Some(("le", le)),
+                        bucket.get_cumulative_count() as f64,
+                    )
+                })
+                .collect();
+            let count = histogram.get_sample_count() as f64;
+            samples.push(sample("_bucket", Some(("le", "+Inf".to_string())), count));
+            samples.push(sample("_sum", None, histogram.get_sample_sum()));
+            samples.push(sample("_count", None, count));
+            samples
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            let mut samples: Vec<JsonSample> = summary
+                .get_quantile()
+                .iter()
+                .map(|quantile| {
+                    let q = quantile.get_quantile().to_string();
+                    sample("", Some(("quantile", q)), quantile.get_value())
+                })
+                .collect();
+            samples.push(sample("_sum", None, summary.get_sample_sum()));
+            samples.push(sample("_count", None, summary.get_sample_count() as f64));
+            samples
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{CounterVec, Histogram, HistogramOpts, Opts, Registry};
+
+    #[test]
+    fn families_are_converted_with_labels_and_histogram_buckets() {
+        let registry = Registry::new();
+        let losses = CounterVec::new(Opts::new("loss_total", "Losses"), &["type"]).unwrap();
+        let gap = Histogram::with_opts(HistogramOpts::new("gap", "Gap").buckets(vec![10.0, 100.0]))
+            .unwrap();
+        registry.register(Box::new(losses.clone())).unwrap();
+        registry.register(Box::new(gap.clone())).unwrap();
+        losses.with_label_values(&["missing"]).inc_by(3.0);
+        gap.observe(50.0);
+        gap.observe(500.0);
+
+        let families = metric_families(&registry.gather());
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[0].name, "gap");
+        assert_eq!(families[0].kind, "histogram");
+        let buckets: Vec<(&str, f64)> = families[0]
+            .samples
+            .iter()
+            .map(|sample| (sample.name.as_str(), sample.value))
+            .collect();
+        assert_eq!(
+            buckets,
+            [
+                ("gap_bucket", 0.0),
+                ("gap_bucket", 1.0),
+                ("gap_bucket", 2.0),
+                ("gap_sum", 550.0),
+                ("gap_count", 2.0)
+            ]
+        );
+        assert_eq!(families[0].samples[2].labels, labels(&[("le", "+Inf")]));
+        assert_eq!(
+            families[1],
+            JsonMetricFamily {
+                name: "loss_total".to_string(),
+                kind: "counter",
+                help: "Losses".to_string(),
+                samples: vec![JsonSample {
+                    name: "loss_total".to_string(),
+                    labels: labels(&[("type", "missing")]),
+                    value: 3.0,
+                }],
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&families[1]).unwrap(),
+            serde_json::json!({
+                "name": "loss_total",
+                "type": "counter",
+                "help": "Losses",
+                "samples": [{"name": "loss_total", "labels": {"type": "missing"}, "value": 3.0}]
+            })
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use log::warn;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// パケットを観測しなくなった送信元・フローを忘れるまでの時間
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 送信元ごとに観測したTTLの分布
+#[derive(Debug, Clone)]
+struct TtlHistory {
+    counts: HashMap<u8, u64>,
+    last_seen: Instant,
+}
+
+impl TtlHistory {
+    /// 最も多く観測したTTL（同数の場合は小さい方）
+    fn most_common(&self) -> Option<u8> {
+        self.counts
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(ttl, _)| *ttl)
+    }
+}
+
+/// TTLの変化による経路変化の簡易検出器
+///
+/// 同じフローのパケットのTTLは経路上のホップ数で決まるため、通常は一定になる。
+/// 直前のパケットから `threshold` を超えて変わった場合は経路の変化か、なりすましを疑う。
+#[derive(Debug, Clone)]
+pub struct RouteChangeDetector {
+    threshold: u8,
+    sources: HashMap<String, TtlHistory>,
+    // フロー（向きのある接続のキー）ごとの直前のTTLと観測時刻
+    flows: HashMap<String, (u8, Instant)>,
+}
+
+impl Default for RouteChangeDetector {
+    fn default() -> Self {
+        Self::new(2)
+    }
+}
+
+impl RouteChangeDetector {
+    /// TTLが `threshold` を超えて変わった場合に経路変化とみなす検出器を作成
+    pub fn new(threshold: u8) -> Self {
+        Self {
+            threshold,
+            sources: HashMap::new(),
+            flows: HashMap::new(),
+        }
+    }
+
+    /// パケットのTTLを記録し、同じフローの直前のパケットからのTTLの変化がしきい値を超えたらtrueを返す
+    pub fn observe(&mut self, src_ip: &str, flow: &str, ttl: u8, now: Instant) -> bool {
+        let history = self
+            .sources
+            .entry(src_ip.to_string())
+            .or_insert_with(|| TtlHistory {
+                counts: HashMap::new(),
+                last_seen: now,
+            });
+        // 変化を判定する前の分布で、この送信元の普段のTTLを求める
+        let usual_ttl = history.most_common();
+        *history.counts.entry(ttl).or_insert(0) += 1;
+        history.last_seen = now;
+
+        let Some((previous, _)) = self.flows.insert(flow.to_string(), (ttl, now)) else {
+            return false;
+        };
+        if previous.abs_diff(ttl) <= self.threshold {
+            return false;
+        }
+        warn!(
+            "経路変化の疑い: {} のTTLが {} から {} に変わりました（フロー {}、普段のTTL {}）",
+            src_ip,
+            previous,
+            ttl,
+            flow,
+            usual_ttl.unwrap_or(previous)
+        );
+        true
+    }
+
+    /// 一定時間パケットを観測していない送信元とフローを削除する
+    pub fn prune(&mut self, now: Instant) {
+        self.sources
+            .retain(|_, history| now.duration_since(history.last_seen) < IDLE_TIMEOUT);
+        self.flows
+            .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < IDLE_TIMEOUT);
+    }
+}
@@ -0,0 +1,129 @@
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// 送信元ごとのSYN送信状況（1ウィンドウ分）
+#[derive(Debug, Clone)]
+struct SourceActivity {
+    window_start: Instant,
+    // SYNを送った宛先（IP, ポート）
+    syn_targets: HashSet<(String, u16)>,
+    // ハンドシェイクが完了した宛先
+    established: HashSet<(String, u16)>,
+}
+
+impl SourceActivity {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            syn_targets: HashSet::new(),
+            established: HashSet::new(),
+        }
+    }
+
+    /// ハンドシェイクが完了していない宛先の数
+    fn unanswered_targets(&self) -> usize {
+        self.syn_targets.len() - self.established.len()
+    }
+}
+
+/// SYNフラッド・ポートスキャンの簡易検出器
+///
+/// 1つの送信元が短いウィンドウ内に多数の異なる宛先（IP, ポート）へSYNを送り、
+/// そのほとんどでハンドシェイクを完了させていない場合にスキャンとみなす。
+#[derive(Debug, Clone)]
+pub struct ScanDetector {
+    window: Duration,
+    threshold: usize,
+    sources: HashMap<String, SourceActivity>,
+}
+
+impl Default for ScanDetector {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10), 100)
+    }
+}
+
+impl ScanDetector {
+    /// `window` 内に `threshold` 件以上の未完了宛先があればスキャンとみなす検出器を作成
+    pub fn new(window: Duration, threshold: usize) -> Self {
+        Self {
+            window,
+            threshold: threshold.max(1),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// TCPパケットのフラグを検査して送信元の状態を更新
+    pub fn observe(
+        &mut self,
+        src_ip: &str,
+        dst_ip: &str,
+        dst_port: u16,
+        syn: bool,
+        ack: bool,
+        now: Instant,
+    ) {
+        if syn && !ack {
+            // 接続開始のSYN
+            let window = self.window;
+            let activity = self
+                .sources
+                .entry(src_ip.to_string())
+                .or_insert_with(|| SourceActivity::new(now));
+            if now.duration_since(activity.window_start) >= window {
+                *activity = SourceActivity::new(now);
+            }
+
+            if activity.syn_targets.insert((dst_ip.to_string(), dst_port))
+                && activity.unanswered_targets() == self.threshold
+            {
+                warn!(
+                    "スキャンの疑い: {} が{}秒以内に{}件の宛先へ未完了のSYNを送信しました",
+                    src_ip,
+                    window.as_secs(),
+                    self.threshold
+                );
+            }
+        } else if ack && !syn {
+            // 3ウェイハンドシェイク最後のACK
+            if let Some(activity) = self.sources.get_mut(src_ip) {
+                let target = (dst_ip.to_string(), dst_port);
+                if activity.syn_targets.contains(&target) {
+                    activity.established.insert(target);
+                }
+            }
+        }
+    }
+
+    /// 期限切れの送信元を削除し、送信元ごとのハンドシェイクが完了していない宛先を `unanswered` に加える
+    ///
+    /// `--workers` では接続ごとに解析するワーカーが分かれ、1つの送信元のSYNが複数の検出器に記録される。
+    /// すべての検出器の宛先を合わせてから `suspected_sources` でしきい値と比べる（`observe` の警告は検出器ごとの宛先数で出す）。
+    pub fn collect_unanswered(
+        &mut self,
+        now: Instant,
+        unanswered: &mut HashMap<String, HashSet<(String, u16)>>,
+    ) {
+        let window = self.window;
+        self.sources
+            .retain(|_, activity| now.duration_since(activity.window_start) < window);
+
+        for (src_ip, activity) in &self.sources {
+            let targets = activity.syn_targets.difference(&activity.established).cloned();
+            unanswered.entry(src_ip.clone()).or_default().extend(targets);
+        }
+    }
+
+    /// `unanswered` のうち、しきい値を超えている送信元と未完了宛先数を返す
+    pub fn suspected_sources(
+        &self,
+        unanswered: &HashMap<String, HashSet<(String, u16)>>,
+    ) -> Vec<(String, usize)> {
+        unanswered
+            .iter()
+            .filter(|(_, targets)| targets.len() >= self.threshold)
+            .map(|(src_ip, targets)| (src_ip.clone(), targets.len()))
+            .collect()
+    }
+}
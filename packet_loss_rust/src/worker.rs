@@ -0,0 +1,99 @@
+use std::io;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+/// 固定数のワーカースレッドに、それぞれの容量制限付きキューから項目を処理させるプール
+///
+/// 項目は `dispatch` に渡したシャードの値でワーカーを選ぶため、同じシャードの項目は
+/// 常に同じワーカーが到着順に処理する（接続ごとのシーケンスの順序が保たれる）。
+pub struct WorkerPool<T> {
+    senders: Vec<SyncSender<T>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// `workers` 個のスレッドを起動する（各キューには `queue_capacity` 個まで溜められる）
+    ///
+    /// 処理は `make_handler` でワーカーごとに作るため、ワーカーは他のワーカーと共有しない状態を持てる。
+    pub fn spawn<H>(
+        workers: usize,
+        queue_capacity: usize,
+        mut make_handler: impl FnMut(usize) -> H,
+    ) -> io::Result<Self>
+    where
+        H: FnMut(T) + Send + 'static,
+    {
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let (sender, receiver) = mpsc::sync_channel(queue_capacity);
+            let mut handler = make_handler(index);
+            let handle = thread::Builder::new()
+                .name(format!("analysis-{}", index))
+                .spawn(move || {
+                    for item in receiver {
+                        handler(item);
+                    }
+                })?;
+            senders.push(sender);
+            handles.push(handle);
+        }
+        Ok(Self { senders, handles })
+    }
+
+    /// `shard` で選んだワーカーのキューに項目を入れる
+    ///
+    /// キャプチャを止めないよう待たずに渡し、キューが満杯の場合は捨ててfalseを返す。
+    pub fn dispatch(&self, shard: u64, item: T) -> bool {
+        let sender = &self.senders[(shard % self.senders.len() as u64) as usize];
+        match sender.try_send(item) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// キューに残っている項目をすべて処理し終えるまで待つ
+    pub fn finish(self) {
+        drop(self.senders);
+        for handle in self.handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn items_of_a_shard_are_handled_in_order_and_full_queues_drop() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let pool = WorkerPool::spawn(1, 1, |_| {
+            let handled = Arc::clone(&handled);
+            let started_tx = started_tx.clone();
+            let release_rx = Arc::clone(&release_rx);
+            move |item: u32| {
+                // 最初の項目の処理中はワーカーを止め、キューを満杯にする
+                if item == 1 {
+                    started_tx.send(()).unwrap();
+                    release_rx.lock().unwrap().recv().unwrap();
+                }
+                handled.lock().unwrap().push(item);
+            }
+        })
+        .unwrap();
+
+        assert!(pool.dispatch(7, 1));
+        started_rx.recv().unwrap();
+        assert!(pool.dispatch(7, 2));
+        assert!(!pool.dispatch(7, 3));
+        release_tx.send(()).unwrap();
+        // 終了時はキューに残った項目を処理してから戻る
+        pool.finish();
+        assert_eq!(*handled.lock().unwrap(), [1, 2]);
+    }
+}
@@ -0,0 +1,156 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Prometheusテキスト形式の1サンプル
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// スクレイプした全サンプル
+#[derive(Debug, Default)]
+pub struct Scrape {
+    pub samples: Vec<Sample>,
+}
+
+impl Scrape {
+    /// 指定した名前の全系列の合計（系列がなければNone）
+    pub fn sum(&self, name: &str) -> Option<f64> {
+        let mut values = self
+            .samples
+            .iter()
+            .filter(|sample| sample.name == name)
+            .map(|sample| sample.value)
+            .peekable();
+        values.peek()?;
+        Some(values.sum())
+    }
+}
+
+/// Prometheusのテキスト形式（exposition format）を解析する
+///
+/// コメント・HELP・TYPE行と、解釈できない行は読み飛ばす。
+pub fn parse(text: &str) -> Scrape {
+    let samples = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(parse_sample)
+        .collect();
+    Scrape { samples }
+}
+
+fn parse_sample(line: &str) -> Option<Sample> {
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+
+    let mut labels = Vec::new();
+    if let Some(body) = rest.strip_prefix('{') {
+        let (parsed, after) = parse_labels(body)?;
+        labels = parsed;
+        rest = after;
+    }
+
+    // 値の後ろのタイムスタンプは使わない
+    let value = match rest.split_whitespace().next()? {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        value => value.parse().ok()?,
+    };
+    Some(Sample { name, labels, value })
+}
+
+/// `{` の直後から `}` までのラベルを解析し、残りの文字列とともに返す
+fn parse_labels(mut body: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let mut labels = Vec::new();
+    loop {
+        body = body.trim_start_matches([' ', ',']);
+        if let Some(rest) = body.strip_prefix('}') {
+            return Some((labels, rest));
+        }
+
+        let (key, rest) = body.split_once('=')?;
+        let mut chars = rest.strip_prefix('"')?.char_indices();
+        let mut value = String::new();
+        let end = loop {
+            match chars.next()? {
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    escaped => value.push(escaped),
+                },
+                (index, '"') => break index,
+                (_, c) => value.push(c),
+            }
+        };
+        labels.push((key.trim().to_string(), value));
+        body = &rest[1 + end + 1..];
+    }
+}
+
+/// `http://host:port/path` 形式のURLからメトリクスを取得する
+///
+/// ローカルのモニタを読むだけなのでHTTP/1.0の平文GETのみに対応する。
+pub fn scrape(url: &str, timeout: Duration) -> Result<Scrape, Box<dyn std::error::Error>> {
+    let target = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("http:// のURLのみ対応しています: {}", url))?;
+    let (host, path) = match target.find('/') {
+        Some(index) => (&target[..index], &target[index..]),
+        None => (target, "/metrics"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = TcpStream::connect(&address)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("HTTPレスポンスを解釈できません")?;
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("メトリクスの取得に失敗しました: {}", status_line).into());
+    }
+
+    Ok(parse(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_labels_and_skips_comments() {
+        let scrape = parse(
+            "# HELP total_tx_bytes_rate Total transmission rate\n\
+             # TYPE total_tx_bytes_rate gauge\n\
+             total_tx_bytes_rate 1250\n\
+             local_ip_tx_bytes_rate{local_ip=\"192.168.1.10\"} 1000 1700000000000\n\
+             local_ip_tx_bytes_rate{local_ip=\"_other\",note=\"a \\\"b\\\"\"} 250\n\
+             broken_line\n",
+        );
+
+        assert_eq!(scrape.samples.len(), 3);
+        assert_eq!(scrape.sum("total_tx_bytes_rate"), Some(1250.0));
+        assert_eq!(scrape.sum("local_ip_tx_bytes_rate"), Some(1250.0));
+        assert_eq!(scrape.sum("missing"), None);
+        assert_eq!(
+            scrape.samples[2].labels,
+            vec![
+                ("local_ip".to_string(), "_other".to_string()),
+                ("note".to_string(), "a \"b\"".to_string()),
+            ]
+        );
+    }
+}
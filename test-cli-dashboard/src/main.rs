@@ -1,13 +1,104 @@
+mod exposition;
+
+use clap::Parser;
 use ratatui::{
     backend::CrosstermBackend,
+    Frame,
     Terminal,
     widgets::Paragraph,
     layout::{Layout, Constraint, Direction},
-    style::{Style, Color},
+    style::{Style, Color, Modifier},
 };
-use crossterm::{execute, terminal::{enable_raw_mode, disable_raw_mode}};
+use crossterm::{event::{self, Event, KeyCode}, execute, terminal::{enable_raw_mode, disable_raw_mode}};
 use std::{io, time::{Duration, Instant}};
 
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Scrape this Prometheus endpoint and show live values (e.g. http://localhost:59121/metrics);
+    /// without it the demo with random numbers is shown
+    #[arg(long)]
+    metrics_url: Option<String>,
+
+    /// Seconds between scrapes in live mode
+    #[arg(long, default_value = "1")]
+    refresh_interval: u64,
+
+    /// Percentage of dropped captured packets at which the capture health row turns red;
+    /// with the default of 0 any drop turns it red, otherwise drops below it show yellow
+    #[arg(long, default_value = "0")]
+    drop_threshold: f64,
+}
+
+/// キャプチャしたパケット数と処理しきれずに捨てたパケット数（モニタのカウンタ）
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct CaptureCounters {
+    captured: f64,
+    dropped: f64,
+}
+
+impl CaptureCounters {
+    /// モニタがキャプチャ数を出していない場合はNone
+    fn from_scrape(scrape: &exposition::Scrape) -> Option<Self> {
+        Some(Self {
+            captured: scrape.sum("captured_packets_total")?,
+            dropped: scrape.sum("channel_dropped_total").unwrap_or(0.0),
+        })
+    }
+}
+
+/// キャプチャの健全性の行と色（前回のスクレイプからの差分で判定する）
+///
+/// モニタ自身がパケットを捨てている間はその下のロス率なども信頼できないため、
+/// 1つでも捨てていれば緑以外の色にする。
+fn health_line(current: Option<CaptureCounters>, previous: Option<CaptureCounters>, threshold_percent: f64) -> (String, Style) {
+    let Some(current) = current else {
+        return ("Capture health: n/a".to_string(), Style::default().fg(Color::DarkGray));
+    };
+
+    // 初回やモニタの再起動でカウンタが戻った場合は起動からの累計で判定する
+    let baseline = previous
+        .filter(|previous| previous.captured <= current.captured && previous.dropped <= current.dropped)
+        .unwrap_or_default();
+    let captured = current.captured - baseline.captured;
+    let dropped = current.dropped - baseline.dropped;
+    let percent = if captured > 0.0 { dropped / captured * 100.0 } else { 0.0 };
+
+    let color = if dropped == 0.0 {
+        Color::Green
+    } else if percent >= threshold_percent {
+        Color::Red
+    } else {
+        Color::Yellow
+    };
+    let text = format!("Capture health: dropped {:.0} / captured {:.0} ({:.2} %)", dropped, captured, percent);
+    (text, Style::default().fg(color).add_modifier(Modifier::BOLD))
+}
+
+/// タイトル・キャプチャの健全性の行・各行を縦に並べて描画する
+fn draw_lines(f: &mut Frame, title_text: &str, health: &(String, Style), lines: &[String], style: Style) {
+    let mut constraints = vec![Constraint::Length(2), Constraint::Length(2)];
+    for _ in lines {
+        constraints.push(Constraint::Length(2));
+    }
+    constraints.push(Constraint::Min(0));
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints(constraints)
+        .split(f.area());
+
+    let title = Paragraph::new(title_text.to_string())
+        .style(Style::default().fg(Color::Yellow));
+    f.render_widget(title, chunks[0]);
+    f.render_widget(Paragraph::new(health.0.clone()).style(health.1), chunks[1]);
+
+    for (i, line) in lines.iter().enumerate() {
+        f.render_widget(Paragraph::new(line.clone()).style(style), chunks[i + 2]);
+    }
+}
+
 /// CLI ダッシュボードを表示する関数
 pub fn cli_dashboard(title_text:&str, count: usize, data_list: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
     enable_raw_mode()?;
@@ -21,29 +112,9 @@ pub fn cli_dashboard(title_text:&str, count: usize, data_list: Vec<String>) -> R
 
     while start_time.elapsed() < duration {
         terminal.draw(|f| {
-            let size = f.area();
-            
-            let mut constraints = vec![Constraint::Length(2)];
-            for _ in 0..count.min(data_list.len()) {
-                constraints.push(Constraint::Length(2));
-            }
-            constraints.push(Constraint::Min(0));
-            
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .margin(1)
-                .constraints(constraints)
-                .split(size);
-
-            let title = Paragraph::new(title_text.to_string())
-                .style(Style::default().fg(Color::Yellow));
-            f.render_widget(title, chunks[0]);
-
-            for i in 0..count.min(data_list.len()) {
-                let widget = Paragraph::new(data_list[i].clone())
-                    .style(Style::default().fg(Color::Green));
-                f.render_widget(widget, chunks[i + 1]);
-            }
+            let lines = &data_list[..count.min(data_list.len())];
+            let health = ("Capture health: n/a (demo data)".to_string(), Style::default().fg(Color::DarkGray));
+            draw_lines(f, title_text, &health, lines, Style::default().fg(Color::Green));
         })?;
 
         std::thread::sleep(Duration::from_millis(500));
@@ -56,7 +127,97 @@ pub fn cli_dashboard(title_text:&str, count: usize, data_list: Vec<String>) -> R
     Ok(())
 }
 
+/// 数値（bps単位）を適切な単位（bps, Kbps, Mbps, Gbps）に変換して文字列で返す
+fn format_bps(value: f64) -> String {
+    const KBPS: f64 = 1_000.0;
+    const MBPS: f64 = 1_000_000.0;
+    const GBPS: f64 = 1_000_000_000.0;
+
+    if value >= GBPS {
+        format!("{:.2} Gbps", value / GBPS)
+    } else if value >= MBPS {
+        format!("{:.2} Mbps", value / MBPS)
+    } else if value >= KBPS {
+        format!("{:.2} Kbps", value / KBPS)
+    } else {
+        format!("{:.0} bps", value)
+    }
+}
+
+/// 接続数として表示する系列（先に見つかったもの）
+///
+/// packet_loss_rust は直近に観測した接続数を、Network-Traffic-Monitor はアイドルで削除されるまでの接続状態の数を出す。
+const ACTIVE_CONNECTION_METRICS: [&str; 2] = ["tcp_monitor_active_connections", "tcp_monitor_connection_map_size"];
+
+/// スクレイプ結果から主要な系列を表示用の行にする（モニタが出していない系列は n/a）
+fn live_lines(scrape: &exposition::Scrape) -> Vec<String> {
+    let rate = |name| scrape.sum(name).map_or("n/a".to_string(), |bytes| format_bps(bytes * 8.0));
+    let value = |name, format: fn(f64) -> String| scrape.sum(name).map_or("n/a".to_string(), format);
+    let connections = ACTIVE_CONNECTION_METRICS
+        .iter()
+        .find_map(|name| scrape.sum(name))
+        .map_or("n/a".to_string(), |v| format!("{:.0}", v));
+
+    vec![
+        format!("TX: {}", rate("total_tx_bytes_rate")),
+        format!("RX: {}", rate("total_rx_bytes_rate")),
+        format!("Loss: {}", value("tcp_connection_loss_percentage_weighted", |v| format!("{:.2} %", v))),
+        format!("Active connections: {}", connections),
+    ]
+}
+
+/// `metrics_url` を定期的にスクレイプして表示し続ける（q または Esc で終了）
+fn live_dashboard(metrics_url: &str, refresh_interval: Duration, drop_threshold: f64) -> Result<(), Box<dyn std::error::Error>> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, crossterm::terminal::EnterAlternateScreen)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut previous_counters = None;
+        loop {
+            let (lines, style, health) = match exposition::scrape(metrics_url, refresh_interval) {
+                Ok(scrape) => {
+                    let counters = CaptureCounters::from_scrape(&scrape);
+                    let health = health_line(counters, previous_counters, drop_threshold);
+                    previous_counters = counters;
+                    (live_lines(&scrape), Style::default().fg(Color::Green), health)
+                }
+                Err(e) => (
+                    vec![format!("Scrape failed: {}", e)],
+                    Style::default().fg(Color::Red),
+                    health_line(None, None, drop_threshold),
+                ),
+            };
+            terminal.draw(|f| draw_lines(f, metrics_url, &health, &lines, style))?;
+
+            // 次のスクレイプまでキー入力を待つ
+            let next_scrape = Instant::now() + refresh_interval;
+            while let Some(remaining) = next_scrape.checked_duration_since(Instant::now()) {
+                if event::poll(remaining)?
+                    && let Event::Key(key) = event::read()?
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    })();
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), crossterm::terminal::LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    result
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    if let Some(metrics_url) = &args.metrics_url {
+        return live_dashboard(metrics_url, Duration::from_secs(args.refresh_interval.max(1)), args.drop_threshold);
+    }
+
     // 使用例：cli_dashboard(データ数, データリスト)
     for i in 0..5 {
         let value1 = [rand::random::<u32>() % 100, (i * 10) as u32];
@@ -79,4 +240,46 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // cli_dashboard("System Monitor", 4, custom_data)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(captured: f64, dropped: f64) -> Option<CaptureCounters> {
+        Some(CaptureCounters { captured, dropped })
+    }
+
+    #[test]
+    fn health_row_flips_color_on_drops_since_the_last_scrape() {
+        let (text, style) = health_line(counters(1000.0, 0.0), None, 0.0);
+        assert_eq!(text, "Capture health: dropped 0 / captured 1000 (0.00 %)");
+        assert_eq!(style.fg, Some(Color::Green));
+
+        // 前回から1000パケット中10パケットを捨てた
+        let (text, style) = health_line(counters(2000.0, 10.0), counters(1000.0, 0.0), 0.0);
+        assert_eq!(text, "Capture health: dropped 10 / captured 1000 (1.00 %)");
+        assert_eq!(style.fg, Some(Color::Red));
+        let (_, style) = health_line(counters(2000.0, 10.0), counters(1000.0, 0.0), 5.0);
+        assert_eq!(style.fg, Some(Color::Yellow));
+
+        // 以前の取りこぼしは次の間隔に持ち越さない
+        let (_, style) = health_line(counters(3000.0, 10.0), counters(2000.0, 10.0), 0.0);
+        assert_eq!(style.fg, Some(Color::Green));
+
+        let (text, _) = health_line(None, None, 0.0);
+        assert_eq!(text, "Capture health: n/a");
+    }
+
+    #[test]
+    fn active_connections_come_from_either_monitor() {
+        let ntm = exposition::parse("total_tx_bytes_rate 125000\ntcp_monitor_connection_map_size 7\n");
+        let lines = live_lines(&ntm);
+        assert_eq!(lines[0], "TX: 1.00 Mbps");
+        assert_eq!(lines[3], "Active connections: 7");
+
+        let plr = exposition::parse("tcp_monitor_active_connections 3\n");
+        assert_eq!(live_lines(&plr)[3], "Active connections: 3");
+        assert_eq!(live_lines(&exposition::parse(""))[3], "Active connections: n/a");
+    }
+}
@@ -1,5 +1,6 @@
-use reqwest::Client;
+use reqwest::{Client, Response};
 use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use crate::types::*;
 
 #[derive(Clone)]
@@ -9,9 +10,18 @@ pub struct PrometheusClient {
 }
 
 impl PrometheusClient {
+    /// デフォルト設定の `Client` でクライアントを作成する
     pub fn new(prometheus_url: &str) -> Self {
+        Self::with_http_client(Client::new(), prometheus_url)
+    }
+
+    /// 作成済みの `Client` を使うクライアントを作成する
+    ///
+    /// `Client` は内部でコネクションプールを共有するため、複数のPrometheusに接続する場合も
+    /// 同じ `Client` を渡せば接続が再利用される。プロキシ・TLS・プール上限などは `Client` 側で設定する。
+    pub fn with_http_client(client: Client, prometheus_url: &str) -> Self {
         Self {
-            client: Client::new(),
+            client,
             base_url: prometheus_url.to_string(),
         }
     }
@@ -27,8 +37,22 @@ impl PrometheusClient {
             .send()
             .await?;
 
-        let prometheus_response: PrometheusResponse = response.json().await?;
-        Ok(prometheus_response)
+        parse_response(response).await
+    }
+
+    /// 式を即時クエリで評価し、アラートとして発火しているか（0以外の結果があるか）を返す
+    ///
+    /// 空のvector、0、NaNの結果は発火していないものとして扱う。
+    pub async fn check_alert(&self, expr: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let response = self.query(expr).await?;
+        if response.status != "success" {
+            return Err(format!("クエリが失敗しました ({}): {}", response.status, expr).into());
+        }
+        if response.data.result_type == "string" {
+            return Err(format!("文字列の結果は評価できません: {}", expr).into());
+        }
+
+        Ok(response.data.has_nonzero_value())
     }
 
     /// 範囲クエリを実行（時系列データを取得）
@@ -53,8 +77,7 @@ impl PrometheusClient {
             .send()
             .await?;
 
-        let prometheus_response: PrometheusResponse = response.json().await?;
-        Ok(prometheus_response)
+        parse_response(response).await
     }
 
     /// 利用可能なメトリクス名を取得
@@ -62,7 +85,7 @@ impl PrometheusClient {
         let url = format!("{}/api/v1/label/__name__/values", self.base_url);
         
         let response = self.client.get(&url).send().await?;
-        let label_response: LabelResponse = response.json().await?;
+        let label_response: LabelResponse = parse_response(response).await?;
         Ok(label_response.data)
     }
 
@@ -71,7 +94,144 @@ impl PrometheusClient {
         let url = format!("{}/api/v1/label/{}/values", self.base_url, label);
         
         let response = self.client.get(&url).send().await?;
-        let label_response: LabelResponse = response.json().await?;
+        let label_response: LabelResponse = parse_response(response).await?;
         Ok(label_response.data)
     }
 }
+
+/// レスポンスをデコードする（エラーレスポンスは `errorType` と `error` を含むエラーにする）
+async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T, Box<dyn std::error::Error>> {
+    let http_status = response.status();
+    let body = response.text().await?;
+
+    if let Ok(error) = serde_json::from_str::<PrometheusErrorResponse>(&body) {
+        return Err(format!("Prometheusがエラーを返しました ({}, {}): {}", http_status, error.error_type, error.error).into());
+    }
+    if !http_status.is_success() {
+        return Err(format!("Prometheusがエラーを返しました ({}): {}", http_status, body).into());
+    }
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Server, StatusCode};
+    use std::convert::Infallible;
+
+    /// `query` パラメータに応じて固定のJSONを返す
+    fn canned_response(path: &str, query: &str) -> (StatusCode, &'static str) {
+        match (path, query) {
+            ("/api/v1/query", "up") => (
+                StatusCode::OK,
+                r#"{"status":"success","data":{"resultType":"vector","result":[
+                    {"metric":{"__name__":"up","job":"node"},"value":[1700000000.0,"1"]}
+                ]}}"#,
+            ),
+            ("/api/v1/query", "absent_metric") => (
+                StatusCode::OK,
+                r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#,
+            ),
+            ("/api/v1/query_range", "up") => (
+                StatusCode::OK,
+                r#"{"status":"success","data":{"resultType":"matrix","result":[
+                    {"metric":{"__name__":"up"},"values":[[1700000000.0,"1"],[1700000015.0,"0"]]}
+                ]}}"#,
+            ),
+            _ => (
+                StatusCode::BAD_REQUEST,
+                r#"{"status":"error","errorType":"bad_data","error":"invalid parameter \"query\""}"#,
+            ),
+        }
+    }
+
+    /// ランダムなポートでPrometheus APIのモックサーバーを起動し、そのURLを返す
+    async fn start_mock_server() -> String {
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let query = req
+                    .uri()
+                    .query()
+                    .unwrap_or_default()
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("query="))
+                    .unwrap_or_default()
+                    .to_string();
+                let (status, body) = canned_response(req.uri().path(), &query);
+                Ok::<_, Infallible>(
+                    hyper::Response::builder()
+                        .status(status)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+            }))
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn query_parses_vector_values() {
+        let client = PrometheusClient::new(&start_mock_server().await);
+
+        let response = client.query("up").await.unwrap();
+        assert_eq!(response.status, "success");
+        let series = response.data.series();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].metric["job"], "node");
+        assert_eq!(series[0].value.as_ref().and_then(PrometheusValue::as_f64), Some(1.0));
+        assert!(client.check_alert("up").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn query_handles_empty_results() {
+        let client = PrometheusClient::new(&start_mock_server().await);
+
+        let response = client.query("absent_metric").await.unwrap();
+        assert!(response.data.series().is_empty());
+        assert!(!client.check_alert("absent_metric").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn query_range_parses_matrix_values() {
+        let client = PrometheusClient::new(&start_mock_server().await);
+        let end = Utc::now();
+
+        let response = client
+            .query_range("up", end - chrono::Duration::seconds(15), end, "15s")
+            .await
+            .unwrap();
+        assert_eq!(response.data.result_type, "matrix");
+        let values = response.data.series()[0].values.as_ref().unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[1].as_f64(), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn clients_can_share_a_prebuilt_http_client() {
+        let http_client = Client::builder()
+            .pool_max_idle_per_host(1)
+            .build()
+            .unwrap();
+        let first = PrometheusClient::with_http_client(http_client.clone(), &start_mock_server().await);
+        let second = PrometheusClient::with_http_client(http_client, &start_mock_server().await);
+
+        assert!(first.check_alert("up").await.unwrap());
+        assert!(!second.check_alert("absent_metric").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_surfaced() {
+        let client = PrometheusClient::new(&start_mock_server().await);
+
+        let error = client.query("rate(").await.unwrap_err().to_string();
+        assert!(error.contains("bad_data"), "{}", error);
+        assert!(error.contains("invalid parameter"), "{}", error);
+    }
+}
@@ -1,48 +1,99 @@
 use prometheus_client::PrometheusClient;
 use chrono::Utc;
+use clap::Parser;
+use std::path::PathBuf;
 use tokio::time::{sleep, Duration};
 
+/// `--queries-file` を指定しない場合の監視対象のメトリクス
+const DEFAULT_QUERIES: &[&str] = &[
+    r#"{job="rust-app", __name__="total_tx_bytes_rate"}"#,
+    r#"{job="rust-app", __name__="total_rx_bytes_rate"}"#,
+];
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Prometheusのクエリ結果を定期的に表示する")]
+struct Args {
+    /// 1行に1つのPromQLを書いたファイル（空行と # で始まる行は無視する）
+    #[arg(long, value_name = "PATH")]
+    queries_file: Option<PathBuf>,
+
+    /// 全クエリを1回だけ実行して終了する（失敗したクエリがあれば終了コード1）
+    #[arg(long)]
+    once: bool,
+}
+
+/// クエリファイルの内容からPromQLの一覧を取り出す
+fn parse_queries(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
     let prometheus_url = "http://localhost:9090";
     let client = PrometheusClient::new(prometheus_url);
     
     // 監視対象のメトリクス
-    let metrics = vec![
-        r#"{job="rust-app", __name__="total_tx_bytes_rate"}"#,
-        r#"{job="rust-app", __name__="total_rx_bytes_rate"}"#,
-    ];
+    let metrics = match &args.queries_file {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("クエリファイル '{}' を読み込めません: {}", path.display(), e))?;
+            let queries = parse_queries(&text);
+            if queries.is_empty() {
+                return Err(format!("クエリファイル '{}' にクエリがありません", path.display()).into());
+            }
+            queries
+        }
+        None => DEFAULT_QUERIES.iter().map(|query| query.to_string()).collect(),
+    };
     
-    println!("メトリクス監視を開始します (Ctrl+C で停止)...\n");
+    if !args.once {
+        println!("メトリクス監視を開始します (Ctrl+C で停止)...\n");
+    }
     
     loop {
         let timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
         println!("=== {} ===", timestamp);
         
+        let mut failed = 0;
         for metric_query in &metrics {
             match client.query(metric_query).await {
                 Ok(response) => {
-                    if response.data.result.is_empty() {
+                    if response.data.series().is_empty() {
                         println!("{}の結果が見つかりませんでした", metric_query);
                     } else {
-                        for result in &response.data.result {
-                            if let Some(metric_name) = result.metric.get("__name__") {
-                                if let Some(value) = &result.value {
-                                    println!("{}: {}", metric_name, value.1);
-                                } else {
-                                    println!("{}: 値なし", metric_name);
-                                }
+                        for result in response.data.series() {
+                            // rate()などの式の結果には __name__ がないためクエリ自体を表示する
+                            let metric_name = result.metric.get("__name__").unwrap_or(metric_query);
+                            if let Some(value) = &result.value {
+                                println!("{}: {}", metric_name, value.1);
+                            } else {
+                                println!("{}: 値なし", metric_name);
                             }
                         }
                     }
                 }
-                Err(e) => println!("クエリエラー ({}): {}", metric_query, e),
+                Err(e) => {
+                    failed += 1;
+                    println!("クエリエラー ({}): {}", metric_query, e);
+                }
             }
         }
         
         println!(); // 空行で区切る
         
-        // 0.1秒待機
+        if args.once {
+            if failed > 0 {
+                return Err(format!("{}件のクエリが失敗しました", failed).into());
+            }
+            return Ok(());
+        }
+        
+        // 1秒待機
         sleep(Duration::from_millis(1000)).await;
     }
 }
@@ -51,10 +102,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 mod tests {
     use super::*;
 
-    #[tokio::test]
-    async fn test_prometheus_client_creation() {
-        let client = PrometheusClient::new("http://localhost:9090");
-        // 基本的なテスト
-        assert!(true);
+    #[test]
+    fn queries_file_skips_comments_and_blank_lines() {
+        let queries = parse_queries(
+            "# 送受信レート\n\
+             total_tx_bytes_rate\n\
+             \n\
+             \x20 rate(tcp_monitor_packet_loss_missing_total[5m])  \n\
+             # up\n",
+        );
+        assert_eq!(
+            queries,
+            vec![
+                "total_tx_bytes_rate".to_string(),
+                "rate(tcp_monitor_packet_loss_missing_total[5m])".to_string(),
+            ]
+        );
     }
 }
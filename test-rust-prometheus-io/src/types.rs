@@ -11,7 +11,50 @@ pub struct PrometheusResponse {
 pub struct PrometheusData {
     #[serde(rename = "resultType")]
     pub result_type: String,
-    pub result: Vec<PrometheusResult>,
+    pub result: QueryResult,
+}
+
+/// クエリ結果の本体（vector/matrixは系列の配列、scalar/stringは単一の値）
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum QueryResult {
+    Series(Vec<PrometheusResult>),
+    Scalar(PrometheusValue),
+}
+
+impl PrometheusData {
+    /// vector/matrixの系列（scalar/stringの場合は空）
+    pub fn series(&self) -> &[PrometheusResult] {
+        match &self.result {
+            QueryResult::Series(series) => series,
+            QueryResult::Scalar(_) => &[],
+        }
+    }
+
+    /// scalar/stringの値（vector/matrixの場合はNone）
+    pub fn scalar(&self) -> Option<&PrometheusValue> {
+        match &self.result {
+            QueryResult::Series(_) => None,
+            QueryResult::Scalar(value) => Some(value),
+        }
+    }
+
+    /// 結果に0以外の値が1つでも含まれるかどうか（アラートの発火判定に使う）
+    ///
+    /// vectorは各系列の値、matrixは各系列の最新の値、scalarはその値を見る。
+    /// 数値として解釈できない値とNaNは0と同じく発火していないものとして扱う。
+    pub fn has_nonzero_value(&self) -> bool {
+        match &self.result {
+            QueryResult::Series(series) => series.iter().any(|result| {
+                result
+                    .value
+                    .as_ref()
+                    .or_else(|| result.values.as_ref().and_then(|values| values.last()))
+                    .is_some_and(PrometheusValue::is_nonzero)
+            }),
+            QueryResult::Scalar(value) => value.is_nonzero(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -24,8 +67,53 @@ pub struct PrometheusResult {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PrometheusValue(pub f64, pub String);
 
+impl PrometheusValue {
+    /// 値を数値として取得（"NaN"、"+Inf" も解釈する）
+    pub fn as_f64(&self) -> Option<f64> {
+        self.1.parse().ok()
+    }
+
+    fn is_nonzero(&self) -> bool {
+        self.as_f64().is_some_and(|value| value != 0.0 && !value.is_nan())
+    }
+}
+
+/// クエリが失敗した場合のレスポンス（`data` を持たない）
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PrometheusErrorResponse {
+    pub status: String,
+    #[serde(rename = "errorType")]
+    pub error_type: String,
+    pub error: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LabelResponse {
     pub status: String,
     pub data: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scalar_and_vector_results_are_both_evaluated() {
+        let scalar: PrometheusResponse = serde_json::from_str(
+            r#"{"status":"success","data":{"resultType":"scalar","result":[1700000000.0,"2"]}}"#,
+        )
+        .unwrap();
+        assert!(scalar.data.series().is_empty());
+        assert!(scalar.data.has_nonzero_value());
+
+        let vector: PrometheusResponse = serde_json::from_str(
+            r#"{"status":"success","data":{"resultType":"vector","result":[
+                {"metric":{"__name__":"up"},"value":[1700000000.0,"0"]},
+                {"metric":{"__name__":"up"},"value":[1700000000.0,"NaN"]}
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(vector.data.series().len(), 2);
+        assert!(!vector.data.has_nonzero_value());
+    }
+}